@@ -4,6 +4,7 @@ use std::future::Future;
 use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
 use std::sync::{Arc, Mutex};
 use std::task::{Poll, Waker};
+use std::time::Duration;
 
 pub type ScheduleFunc = Box<dyn Fn(Task<()>) + Send + Sync + 'static>;
 
@@ -76,6 +77,13 @@ where
     TOKIO.spawn(future)
 }
 
+/// Returns a future that resolves once `duration` has elapsed.
+/// The timer is driven by the tokio runtime, so unlike sleeping in
+/// spawn_into_new_thread, no thread is tied up while waiting.
+pub async fn sleep(duration: Duration) {
+    let _ = tokio_spawn(async move { tokio::time::delay_for(duration).await }).await;
+}
+
 /// Spawn a new thread to execute the provided function.
 /// Returns a JoinHandle that implements the Future trait
 /// and that can be used to await and yield the return value
@@ -126,10 +126,12 @@ pub trait SlavePty {
 
 /// Represents the exit status of a child process.
 /// This is rather anemic in the current version of this crate,
-/// holding only an indicator of success or failure.
+/// holding only an indicator of success or failure and the
+/// exit code, if known.
 #[derive(Debug, Clone)]
 pub struct ExitStatus {
     successful: bool,
+    code: Option<u32>,
 }
 
 impl ExitStatus {
@@ -137,18 +139,26 @@ impl ExitStatus {
     pub fn with_exit_code(code: u32) -> Self {
         Self {
             successful: code == 0,
+            code: Some(code),
         }
     }
 
     pub fn success(&self) -> bool {
         self.successful
     }
+
+    /// Returns the exit code of the process, or None if it is not
+    /// known; eg: because the process was terminated by a signal
+    pub fn exit_code(&self) -> Option<u32> {
+        self.code
+    }
 }
 
 impl From<std::process::ExitStatus> for ExitStatus {
     fn from(status: std::process::ExitStatus) -> ExitStatus {
         ExitStatus {
             successful: status.success(),
+            code: status.code().map(|code| code as u32),
         }
     }
 }
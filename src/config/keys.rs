@@ -82,6 +82,49 @@ impl std::convert::TryInto<KeyAssignment> for &Key {
                     .parse()?,
             ),
             KeyAction::ShowTabNavigator => KeyAssignment::ShowTabNavigator,
+            KeyAction::ResyncTab => KeyAssignment::ResyncTab,
+            KeyAction::ShowRemoteTabInfo => KeyAssignment::ShowRemoteTabInfo,
+            KeyAction::ToggleFollowScroll => KeyAssignment::ToggleFollowScroll,
+            KeyAction::SearchRemoteScrollback => KeyAssignment::SearchRemoteScrollback(
+                self.arg
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing arg for {:?}", self))?
+                    .to_owned(),
+            ),
+            KeyAction::CopyRemoteScreen => KeyAssignment::CopyRemoteScreen,
+            KeyAction::SetScrollbackLines => KeyAssignment::SetScrollbackLines(
+                self.arg
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing arg for {:?}", self))?
+                    .parse()?,
+            ),
+            KeyAction::CancelPaste => KeyAssignment::CancelPaste,
+            KeyAction::CaptureRemoteOutput => KeyAssignment::CaptureRemoteOutput(
+                self.arg
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing arg for {:?}", self))?
+                    .to_owned(),
+            ),
+            KeyAction::ToggleReadOnly => KeyAssignment::ToggleReadOnly,
+            KeyAction::SetRemoteFetchRate => {
+                let arg = self
+                    .arg
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing arg for {:?}", self))?;
+
+                if arg == "default" {
+                    KeyAssignment::SetRemoteFetchRate(None)
+                } else {
+                    KeyAssignment::SetRemoteFetchRate(Some(arg.parse()?))
+                }
+            }
+            KeyAction::CopyRemoteTail => KeyAssignment::CopyRemoteTail(
+                self.arg
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing arg for {:?}", self))?
+                    .parse()?,
+            ),
+            KeyAction::ResetTerminal => KeyAssignment::ResetTerminal,
         })
     }
 }
@@ -110,6 +153,18 @@ pub enum KeyAction {
     MoveTabRelative,
     ScrollByPage,
     ShowTabNavigator,
+    ResyncTab,
+    ShowRemoteTabInfo,
+    ToggleFollowScroll,
+    SearchRemoteScrollback,
+    CopyRemoteScreen,
+    SetScrollbackLines,
+    CancelPaste,
+    CaptureRemoteOutput,
+    ToggleReadOnly,
+    SetRemoteFetchRate,
+    CopyRemoteTail,
+    ResetTerminal,
 }
 
 fn de_keycode<'de, D>(deserializer: D) -> Result<KeyCode, D::Error>
@@ -286,6 +286,19 @@ pub struct Config {
     #[serde(default = "default_scrollback_lines")]
     pub scrollback_lines: usize,
 
+    /// Pastes into multiplexer tabs that are larger than this many
+    /// bytes are sent to the server in chunks of this size, waiting
+    /// for each to be acknowledged before sending the next, so that
+    /// a large paste doesn't stall the connection.
+    #[serde(default = "default_mux_paste_chunk_size")]
+    pub mux_paste_chunk_size: usize,
+
+    /// The most lines of scrollback that a multiplexer client may
+    /// ask the server to retain for a tab.  Larger requests are
+    /// clamped to this value.
+    #[serde(default = "default_mux_max_scrollback_lines")]
+    pub mux_max_scrollback_lines: usize,
+
     /// If no `prog` is specified on the command line, use this
     /// instead of running the user's shell.
     /// For example, to have `wezterm` always run `top` by default,
@@ -390,6 +403,185 @@ pub struct Config {
     #[serde(default = "default_ratelimit_line_prefetches_per_second")]
     pub ratelimit_mux_line_prefetches_per_second: u32,
 
+    /// Like ratelimit_mux_line_prefetches_per_second, but applies to
+    /// multiplexer tabs that are in the background, so that they don't
+    /// compete with the tab that is being looked at.
+    #[serde(default = "default_ratelimit_background_line_prefetches_per_second")]
+    pub ratelimit_mux_background_line_prefetches_per_second: u32,
+
+    /// Constrains the rate at which programs running in a multiplexer
+    /// tab may set the local clipboard (eg: via OSC 52).
+    /// Updates that exceed this rate are dropped.
+    /// The default value is 5 per second.
+    #[serde(default = "default_ratelimit_remote_clipboard_sets_per_second")]
+    pub ratelimit_remote_clipboard_sets_per_second: u32,
+
+    /// Constrains the rate at which programs running in a multiplexer
+    /// tab may raise desktop notifications (eg: via OSC 9 or OSC 777).
+    /// Notifications that exceed this rate are dropped.
+    /// The default value is 1 per second.
+    #[serde(default = "default_ratelimit_remote_notifications_per_second")]
+    pub ratelimit_remote_notifications_per_second: u32,
+
+    /// When scrolling through the scrollback of a multiplexer tab,
+    /// how many rows beyond the viewport (in the direction of travel)
+    /// should be speculatively fetched from the server.
+    /// The default is one screenful.
+    pub mux_prefetch_rows: Option<usize>,
+
+    /// The maximum number of lines that the multiplexer client will
+    /// cache for each tab.  When the cache is full, the least recently
+    /// used lines are discarded and will be fetched again from the
+    /// server if they are needed.
+    #[serde(default = "default_mux_client_line_cache_size")]
+    pub mux_client_line_cache_size: usize,
+
+    /// Lines cached by the multiplexer client that haven't been
+    /// displayed for this many seconds are discarded, and will be
+    /// fetched again from the server if they are needed.
+    /// Set to 0 to keep lines until the cache is full.
+    #[serde(default = "default_mux_line_cache_ttl_seconds")]
+    pub mux_line_cache_ttl_seconds: u64,
+
+    /// When the multiplexer client is waiting on the server for more
+    /// than this many rows, it is falling behind the output of the tab.
+    /// Further changes to rows outside of the viewport are then deferred
+    /// until those rows are displayed, rather than being fetched straight
+    /// away.  This complements ratelimit_mux_line_prefetches_per_second,
+    /// which limits how often we send fetch requests rather than how
+    /// many rows are outstanding.
+    #[serde(default = "default_mux_max_pending_fetch_rows")]
+    pub mux_max_pending_fetch_rows: usize,
+
+    /// The most line fetch requests that the multiplexer client will
+    /// have outstanding for a tab at once.  Rows that need fetching
+    /// while that many requests are in flight are queued, and are
+    /// requested together once one of them completes.
+    #[serde(default = "default_mux_max_concurrent_fetches")]
+    pub mux_max_concurrent_fetches: usize,
+
+    /// If set to true, the multiplexer client tracks which columns of
+    /// a row were changed by a patch from the server, so that only
+    /// those cells need to be repainted rather than the whole row.
+    /// This helps with programs that repeatedly update a small part of
+    /// a single line, such as a status bar.
+    #[serde(default)]
+    pub mux_cell_granular_updates: bool,
+
+    /// The character used to fill the rows of a multiplexer tab that
+    /// are still being fetched from the server, so that they can be
+    /// told apart from rows that are genuinely empty.  The default is
+    /// to leave such rows blank.  Only single width characters are
+    /// supported.
+    #[serde(default)]
+    pub mux_placeholder_char: Option<char>,
+
+    /// Whether mux_placeholder_char is drawn at half intensity
+    #[serde(default = "default_true")]
+    pub mux_placeholder_dim: bool,
+
+    /// The maximum number of queued mouse events that the multiplexer
+    /// client will send to the server in a single batch.
+    /// Larger values reduce the number of round trips required to
+    /// deliver a burst of mouse activity (such as a drag gesture)
+    /// over a high latency link.
+    #[serde(default = "default_mux_mouse_event_batch_size")]
+    pub mux_mouse_event_batch_size: usize,
+
+    /// The maximum number of mouse events that the multiplexer client
+    /// will hold in its queue while waiting for the server.
+    /// When the limit is reached, the oldest mouse move and wheel
+    /// events are discarded; button presses and releases are retained.
+    #[serde(default = "default_mouse_event_queue_limit")]
+    pub mouse_event_queue_limit: usize,
+
+    /// Mouse move events that have been waiting in the multiplexer
+    /// client queue for longer than this many milliseconds are
+    /// considered obsolete and are not sent to the server.
+    /// Button presses and releases are always delivered.
+    #[serde(default = "default_mouse_move_expiry_ms")]
+    pub mouse_move_expiry_ms: u64,
+
+    /// The multiplexer client polls the server for changes to a tab,
+    /// doubling the interval between polls while nothing changes.
+    /// This is the shortest interval, in milliseconds, which is used
+    /// immediately after activity in the tab.
+    #[serde(default = "default_mux_poll_interval_base_ms")]
+    pub mux_poll_interval_base_ms: u64,
+
+    /// The longest interval, in milliseconds, between polls for
+    /// changes to an idle multiplexer tab.
+    /// If this is smaller than mux_poll_interval_base_ms then
+    /// mux_poll_interval_base_ms is used instead.
+    #[serde(default = "default_mux_poll_interval_max_ms")]
+    pub mux_poll_interval_max_ms: u64,
+
+    /// When nothing has been received from a multiplexer server for
+    /// this many milliseconds, the client sends it a ping, so that
+    /// idle connections aren't dropped by NAT or firewall timeouts.
+    /// Set to 0 to disable the keepalive.
+    #[serde(default = "default_mux_keepalive_interval_ms")]
+    pub mux_keepalive_interval_ms: u64,
+
+    /// If the server doesn't respond to a keepalive ping within this
+    /// many milliseconds, the connection is considered to be lost.
+    #[serde(default = "default_mux_keepalive_timeout_ms")]
+    pub mux_keepalive_timeout_ms: u64,
+
+    /// If set to true, tabs attached via a TLS domain will try to
+    /// re-establish contact with the server after a failed poll,
+    /// rather than immediately being treated as dead.
+    #[serde(default)]
+    pub mux_auto_reconnect: bool,
+
+    /// When `mux_auto_reconnect` is enabled, the number of times that
+    /// we will try to reconnect a tab before giving up on it.
+    #[serde(default = "default_mux_reconnect_max_attempts")]
+    pub mux_reconnect_max_attempts: u32,
+
+    /// If a multiplexer tab hears nothing from the server for this
+    /// many seconds while it is polling for changes, the tab is
+    /// treated as dead rather than retrying indefinitely.
+    /// Each reconnection attempt starts the period afresh.
+    /// Set to 0 to never time out.
+    #[serde(default)]
+    pub mux_idle_timeout_seconds: u64,
+
+    /// When a tab reconnects, the number of rows either side of the
+    /// visible portion of the tab that are fetched straight away.
+    /// The rest are fetched on demand as you scroll to them, so that
+    /// catching up after a long disconnect doesn't stall the client.
+    #[serde(default = "default_mux_reconnect_initial_rows")]
+    pub mux_reconnect_initial_rows: usize,
+
+    /// The largest clipboard, in bytes, that the multiplexer client
+    /// will send to the server when a remote program asks to read
+    /// the clipboard.  Larger clipboards are not sent; the program
+    /// receives an empty response instead.
+    #[serde(default = "default_mux_clipboard_request_max_bytes")]
+    pub mux_clipboard_request_max_bytes: usize,
+
+    /// Data written to a multiplexer tab is buffered and sent to the
+    /// server in the background so that small writes can be coalesced.
+    /// When the buffer grows to this many bytes the write blocks until
+    /// the buffered data has been sent.
+    #[serde(default = "default_mux_write_buffer_size")]
+    pub mux_write_buffer_size: usize,
+
+    /// When several clients are attached to the same multiplexer tab,
+    /// setting this to true causes the viewport of a tab to follow the
+    /// scroll position of the other clients.  Each client always
+    /// publishes its own scroll position; this only controls whether
+    /// we apply the positions published by others.
+    #[serde(default)]
+    pub mux_follow_scroll_position: bool,
+
+    /// Whether programs running in a multiplexer tab may set the local
+    /// clipboard.  Set this to false when connecting to servers that
+    /// you don't trust.
+    #[serde(default = "default_true")]
+    pub enable_remote_clipboard_write: bool,
+
     #[serde(default)]
     pub keys: Vec<Key>,
 
@@ -786,6 +978,78 @@ fn default_ratelimit_line_prefetches_per_second() -> u32 {
     10
 }
 
+fn default_ratelimit_background_line_prefetches_per_second() -> u32 {
+    2
+}
+
+fn default_ratelimit_remote_clipboard_sets_per_second() -> u32 {
+    5
+}
+
+fn default_ratelimit_remote_notifications_per_second() -> u32 {
+    1
+}
+
+fn default_mux_client_line_cache_size() -> usize {
+    10_000
+}
+
+fn default_mux_line_cache_ttl_seconds() -> u64 {
+    300
+}
+
+fn default_mux_max_pending_fetch_rows() -> usize {
+    1000
+}
+
+fn default_mux_max_concurrent_fetches() -> usize {
+    4
+}
+
+fn default_mux_poll_interval_base_ms() -> u64 {
+    20
+}
+
+fn default_mux_poll_interval_max_ms() -> u64 {
+    30_000
+}
+
+fn default_mux_keepalive_interval_ms() -> u64 {
+    15_000
+}
+
+fn default_mux_keepalive_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_mux_mouse_event_batch_size() -> usize {
+    32
+}
+
+fn default_mouse_event_queue_limit() -> usize {
+    256
+}
+
+fn default_mouse_move_expiry_ms() -> u64 {
+    100
+}
+
+fn default_mux_reconnect_max_attempts() -> u32 {
+    8
+}
+
+fn default_mux_reconnect_initial_rows() -> usize {
+    10
+}
+
+fn default_mux_clipboard_request_max_bytes() -> usize {
+    128 * 1024
+}
+
+fn default_mux_write_buffer_size() -> usize {
+    64 * 1024
+}
+
 fn default_ratelimit_output_bytes_per_second() -> u32 {
     200_000
 }
@@ -808,6 +1072,14 @@ fn default_scrollback_lines() -> usize {
     3500
 }
 
+fn default_mux_paste_chunk_size() -> usize {
+    16 * 1024
+}
+
+fn default_mux_max_scrollback_lines() -> usize {
+    100_000
+}
+
 fn default_hyperlink_rules() -> Vec<hyperlink::Rule> {
     vec![
         // URL with a protocol
@@ -141,3 +141,43 @@ pub fn tab_navigator(
 
     Ok(())
 }
+
+/// Shows the state of a remote tab until a key is pressed
+pub fn remote_tab_info(
+    _tab_id: TabId,
+    mut term: TermWizTerminal,
+    info: Vec<String>,
+) -> anyhow::Result<()> {
+    use termwiz::input::{InputEvent, KeyCode, KeyEvent};
+    use termwiz::surface::Position;
+
+    let mut changes = vec![
+        Change::Title("Remote Tab Info".to_string()),
+        Change::ClearScreen(ColorAttribute::Default),
+        Change::CursorPosition {
+            x: Position::Absolute(0),
+            y: Position::Absolute(0),
+        },
+    ];
+    for line in info {
+        changes.push(Change::Text(format!("{}\r\n", line)));
+    }
+    changes.push(Change::Text("\r\nPress Escape to close\r\n".to_string()));
+    term.render(&changes)?;
+
+    while let Ok(Some(event)) = term.poll_input(None) {
+        match event {
+            InputEvent::Key(KeyEvent {
+                key: KeyCode::Escape,
+                ..
+            })
+            | InputEvent::Key(KeyEvent {
+                key: KeyCode::Enter,
+                ..
+            }) => break,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -6,15 +6,20 @@ use crate::config::{configuration, ConfigHandle};
 use crate::font::units::*;
 use crate::font::FontConfiguration;
 use crate::frontend::front_end;
-use crate::frontend::gui::overlay::{start_overlay, tab_navigator};
+use crate::frontend::gui::overlay::{remote_tab_info, start_overlay, tab_navigator};
 use crate::frontend::gui::scrollbar::*;
 use crate::frontend::gui::selection::*;
 use crate::frontend::gui::tabbar::{TabBarItem, TabBarState};
 use crate::keyassignment::{KeyAssignment, KeyMap, SpawnTabDomain};
-use crate::mux::renderable::{Renderable, RenderableDimensions, StableCursorPosition};
+use crate::mux::renderable::{
+    Renderable, RenderableDimensions, SelectionRegion, StableCursorPosition,
+};
+use crate::mux::search::SearchKind;
 use crate::mux::tab::{Tab, TabId};
 use crate::mux::window::WindowId as MuxWindowId;
 use crate::mux::Mux;
+use crate::server::codec::PaneNode;
+use crate::server::tab::{ClientTab, ReconnectState};
 use ::term::input::MouseButton as TMB;
 use ::term::input::MouseEventKind as TMEK;
 use ::window::bitmaps::atlas::{OutOfTextureSpace, SpriteSlice};
@@ -45,6 +50,10 @@ use termwiz::color::RgbColor;
 use termwiz::hyperlink::Hyperlink;
 use termwiz::surface::CursorShape;
 
+/// How long closing a remote tab waits for its queued input to reach
+/// the server before giving up on it
+const REMOTE_TAB_DETACH_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Debug, Clone, Copy)]
 struct RowsAndCols {
     rows: usize,
@@ -56,6 +65,7 @@ struct RowsAndCols {
 struct ClipboardHelper {
     window: Window,
     clipboard_contents: Arc<Mutex<Option<String>>>,
+    primary_selection: Arc<Mutex<Option<String>>>,
 }
 
 impl term::Clipboard for ClipboardHelper {
@@ -80,6 +90,14 @@ impl term::Clipboard for ClipboardHelper {
         self.window.set_clipboard(data.unwrap_or_else(String::new));
         Ok(())
     }
+
+    fn set_primary_selection(&self, data: Option<String>) -> anyhow::Result<()> {
+        // The window layer only knows about a single clipboard, so we
+        // hold on to the primary selection ourselves and use it for
+        // middle mouse button paste
+        *self.primary_selection.lock().unwrap() = data;
+        Ok(())
+    }
 }
 
 struct PrevCursorPos {
@@ -155,6 +173,10 @@ pub struct TermWindow {
     /// just for middle mouse button paste function
     clipboard_contents: Arc<Mutex<Option<String>>>,
 
+    /// The primary selection, as set by the application in a tab.
+    /// This is cleared when the user makes a local selection.
+    primary_selection: Arc<Mutex<Option<String>>>,
+
     current_mouse_button: Option<MousePress>,
 
     /// Keeps track of double and triple clicks
@@ -534,6 +556,7 @@ impl TermWindow {
         )?);
 
         let clipboard_contents = Arc::new(Mutex::new(None));
+        let primary_selection = Arc::new(Mutex::new(None));
 
         let window = Window::new_window(
             "wezterm",
@@ -559,6 +582,7 @@ impl TermWindow {
                 prev_cursor: PrevCursorPos::new(),
                 last_scroll_info: RenderableDimensions::default(),
                 clipboard_contents: Arc::clone(&clipboard_contents),
+                primary_selection: Arc::clone(&primary_selection),
                 tab_state: RefCell::new(HashMap::new()),
                 current_mouse_button: None,
                 last_mouse_click: None,
@@ -610,6 +634,14 @@ impl TermWindow {
 
                                 // If the model is dirty, arrange to re-paint
                                 let dims = render.get_dimensions();
+
+                                // Follow the scroll position of other viewers
+                                if let Some(viewport) = tab.take_followed_viewport() {
+                                    myself.tab_state(tab.tab_id()).viewport =
+                                        Self::clamp_viewport(viewport, dims);
+                                    myself.window.as_ref().unwrap().invalidate();
+                                }
+
                                 let viewport = myself
                                     .get_viewport(tab.tab_id())
                                     .unwrap_or(dims.physical_top);
@@ -654,6 +686,7 @@ impl TermWindow {
         let clipboard: Arc<dyn term::Clipboard> = Arc::new(ClipboardHelper {
             window: window.clone(),
             clipboard_contents,
+            primary_selection,
         });
         tab.set_clipboard(&clipboard);
         Mux::get()
@@ -1016,18 +1049,105 @@ impl TermWindow {
         promise::spawn::spawn(future);
     }
 
+    fn show_remote_tab_info(&mut self) {
+        let mux = Mux::get().unwrap();
+        let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
+            Some(tab) => tab,
+            None => return,
+        };
+        if tab.downcast_ref::<ClientTab>().is_none() {
+            return;
+        }
+
+        // The pane layout has to be fetched from the server, so the
+        // overlay is started once that has arrived
+        let window = self.window.clone().unwrap();
+        promise::spawn::spawn(async move {
+            let client_tab = tab.downcast_ref::<ClientTab>().unwrap();
+            let mut info = describe_remote_tab(client_tab);
+            match client_tab.pane_tree().await {
+                Ok(tree) => {
+                    info.push("Panes:".to_string());
+                    describe_pane_tree(&tree, 1, &mut info);
+                }
+                Err(err) => info.push(format!("Panes: {}", err)),
+            }
+
+            let tab_id = tab.tab_id();
+            window.apply(move |myself, _| {
+                if let Some(myself) = myself.downcast_mut::<Self>() {
+                    if let Some(tab) = Mux::get().unwrap().get_tab(tab_id) {
+                        let info = std::mem::take(&mut info);
+                        let (overlay, future) = start_overlay(myself, &tab, move |tab_id, term| {
+                            remote_tab_info(tab_id, term, info)
+                        });
+                        myself.assign_overlay(tab_id, overlay);
+                        promise::spawn::spawn(future);
+                    }
+                }
+                Ok(())
+            });
+        });
+    }
+
+    /// Scroll to the closest match for `pattern` above the viewport,
+    /// searching the full scrollback held by the server, so that
+    /// repeating the search steps back through older matches
+    fn search_remote_scrollback(&mut self, tab: &Rc<dyn Tab>, pattern: &str) {
+        if tab.downcast_ref::<ClientTab>().is_none() {
+            return;
+        }
+        let before = self.get_viewport(tab.tab_id());
+        let tab = Rc::clone(tab);
+        let pattern = pattern.to_string();
+        let window = self.window.clone().unwrap();
+        promise::spawn::spawn(async move {
+            let client_tab = tab.downcast_ref::<ClientTab>().unwrap();
+            let response = client_tab
+                .search_scrollback(&pattern, SearchKind::CaseInsensitive, before)
+                .await?;
+            let row = match response.matches.first() {
+                Some(m) => m.stable_row,
+                None => {
+                    log::info!("no more matches for {:?}", pattern);
+                    return Ok(());
+                }
+            };
+            let tab_id = tab.tab_id();
+            window.apply(move |myself, _| {
+                if let Some(myself) = myself.downcast_mut::<Self>() {
+                    if let Some(tab) = Mux::get().unwrap().get_tab(tab_id) {
+                        let dims = tab.renderer().get_dimensions();
+                        myself.set_viewport(tab_id, Some(row), dims);
+                        if let Some(win) = myself.window.as_ref() {
+                            win.invalidate();
+                        }
+                    }
+                }
+                Ok(())
+            });
+            Ok::<(), anyhow::Error>(())
+        });
+    }
+
     fn scroll_by_page(&mut self, amount: isize) -> anyhow::Result<()> {
         let tab = match self.get_active_tab_or_overlay() {
             Some(tab) => tab,
             None => return Ok(()),
         };
-        let render = tab.renderer();
-        let dims = render.get_dimensions();
+        let dims = tab.renderer().get_dimensions();
+        let rows = dims.viewport_rows as isize;
         let position = self
             .get_viewport(tab.tab_id())
             .unwrap_or(dims.physical_top)
-            .saturating_add(amount * dims.viewport_rows as isize);
+            .saturating_add(amount * rows);
         self.set_viewport(tab.tab_id(), Some(position), dims);
+        if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+            // Fetch the page beyond this one, so that it is ready
+            // if the user keeps scrolling in the same direction
+            let next = position.saturating_add(amount.signum() * rows);
+            client_tab.warm_region(next..next.saturating_add(rows));
+        }
         if let Some(win) = self.window.as_ref() {
             win.invalidate();
         }
@@ -1063,6 +1183,7 @@ impl TermWindow {
         let clipboard: Arc<dyn term::Clipboard> = Arc::new(ClipboardHelper {
             window: self.window.as_ref().unwrap().clone(),
             clipboard_contents: Arc::clone(&self.clipboard_contents),
+            primary_selection: Arc::clone(&self.primary_selection),
         });
         let domain = domain.clone();
 
@@ -1208,6 +1329,75 @@ impl TermWindow {
             MoveTabRelative(n) => self.move_tab_relative(*n)?,
             ScrollByPage(n) => self.scroll_by_page(*n)?,
             ShowTabNavigator => self.show_tab_navigator(),
+            ResyncTab => {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    client_tab.refresh();
+                }
+                self.window.as_ref().unwrap().invalidate();
+            }
+            ShowRemoteTabInfo => self.show_remote_tab_info(),
+            ToggleFollowScroll => {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    client_tab.set_follow_scroll(!client_tab.is_following_scroll());
+                }
+            }
+            SearchRemoteScrollback(pattern) => self.search_remote_scrollback(tab, pattern),
+            CopyRemoteScreen => {
+                if tab.downcast_ref::<ClientTab>().is_some() {
+                    let tab = Rc::clone(tab);
+                    let window = self.window.clone().unwrap();
+                    promise::spawn::spawn(async move {
+                        let client_tab = tab.downcast_ref::<ClientTab>().unwrap();
+                        match client_tab.capture_viewport_text().await {
+                            Ok(text) => {
+                                window.set_clipboard(text);
+                            }
+                            Err(err) => log::error!("capturing remote screen: {}", err),
+                        }
+                    });
+                }
+            }
+            SetScrollbackLines(n) => tab.set_scrollback_lines(*n)?,
+            CancelPaste => {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    client_tab.cancel_paste();
+                }
+            }
+            CaptureRemoteOutput(path) => {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    capture_remote_output(client_tab, path)?;
+                }
+            }
+            ToggleReadOnly => {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    client_tab.set_read_only(!tab.is_read_only());
+                }
+            }
+            SetRemoteFetchRate(rate) => {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    client_tab.set_fetch_rate(*rate);
+                }
+            }
+            CopyRemoteTail(n) => {
+                if tab.downcast_ref::<ClientTab>().is_some() {
+                    let tab = Rc::clone(tab);
+                    let n = *n;
+                    let window = self.window.clone().unwrap();
+                    promise::spawn::spawn(async move {
+                        let client_tab = tab.downcast_ref::<ClientTab>().unwrap();
+                        match client_tab.tail(n).await {
+                            Ok(text) => {
+                                window.set_clipboard(text);
+                            }
+                            Err(err) => log::error!("capturing remote tail: {}", err),
+                        }
+                    });
+                }
+            }
+            ResetTerminal => {
+                tab.reset_terminal()?;
+                self.window.as_ref().unwrap().invalidate();
+            }
         };
         Ok(())
     }
@@ -1316,6 +1506,7 @@ impl TermWindow {
         let mux = Mux::get().unwrap();
         if let Some(window) = mux.get_window(self.mux_window_id) {
             for tab in window.iter() {
+                tab.set_dpi(dimensions.dpi);
                 tab.resize(size).ok();
             }
         };
@@ -1369,7 +1560,21 @@ impl TermWindow {
             Some(tab) => tab,
             None => return,
         };
-        mux.remove_tab(tab.tab_id());
+        if tab.downcast_ref::<ClientTab>().is_some() {
+            // Let the keys typed just before closing reach the remote
+            // program; detach removes the tab from the mux once it is done
+            let activity = crate::frontend::activity::Activity::new();
+            let tab = Rc::clone(&tab);
+            promise::spawn::spawn(async move {
+                let client_tab = tab.downcast_ref::<ClientTab>().unwrap();
+                if let Err(err) = client_tab.detach(REMOTE_TAB_DETACH_TIMEOUT).await {
+                    log::error!("detaching remote tab: {}", err);
+                }
+                drop(activity);
+            });
+        } else {
+            mux.remove_tab(tab.tab_id());
+        }
         if let Some(mut win) = mux.get_window_mut(self.mux_window_id) {
             win.remove_by_id(tab.tab_id());
         }
@@ -2350,6 +2555,22 @@ impl TermWindow {
         RefMut::map(self.tab_state(tab_id), |state| &mut state.selection)
     }
 
+    /// Show our selection to the other clients attached to a remote tab
+    fn publish_selection(&self, tab: &Rc<dyn Tab>) {
+        if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+            let region = self
+                .selection(tab.tab_id())
+                .range
+                .map(|sel| SelectionRegion {
+                    start_x: sel.start.x,
+                    start_y: sel.start.y,
+                    end_x: sel.end.x,
+                    end_y: sel.end.y,
+                });
+            client_tab.publish_selection(region);
+        }
+    }
+
     fn get_viewport(&self, tab_id: TabId) -> Option<StableRowIndex> {
         self.tab_state(tab_id).viewport
     }
@@ -2360,7 +2581,18 @@ impl TermWindow {
         position: Option<StableRowIndex>,
         dims: RenderableDimensions,
     ) {
-        let pos = match position {
+        let pos = Self::clamp_viewport(position, dims);
+        self.tab_state(tab_id).viewport = pos;
+        if let Some(tab) = Mux::get().unwrap().get_tab(tab_id) {
+            tab.viewport_changed(pos);
+        }
+    }
+
+    fn clamp_viewport(
+        position: Option<StableRowIndex>,
+        dims: RenderableDimensions,
+    ) -> Option<StableRowIndex> {
+        match position {
             Some(pos) => {
                 // Drop out of scrolling mode if we're off the bottom
                 if pos >= dims.physical_top {
@@ -2370,8 +2602,7 @@ impl TermWindow {
                 }
             }
             None => None,
-        };
-        self.tab_state(tab_id).viewport = pos;
+        }
     }
 
     fn mouse_event_tab_bar(&mut self, x: usize, event: &MouseEvent, context: &dyn WindowOps) {
@@ -2560,7 +2791,9 @@ impl TermWindow {
                         // Initiate a selection
                         self.selection(tab.tab_id())
                             .begin(SelectionCoordinate { x, y: stable_row });
+                        self.primary_selection.lock().unwrap().take();
                         self.window.as_ref().unwrap().set_clipboard(String::new());
+                        self.publish_selection(&tab);
                     } else {
                         // Extend selection
                         let end = SelectionCoordinate { x, y: stable_row };
@@ -2595,6 +2828,7 @@ impl TermWindow {
                         });
                     } else {
                         self.window.as_ref().unwrap().set_clipboard(text);
+                        self.publish_selection(&tab);
                         context.invalidate();
                     }
                 }
@@ -2611,6 +2845,7 @@ impl TermWindow {
                     let text = self.selection_text(&tab);
 
                     self.window.as_ref().unwrap().set_clipboard(text);
+                    self.publish_selection(&tab);
                     context.invalidate();
                 }
 
@@ -2702,6 +2937,10 @@ impl TermWindow {
                         ..
                     }),
                 ) => {
+                    if let Some(text) = self.primary_selection.lock().unwrap().clone() {
+                        tab.trickle_paste(text).ok();
+                        return;
+                    }
                     let tab_id = tab.tab_id();
                     let future = self.window.as_ref().unwrap().get_clipboard();
                     promise::spawn::spawn(async move {
@@ -2750,7 +2989,13 @@ impl TermWindow {
                         TMB::WheelDown((-amount) as usize)
                     }
                 }
-                WMEK::HorzWheel(_) => TMB::None,
+                WMEK::HorzWheel(amount) => {
+                    if amount > 0 {
+                        TMB::WheelRight(amount as usize)
+                    } else {
+                        TMB::WheelLeft((-amount) as usize)
+                    }
+                }
             },
             x,
             y,
@@ -2765,6 +3010,20 @@ impl TermWindow {
         )
         .ok();
 
+        if let WMEK::Release(_) = event.kind {
+            if tab.downcast_ref::<ClientTab>().is_some() {
+                // Deliver the end of the gesture ahead of any keys
+                // that the user presses next
+                let tab = Rc::clone(&tab);
+                promise::spawn::spawn(async move {
+                    let client_tab = tab.downcast_ref::<ClientTab>().unwrap();
+                    if let Err(err) = client_tab.flush_mouse().await {
+                        log::error!("flush_mouse: {}", err);
+                    }
+                });
+            }
+        }
+
         match event.kind {
             WMEK::Move => {}
             _ => {
@@ -2823,6 +3082,115 @@ fn rgbcolor_to_window_color(color: RgbColor) -> Color {
     Color::rgba(color.red, color.green, color.blue, 0xff)
 }
 
+/// Summarize the state of a remote tab for the ShowRemoteTabInfo overlay
+fn describe_remote_tab(tab: &ClientTab) -> Vec<String> {
+    let connection = match tab.reconnect_state() {
+        ReconnectState::Connected => "connected".to_string(),
+        ReconnectState::Reconnecting {
+            attempt,
+            next_attempt,
+        } => format!(
+            "reconnect attempt {} in {:?}",
+            attempt,
+            next_attempt.saturating_duration_since(Instant::now())
+        ),
+    };
+    let status = match tab.dead_reason() {
+        Some(reason) => format!("closed because {}", reason),
+        None => "running".to_string(),
+    };
+    let latency = match tab.latency() {
+        Some(latency) => format!("{:?}", latency),
+        None => "unknown".to_string(),
+    };
+    let remote_selection = match tab.remote_selection() {
+        Some(sel) => format!(
+            "({}, {}) to ({}, {})",
+            sel.start_x, sel.start_y, sel.end_x, sel.end_y
+        ),
+        None => "none".to_string(),
+    };
+    let paste = match tab.paste_progress() {
+        Some(progress) => format!("{} of {} bytes sent", progress.sent, progress.total),
+        None => "none".to_string(),
+    };
+    let stats = tab.cache_stats();
+    let traffic = tab.traffic();
+    let connection_traffic = tab.connection_traffic();
+    vec![
+        format!("Status: {}", status),
+        format!("Connection: {}", connection),
+        format!("Round trip time: {}", latency),
+        format!("Last activity: {:?} ago", tab.last_activity().elapsed()),
+        format!("Paste in progress: {}", paste),
+        format!("Selection by another client: {}", remote_selection),
+        format!("Viewport up to date: {}", tab.viewport_ready()),
+        format!("Rows that failed to fetch: {}", tab.fetch_error_count()),
+        format!(
+            "Cached rows: {} ({} dirty, {} fetching, {} dirty and fetching, {} stale)",
+            stats.line, stats.dirty, stats.fetching, stats.dirty_and_fetching, stats.stale
+        ),
+        format!(
+            "Fetches: {} issued, {} completed, {} admitted, {} throttled",
+            stats.fetches_issued,
+            stats.fetches_completed,
+            stats.fetches_admitted,
+            stats.fetches_throttled
+        ),
+        format!("Poll interval: {:?}", stats.poll_interval),
+        format!(
+            "Traffic: {} bytes received, {} bytes sent",
+            traffic.rx_bytes, traffic.tx_bytes
+        ),
+        format!(
+            "Connection traffic: {} bytes received, {} bytes sent",
+            connection_traffic.rx_bytes, connection_traffic.tx_bytes
+        ),
+    ]
+}
+
+/// Describe the layout of the panes within a remote tab, one line
+/// per node, indented by its depth in the tree
+fn describe_pane_tree(node: &PaneNode, depth: usize, info: &mut Vec<String>) {
+    let indent = "  ".repeat(depth);
+    match node {
+        PaneNode::Leaf { tab_id, size } => info.push(format!(
+            "{}pane {} ({}x{})",
+            indent, tab_id, size.cols, size.rows
+        )),
+        PaneNode::Split {
+            direction,
+            first,
+            second,
+        } => {
+            info.push(format!("{}{:?} split", indent, direction));
+            describe_pane_tree(first, depth + 1, info);
+            describe_pane_tree(second, depth + 1, info);
+        }
+    }
+}
+
+/// Append the raw output of the remote tab to the file at `path`
+/// until the tab goes away
+fn capture_remote_output(tab: &ClientTab, path: &str) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let output = tab.subscribe_output();
+    let path = path.to_string();
+    std::thread::spawn(move || {
+        use std::io::Write;
+        for data in output {
+            if let Err(err) = file.write_all(&data) {
+                log::error!("writing remote output to {}: {}", path, err);
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
 fn window_mods_to_termwiz_mods(modifiers: ::window::Modifiers) -> termwiz::input::Modifiers {
     let mut result = termwiz::input::Modifiers::NONE;
     if modifiers.contains(::window::Modifiers::SHIFT) {
@@ -39,6 +39,18 @@ pub enum KeyAssignment {
     MoveTab(usize),
     ScrollByPage(isize),
     ShowTabNavigator,
+    ResyncTab,
+    ShowRemoteTabInfo,
+    ToggleFollowScroll,
+    SearchRemoteScrollback(String),
+    CopyRemoteScreen,
+    SetScrollbackLines(usize),
+    CancelPaste,
+    CaptureRemoteOutput(String),
+    ToggleReadOnly,
+    SetRemoteFetchRate(Option<u32>),
+    CopyRemoteTail(usize),
+    ResetTerminal,
 }
 
 pub struct KeyMap(HashMap<(KeyCode, KeyModifiers), KeyAssignment>);
@@ -1,5 +1,5 @@
 use crate::mux::domain::DomainId;
-use crate::mux::renderable::Renderable;
+use crate::mux::renderable::{Renderable, TabModeState};
 use crate::mux::tab::{alloc_tab_id, Tab, TabId};
 use anyhow::Error;
 use portable_pty::{Child, MasterPty, PtySize};
@@ -7,6 +7,7 @@ use std::cell::{RefCell, RefMut};
 use std::sync::Arc;
 use term::color::ColorPalette;
 use term::{Clipboard, KeyCode, KeyModifiers, MouseEvent, Terminal, TerminalHost};
+use termwiz::escape::osc::Progress;
 use url::Url;
 
 pub struct LocalTab {
@@ -79,6 +80,12 @@ impl Tab for LocalTab {
             .send_paste(text, &mut *self.pty.borrow_mut())
     }
 
+    fn send_paste_chunk(&self, text: &str, first: bool, last: bool) -> Result<(), Error> {
+        self.terminal
+            .borrow_mut()
+            .send_paste_chunk(text, first, last, &mut *self.pty.borrow_mut())
+    }
+
     fn get_title(&self) -> String {
         self.terminal.borrow_mut().get_title().to_string()
     }
@@ -87,6 +94,10 @@ impl Tab for LocalTab {
         self.terminal.borrow().palette()
     }
 
+    fn palette_override(&self) -> Option<ColorPalette> {
+        self.terminal.borrow().palette_override().cloned()
+    }
+
     fn domain_id(&self) -> DomainId {
         self.domain_id
     }
@@ -95,9 +106,43 @@ impl Tab for LocalTab {
         self.terminal.borrow().is_mouse_grabbed()
     }
 
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        self.terminal.borrow().bracketed_paste_enabled()
+    }
+
+    fn mode_state(&self) -> TabModeState {
+        let terminal = self.terminal.borrow();
+        TabModeState {
+            alt_screen: terminal.is_alt_screen_active(),
+            cursor_visible: terminal.is_cursor_visible(),
+            application_keypad: terminal.is_application_keypad(),
+        }
+    }
+
     fn get_current_working_dir(&self) -> Option<Url> {
         self.terminal.borrow().get_current_dir().cloned()
     }
+
+    fn get_progress(&self) -> Progress {
+        self.terminal.borrow().get_progress()
+    }
+
+    fn set_scrollback_lines(&self, lines: usize) -> anyhow::Result<()> {
+        self.terminal.borrow_mut().set_scrollback_size(lines);
+        Ok(())
+    }
+
+    fn reset_terminal(&self) -> anyhow::Result<()> {
+        self.terminal.borrow_mut().full_reset();
+        Ok(())
+    }
+
+    fn exit_code(&self) -> Option<u32> {
+        match self.process.borrow_mut().try_wait() {
+            Ok(Some(status)) => status.exit_code(),
+            _ => None,
+        }
+    }
 }
 
 impl LocalTab {
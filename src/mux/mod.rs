@@ -1,3 +1,4 @@
+use crate::mux::renderable::SelectionRegion;
 use crate::mux::tab::{Tab, TabId};
 use crate::mux::window::{Window, WindowId};
 use crate::ratelim::RateLimiter;
@@ -13,17 +14,66 @@ use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use term::TerminalHost;
+use term::{StableRowIndex, TerminalHost};
+use termwiz::escape::osc::Progress;
 use thiserror::*;
 
 pub mod domain;
 pub mod renderable;
+pub mod search;
 pub mod tab;
 pub mod window;
 
 #[derive(Clone, Debug)]
 pub enum MuxNotification {
     TabOutput(TabId),
+    TabTitleChanged(TabId),
+    WorkingDirChanged(TabId),
+    Bell(TabId),
+    ClipboardRequested(TabId),
+    /// The tab was removed from the mux
+    TabRemoved(TabId),
+    /// The application running in the tab started or stopped
+    /// grabbing the mouse
+    MouseGrabChanged(TabId),
+    /// The application running in the tab changed one of the modes
+    /// reported by Tab::mode_state
+    TabModesChanged(TabId),
+    /// Every row of the first viewport displayed for a tab attached
+    /// from a remote mux has arrived.  Sent once per tab.
+    TabFirstPaint(TabId),
+    /// The program running in the tab exited, with the
+    /// specified exit code, if known
+    TabExited(TabId, Option<u32>),
+    /// Raw bytes read from the pty of a tab whose output is tapped.
+    /// Only sent while someone holds a tap via Mux::tap_output.
+    TabOutputBytes(TabId, Arc<Vec<u8>>),
+    /// A client scrolled the viewport of a tab.  None means that the
+    /// viewport is tracking the bottom of the output.
+    TabScrolled {
+        tab_id: TabId,
+        viewport: Option<StableRowIndex>,
+        /// The session that published the position, which doesn't
+        /// need to be told about it
+        origin: usize,
+    },
+    /// A client selected some text in a tab, or cleared its selection
+    SelectionChanged {
+        tab_id: TabId,
+        range: Option<SelectionRegion>,
+        /// The session that published the selection
+        origin: usize,
+    },
+    /// The application running in the tab reported a change in the
+    /// progress of a long running operation
+    TabProgressChanged(TabId),
+    /// The application running in the tab asked for a desktop
+    /// notification to be shown, via OSC 9 or OSC 777
+    DesktopNotification {
+        tab_id: TabId,
+        title: Option<String>,
+        body: String,
+    },
 }
 
 static SUB_ID: AtomicUsize = AtomicUsize::new(0);
@@ -37,6 +87,8 @@ pub struct Mux {
     domains: RefCell<HashMap<DomainId, Arc<dyn Domain>>>,
     domains_by_name: RefCell<HashMap<String, Arc<dyn Domain>>>,
     subscribers: RefCell<HashMap<usize, PollableSender<MuxNotification>>>,
+    /// The number of taps held on the raw output of each tab
+    output_taps: RefCell<HashMap<TabId, usize>>,
 }
 
 fn read_from_tab_pty(tab_id: TabId, mut reader: Box<dyn std::io::Read>) {
@@ -71,10 +123,17 @@ fn read_from_tab_pty(tab_id: TabId, mut reader: Box<dyn std::io::Read>) {
                                     tab.advance_bytes(
                                         &data,
                                         &mut Host {
+                                            tab_id,
                                             writer: &mut *tab.writer(),
                                         },
                                     );
                                     mux.notify(MuxNotification::TabOutput(tab_id));
+                                    if mux.is_output_tapped(tab_id) {
+                                        mux.notify(MuxNotification::TabOutputBytes(
+                                            tab_id,
+                                            Arc::new(data),
+                                        ));
+                                    }
                                 }
                             });
                         }
@@ -89,6 +148,9 @@ fn read_from_tab_pty(tab_id: TabId, mut reader: Box<dyn std::io::Read>) {
     }
     promise::spawn::spawn_into_main_thread(async move {
         let mux = Mux::get().unwrap();
+        if let Some(tab) = mux.get_tab(tab_id) {
+            mux.notify(MuxNotification::TabExited(tab_id, tab.exit_code()));
+        }
         mux.remove_tab(tab_id);
     });
 }
@@ -98,6 +160,7 @@ fn read_from_tab_pty(tab_id: TabId, mut reader: Box<dyn std::io::Read>) {
 /// As such it only really has Host::writer get called.
 /// The GUI driven flows provide their own impl of TerminalHost.
 struct Host<'a> {
+    tab_id: TabId,
     writer: &'a mut dyn std::io::Write,
 }
 
@@ -105,6 +168,40 @@ impl<'a> TerminalHost for Host<'a> {
     fn writer(&mut self) -> &mut dyn std::io::Write {
         &mut self.writer
     }
+
+    fn set_title(&mut self, _title: &str) {
+        Mux::get()
+            .unwrap()
+            .notify(MuxNotification::TabTitleChanged(self.tab_id));
+    }
+
+    fn bell(&mut self) {
+        Mux::get()
+            .unwrap()
+            .notify(MuxNotification::Bell(self.tab_id));
+    }
+
+    fn request_clipboard(&mut self) {
+        Mux::get()
+            .unwrap()
+            .notify(MuxNotification::ClipboardRequested(self.tab_id));
+    }
+
+    fn set_progress(&mut self, _progress: Progress) {
+        Mux::get()
+            .unwrap()
+            .notify(MuxNotification::TabProgressChanged(self.tab_id));
+    }
+
+    fn show_notification(&mut self, title: Option<&str>, body: &str) {
+        Mux::get()
+            .unwrap()
+            .notify(MuxNotification::DesktopNotification {
+                tab_id: self.tab_id,
+                title: title.map(str::to_string),
+                body: body.to_string(),
+            });
+    }
 }
 
 thread_local! {
@@ -131,6 +228,7 @@ impl Mux {
             domains_by_name: RefCell::new(domains_by_name),
             domains: RefCell::new(domains),
             subscribers: RefCell::new(HashMap::new()),
+            output_taps: RefCell::new(HashMap::new()),
         }
     }
 
@@ -142,10 +240,41 @@ impl Mux {
     }
 
     pub fn notify(&self, notification: MuxNotification) {
+        if let MuxNotification::TabTitleChanged(tab_id)
+        | MuxNotification::WorkingDirChanged(tab_id) = &notification
+        {
+            // Arrange for the tab bar to be redrawn
+            if let Ok(mut windows) = self.windows.try_borrow_mut() {
+                for window in windows.values_mut() {
+                    if window.idx_by_id(*tab_id).is_some() {
+                        window.invalidate();
+                    }
+                }
+            }
+        }
         let mut subscribers = self.subscribers.borrow_mut();
         subscribers.retain(|_, tx| tx.send(notification.clone()).is_ok());
     }
 
+    /// Take (`enable` is true) or release a tap on the raw output of
+    /// a tab.  While at least one tap is held, the bytes read from its
+    /// pty are broadcast as MuxNotification::TabOutputBytes.
+    pub fn tap_output(&self, tab_id: TabId, enable: bool) {
+        let mut taps = self.output_taps.borrow_mut();
+        if enable {
+            *taps.entry(tab_id).or_insert(0) += 1;
+        } else if let Some(count) = taps.get_mut(&tab_id) {
+            *count -= 1;
+            if *count == 0 {
+                taps.remove(&tab_id);
+            }
+        }
+    }
+
+    pub fn is_output_tapped(&self, tab_id: TabId) -> bool {
+        self.output_taps.borrow().contains_key(&tab_id)
+    }
+
     pub fn default_domain(&self) -> Arc<dyn Domain> {
         self.default_domain
             .borrow()
@@ -210,7 +339,11 @@ impl Mux {
 
     pub fn remove_tab(&self, tab_id: TabId) {
         debug!("removing tab {}", tab_id);
-        self.tabs.borrow_mut().remove(&tab_id);
+        let removed = self.tabs.borrow_mut().remove(&tab_id);
+        self.output_taps.borrow_mut().remove(&tab_id);
+        if removed.is_some() {
+            self.notify(MuxNotification::TabRemoved(tab_id));
+        }
         self.prune_dead_windows();
     }
 
@@ -234,6 +367,7 @@ impl Mux {
 
         for tab_id in dead_tab_ids {
             self.tabs.borrow_mut().remove(&tab_id);
+            self.notify(MuxNotification::TabRemoved(tab_id));
         }
 
         for window_id in dead_windows {
@@ -31,6 +31,69 @@ pub struct RenderableDimensions {
     /// The top of the scrollback (the earliest row we remember)
     /// expressed as a stable index.
     pub scrollback_top: StableRowIndex,
+
+    /// The size of the viewport in pixels, which programs that
+    /// display images use to work out the size of a cell.
+    /// Zero if unknown.
+    #[serde(default)]
+    pub pixel_width: usize,
+    #[serde(default)]
+    pub pixel_height: usize,
+    /// The DPI at which the viewport is displayed; zero if unknown,
+    /// as is the case for a terminal that isn't being displayed
+    #[serde(default)]
+    pub dpi: u32,
+}
+
+/// Terminal modes set by the application running in a tab that
+/// affect how the gui should behave locally
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TabModeState {
+    /// The alternate screen is active, so there is no scrollback
+    pub alt_screen: bool,
+    pub cursor_visible: bool,
+    pub application_keypad: bool,
+}
+
+impl Default for TabModeState {
+    fn default() -> Self {
+        Self {
+            alt_screen: false,
+            cursor_visible: true,
+            application_keypad: false,
+        }
+    }
+}
+
+/// A region of text selected by a client, expressed in terms of
+/// stable rows so that it remains anchored to the text as the
+/// screen scrolls.  The end is inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct SelectionRegion {
+    pub start_x: usize,
+    pub start_y: StableRowIndex,
+    pub end_x: usize,
+    pub end_y: StableRowIndex,
+}
+
+impl SelectionRegion {
+    /// Returns the rows spanned by the selection, irrespective of
+    /// the direction in which it was made
+    pub fn rows(&self) -> Range<StableRowIndex> {
+        self.start_y.min(self.end_y)..self.start_y.max(self.end_y) + 1
+    }
+}
+
+/// Describes whether a line returned by get_lines_with_status is
+/// up to date, or is provisional content that is about to change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineStatus {
+    /// The line is up to date
+    Current,
+    /// The line is an older copy of the row that will be refreshed
+    Stale,
+    /// We don't have the row yet; a placeholder was returned instead
+    Placeholder,
 }
 
 /// Renderable allows passing something that isn't an actual term::Terminal
@@ -45,6 +108,13 @@ pub trait Renderable: Downcast {
     /// have their dirty flag set to true.
     fn get_dirty_lines(&self, lines: Range<StableRowIndex>) -> RangeSet<StableRowIndex>;
 
+    /// For a row reported by get_dirty_lines, returns the range of
+    /// columns that changed, if that is known to be less than the
+    /// whole row.  None means that the whole row must be repainted.
+    fn get_dirty_columns(&self, _stable_row: StableRowIndex) -> Option<Range<usize>> {
+        None
+    }
+
     /// Returns a set of lines from the scrollback or visible portion of
     /// the display.  The lines are indexed using StableRowIndex, which
     /// can be invalidated if the scrollback is busy, or when switching
@@ -62,6 +132,22 @@ pub trait Renderable: Downcast {
     /// have its dirty bit set appropriately.
     fn get_lines(&mut self, lines: Range<StableRowIndex>) -> (StableRowIndex, Vec<Line>);
 
+    /// Like get_lines, but also reports the status of each line, so
+    /// that provisional content can be rendered differently
+    fn get_lines_with_status(
+        &mut self,
+        lines: Range<StableRowIndex>,
+    ) -> (StableRowIndex, Vec<(Line, LineStatus)>) {
+        let (first, lines) = self.get_lines(lines);
+        (
+            first,
+            lines
+                .into_iter()
+                .map(|line| (line, LineStatus::Current))
+                .collect(),
+        )
+    }
+
     /// Returns render related dimensions
     fn get_dimensions(&self) -> RenderableDimensions;
 }
@@ -125,6 +211,9 @@ impl Renderable for Terminal {
             scrollback_rows: screen.lines.len(),
             physical_top: screen.visible_row_to_stable_row(0),
             scrollback_top: screen.phys_to_stable_row_index(0),
+            pixel_width: self.pixel_width(),
+            pixel_height: self.pixel_height(),
+            dpi: 0,
         }
     }
 }
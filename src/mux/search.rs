@@ -0,0 +1,153 @@
+//! Searching the scrollback of a tab
+use crate::mux::renderable::Renderable;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use term::{Line, StableRowIndex};
+
+/// The most matches that a single search will return
+pub const MAX_SEARCH_RESULTS: usize = 1000;
+
+/// How many rows to retrieve from the renderer at a time
+const SEARCH_CHUNK_ROWS: StableRowIndex = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SearchKind {
+    /// Match the pattern text exactly
+    CaseSensitive,
+    /// Match the pattern text, ignoring case
+    CaseInsensitive,
+    /// The pattern is a regular expression
+    Regex,
+}
+
+/// A match within a single row
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SearchMatch {
+    pub stable_row: StableRowIndex,
+    /// The range of cells that matched
+    pub cols: Range<usize>,
+}
+
+pub struct Pattern {
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str, kind: SearchKind) -> anyhow::Result<Self> {
+        let regex = match kind {
+            SearchKind::CaseSensitive => Regex::new(&regex::escape(pattern))?,
+            SearchKind::CaseInsensitive => RegexBuilder::new(&regex::escape(pattern))
+                .case_insensitive(true)
+                .build()?,
+            SearchKind::Regex => Regex::new(pattern)?,
+        };
+        Ok(Self { regex })
+    }
+
+    /// Returns the ranges of cells in the line that match the pattern
+    fn find_in_line(&self, line: &Line) -> Vec<Range<usize>> {
+        let mut text = String::new();
+        // Maps each byte offset in text to the cell that produced it
+        let mut byte_to_cell = vec![];
+        for (idx, cell) in line.visible_cells() {
+            let s = cell.str();
+            text.push_str(s);
+            byte_to_cell.extend(std::iter::repeat(idx).take(s.len()));
+        }
+        byte_to_cell.push(line.cells().len());
+
+        self.regex
+            .find_iter(&text)
+            .filter(|m| m.start() != m.end())
+            .map(|m| byte_to_cell[m.start()]..byte_to_cell[m.end()])
+            .collect()
+    }
+}
+
+/// Search the rows of the renderer from the bottom up, starting with
+/// the row above `before`, or the bottom of the screen if it is None.
+/// Returns up to `limit` matches.  If the search stopped early then
+/// also returns the row to pass as `before` to resume the search.
+/// The matches on a row are never split across calls, so more than
+/// `limit` matches are returned if a single row has that many.
+pub fn search(
+    renderer: &mut dyn Renderable,
+    pattern: &Pattern,
+    before: Option<StableRowIndex>,
+    limit: usize,
+) -> (Vec<SearchMatch>, Option<StableRowIndex>) {
+    let dims = renderer.get_dimensions();
+    let bottom = dims.physical_top + dims.viewport_rows as StableRowIndex;
+    let mut end = before.map(|row| row.min(bottom)).unwrap_or(bottom);
+    let mut matches = vec![];
+
+    while end > dims.scrollback_top {
+        let start = (end - SEARCH_CHUNK_ROWS).max(dims.scrollback_top);
+        let (first_row, lines) = renderer.get_lines(start..end);
+        for (idx, line) in lines.iter().enumerate().rev() {
+            let stable_row = first_row + idx as StableRowIndex;
+            let found = pattern.find_in_line(line);
+            if found.is_empty() {
+                continue;
+            }
+            if !matches.is_empty() && matches.len() + found.len() > limit {
+                return (matches, Some(stable_row + 1));
+            }
+            matches.extend(
+                found
+                    .into_iter()
+                    .map(|cols| SearchMatch { stable_row, cols }),
+            );
+            if matches.len() >= limit {
+                let next = if stable_row > dims.scrollback_top {
+                    Some(stable_row)
+                } else {
+                    None
+                };
+                return (matches, next);
+            }
+        }
+        end = start;
+    }
+
+    (matches, None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use term::CellAttributes;
+
+    fn find(pattern: &str, kind: SearchKind, text: &str) -> Vec<Range<usize>> {
+        let line = Line::from_text(text, &CellAttributes::default());
+        Pattern::new(pattern, kind).unwrap().find_in_line(&line)
+    }
+
+    #[test]
+    fn search_kinds() {
+        assert_eq!(
+            find("foo", SearchKind::CaseSensitive, "foo Foo foo"),
+            vec![0..3, 8..11]
+        );
+        assert_eq!(
+            find("foo", SearchKind::CaseInsensitive, "foo Foo"),
+            vec![0..3, 4..7]
+        );
+        assert_eq!(
+            find("a.c", SearchKind::CaseSensitive, "abc a.c"),
+            vec![4..7]
+        );
+        assert_eq!(find("a.c", SearchKind::Regex, "abc a.c"), vec![0..3, 4..7]);
+        assert!(Pattern::new("(", SearchKind::Regex).is_err());
+    }
+
+    #[test]
+    fn search_wide_cells() {
+        // The double width character occupies cells 0 and 1
+        assert_eq!(
+            find("b", SearchKind::CaseSensitive, "\u{4e00}ab"),
+            vec![3..4]
+        );
+    }
+}
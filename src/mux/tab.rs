@@ -1,12 +1,13 @@
 use crate::mux::domain::DomainId;
-use crate::mux::renderable::Renderable;
+use crate::mux::renderable::{Renderable, TabModeState};
 use crate::mux::Mux;
 use downcast_rs::{impl_downcast, Downcast};
 use portable_pty::PtySize;
 use std::cell::RefMut;
 use std::sync::{Arc, Mutex};
 use term::color::ColorPalette;
-use term::{Clipboard, KeyCode, KeyModifiers, MouseEvent, TerminalHost};
+use term::{Clipboard, KeyCode, KeyModifiers, MouseEvent, StableRowIndex, TerminalHost};
+use termwiz::escape::osc::Progress;
 use url::Url;
 
 static TAB_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
@@ -49,26 +50,113 @@ pub trait Tab: Downcast {
     fn renderer(&self) -> RefMut<dyn Renderable>;
     fn get_title(&self) -> String;
     fn send_paste(&self, text: &str) -> anyhow::Result<()>;
+
+    /// Send part of a paste that is delivered in several chunks.
+    /// `first` and `last` indicate where the chunk falls in the paste,
+    /// so that bracketed paste markers surround the paste as a whole.
+    fn send_paste_chunk(&self, text: &str, _first: bool, _last: bool) -> anyhow::Result<()> {
+        self.send_paste(text)
+    }
     fn reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>>;
     fn writer(&self) -> RefMut<dyn std::io::Write>;
     fn resize(&self, size: PtySize) -> anyhow::Result<()>;
     fn key_down(&self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()>;
     fn mouse_event(&self, event: MouseEvent, host: &mut dyn TerminalHost) -> anyhow::Result<()>;
+    /// Feed output from the program into the terminal.  The terminal
+    /// answers the queries that it finds there, such as Device Attributes
+    /// (DA1/DA2) and Device Status Reports, by writing to `host.writer()`,
+    /// which leads back to the program.  For a tab in a remote mux the
+    /// terminal on the server parses the output and answers on our behalf,
+    /// directly into the remote pty; its responses are never routed through
+    /// the client, and so are neither lost nor answered twice when several
+    /// clients are attached.
     fn advance_bytes(&self, buf: &[u8], host: &mut dyn TerminalHost);
     fn is_dead(&self) -> bool;
     fn palette(&self) -> ColorPalette;
     fn domain_id(&self) -> DomainId;
 
+    /// Returns the palette if the application running in the terminal
+    /// has changed it from the one that is derived from the config
+    fn palette_override(&self) -> Option<ColorPalette> {
+        None
+    }
+
     /// Returns true if the terminal has grabbed the mouse and wants to
     /// give the embedded application a chance to process events.
     /// In practice this controls whether the gui will perform local
     /// handling of clicks.
     fn is_mouse_grabbed(&self) -> bool;
 
+    /// Returns true if the tab refuses input, so that the gui can
+    /// indicate that it is only being watched
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
     fn set_clipboard(&self, _clipboard: &Arc<dyn Clipboard>) {}
 
+    /// Returns the terminal modes set by the application running in
+    /// the terminal
+    fn mode_state(&self) -> TabModeState {
+        TabModeState::default()
+    }
+
+    /// Returns true if the application running in the terminal has
+    /// enabled bracketed paste mode.  Pastes passed to send_paste are
+    /// bracketed by the terminal that owns the pty, so callers don't
+    /// need to wrap the text themselves.
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        false
+    }
+
+    /// Called to inform the tab whether it is the active, visible
+    /// tab in its window.  Remote tabs use this to avoid polling
+    /// for changes that nobody can see.
+    fn set_focused(&self, _focused: bool) {}
+
+    /// Called before resize to inform the tab of the DPI of the
+    /// display that it is shown on.  Remote tabs tell the server
+    /// about a change so that programs that care about the pixel
+    /// geometry of the terminal can take another look at it.
+    fn set_dpi(&self, _dpi: usize) {}
+
+    /// Called when the user scrolls the viewport of the tab.
+    /// None means that the viewport is tracking the bottom of the output.
+    fn viewport_changed(&self, _viewport: Option<StableRowIndex>) {}
+
+    /// If the tab is following the scroll position of another viewer,
+    /// returns the position that it moved to since the last call, so
+    /// that the viewport can be updated to match.
+    fn take_followed_viewport(&self) -> Option<Option<StableRowIndex>> {
+        None
+    }
+
     fn get_current_working_dir(&self) -> Option<Url>;
 
+    /// Returns the progress most recently reported by the program
+    /// running in the tab, which the GUI may show on the tab
+    fn get_progress(&self) -> Progress {
+        Progress::None
+    }
+
+    /// Change the number of lines of scrollback that the tab retains.
+    /// Shrinking it discards the oldest lines.
+    fn set_scrollback_lines(&self, _lines: usize) -> anyhow::Result<()> {
+        anyhow::bail!("this tab doesn't support changing its scrollback size");
+    }
+
+    /// Apply a full reset (RIS) to the terminal, as though the program
+    /// running in the tab had asked for one
+    fn reset_terminal(&self) -> anyhow::Result<()> {
+        anyhow::bail!("this tab doesn't support being reset");
+    }
+
+    /// Returns the exit code of the program running in the tab,
+    /// if it has exited and the code is known
+    fn exit_code(&self) -> Option<u32> {
+        None
+    }
+
     fn trickle_paste(&self, text: String) -> anyhow::Result<()> {
         if text.len() <= PASTE_CHUNK_SIZE {
             // Send it all now
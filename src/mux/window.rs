@@ -45,11 +45,19 @@ impl Window {
         }
     }
 
+    /// Let each tab know whether it is the active tab
+    fn update_focus(&self) {
+        for (idx, tab) in self.tabs.iter().enumerate() {
+            tab.set_focused(idx == self.active);
+        }
+    }
+
     pub fn insert(&mut self, index: usize, tab: &Rc<dyn Tab>) {
         self.check_that_tab_isnt_already_in_window(tab);
         self.assign_clipboard_to_tab(tab);
         self.tabs.insert(index, Rc::clone(tab));
         self.invalidated = true;
+        self.update_focus();
     }
 
     pub fn push(&mut self, tab: &Rc<dyn Tab>) {
@@ -57,6 +65,7 @@ impl Window {
         self.assign_clipboard_to_tab(tab);
         self.tabs.push(Rc::clone(tab));
         self.invalidated = true;
+        self.update_focus();
     }
 
     pub fn is_empty(&self) -> bool {
@@ -82,7 +91,9 @@ impl Window {
 
     pub fn remove_by_idx(&mut self, idx: usize) -> Rc<dyn Tab> {
         self.invalidated = true;
-        self.tabs.remove(idx)
+        let tab = self.tabs.remove(idx);
+        self.update_focus();
+        tab
     }
 
     pub fn remove_by_id(&mut self, id: TabId) -> bool {
@@ -91,6 +102,8 @@ impl Window {
             let len = self.tabs.len();
             if len > 0 && self.active == idx && idx >= len {
                 self.set_active(len - 1);
+            } else {
+                self.update_focus();
             }
             true
         } else {
@@ -98,6 +111,10 @@ impl Window {
         }
     }
 
+    pub fn invalidate(&mut self) {
+        self.invalidated = true;
+    }
+
     pub fn check_and_reset_invalidated(&mut self) -> bool {
         let res = self.invalidated;
         self.invalidated = false;
@@ -117,6 +134,7 @@ impl Window {
         assert!(idx < self.tabs.len());
         self.invalidated = true;
         self.active = idx;
+        self.update_focus();
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &Rc<dyn Tab>> {
@@ -2,6 +2,7 @@ use crate::config::{configuration, SshDomain, TlsDomainClient, UnixDomain};
 use crate::connui::ConnectionUI;
 use crate::mux::domain::alloc_domain_id;
 use crate::mux::domain::DomainId;
+use crate::mux::tab::TabId;
 use crate::mux::Mux;
 use crate::server::codec::*;
 use crate::server::domain::{ClientDomain, ClientDomainConfig};
@@ -19,8 +20,9 @@ use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 enum ReaderMessage {
     SendPdu { pdu: Pdu, promise: Promise<Pdu> },
@@ -30,6 +32,55 @@ enum ReaderMessage {
 pub struct Client {
     sender: PollableSender<ReaderMessage>,
     local_domain_id: DomainId,
+    is_reconnectable: bool,
+    traffic: Arc<Mutex<Traffic>>,
+}
+
+/// The number of bytes sent and received over a connection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficCounts {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Accounts for the bytes sent and received by a client, both in
+/// total and for each tab.  Responses are attributed to the tab of
+/// the request that produced them; pdus that don't relate to a single
+/// tab (such as a poll of several tabs) only count towards the total.
+#[derive(Debug, Default)]
+struct Traffic {
+    total: TrafficCounts,
+    per_tab: HashMap<TabId, TrafficCounts>,
+}
+
+impl Traffic {
+    fn record_tx(&mut self, tab_id: Option<TabId>, size: usize) {
+        self.total.tx_bytes += size as u64;
+        if let Some(tab_id) = tab_id {
+            self.per_tab.entry(tab_id).or_default().tx_bytes += size as u64;
+        }
+    }
+
+    fn record_rx(&mut self, tab_id: Option<TabId>, size: usize) {
+        self.total.rx_bytes += size as u64;
+        if let Some(tab_id) = tab_id {
+            self.per_tab.entry(tab_id).or_default().rx_bytes += size as u64;
+        }
+    }
+}
+
+/// Counts the bytes read through it
+struct CountingReader<'a, R: Read> {
+    inner: &'a mut R,
+    count: usize,
+}
+
+impl<'a, R: Read> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.count += size;
+        Ok(size)
+    }
 }
 
 macro_rules! rpc {
@@ -108,31 +159,129 @@ fn process_unilateral(local_domain_id: DomainId, decoded: DecodedPdu) -> anyhow:
     Ok(())
 }
 
+/// Decides when to ping the server over an otherwise idle connection,
+/// and detects when the server stops responding to those pings
+struct Keepalive {
+    /// How long the connection may be idle before we ping;
+    /// zero disables the keepalive
+    interval: Duration,
+    timeout: Duration,
+    last_rx: Instant,
+    /// The serial number and send time of the outstanding ping
+    ping: Option<(u64, Instant)>,
+}
+
+impl Keepalive {
+    fn new(interval: Duration, timeout: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            timeout,
+            last_rx: now,
+            ping: None,
+        }
+    }
+
+    /// Record that we received a pdu.  Returns true if it is the
+    /// response to our ping, which has no promise waiting on it.
+    fn received(&mut self, serial: u64, now: Instant) -> bool {
+        self.last_rx = now;
+        match self.ping {
+            Some((ping_serial, _)) if ping_serial == serial => {
+                self.ping = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ping_due(&self, now: Instant) -> bool {
+        self.interval != Duration::from_secs(0)
+            && self.ping.is_none()
+            && now.saturating_duration_since(self.last_rx) >= self.interval
+    }
+
+    fn ping_sent(&mut self, serial: u64, now: Instant) {
+        self.ping = Some((serial, now));
+    }
+
+    /// Returns true if we have heard nothing from the server since
+    /// we pinged it, and have waited long enough for a response
+    fn timed_out(&self, now: Instant) -> bool {
+        match self.ping {
+            Some((_, sent)) => {
+                self.last_rx < sent && now.saturating_duration_since(sent) >= self.timeout
+            }
+            None => false,
+        }
+    }
+
+    /// How long we can wait for the connection before we need to
+    /// ping or check for a timeout
+    fn wait_time(&self, now: Instant) -> Option<Duration> {
+        let deadline = match self.ping {
+            Some((_, sent)) => sent + self.timeout,
+            None if self.interval == Duration::from_secs(0) => return None,
+            None => self.last_rx + self.interval,
+        };
+        Some(deadline.saturating_duration_since(now))
+    }
+}
+
 fn client_thread(
     reconnectable: &mut Reconnectable,
     local_domain_id: DomainId,
     rx: &mut PollableReceiver<ReaderMessage>,
+    traffic: &Mutex<Traffic>,
 ) -> anyhow::Result<()> {
     let mut next_serial = 1u64;
     let mut promises = HashMap::new();
     let mut read_buffer = Vec::with_capacity(1024);
+    let mut keepalive = {
+        let config = configuration();
+        Keepalive::new(
+            Duration::from_millis(config.mux_keepalive_interval_ms),
+            Duration::from_millis(config.mux_keepalive_timeout_ms),
+            Instant::now(),
+        )
+    };
 
     loop {
+        let now = Instant::now();
+        if keepalive.timed_out(now) {
+            let reason = "server did not respond to keepalive ping";
+            log::error!("{}", reason);
+            for (_, (mut promise, _)) in promises.into_iter() {
+                promise.result(Err(anyhow!("{}", reason)));
+            }
+            bail!(reason);
+        }
+        if keepalive.ping_due(now) {
+            let serial = next_serial;
+            next_serial += 1;
+            log::trace!("sending keepalive ping with serial {}", serial);
+            let size = Pdu::Ping(Ping {}).encode(reconnectable.stream(), serial)?;
+            reconnectable.stream().flush()?;
+            traffic.lock().unwrap().record_tx(None, size);
+            keepalive.ping_sent(serial, now);
+        }
+
         loop {
             match rx.try_recv() {
                 Ok(msg) => match msg {
                     ReaderMessage::SendPdu { pdu, promise } => {
                         let serial = next_serial;
                         next_serial += 1;
-                        promises.insert(serial, promise);
+                        let tab_id = pdu.tab_id();
+                        promises.insert(serial, (promise, tab_id));
 
-                        pdu.encode(reconnectable.stream(), serial)?;
+                        let size = pdu.encode(reconnectable.stream(), serial)?;
                         reconnectable.stream().flush()?;
+                        traffic.lock().unwrap().record_tx(tab_id, size);
                     }
                 },
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
-                    for (_, mut promise) in promises.into_iter() {
+                    for (_, (mut promise, _)) in promises.into_iter() {
                         promise.result(Err(anyhow!("Client was destroyed")));
                     }
                     bail!("Client was destroyed");
@@ -141,7 +290,7 @@ fn client_thread(
         }
 
         let mut poll_array = [rx.as_poll_fd(), reconnectable.stream().as_poll_fd()];
-        poll_for_read(&mut poll_array);
+        poll_for_read_with_timeout(&mut poll_array, keepalive.wait_time(Instant::now()));
 
         if poll_array[1].revents != 0 || reconnectable.stream().has_read_buffered() {
             // When TLS is enabled on a stream, it may require a mixture of
@@ -152,7 +301,14 @@ fn client_thread(
             // avoid blocking.
             loop {
                 reconnectable.stream().set_non_blocking(true)?;
-                let res = Pdu::try_read_and_decode(reconnectable.stream(), &mut read_buffer);
+                let buffered = read_buffer.len();
+                let mut reader = CountingReader {
+                    inner: reconnectable.stream(),
+                    count: 0,
+                };
+                let res = Pdu::try_read_and_decode(&mut reader, &mut read_buffer);
+                // The size of the frame is whatever it took out of the buffer
+                let size = (buffered + reader.count).saturating_sub(read_buffer.len());
                 reconnectable.stream().set_non_blocking(false)?;
                 match res {
                     Ok(None) => {
@@ -161,9 +317,17 @@ fn client_thread(
                     }
                     Ok(Some(decoded)) => {
                         log::trace!("decoded serial {}", decoded.serial);
-                        if decoded.serial == 0 {
+                        if keepalive.received(decoded.serial, Instant::now()) {
+                            traffic.lock().unwrap().record_rx(None, size);
+                        } else if decoded.serial == 0 {
+                            traffic
+                                .lock()
+                                .unwrap()
+                                .record_rx(decoded.pdu.tab_id(), size);
                             process_unilateral(local_domain_id, decoded)?;
-                        } else if let Some(mut promise) = promises.remove(&decoded.serial) {
+                        } else if let Some((mut promise, tab_id)) = promises.remove(&decoded.serial)
+                        {
+                            traffic.lock().unwrap().record_rx(tab_id, size);
                             promise.result(Ok(decoded.pdu));
                         } else {
                             log::error!(
@@ -176,7 +340,7 @@ fn client_thread(
                     Err(err) => {
                         let reason = format!("Error while decoding response pdu: {}", err);
                         log::error!("{}", reason);
-                        for (_, mut promise) in promises.into_iter() {
+                        for (_, (mut promise, _)) in promises.into_iter() {
                             promise.result(Err(anyhow!("{}", reason)));
                         }
                         bail!(reason);
@@ -571,6 +735,9 @@ impl Reconnectable {
 impl Client {
     fn new(local_domain_id: DomainId, mut reconnectable: Reconnectable) -> Self {
         let (sender, mut receiver) = pollable_channel().expect("failed to create pollable_channel");
+        let is_reconnectable = reconnectable.reconnectable();
+        let traffic = Arc::new(Mutex::new(Traffic::default()));
+        let thread_traffic = Arc::clone(&traffic);
 
         thread::spawn(move || {
             const BASE_INTERVAL: Duration = Duration::from_secs(1);
@@ -578,7 +745,12 @@ impl Client {
 
             let mut backoff = BASE_INTERVAL;
             loop {
-                if let Err(e) = client_thread(&mut reconnectable, local_domain_id, &mut receiver) {
+                if let Err(e) = client_thread(
+                    &mut reconnectable,
+                    local_domain_id,
+                    &mut receiver,
+                    &thread_traffic,
+                ) {
                     if !reconnectable.reconnectable() {
                         log::debug!("client thread ended: {}", e);
                         break;
@@ -632,14 +804,52 @@ impl Client {
         Self {
             sender,
             local_domain_id,
+            is_reconnectable,
+            traffic,
+        }
+    }
+
+    /// Construct a client that isn't connected to anything;
+    /// any rpc issued through it will fail.
+    #[cfg(test)]
+    pub fn new_for_test(local_domain_id: DomainId) -> Self {
+        let (sender, _receiver) = pollable_channel().expect("failed to create pollable_channel");
+        Self {
+            sender,
+            local_domain_id,
+            is_reconnectable: false,
+            traffic: Arc::new(Mutex::new(Traffic::default())),
         }
     }
 
+    /// Returns the number of bytes sent and received over this connection
+    pub fn traffic(&self) -> TrafficCounts {
+        self.traffic.lock().unwrap().total
+    }
+
+    /// Returns the number of bytes sent and received on behalf of the
+    /// specified remote tab
+    pub fn tab_traffic(&self, tab_id: TabId) -> TrafficCounts {
+        self.traffic
+            .lock()
+            .unwrap()
+            .per_tab
+            .get(&tab_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     #[allow(dead_code)]
     pub fn local_domain_id(&self) -> DomainId {
         self.local_domain_id
     }
 
+    /// Returns true if the underlying transport will re-establish
+    /// the connection after it is lost
+    pub fn is_reconnectable(&self) -> bool {
+        self.is_reconnectable
+    }
+
     pub fn new_default_unix_domain(initial: bool) -> anyhow::Result<Self> {
         let config = configuration();
         let unix_dom = config
@@ -691,9 +901,104 @@ impl Client {
     rpc!(write_to_tab, WriteToTab, UnitResponse);
     rpc!(send_paste, SendPaste, UnitResponse);
     rpc!(key_down, SendKeyDown, UnitResponse);
-    rpc!(mouse_event, SendMouseEvent, UnitResponse);
+    rpc!(key_downs, SendKeyDowns, UnitResponse);
+    rpc!(mouse_events, SendMouseEvents, UnitResponse);
     rpc!(resize, Resize, UnitResponse);
-    rpc!(get_tab_render_changes, GetTabRenderChanges, UnitResponse);
+    rpc!(
+        get_multi_tab_render_changes,
+        GetMultiTabRenderChanges,
+        GetMultiTabRenderChangesResponse
+    );
     rpc!(get_lines, GetLines, GetLinesResponse);
+    rpc!(get_lines_delta, GetLinesDelta, GetLinesDeltaResponse);
     rpc!(get_codec_version, GetCodecVersion, GetCodecVersionResponse);
+    rpc!(clipboard_response, GetClipboardResponse, UnitResponse);
+    rpc!(set_scroll_position, TabScrollPosition, UnitResponse);
+    rpc!(set_output_tap, SetOutputTap, UnitResponse);
+    rpc!(set_selection, SetSelection, UnitResponse);
+    rpc!(get_pane_tree, GetPaneTree, GetPaneTreeResponse);
+    rpc!(reset_terminal, ResetTerminal, UnitResponse);
+    rpc!(
+        search_scrollback,
+        SearchScrollback,
+        SearchScrollbackResponse
+    );
+    rpc!(
+        set_scrollback_lines,
+        SetScrollbackLines,
+        SetScrollbackLinesResponse
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn traffic_counts_frame_sizes() {
+        let mut encoded = Vec::new();
+        let ping_size = Pdu::Ping(Ping {}).encode(&mut encoded, 1).unwrap();
+        let lines = Pdu::GetLines(GetLines {
+            tab_id: 3,
+            lines: vec![0..100],
+            anchor: None,
+        });
+        let lines_size = lines.encode(&mut encoded, 2).unwrap();
+
+        let mut traffic = Traffic::default();
+        let mut stream = encoded.as_slice();
+        let mut read_buffer = vec![];
+        for _ in 0..2 {
+            let buffered = read_buffer.len();
+            let mut reader = CountingReader {
+                inner: &mut stream,
+                count: 0,
+            };
+            let decoded = Pdu::try_read_and_decode(&mut reader, &mut read_buffer)
+                .unwrap()
+                .unwrap();
+            let size = (buffered + reader.count).saturating_sub(read_buffer.len());
+            traffic.record_rx(decoded.pdu.tab_id(), size);
+        }
+
+        assert_eq!(traffic.total.rx_bytes, (ping_size + lines_size) as u64);
+        assert_eq!(traffic.per_tab[&3].rx_bytes, lines_size as u64);
+        assert_eq!(traffic.per_tab[&3].tx_bytes, 0);
+    }
+
+    #[test]
+    fn keepalive_pings_idle_connections() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(10);
+        let timeout = Duration::from_secs(5);
+        let mut keepalive = Keepalive::new(interval, timeout, start);
+
+        assert!(!keepalive.ping_due(start + Duration::from_secs(9)));
+        assert_eq!(
+            keepalive.wait_time(start + Duration::from_secs(9)),
+            Some(Duration::from_secs(1))
+        );
+
+        // Traffic from the server postpones the ping
+        assert!(!keepalive.received(3, start + Duration::from_secs(5)));
+        assert!(!keepalive.ping_due(start + Duration::from_secs(10)));
+
+        let sent = start + Duration::from_secs(15);
+        assert!(keepalive.ping_due(sent));
+        keepalive.ping_sent(7, sent);
+        assert!(!keepalive.ping_due(sent));
+        assert!(!keepalive.timed_out(sent + Duration::from_secs(4)));
+        assert!(keepalive.timed_out(sent + timeout));
+
+        assert!(keepalive.received(7, sent + Duration::from_secs(1)));
+        assert!(!keepalive.timed_out(sent + timeout));
+    }
+
+    #[test]
+    fn keepalive_disabled() {
+        let start = Instant::now();
+        let keepalive = Keepalive::new(Duration::from_secs(0), Duration::from_secs(5), start);
+        assert!(!keepalive.ping_due(start + Duration::from_secs(3600)));
+        assert_eq!(keepalive.wait_time(start), None);
+    }
 }
@@ -12,19 +12,25 @@
 #![cfg_attr(feature = "cargo-clippy", allow(clippy::range_plus_one))]
 
 use crate::mux::domain::DomainId;
-use crate::mux::renderable::{RenderableDimensions, StableCursorPosition};
+use crate::mux::renderable::{
+    RenderableDimensions, SelectionRegion, StableCursorPosition, TabModeState,
+};
+use crate::mux::search::{SearchKind, SearchMatch};
 use crate::mux::tab::TabId;
 use crate::mux::window::WindowId;
 use anyhow::{bail, Error};
+use bitflags::bitflags;
 use leb128;
-use log::debug;
+use log::trace;
 use portable_pty::{CommandBuilder, PtySize};
 use rangeset::*;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use std::ops::Range;
 use std::sync::Arc;
-use term::StableRowIndex;
+use term::color::{ColorPalette, RgbColor};
+use term::{ClipboardSelection, StableRowIndex};
+use termwiz::escape::osc::Progress;
 use termwiz::hyperlink::Hyperlink;
 use termwiz::surface::Line;
 use url::Url;
@@ -139,7 +145,10 @@ pub struct DecodedPdu {
     pub pdu: Pdu,
 }
 
-/// If the serialized size is larger than this, then we'll consider compressing it
+/// If the serialized size is larger than this, then we'll consider compressing it.
+/// Every peer that speaks this CODEC_VERSION can decode compressed frames, and
+/// we only use the compressed form when it is smaller, so this threshold only
+/// trades cpu for bandwidth; the trace logging in serialize() can help to tune it.
 const COMPRESS_THRESH: usize = 32;
 
 fn serialize<T: serde::Serialize>(t: &T) -> Result<(Vec<u8>, bool), Error> {
@@ -158,10 +167,11 @@ fn serialize<T: serde::Serialize>(t: &T) -> Result<(Vec<u8>, bool), Error> {
     drop(encode);
     compress.finish()?;
 
-    debug!(
-        "serialized+compress len {} vs {}",
+    trace!(
+        "serialized+compress len {} vs {} (ratio {:.2})",
         compressed.len(),
-        uncompressed.len()
+        uncompressed.len(),
+        compressed.len() as f64 / uncompressed.len() as f64
     );
 
     if compressed.len() < uncompressed.len() {
@@ -196,7 +206,8 @@ macro_rules! pdu {
         }
 
         impl Pdu {
-            pub fn encode<W: std::io::Write>(&self, w: W, serial: u64) -> Result<(), Error> {
+            /// Encode the pdu to the stream, returning the size of the frame
+            pub fn encode<W: std::io::Write>(&self, w: W, serial: u64) -> Result<usize, Error> {
                 match self {
                     Pdu::Invalid{..} => bail!("attempted to serialize Pdu::Invalid"),
                     $(
@@ -204,7 +215,7 @@ macro_rules! pdu {
                             let (data, is_compressed) = serialize(s)?;
                             let encoded_size = encode_raw($vers, serial, &data, is_compressed, w)?;
                             metrics::value!("pdu.size", encoded_size as u64, "pdu" => stringify!($name));
-                            Ok(())
+                            Ok(encoded_size)
                         }
                     ,)*
                 }
@@ -237,8 +248,11 @@ macro_rules! pdu {
 
 /// The overall version of the codec.
 /// This must be bumped when backwards incompatible changes
-/// are made to the types and protocol.
-pub const CODEC_VERSION: usize = 2;
+/// are made to the types and protocol.  Peers with the same version
+/// can always talk to each other, so additive changes (new Pdus, or
+/// new ways of using existing ones) don't bump it: they are gated on
+/// a ProtocolFeatures bit instead.
+pub const CODEC_VERSION: usize = 32;
 
 // Defines the Pdu enum.
 // Each struct has an explicit identifying number.
@@ -265,6 +279,33 @@ pdu! {
     GetTabRenderChangesResponse: 25,
     GetCodecVersion: 26,
     GetCodecVersionResponse: 27,
+    SendMouseEvents: 28,
+    SetTabTitle: 29,
+    Bell: 30,
+    GetClipboardRequest: 31,
+    GetClipboardResponse: 32,
+    GetMultiTabRenderChanges: 33,
+    GetMultiTabRenderChangesResponse: 34,
+    TabExited: 35,
+    TabScrollPosition: 36,
+    SearchScrollback: 37,
+    SearchScrollbackResponse: 38,
+    SendKeyDowns: 39,
+    GetLinesDelta: 40,
+    GetLinesDeltaResponse: 41,
+    SetPalette: 42,
+    SetScrollbackLines: 43,
+    SetScrollbackLinesResponse: 44,
+    TabRemoved: 45,
+    SetOutputTap: 46,
+    TabOutputBytes: 47,
+    SetSelection: 48,
+    GetPaneTree: 49,
+    GetPaneTreeResponse: 50,
+    SetWorkingDir: 51,
+    ResetTerminal: 52,
+    Notification: 53,
+    SetTabProgress: 54,
 }
 
 impl Pdu {
@@ -332,10 +373,37 @@ impl Pdu {
 
     pub fn tab_id(&self) -> Option<TabId> {
         match self {
+            Pdu::WriteToTab(WriteToTab { tab_id, .. }) => Some(*tab_id),
+            Pdu::SendPaste(SendPaste { tab_id, .. }) => Some(*tab_id),
+            Pdu::SendKeyDown(SendKeyDown { tab_id, .. }) => Some(*tab_id),
+            Pdu::SendKeyDowns(SendKeyDowns { tab_id, .. }) => Some(*tab_id),
+            Pdu::SendMouseEvent(SendMouseEvent { tab_id, .. }) => Some(*tab_id),
+            Pdu::SendMouseEvents(SendMouseEvents { tab_id, .. }) => Some(*tab_id),
+            Pdu::Resize(Resize { tab_id, .. }) => Some(*tab_id),
+            Pdu::GetLines(GetLines { tab_id, .. }) => Some(*tab_id),
+            Pdu::GetLinesDelta(GetLinesDelta { tab_id, .. }) => Some(*tab_id),
+            Pdu::GetTabRenderChanges(GetTabRenderChanges { tab_id }) => Some(*tab_id),
+            Pdu::SearchScrollback(SearchScrollback { tab_id, .. }) => Some(*tab_id),
+            Pdu::SetScrollbackLines(SetScrollbackLines { tab_id, .. }) => Some(*tab_id),
             Pdu::GetTabRenderChangesResponse(GetTabRenderChangesResponse { tab_id, .. }) => {
                 Some(*tab_id)
             }
             Pdu::SetClipboard(SetClipboard { tab_id, .. }) => Some(*tab_id),
+            Pdu::SetTabTitle(SetTabTitle { tab_id, .. }) => Some(*tab_id),
+            Pdu::Bell(Bell { tab_id }) => Some(*tab_id),
+            Pdu::GetClipboardRequest(GetClipboardRequest { tab_id }) => Some(*tab_id),
+            Pdu::TabExited(TabExited { tab_id, .. }) => Some(*tab_id),
+            Pdu::TabScrollPosition(TabScrollPosition { tab_id, .. }) => Some(*tab_id),
+            Pdu::SetPalette(SetPalette { tab_id, .. }) => Some(*tab_id),
+            Pdu::TabRemoved(TabRemoved { tab_id }) => Some(*tab_id),
+            Pdu::SetOutputTap(SetOutputTap { tab_id, .. }) => Some(*tab_id),
+            Pdu::TabOutputBytes(TabOutputBytes { tab_id, .. }) => Some(*tab_id),
+            Pdu::SetSelection(SetSelection { tab_id, .. }) => Some(*tab_id),
+            Pdu::GetPaneTree(GetPaneTree { tab_id }) => Some(*tab_id),
+            Pdu::SetWorkingDir(SetWorkingDir { tab_id, .. }) => Some(*tab_id),
+            Pdu::ResetTerminal(ResetTerminal { tab_id }) => Some(*tab_id),
+            Pdu::Notification(Notification { tab_id, .. }) => Some(*tab_id),
+            Pdu::SetTabProgress(SetTabProgress { tab_id, .. }) => Some(*tab_id),
             _ => None,
         }
     }
@@ -349,13 +417,77 @@ pub struct ErrorResponse {
     pub reason: String,
 }
 
+bitflags! {
+    /// Optional parts of the protocol that a peer may or may not
+    /// implement.
+    ///
+    /// These are negotiated as part of the GetCodecVersion handshake:
+    /// the client sends the features that it supports, and the server
+    /// responds with those that it also supports.  That intersection is
+    /// recorded by both the client and the server session, and consulted
+    /// wherever they have a choice of encoding.  Bits that a peer doesn't
+    /// know about are dropped by `negotiate`, so a peer may advertise
+    /// features that the other side lacks.  This only helps peers with
+    /// the same CODEC_VERSION: features are how that version avoids
+    /// being bumped for additive changes, not a way to bridge two
+    /// different versions.
+    ///
+    /// To add a feature, define the next unused bit here and gate the
+    /// new behavior on it.  The bits are part of the protocol, so they
+    /// must never be reused or renumbered.
+    #[derive(Default, Serialize, Deserialize)]
+    pub struct ProtocolFeatures: u64 {
+        /// GetLinesDelta may be used in place of GetLines
+        const LINE_DELTAS = 1;
+        /// GetLinesResponse may use SerializedLines::with_blank_runs
+        const BLANK_RUNS = 2;
+        /// Responses carry seqno and delta_seq values; without this
+        /// they are zero and the client doesn't check their order
+        const SEQUENCE_NUMBERS = 4;
+    }
+}
+
+impl ProtocolFeatures {
+    /// The features that are implemented by this build
+    pub fn supported() -> Self {
+        Self::all()
+    }
+
+    /// Returns the features that both we and the peer support, given
+    /// those that the peer advertised
+    pub fn negotiate(peer: Self) -> Self {
+        Self::supported() & peer
+    }
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct GetCodecVersion {}
+pub struct GetCodecVersion {
+    /// The features that the client supports
+    #[serde(deserialize_with = "default_if_missing")]
+    pub features: ProtocolFeatures,
+}
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetCodecVersionResponse {
     pub codec_vers: usize,
     pub version_string: String,
+    /// The features that both the server and client support
+    #[serde(deserialize_with = "default_if_missing")]
+    pub features: ProtocolFeatures,
+}
+
+/// Decodes a trailing field that older peers don't send.
+/// varbincode encodes a struct as a plain sequence of its fields, so
+/// `#[serde(default)]` doesn't help when a field is missing from the
+/// end of the data; we see an error reading it instead.
+/// The handshake must decode whatever the version of the peer, as it
+/// is how we find out that version in the first place.
+fn default_if_missing<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(T::deserialize(deserializer).unwrap_or_default())
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -438,6 +570,16 @@ pub struct WriteToTab {
 pub struct SendPaste {
     pub tab_id: TabId,
     pub data: String,
+    /// Set if the data is one of several chunks of a larger paste
+    #[serde(default)]
+    pub chunk: Option<PasteChunk>,
+}
+
+/// Where a chunk falls within a paste that is sent in several pieces
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Copy)]
+pub struct PasteChunk {
+    pub first: bool,
+    pub last: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -446,16 +588,315 @@ pub struct SendKeyDown {
     pub event: termwiz::input::KeyEvent,
 }
 
+/// A burst of key presses, to be applied in order
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SendKeyDowns {
+    pub tab_id: TabId,
+    pub events: Vec<termwiz::input::KeyEvent>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SendMouseEvent {
     pub tab_id: TabId,
     pub event: term::input::MouseEvent,
 }
 
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SendMouseEvents {
+    pub tab_id: TabId,
+    pub events: Vec<term::input::MouseEvent>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SetClipboard {
     pub tab_id: TabId,
     pub clipboard: Option<String>,
+    #[serde(default)]
+    pub selection: ClipboardSelection,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetTabTitle {
+    pub tab_id: TabId,
+    pub title: String,
+}
+
+/// Sent by the server when the working directory of a tab changes,
+/// so that the client needn't wait for the next render delta.
+/// The url is carried as a string so that a malformed value doesn't
+/// prevent the rest of the stream from being decoded.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetWorkingDir {
+    pub tab_id: TabId,
+    pub url: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ProgressState {
+    None,
+    Indeterminate,
+    Normal,
+    Error,
+}
+
+/// Sent by the server when the program running in a tab reports a
+/// change in the progress of a long running operation.  The percent
+/// is only meaningful for the Normal and Error states.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetTabProgress {
+    pub tab_id: TabId,
+    pub state: ProgressState,
+    pub percent: u8,
+}
+
+impl SetTabProgress {
+    pub fn new(tab_id: TabId, progress: Progress) -> Self {
+        let (state, percent) = match progress {
+            Progress::None => (ProgressState::None, 0),
+            Progress::Indeterminate => (ProgressState::Indeterminate, 0),
+            Progress::Normal(percent) => (ProgressState::Normal, percent),
+            Progress::Error(percent) => (ProgressState::Error, percent),
+        };
+        Self {
+            tab_id,
+            state,
+            percent,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let percent = self.percent.min(100);
+        match self.state {
+            ProgressState::None => Progress::None,
+            ProgressState::Indeterminate => Progress::Indeterminate,
+            ProgressState::Normal => Progress::Normal(percent),
+            ProgressState::Error => Progress::Error(percent),
+        }
+    }
+}
+
+/// Sent by the server when the program running in a tab asks
+/// for a desktop notification to be shown
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Notification {
+    pub tab_id: TabId,
+    pub title: Option<String>,
+    pub body: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Bell {
+    pub tab_id: TabId,
+}
+
+/// Sent by the server when the program running in a tab has exited
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TabExited {
+    pub tab_id: TabId,
+    pub exit_code: Option<u32>,
+}
+
+/// Sent by the server when a tab is removed, such as when another
+/// client closes it
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TabRemoved {
+    pub tab_id: TabId,
+}
+
+/// Sent by a client to start (`enable` is true) or stop receiving
+/// the raw output of a tab as TabOutputBytes
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetOutputTap {
+    pub tab_id: TabId,
+    pub enable: bool,
+}
+
+/// Sent by the server to a client that has tapped the output of a
+/// tab, carrying the bytes that were read from its pty
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TabOutputBytes {
+    pub tab_id: TabId,
+    pub data: Vec<u8>,
+}
+
+/// Sent by a client when it scrolls the viewport of a tab, and relayed
+/// by the server to the other clients attached to the tab.
+/// A viewport of None means that it is tracking the bottom of the output.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TabScrollPosition {
+    pub tab_id: TabId,
+    pub viewport: Option<StableRowIndex>,
+}
+
+/// Sent by a client when it selects text in a tab, and relayed by the
+/// server to the other clients attached to the tab so that they can
+/// show it.  A range of None means that the selection was cleared.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetSelection {
+    pub tab_id: TabId,
+    pub range: Option<SelectionRegion>,
+}
+
+/// Asks the server for the layout of the panes within a tab
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetPaneTree {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Copy)]
+pub enum SplitDirection {
+    /// The panes are side by side
+    Horizontal,
+    /// One pane is above the other
+    Vertical,
+}
+
+/// The layout of the panes within a tab.  Tabs in the mux are not
+/// split at present, so servers report a single pane that covers
+/// the whole tab.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub enum PaneNode {
+    /// A pane, identified by the id of the remote tab that renders it
+    Leaf { tab_id: TabId, size: PtySize },
+    Split {
+        direction: SplitDirection,
+        first: Box<PaneNode>,
+        second: Box<PaneNode>,
+    },
+}
+
+impl PaneNode {
+    /// Returns the ids of the panes, in layout order
+    pub fn panes(&self) -> Vec<TabId> {
+        match self {
+            PaneNode::Leaf { tab_id, .. } => vec![*tab_id],
+            PaneNode::Split { first, second, .. } => {
+                let mut panes = first.panes();
+                panes.extend(second.panes());
+                panes
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetPaneTreeResponse {
+    pub tree: PaneNode,
+}
+
+/// Asks the server to apply a full reset (RIS) to the terminal of a tab
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ResetTerminal {
+    pub tab_id: TabId,
+}
+
+/// Sent by the server when the program running in a tab changes the
+/// palette via escape sequences.  A palette of None means that the
+/// palette was reset to the one from the config.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetPalette {
+    pub tab_id: TabId,
+    pub palette: Option<SerdePalette>,
+}
+
+/// A serializable form of ColorPalette
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct SerdePalette {
+    pub colors: Vec<RgbColor>,
+    pub foreground: RgbColor,
+    pub background: RgbColor,
+    pub cursor_fg: RgbColor,
+    pub cursor_bg: RgbColor,
+    pub cursor_border: RgbColor,
+    pub selection_fg: RgbColor,
+    pub selection_bg: RgbColor,
+    pub scrollbar_thumb: RgbColor,
+}
+
+impl From<&ColorPalette> for SerdePalette {
+    fn from(palette: &ColorPalette) -> SerdePalette {
+        SerdePalette {
+            colors: palette.colors.0.to_vec(),
+            foreground: palette.foreground,
+            background: palette.background,
+            cursor_fg: palette.cursor_fg,
+            cursor_bg: palette.cursor_bg,
+            cursor_border: palette.cursor_border,
+            selection_fg: palette.selection_fg,
+            selection_bg: palette.selection_bg,
+            scrollbar_thumb: palette.scrollbar_thumb,
+        }
+    }
+}
+
+impl Into<ColorPalette> for SerdePalette {
+    fn into(self) -> ColorPalette {
+        let mut palette = ColorPalette::default();
+        for (idx, color) in self.colors.into_iter().take(256).enumerate() {
+            palette.colors.0[idx] = color;
+        }
+        palette.foreground = self.foreground;
+        palette.background = self.background;
+        palette.cursor_fg = self.cursor_fg;
+        palette.cursor_bg = self.cursor_bg;
+        palette.cursor_border = self.cursor_border;
+        palette.selection_fg = self.selection_fg;
+        palette.selection_bg = self.selection_bg;
+        palette.scrollbar_thumb = self.scrollbar_thumb;
+        palette
+    }
+}
+
+/// Changes the number of lines of scrollback that the server retains
+/// for a tab.  The server may clamp the number of lines.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetScrollbackLines {
+    pub tab_id: TabId,
+    pub lines: usize,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetScrollbackLinesResponse {
+    /// The number of lines that the server actually applied
+    pub lines: usize,
+    /// The dimensions of the tab after the change
+    pub dimensions: RenderableDimensions,
+}
+
+/// Searches the full scrollback of a tab on the server
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SearchScrollback {
+    pub tab_id: TabId,
+    pub pattern: String,
+    pub kind: SearchKind,
+    /// Only search the rows above this one; used to resume a search
+    /// from the `next` row of a prior response
+    pub before: Option<StableRowIndex>,
+    /// The maximum number of matches to return.  The server may
+    /// impose a lower limit.
+    pub limit: usize,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SearchScrollbackResponse {
+    /// The matches, ordered from the bottom of the scrollback up
+    pub matches: Vec<SearchMatch>,
+    /// If set, there may be more matches above this row
+    pub next: Option<StableRowIndex>,
+}
+
+/// Sent by the server to ask the client for the contents of
+/// its clipboard
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetClipboardRequest {
+    pub tab_id: TabId,
+}
+
+/// Sent by the client in reply to GetClipboardRequest
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetClipboardResponse {
+    pub tab_id: TabId,
+    pub clipboard: String,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -469,6 +910,21 @@ pub struct GetTabRenderChanges {
     pub tab_id: TabId,
 }
 
+/// Requests the render changes for several tabs in a single round trip.
+/// The changes are delivered as unilateral GetTabRenderChangesResponse
+/// pdus, in the same way as for GetTabRenderChanges.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetMultiTabRenderChanges {
+    pub tab_ids: Vec<TabId>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetMultiTabRenderChangesResponse {
+    /// The tabs for which the changes could not be computed,
+    /// along with the reason
+    pub errors: Vec<(TabId, String)>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetTabRenderChangesResponse {
     pub tab_id: TabId,
@@ -481,12 +937,58 @@ pub struct GetTabRenderChangesResponse {
     /// Lines that the server thought we'd almost certainly
     /// want to fetch as soon as we received this response
     pub bonus_lines: SerializedLines,
+    #[serde(default)]
+    pub bracketed_paste: bool,
+    /// The sequence number of bonus_lines; see GetLinesResponse
+    #[serde(default)]
+    pub seqno: u64,
+    /// The terminal modes set by the application in the tab
+    #[serde(default)]
+    pub modes: TabModeState,
+    /// Counts the deltas sent for the tab, starting at 1, so that the
+    /// client can tell if it missed one.  Zero if not tracked.
+    #[serde(default)]
+    pub delta_seq: u64,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetLines {
     pub tab_id: TabId,
     pub lines: Vec<Range<StableRowIndex>>,
+    /// If set, `lines` are offsets from the anchor rather than
+    /// stable row indices
+    #[serde(default)]
+    pub anchor: Option<RowAnchor>,
+}
+
+/// A row that the server locates when it handles a request, so that
+/// a client can ask for rows relative to it without racing changes
+/// to the stable row indices, such as while output is scrolling
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Copy)]
+pub enum RowAnchor {
+    /// The top row of the screen
+    PhysicalTop,
+}
+
+impl RowAnchor {
+    /// Returns the stable row index of the anchor
+    pub fn resolve(self, dims: &RenderableDimensions) -> StableRowIndex {
+        match self {
+            Self::PhysicalTop => dims.physical_top,
+        }
+    }
+
+    /// Convert ranges of offsets from the anchor, which is at
+    /// `anchor_row`, into ranges of stable row indices
+    pub fn apply(
+        anchor_row: StableRowIndex,
+        lines: Vec<Range<StableRowIndex>>,
+    ) -> Vec<Range<StableRowIndex>> {
+        lines
+            .into_iter()
+            .map(|r| r.start + anchor_row..r.end + anchor_row)
+            .collect()
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -512,13 +1014,78 @@ struct LineHyperlink {
 pub struct SerializedLines {
     lines: Vec<(StableRowIndex, Line)>,
     hyperlinks: Vec<LineHyperlink>,
+    #[serde(default)]
+    blank_runs: Vec<BlankLines>,
     // TODO: image references
 }
 
+/// A run of consecutive rows that are all the same blank line,
+/// which we send as a single copy of the line
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct BlankLines {
+    start: StableRowIndex,
+    count: usize,
+    template: Line,
+}
+
+/// Returns true if every cell of the line is a space with the same
+/// attributes, and no hyperlink
+fn is_uniform_blank(line: &Line) -> bool {
+    match line.cells().first() {
+        Some(first) => {
+            first.str() == " "
+                && first.attrs().hyperlink.is_none()
+                && line.cells().iter().all(|cell| cell == first)
+        }
+        None => false,
+    }
+}
+
 impl SerializedLines {
     pub fn lines(self) -> Vec<(StableRowIndex, Line)> {
         self.into()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.blank_runs.is_empty()
+    }
+
+    /// Like From<Vec<(StableRowIndex, Line)>>, but consecutive rows that
+    /// hold the same blank line are sent as a single copy of that line.
+    /// Only used when ProtocolFeatures::BLANK_RUNS has been negotiated.
+    pub fn with_blank_runs(lines: Vec<(StableRowIndex, Line)>) -> Self {
+        let mut blank_runs: Vec<BlankLines> = vec![];
+        let mut others = vec![];
+        for (stable_row, line) in lines {
+            if is_uniform_blank(&line) {
+                if let Some(run) = blank_runs.last_mut() {
+                    if run.start + run.count as StableRowIndex == stable_row
+                        && run.template.cells() == line.cells()
+                    {
+                        run.count += 1;
+                        continue;
+                    }
+                }
+                blank_runs.push(BlankLines {
+                    start: stable_row,
+                    count: 1,
+                    template: line,
+                });
+            } else {
+                others.push((stable_row, line));
+            }
+        }
+
+        // A run of a single row doesn't save anything
+        let (blank_runs, singles): (Vec<BlankLines>, Vec<BlankLines>) =
+            blank_runs.into_iter().partition(|run| run.count > 1);
+        others.extend(singles.into_iter().map(|run| (run.start, run.template)));
+        others.sort_by_key(|(stable_row, _)| *stable_row);
+
+        let mut result: Self = others.into();
+        result.blank_runs = blank_runs;
+        result
+    }
 }
 
 impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
@@ -586,7 +1153,11 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
             }
         }
 
-        Self { lines, hyperlinks }
+        Self {
+            lines,
+            hyperlinks,
+            blank_runs: vec![],
+        }
     }
 }
 
@@ -594,7 +1165,7 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
 /// serialization, and return the line data.
 impl Into<Vec<(StableRowIndex, Line)>> for SerializedLines {
     fn into(self) -> Vec<(StableRowIndex, Line)> {
-        if self.hyperlinks.is_empty() {
+        let mut lines = if self.hyperlinks.is_empty() {
             self.lines
         } else {
             let mut lines = self.lines;
@@ -616,7 +1187,17 @@ impl Into<Vec<(StableRowIndex, Line)>> for SerializedLines {
             }
 
             lines
+        };
+
+        if !self.blank_runs.is_empty() {
+            for run in self.blank_runs {
+                for idx in 0..run.count {
+                    lines.push((run.start + idx as StableRowIndex, run.template.clone()));
+                }
+            }
+            lines.sort_by_key(|(stable_row, _)| *stable_row);
         }
+        lines
     }
 }
 
@@ -624,6 +1205,53 @@ impl Into<Vec<(StableRowIndex, Line)>> for SerializedLines {
 pub struct GetLinesResponse {
     pub tab_id: TabId,
     pub lines: SerializedLines,
+    /// Increases each time the server sends lines for the tab, so
+    /// that the client can tell which of two copies of a line is newer
+    #[serde(default)]
+    pub seqno: u64,
+    /// The stable row index that the anchor of the request resolved
+    /// to, if it had one
+    #[serde(default)]
+    pub anchor_row: Option<StableRowIndex>,
+}
+
+/// Like GetLines, but the client also tells us which version of a row
+/// it already holds, so that only the cells that have changed since
+/// that version need to be sent
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetLinesDelta {
+    pub tab_id: TabId,
+    pub lines: Vec<Range<StableRowIndex>>,
+    pub known: Vec<(StableRowIndex, u64)>,
+}
+
+/// Describes how to apply the corresponding line of a
+/// GetLinesDeltaResponse
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Copy)]
+pub enum LineDelta {
+    /// The line is the complete row
+    Full { version: u64 },
+    /// The line holds the cells of the row from `start` onwards that
+    /// differ from the `base` version of the row.  The cells after
+    /// those are unchanged, other than that the row now has `width`
+    /// cells.
+    Patch {
+        base: u64,
+        version: u64,
+        start: usize,
+        width: usize,
+    },
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetLinesDeltaResponse {
+    pub tab_id: TabId,
+    pub lines: SerializedLines,
+    /// One entry for each of the lines
+    pub deltas: Vec<LineDelta>,
+    /// See GetLinesResponse
+    #[serde(default)]
+    pub seqno: u64,
 }
 
 #[cfg(test)]
@@ -657,6 +1285,90 @@ mod test {
         }
     }
 
+    #[test]
+    fn unknown_features_ignored() {
+        let mut encoded = Vec::new();
+        // A feature bit from some future version
+        let future = unsafe { ProtocolFeatures::from_bits_unchecked(1 << 63) };
+        Pdu::GetCodecVersion(GetCodecVersion {
+            features: ProtocolFeatures::LINE_DELTAS | future,
+        })
+        .encode(&mut encoded, 0x1)
+        .unwrap();
+
+        match Pdu::decode(encoded.as_slice()).unwrap().pdu {
+            Pdu::GetCodecVersion(GetCodecVersion { features }) => {
+                assert_eq!(
+                    ProtocolFeatures::negotiate(features),
+                    ProtocolFeatures::LINE_DELTAS
+                );
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        assert_eq!(
+            ProtocolFeatures::negotiate(ProtocolFeatures::empty()),
+            ProtocolFeatures::empty()
+        );
+    }
+
+    #[test]
+    fn handshake_from_older_peer() {
+        // The handshake as sent by peers from before features existed
+        #[derive(Serialize)]
+        struct OldGetCodecVersion {}
+        #[derive(Serialize)]
+        struct OldGetCodecVersionResponse {
+            codec_vers: usize,
+            version_string: String,
+        }
+
+        let (data, is_compressed) = serialize(&OldGetCodecVersion {}).unwrap();
+        let req: GetCodecVersion = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(req.features, ProtocolFeatures::empty());
+
+        let (data, is_compressed) = serialize(&OldGetCodecVersionResponse {
+            codec_vers: 2,
+            version_string: "old".to_owned(),
+        })
+        .unwrap();
+        let resp: GetCodecVersionResponse = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(resp.codec_vers, 2);
+        assert_eq!(resp.version_string, "old");
+        assert_eq!(resp.features, ProtocolFeatures::empty());
+    }
+
+    #[test]
+    fn row_anchor_resolves() {
+        let dims = RenderableDimensions {
+            cols: 80,
+            viewport_rows: 24,
+            scrollback_rows: 124,
+            physical_top: 100,
+            scrollback_top: 0,
+            pixel_width: 0,
+            pixel_height: 0,
+            dpi: 0,
+        };
+        let anchor_row = RowAnchor::PhysicalTop.resolve(&dims);
+        assert_eq!(anchor_row, 100);
+        assert_eq!(
+            RowAnchor::apply(anchor_row, vec![0..24, -10..-5]),
+            vec![100..124, 90..95]
+        );
+
+        let pdu = Pdu::GetLines(GetLines {
+            tab_id: 1,
+            lines: vec![0..24],
+            anchor: Some(RowAnchor::PhysicalTop),
+        });
+        let mut encoded = Vec::new();
+        pdu.encode(&mut encoded, 0x42).unwrap();
+        assert_eq!(
+            DecodedPdu { serial: 0x42, pdu },
+            Pdu::decode(encoded.as_slice()).unwrap()
+        );
+    }
+
     #[test]
     fn test_pdu_ping() {
         let mut encoded = Vec::new();
@@ -746,4 +1458,108 @@ mod test {
             Pdu::decode(encoded.as_slice()).unwrap()
         );
     }
+
+    #[test]
+    fn test_get_lines_response_compressed() {
+        let attrs = termwiz::cell::CellAttributes::default();
+        let lines: Vec<(StableRowIndex, Line)> = (0..50)
+            .map(|row| {
+                (
+                    row,
+                    Line::from_text("hello there, compressible text", &attrs),
+                )
+            })
+            .collect();
+        let pdu = Pdu::GetLinesResponse(GetLinesResponse {
+            tab_id: 1,
+            lines: lines.into(),
+            seqno: 1,
+            anchor_row: None,
+        });
+
+        let mut encoded = Vec::new();
+        pdu.encode(&mut encoded, 0x42).unwrap();
+        assert!(decode_raw(encoded.as_slice()).unwrap().is_compressed);
+        assert_eq!(
+            DecodedPdu { serial: 0x42, pdu },
+            Pdu::decode(encoded.as_slice()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_palette_round_trip() {
+        let mut palette = ColorPalette::default();
+        palette.colors.0[200] = RgbColor::new(1, 2, 3);
+        palette.background = RgbColor::new(4, 5, 6);
+
+        let serde_palette = SerdePalette::from(&palette);
+        let decoded: ColorPalette = serde_palette.clone().into();
+        assert_eq!(decoded.colors.0[200], RgbColor::new(1, 2, 3));
+        assert_eq!(decoded.background, RgbColor::new(4, 5, 6));
+        assert_eq!(SerdePalette::from(&decoded), serde_palette);
+    }
+
+    #[test]
+    fn test_blank_runs() {
+        let mut red = term::CellAttributes::default();
+        red.set_background(term::color::AnsiColor::Maroon);
+        let lines: Vec<(StableRowIndex, Line)> = vec![
+            (0, Line::with_width(4)),
+            (1, Line::with_width(4)),
+            (2, Line::with_width(4)),
+            (3, Line::from_text("text", &Default::default())),
+            (4, Line::with_width(4)),
+            (5, Line::from_text("    ", &red)),
+            (6, Line::from_text("    ", &red)),
+        ];
+
+        let serialized = SerializedLines::with_blank_runs(lines.clone());
+        assert_eq!(serialized.lines.len(), 2);
+        assert_eq!(serialized.blank_runs.len(), 2);
+
+        let pdu = Pdu::GetLinesResponse(GetLinesResponse {
+            tab_id: 1,
+            lines: serialized,
+            seqno: 1,
+            anchor_row: None,
+        });
+        let mut encoded = Vec::new();
+        pdu.encode(&mut encoded, 0x42).unwrap();
+        let decoded = match Pdu::decode(encoded.as_slice()).unwrap().pdu {
+            Pdu::GetLinesResponse(response) => response.lines.lines(),
+            pdu => panic!("unexpected {:?}", pdu),
+        };
+        let rows = |lines: &[(StableRowIndex, Line)]| {
+            lines
+                .iter()
+                .map(|(row, line)| (*row, line.cells().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rows(&decoded), rows(&lines));
+    }
+
+    #[test]
+    fn pane_tree_round_trip() {
+        let leaf = |tab_id| {
+            Box::new(PaneNode::Leaf {
+                tab_id,
+                size: PtySize::default(),
+            })
+        };
+        let tree = PaneNode::Split {
+            direction: SplitDirection::Horizontal,
+            first: leaf(3),
+            second: Box::new(PaneNode::Split {
+                direction: SplitDirection::Vertical,
+                first: leaf(1),
+                second: leaf(2),
+            }),
+        };
+        assert_eq!(tree.panes(), vec![3, 1, 2]);
+
+        let pdu = Pdu::GetPaneTreeResponse(GetPaneTreeResponse { tree });
+        let mut encoded = Vec::new();
+        pdu.encode(&mut encoded, 0x42).unwrap();
+        assert_eq!(Pdu::decode(encoded.as_slice()).unwrap().pdu, pdu);
+    }
 }
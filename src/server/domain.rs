@@ -6,7 +6,9 @@ use crate::mux::tab::{Tab, TabId};
 use crate::mux::window::WindowId;
 use crate::mux::Mux;
 use crate::server::client::Client;
-use crate::server::codec::{GetCodecVersion, ListTabsResponse, Spawn, CODEC_VERSION};
+use crate::server::codec::{
+    GetCodecVersion, ListTabsResponse, ProtocolFeatures, Spawn, CODEC_VERSION,
+};
 use crate::server::tab::ClientTab;
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
@@ -16,6 +18,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub struct ClientInner {
     pub client: Client,
@@ -23,6 +26,27 @@ pub struct ClientInner {
     pub remote_domain_id: DomainId,
     remote_to_local_window: Mutex<HashMap<WindowId, WindowId>>,
     remote_to_local_tab: Mutex<HashMap<TabId, TabId>>,
+    /// Maps the remote tab id to the local tab id for the tabs whose
+    /// poll is due.  They are sent to the server together in a single
+    /// GetMultiTabRenderChanges request.
+    pending_polls: Mutex<HashMap<TabId, TabId>>,
+    /// The optional protocol features that both we and the server
+    /// support, as negotiated when we attached
+    features: Mutex<ProtocolFeatures>,
+    /// The smoothed round trip time of our polls
+    latency: Mutex<Option<Duration>>,
+}
+
+/// The weight given to each new round trip time sample, expressed
+/// as a divisor; as for TCP, each sample contributes 1/8th
+const LATENCY_SAMPLE_WEIGHT: u32 = 8;
+
+/// Fold a round trip time sample into the moving average
+fn smoothed_latency(prior: Option<Duration>, sample: Duration) -> Duration {
+    match prior {
+        None => sample,
+        Some(prior) => (prior * (LATENCY_SAMPLE_WEIGHT - 1) + sample) / LATENCY_SAMPLE_WEIGHT,
+    }
 }
 
 impl ClientInner {
@@ -45,6 +69,46 @@ impl ClientInner {
         );
     }
 
+    /// Queue a poll for a tab.  Returns true if the queue was previously
+    /// empty, in which case the caller is responsible for arranging for
+    /// take_pending_polls to be called.
+    pub fn queue_poll(&self, remote_tab_id: TabId, local_tab_id: TabId) -> bool {
+        let mut pending = self.pending_polls.lock().unwrap();
+        let was_empty = pending.is_empty();
+        pending.insert(remote_tab_id, local_tab_id);
+        was_empty
+    }
+
+    /// Forget about a tab that was removed from the server
+    pub fn forget_tab(&self, remote_tab_id: TabId) {
+        self.remote_to_local_tab
+            .lock()
+            .unwrap()
+            .remove(&remote_tab_id);
+        self.cancel_poll(remote_tab_id);
+    }
+
+    /// Remove a tab from the set of tabs waiting on a poll
+    pub fn cancel_poll(&self, remote_tab_id: TabId) {
+        self.pending_polls.lock().unwrap().remove(&remote_tab_id);
+    }
+
+    /// Record how long a request to the server took to complete
+    pub fn record_round_trip(&self, elapsed: Duration) {
+        let mut latency = self.latency.lock().unwrap();
+        *latency = Some(smoothed_latency(*latency, elapsed));
+    }
+
+    /// Returns the estimated round trip time to the server, if we
+    /// have completed any requests yet
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.lock().unwrap()
+    }
+
+    pub fn take_pending_polls(&self) -> HashMap<TabId, TabId> {
+        std::mem::replace(&mut *self.pending_polls.lock().unwrap(), HashMap::new())
+    }
+
     fn local_to_remote_window(&self, local_window_id: WindowId) -> Option<WindowId> {
         let map = self.remote_to_local_window.lock().unwrap();
         for (remote, local) in map.iter() {
@@ -94,14 +158,26 @@ impl ClientInner {
             remote_domain_id,
             remote_to_local_window: Mutex::new(HashMap::new()),
             remote_to_local_tab: Mutex::new(HashMap::new()),
+            pending_polls: Mutex::new(HashMap::new()),
+            features: Mutex::new(ProtocolFeatures::empty()),
+            latency: Mutex::new(None),
         }
     }
+
+    /// Returns true if the server has agreed to use `feature`
+    pub fn supports(&self, feature: ProtocolFeatures) -> bool {
+        self.features.lock().unwrap().contains(feature)
+    }
 }
 
 pub struct ClientDomain {
     config: ClientDomainConfig,
     inner: RefCell<Option<Arc<ClientInner>>>,
     local_domain_id: DomainId,
+    /// The caches of the tabs that we held when we were detached,
+    /// keyed by remote tab id, so that they can be shown straight
+    /// away when we attach again
+    snapshots: RefCell<HashMap<TabId, Vec<u8>>>,
 }
 
 impl ClientDomain {
@@ -111,6 +187,7 @@ impl ClientDomain {
             config,
             inner: RefCell::new(None),
             local_domain_id,
+            snapshots: RefCell::new(HashMap::new()),
         }
     }
 
@@ -122,6 +199,24 @@ impl ClientDomain {
         log::info!("detached domain {}", self.local_domain_id);
         self.inner.borrow_mut().take();
         let mux = Mux::get().unwrap();
+        let mut snapshots = self.snapshots.borrow_mut();
+        for tab in mux.iter_tabs() {
+            if tab.domain_id() != self.local_domain_id {
+                continue;
+            }
+            if let Some(tab) = tab.downcast_ref::<ClientTab>() {
+                match tab.snapshot_cache() {
+                    Ok(data) => {
+                        snapshots.insert(tab.remote_tab_id(), data);
+                    }
+                    Err(err) => log::error!(
+                        "snapshotting the cache of remote tab {}: {}",
+                        tab.remote_tab_id(),
+                        err
+                    ),
+                }
+            }
+        }
         mux.domain_was_detached(self.local_domain_id);
     }
 
@@ -151,6 +246,7 @@ impl ClientDomain {
         domain_id: DomainId,
         client: Client,
         tabs: ListTabsResponse,
+        features: ProtocolFeatures,
     ) -> anyhow::Result<()> {
         let mux = Mux::get().unwrap();
         let domain = mux
@@ -161,6 +257,7 @@ impl ClientDomain {
             .ok_or_else(|| anyhow!("domain {} is not a ClientDomain", domain_id))?;
 
         let inner = Arc::new(ClientInner::new(domain_id, client));
+        *inner.features.lock().unwrap() = features;
         *domain.inner.borrow_mut() = Some(Arc::clone(&inner));
 
         log::debug!("ListTabs result {:#?}", tabs);
@@ -172,12 +269,17 @@ impl ClientDomain {
                 entry.window_id,
                 entry.title
             );
-            let tab: Rc<dyn Tab> = Rc::new(ClientTab::new(
-                &inner,
-                entry.tab_id,
-                entry.size,
-                &entry.title,
-            ));
+            let tab = ClientTab::new(&inner, entry.tab_id, entry.size, &entry.title);
+            if let Some(data) = domain.snapshots.borrow_mut().remove(&entry.tab_id) {
+                if let Err(err) = tab.restore_cache(&data) {
+                    log::error!(
+                        "restoring the cache of remote tab {}: {}",
+                        entry.tab_id,
+                        err
+                    );
+                }
+            }
+            let tab: Rc<dyn Tab> = Rc::new(tab);
             mux.add_tab(&tab)?;
 
             if let Some(local_window_id) = inner.remote_to_local_window(entry.window_id) {
@@ -199,6 +301,8 @@ impl ClientDomain {
                     .unwrap();
             }
         }
+        // The remaining tabs no longer exist on the server
+        domain.snapshots.borrow_mut().clear();
 
         Ok(())
     }
@@ -264,12 +368,27 @@ impl Domain for ClientDomain {
         }))
         .await?;
 
-        match client.get_codec_version(GetCodecVersion {}).await {
-            Ok(info) if info.codec_vers == CODEC_VERSION => log::info!(
-                "Server version is {} (codec version {})",
-                info.version_string,
-                info.codec_vers
-            ),
+        let features = match client
+            .get_codec_version(GetCodecVersion {
+                features: ProtocolFeatures::supported(),
+            })
+            .await
+        {
+            Ok(info) if info.codec_vers == CODEC_VERSION => {
+                // The same codec version means that we share the same
+                // types; whatever either of us added since is covered
+                // by the features that we both support.
+                // Don't rely on the server having limited its response
+                // to the features that we asked for
+                let features = ProtocolFeatures::negotiate(info.features);
+                log::info!(
+                    "Server version is {} (codec version {}, features {:?})",
+                    info.version_string,
+                    info.codec_vers,
+                    features
+                );
+                features
+            }
             Ok(info) => {
                 bail!(
                     "Please install the same version of wezterm on both \
@@ -296,7 +415,7 @@ impl Domain for ClientDomain {
 
         let tabs = client.list_tabs().await?;
 
-        ClientDomain::finish_attach(domain_id, client, tabs)?;
+        ClientDomain::finish_attach(domain_id, client, tabs, features)?;
         drop(activity);
         Ok(())
     }
@@ -313,3 +432,20 @@ impl Domain for ClientDomain {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_is_smoothed() {
+        let ms = Duration::from_millis;
+        let latency = smoothed_latency(None, ms(80));
+        assert_eq!(latency, ms(80));
+        // A single spike only moves the estimate by an eighth
+        let latency = smoothed_latency(Some(latency), ms(880));
+        assert_eq!(latency, ms(180));
+        let latency = smoothed_latency(Some(latency), ms(180));
+        assert_eq!(latency, ms(180));
+    }
+}
@@ -1,4 +1,6 @@
-use crate::mux::renderable::{RenderableDimensions, StableCursorPosition};
+use crate::config::configuration;
+use crate::mux::renderable::{RenderableDimensions, StableCursorPosition, TabModeState};
+use crate::mux::search::{search, Pattern, MAX_SEARCH_RESULTS};
 use crate::mux::tab::{Tab, TabId};
 use crate::mux::{Mux, MuxNotification, MuxSubscriber};
 use crate::server::codec::*;
@@ -10,11 +12,14 @@ use portable_pty::PtySize;
 use promise::spawn::spawn_into_main_thread;
 use rangeset::RangeSet;
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use term::terminal::Clipboard;
-use term::StableRowIndex;
+use term::{ClipboardSelection, Line, StableRowIndex};
+use termwiz::escape::osc::{OperatingSystemCommand, Progress, Selection};
 use url::Url;
 
 pub struct ClientSession<S: ReadAndWrite> {
@@ -23,16 +28,63 @@ pub struct ClientSession<S: ReadAndWrite> {
     to_write_tx: PollableSender<DecodedPdu>,
     mux_rx: MuxSubscriber,
     per_tab: HashMap<TabId, Arc<Mutex<PerTab>>>,
+    /// Identifies the session as the origin of the notifications
+    /// that it causes the mux to broadcast
+    session_id: usize,
+    /// The tabs whose raw output this session has tapped
+    output_taps: HashSet<TabId>,
+    /// The features negotiated with the client by GetCodecVersion
+    features: ProtocolFeatures,
 }
 
+static SESSION_ID: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Default, Debug)]
 struct PerTab {
     cursor_position: StableCursorPosition,
     title: String,
     working_dir: Option<Url>,
+    /// The progress that we last sent to the client
+    progress: Progress,
     dimensions: RenderableDimensions,
     dirty_lines: RangeSet<StableRowIndex>,
     mouse_grabbed: bool,
+    bracketed_paste: bool,
+    modes: TabModeState,
+    /// The lines most recently sent to the client via GetLinesDelta,
+    /// keyed by row, along with the version that we assigned to them
+    sent_lines: HashMap<StableRowIndex, (u64, Line)>,
+    next_line_version: u64,
+    /// Incremented each time we send lines to the client
+    seqno: u64,
+    /// Incremented each time we send a render delta
+    delta_seq: u64,
+    /// Set if the client negotiated ProtocolFeatures::SEQUENCE_NUMBERS;
+    /// if not, seqno and delta_seq are sent as zero
+    sequenced: bool,
+    /// The palette that we last sent to the client
+    palette: Option<SerdePalette>,
+}
+
+/// The most rows for which PerTab remembers the line that it sent
+const MAX_SENT_LINES: usize = 1000;
+
+/// Returns the range of cells in `new` that differ from `old`.
+/// Cells beyond the end of the range are the same in both lines.
+fn changed_cells(old: &Line, new: &Line) -> Range<usize> {
+    let old = old.cells();
+    let new = new.cells();
+    let common = old.len().min(new.len());
+    let start = (0..common)
+        .find(|&idx| old[idx] != new[idx])
+        .unwrap_or(common);
+    let mut end = new.len();
+    if old.len() == new.len() {
+        while end > start && old[end - 1] == new[end - 1] {
+            end -= 1;
+        }
+    }
+    start..end
 }
 
 impl PerTab {
@@ -43,6 +95,16 @@ impl PerTab {
             changed = true;
         }
 
+        let bracketed_paste = tab.is_bracketed_paste_enabled();
+        if bracketed_paste != self.bracketed_paste {
+            changed = true;
+        }
+
+        let modes = tab.mode_state();
+        if modes != self.modes {
+            changed = true;
+        }
+
         let dims = tab.renderer().get_dimensions();
         if dims != self.dimensions {
             changed = true;
@@ -103,9 +165,15 @@ impl PerTab {
         self.dimensions = dims;
         self.dirty_lines = all_dirty_lines;
         self.mouse_grabbed = mouse_grabbed;
+        self.bracketed_paste = bracketed_paste;
+        self.modes = modes;
 
         let dirty_lines = dirty_delta.iter().cloned().collect();
         let bonus_lines = bonus_lines.into();
+        let seqno = self.next_seqno();
+        if self.sequenced {
+            self.delta_seq += 1;
+        }
         Some(GetTabRenderChangesResponse {
             tab_id: tab.tab_id(),
             mouse_grabbed,
@@ -115,12 +183,125 @@ impl PerTab {
             title,
             bonus_lines,
             working_dir: working_dir.map(Into::into),
+            bracketed_paste,
+            seqno,
+            modes,
+            delta_seq: self.delta_seq,
         })
     }
 
+    fn next_seqno(&mut self) -> u64 {
+        if !self.sequenced {
+            return 0;
+        }
+        self.seqno += 1;
+        self.seqno
+    }
+
     fn mark_clean(&mut self, stable_row: StableRowIndex) {
         self.dirty_lines.remove(stable_row);
     }
+
+    /// Prepare a line to be sent in a GetLinesDeltaResponse.  If the
+    /// client holds the version of the row that we last sent then we
+    /// only send the cells that have changed since then.
+    fn line_delta(
+        &mut self,
+        stable_row: StableRowIndex,
+        known: Option<u64>,
+        line: Line,
+    ) -> (Line, LineDelta) {
+        let version = self.next_line_version;
+        self.next_line_version += 1;
+
+        let result = match (known, self.sent_lines.get(&stable_row)) {
+            (Some(base), Some((sent_version, sent))) if base == *sent_version => {
+                let changed = changed_cells(sent, &line);
+                (
+                    Line::from_cells(line.cells()[changed.clone()].to_vec()),
+                    LineDelta::Patch {
+                        base,
+                        version,
+                        start: changed.start,
+                        width: line.cells().len(),
+                    },
+                )
+            }
+            _ => (line.clone(), LineDelta::Full { version }),
+        };
+
+        if self.sent_lines.len() >= MAX_SENT_LINES {
+            // Forget the rows furthest from the cursor
+            let cursor_row = self.cursor_position.y;
+            let keep = (MAX_SENT_LINES / 2) as StableRowIndex;
+            self.sent_lines
+                .retain(|row, _| (row - cursor_row).abs() < keep);
+        }
+        self.sent_lines.insert(stable_row, (version, line));
+        result
+    }
+}
+
+/// Push the title to the client if it has changed since we last
+/// told it about the title
+fn maybe_push_tab_title(
+    tab: &Rc<dyn Tab>,
+    sender: PollableSender<DecodedPdu>,
+    per_tab: Arc<Mutex<PerTab>>,
+) -> anyhow::Result<()> {
+    let mut per_tab = per_tab.lock().unwrap();
+    let title = tab.get_title();
+    if title != per_tab.title {
+        per_tab.title = title.clone();
+        sender.send(DecodedPdu {
+            pdu: Pdu::SetTabTitle(SetTabTitle {
+                tab_id: tab.tab_id(),
+                title,
+            }),
+            serial: 0,
+        })?;
+    }
+    Ok(())
+}
+
+fn maybe_push_working_dir(
+    tab: &Rc<dyn Tab>,
+    sender: PollableSender<DecodedPdu>,
+    per_tab: Arc<Mutex<PerTab>>,
+) -> anyhow::Result<()> {
+    let mut per_tab = per_tab.lock().unwrap();
+    // There is no way to push the absence of a directory; that is
+    // left to the render delta
+    if let Some(url) = tab.get_current_working_dir() {
+        if Some(&url) != per_tab.working_dir.as_ref() {
+            sender.send(DecodedPdu {
+                pdu: Pdu::SetWorkingDir(SetWorkingDir {
+                    tab_id: tab.tab_id(),
+                    url: url.as_str().to_string(),
+                }),
+                serial: 0,
+            })?;
+            per_tab.working_dir = Some(url);
+        }
+    }
+    Ok(())
+}
+
+fn maybe_push_tab_progress(
+    tab: &Rc<dyn Tab>,
+    sender: PollableSender<DecodedPdu>,
+    per_tab: Arc<Mutex<PerTab>>,
+) -> anyhow::Result<()> {
+    let mut per_tab = per_tab.lock().unwrap();
+    let progress = tab.get_progress();
+    if progress != per_tab.progress {
+        per_tab.progress = progress;
+        sender.send(DecodedPdu {
+            pdu: Pdu::SetTabProgress(SetTabProgress::new(tab.tab_id(), progress)),
+            serial: 0,
+        })?;
+    }
+    Ok(())
 }
 
 fn maybe_push_tab_changes(
@@ -129,6 +310,21 @@ fn maybe_push_tab_changes(
     per_tab: Arc<Mutex<PerTab>>,
 ) -> anyhow::Result<()> {
     let mut per_tab = per_tab.lock().unwrap();
+
+    // The palette is pushed ahead of the changes so that the client
+    // renders them with the new colors
+    let palette = tab.palette_override().as_ref().map(SerdePalette::from);
+    if palette != per_tab.palette {
+        per_tab.palette = palette.clone();
+        sender.send(DecodedPdu {
+            pdu: Pdu::SetPalette(SetPalette {
+                tab_id: tab.tab_id(),
+                palette,
+            }),
+            serial: 0,
+        })?;
+    }
+
     if let Some(resp) = per_tab.compute_changes(tab) {
         sender.send(DecodedPdu {
             pdu: Pdu::GetTabRenderChangesResponse(resp),
@@ -149,11 +345,24 @@ impl Clipboard for RemoteClipboard {
     }
 
     fn set_contents(&self, clipboard: Option<String>) -> anyhow::Result<()> {
+        self.set_selection_contents(ClipboardSelection::Clipboard, clipboard)
+    }
+
+    fn set_primary_selection(&self, clipboard: Option<String>) -> anyhow::Result<()> {
+        self.set_selection_contents(ClipboardSelection::PrimarySelection, clipboard)
+    }
+
+    fn set_selection_contents(
+        &self,
+        selection: ClipboardSelection,
+        clipboard: Option<String>,
+    ) -> anyhow::Result<()> {
         self.sender.send(DecodedPdu {
             serial: 0,
             pdu: Pdu::SetClipboard(SetClipboard {
                 tab_id: self.tab_id,
                 clipboard,
+                selection,
             }),
         })?;
         Ok(())
@@ -182,6 +391,9 @@ impl<S: ReadAndWrite> ClientSession<S> {
             to_write_tx,
             mux_rx,
             per_tab: HashMap::new(),
+            session_id: SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            output_taps: HashSet::new(),
+            features: ProtocolFeatures::empty(),
         }
     }
 
@@ -192,16 +404,21 @@ impl<S: ReadAndWrite> ClientSession<S> {
     }
 
     fn per_tab(&mut self, tab_id: TabId) -> Arc<Mutex<PerTab>> {
-        Arc::clone(
-            self.per_tab
-                .entry(tab_id)
-                .or_insert_with(|| Arc::new(Mutex::new(PerTab::default()))),
-        )
+        let sequenced = self.features.contains(ProtocolFeatures::SEQUENCE_NUMBERS);
+        Arc::clone(self.per_tab.entry(tab_id).or_insert_with(|| {
+            Arc::new(Mutex::new(PerTab {
+                sequenced,
+                ..PerTab::default()
+            }))
+        }))
     }
 
     fn process(&mut self) -> Result<(), Error> {
         let mut read_buffer = Vec::with_capacity(1024);
         let mut tabs_to_output = HashSet::new();
+        let mut titles_to_push = HashSet::new();
+        let mut dirs_to_push = HashSet::new();
+        let mut progress_to_push = HashSet::new();
 
         loop {
             loop {
@@ -219,13 +436,166 @@ impl<S: ReadAndWrite> ClientSession<S> {
                 match self.mux_rx.try_recv() {
                     Ok(notif) => match notif {
                         // Coalesce multiple TabOutputs for the same tab
-                        MuxNotification::TabOutput(tab_id) => tabs_to_output.insert(tab_id),
+                        MuxNotification::TabOutput(tab_id) => {
+                            tabs_to_output.insert(tab_id);
+                        }
+                        MuxNotification::TabTitleChanged(tab_id) => {
+                            titles_to_push.insert(tab_id);
+                        }
+                        MuxNotification::WorkingDirChanged(tab_id) => {
+                            dirs_to_push.insert(tab_id);
+                        }
+                        MuxNotification::TabProgressChanged(tab_id) => {
+                            progress_to_push.insert(tab_id);
+                        }
+                        MuxNotification::TabRemoved(tab_id) => {
+                            tabs_to_output.remove(&tab_id);
+                            titles_to_push.remove(&tab_id);
+                            dirs_to_push.remove(&tab_id);
+                            progress_to_push.remove(&tab_id);
+                            self.per_tab.remove(&tab_id);
+                            self.output_taps.remove(&tab_id);
+                            self.to_write_tx.send(DecodedPdu {
+                                pdu: Pdu::TabRemoved(TabRemoved { tab_id }),
+                                serial: 0,
+                            })?;
+                        }
+                        // As are the mouse grab state and the modes
+                        MuxNotification::MouseGrabChanged(tab_id)
+                        | MuxNotification::TabModesChanged(tab_id) => {
+                            tabs_to_output.insert(tab_id);
+                        }
+                        // Only of interest to the gui of a client
+                        MuxNotification::TabFirstPaint(_) => {}
+                        MuxNotification::TabOutputBytes(tab_id, data) => {
+                            if self.output_taps.contains(&tab_id) {
+                                self.to_write_tx.send(DecodedPdu {
+                                    pdu: Pdu::TabOutputBytes(TabOutputBytes {
+                                        tab_id,
+                                        data: data.to_vec(),
+                                    }),
+                                    serial: 0,
+                                })?;
+                            }
+                        }
+                        MuxNotification::Bell(tab_id) => {
+                            self.to_write_tx.send(DecodedPdu {
+                                pdu: Pdu::Bell(Bell { tab_id }),
+                                serial: 0,
+                            })?;
+                        }
+                        MuxNotification::DesktopNotification {
+                            tab_id,
+                            title,
+                            body,
+                        } => {
+                            self.to_write_tx.send(DecodedPdu {
+                                pdu: Pdu::Notification(Notification {
+                                    tab_id,
+                                    title,
+                                    body,
+                                }),
+                                serial: 0,
+                            })?;
+                        }
+                        MuxNotification::ClipboardRequested(tab_id) => {
+                            self.to_write_tx.send(DecodedPdu {
+                                pdu: Pdu::GetClipboardRequest(GetClipboardRequest { tab_id }),
+                                serial: 0,
+                            })?;
+                        }
+                        MuxNotification::TabScrolled {
+                            tab_id,
+                            viewport,
+                            origin,
+                        } => {
+                            if origin != self.session_id {
+                                self.to_write_tx.send(DecodedPdu {
+                                    pdu: Pdu::TabScrollPosition(TabScrollPosition {
+                                        tab_id,
+                                        viewport,
+                                    }),
+                                    serial: 0,
+                                })?;
+                            }
+                        }
+                        MuxNotification::SelectionChanged {
+                            tab_id,
+                            range,
+                            origin,
+                        } => {
+                            if origin != self.session_id {
+                                self.to_write_tx.send(DecodedPdu {
+                                    pdu: Pdu::SetSelection(SetSelection { tab_id, range }),
+                                    serial: 0,
+                                })?;
+                            }
+                        }
+                        MuxNotification::TabExited(tab_id, exit_code) => {
+                            tabs_to_output.remove(&tab_id);
+                            let sender = self.to_write_tx.clone();
+                            let per_tab = self.per_tab(tab_id);
+                            spawn_into_main_thread(async move {
+                                // Push any final changes while we still can,
+                                // so that the client can keep showing them
+                                let mux = Mux::get().unwrap();
+                                if let Some(tab) = mux.get_tab(tab_id) {
+                                    maybe_push_tab_changes(&tab, sender.clone(), per_tab)?;
+                                }
+                                sender.send(DecodedPdu {
+                                    pdu: Pdu::TabExited(TabExited { tab_id, exit_code }),
+                                    serial: 0,
+                                })?;
+                                Ok::<(), anyhow::Error>(())
+                            });
+                        }
                     },
                     Err(TryRecvError::Empty) => break,
                     Err(TryRecvError::Disconnected) => bail!("mux_rx is Disconnected"),
                 };
             }
 
+            // Titles are pushed ahead of any render changes so that
+            // the change isn't folded into the render delta
+            for tab_id in titles_to_push.drain() {
+                let sender = self.to_write_tx.clone();
+                let per_tab = self.per_tab(tab_id);
+                spawn_into_main_thread(async move {
+                    let mux = Mux::get().unwrap();
+                    let tab = mux
+                        .get_tab(tab_id)
+                        .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                    maybe_push_tab_title(&tab, sender, per_tab)?;
+                    Ok::<(), anyhow::Error>(())
+                });
+            }
+
+            for tab_id in dirs_to_push.drain() {
+                let sender = self.to_write_tx.clone();
+                let per_tab = self.per_tab(tab_id);
+                spawn_into_main_thread(async move {
+                    let mux = Mux::get().unwrap();
+                    let tab = mux
+                        .get_tab(tab_id)
+                        .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                    maybe_push_working_dir(&tab, sender, per_tab)?;
+                    Ok::<(), anyhow::Error>(())
+                });
+            }
+
+            for tab_id in progress_to_push.drain() {
+                let sender = self.to_write_tx.clone();
+                let per_tab = self.per_tab(tab_id);
+                spawn_into_main_thread(async move {
+                    let mux = Mux::get().unwrap();
+                    let tab = mux
+                        .get_tab(tab_id)
+                        .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                    maybe_push_tab_progress(&tab, sender, per_tab)?;
+                    Ok::<(), anyhow::Error>(())
+                });
+            }
+
             for tab_id in tabs_to_output.drain() {
                 let sender = self.to_write_tx.clone();
                 let per_tab = self.per_tab(tab_id);
@@ -234,6 +604,12 @@ impl<S: ReadAndWrite> ClientSession<S> {
                     let tab = mux
                         .get_tab(tab_id)
                         .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                    // OSC 7 arrives as part of the output, so push any
+                    // change to the directory ahead of the render delta
+                    maybe_push_working_dir(&tab, sender.clone(), per_tab.clone())?;
+                    // Likewise a client that attached after the progress
+                    // was reported learns of it here
+                    maybe_push_tab_progress(&tab, sender.clone(), per_tab.clone())?;
                     maybe_push_tab_changes(&tab, sender, per_tab)?;
                     Ok::<(), anyhow::Error>(())
                 });
@@ -343,7 +719,11 @@ impl<S: ReadAndWrite> ClientSession<S> {
                     );
                 });
             }
-            Pdu::SendPaste(SendPaste { tab_id, data }) => {
+            Pdu::SendPaste(SendPaste {
+                tab_id,
+                data,
+                chunk,
+            }) => {
                 let sender = self.to_write_tx.clone();
                 let per_tab = self.per_tab(tab_id);
                 spawn_into_main_thread(async move {
@@ -353,7 +733,12 @@ impl<S: ReadAndWrite> ClientSession<S> {
                             let tab = mux
                                 .get_tab(tab_id)
                                 .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
-                            tab.send_paste(&data)?;
+                            match chunk {
+                                Some(PasteChunk { first, last }) => {
+                                    tab.send_paste_chunk(&data, first, last)?
+                                }
+                                None => tab.send_paste(&data)?,
+                            }
                             maybe_push_tab_changes(&tab, sender, per_tab)?;
                             Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
@@ -396,6 +781,26 @@ impl<S: ReadAndWrite> ClientSession<S> {
                     )
                 });
             }
+            Pdu::SendKeyDowns(SendKeyDowns { tab_id, events }) => {
+                let sender = self.to_write_tx.clone();
+                let per_tab = self.per_tab(tab_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            for event in events {
+                                tab.key_down(event.key, event.modifiers)?;
+                            }
+                            maybe_push_tab_changes(&tab, sender, per_tab)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
             Pdu::SendMouseEvent(SendMouseEvent { tab_id, event }) => {
                 let sender = self.to_write_tx.clone();
                 let per_tab = self.per_tab(tab_id);
@@ -417,6 +822,31 @@ impl<S: ReadAndWrite> ClientSession<S> {
                     )
                 });
             }
+            Pdu::SendMouseEvents(SendMouseEvents { tab_id, events }) => {
+                let sender = self.to_write_tx.clone();
+                let per_tab = self.per_tab(tab_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            let mut host = BufferedTerminalHost {
+                                write: tab.writer(),
+                            };
+                            // Apply the whole batch in order before we
+                            // consider pushing the resultant changes
+                            for event in events {
+                                tab.mouse_event(event, &mut host)?;
+                            }
+                            maybe_push_tab_changes(&tab, sender, per_tab)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
 
             Pdu::Spawn(spawn) => {
                 let sender = self.to_write_tx.clone();
@@ -443,8 +873,44 @@ impl<S: ReadAndWrite> ClientSession<S> {
                 });
             }
 
-            Pdu::GetLines(GetLines { tab_id, lines }) => {
+            Pdu::GetMultiTabRenderChanges(GetMultiTabRenderChanges { tab_ids }) => {
+                let sender = self.to_write_tx.clone();
+                let per_tabs: Vec<_> = tab_ids
+                    .into_iter()
+                    .map(|tab_id| (tab_id, self.per_tab(tab_id)))
+                    .collect();
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let mut errors = vec![];
+                            for (tab_id, per_tab) in per_tabs {
+                                let result = mux
+                                    .get_tab(tab_id)
+                                    .ok_or_else(|| anyhow!("no such tab {}", tab_id))
+                                    .and_then(|tab| {
+                                        maybe_push_tab_changes(&tab, sender.clone(), per_tab)
+                                    });
+                                if let Err(err) = result {
+                                    errors.push((tab_id, err.to_string()));
+                                }
+                            }
+                            Ok(Pdu::GetMultiTabRenderChangesResponse(
+                                GetMultiTabRenderChangesResponse { errors },
+                            ))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::GetLines(GetLines {
+                tab_id,
+                lines,
+                anchor,
+            }) => {
                 let per_tab = self.per_tab(tab_id);
+                let blank_runs = self.features.contains(ProtocolFeatures::BLANK_RUNS);
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
@@ -457,6 +923,13 @@ impl<S: ReadAndWrite> ClientSession<S> {
                             let mut lines_and_indices = vec![];
                             let mut per_tab = per_tab.lock().unwrap();
 
+                            let anchor_row =
+                                anchor.map(|anchor| anchor.resolve(&renderer.get_dimensions()));
+                            let lines = match anchor_row {
+                                Some(anchor_row) => RowAnchor::apply(anchor_row, lines),
+                                None => lines,
+                            };
+
                             for range in lines {
                                 let (first_row, lines) = renderer.get_lines(range);
                                 for (idx, line) in lines.into_iter().enumerate() {
@@ -465,9 +938,197 @@ impl<S: ReadAndWrite> ClientSession<S> {
                                     lines_and_indices.push((stable_row, line));
                                 }
                             }
+                            let lines = if blank_runs {
+                                SerializedLines::with_blank_runs(lines_and_indices)
+                            } else {
+                                lines_and_indices.into()
+                            };
                             Ok(Pdu::GetLinesResponse(GetLinesResponse {
+                                tab_id,
+                                lines,
+                                seqno: per_tab.next_seqno(),
+                                anchor_row,
+                            }))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::GetLinesDelta(GetLinesDelta {
+                tab_id,
+                lines,
+                known,
+            }) => {
+                let per_tab = self.per_tab(tab_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            let mut renderer = tab.renderer();
+                            let known: HashMap<StableRowIndex, u64> = known.into_iter().collect();
+
+                            let mut lines_and_indices = vec![];
+                            let mut deltas = vec![];
+                            let mut per_tab = per_tab.lock().unwrap();
+
+                            for range in lines {
+                                let (first_row, lines) = renderer.get_lines(range);
+                                for (idx, line) in lines.into_iter().enumerate() {
+                                    let stable_row = first_row + idx as StableRowIndex;
+                                    per_tab.mark_clean(stable_row);
+                                    let (line, delta) = per_tab.line_delta(
+                                        stable_row,
+                                        known.get(&stable_row).cloned(),
+                                        line,
+                                    );
+                                    lines_and_indices.push((stable_row, line));
+                                    deltas.push(delta);
+                                }
+                            }
+                            Ok(Pdu::GetLinesDeltaResponse(GetLinesDeltaResponse {
                                 tab_id,
                                 lines: lines_and_indices.into(),
+                                deltas,
+                                seqno: per_tab.next_seqno(),
+                            }))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::GetClipboardResponse(GetClipboardResponse { tab_id, clipboard }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            // Answer the OSC 52 query that prompted the request
+                            let response = OperatingSystemCommand::SetSelection(
+                                Selection::CLIPBOARD,
+                                clipboard,
+                            );
+                            write!(tab.writer(), "{}", response)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::TabScrollPosition(TabScrollPosition { tab_id, viewport }) => {
+                let origin = self.session_id;
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            mux.notify(MuxNotification::TabScrolled {
+                                tab_id,
+                                viewport,
+                                origin,
+                            });
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::SetSelection(SetSelection { tab_id, range }) => {
+                let origin = self.session_id;
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            mux.notify(MuxNotification::SelectionChanged {
+                                tab_id,
+                                range,
+                                origin,
+                            });
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::GetPaneTree(GetPaneTree { tab_id }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            let dims = tab.renderer().get_dimensions();
+                            let tree = PaneNode::Leaf {
+                                tab_id,
+                                size: PtySize {
+                                    rows: dims.viewport_rows as u16,
+                                    cols: dims.cols as u16,
+                                    pixel_width: dims.pixel_width as u16,
+                                    pixel_height: dims.pixel_height as u16,
+                                },
+                            };
+                            Ok(Pdu::GetPaneTreeResponse(GetPaneTreeResponse { tree }))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::ResetTerminal(ResetTerminal { tab_id }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            tab.reset_terminal()?;
+                            // Let the other clients know about the cleared screen
+                            mux.notify(MuxNotification::TabOutput(tab_id));
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::SearchScrollback(SearchScrollback {
+                tab_id,
+                pattern,
+                kind,
+                before,
+                limit,
+            }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            let pattern = Pattern::new(&pattern, kind)?;
+                            let (matches, next) = search(
+                                &mut *tab.renderer(),
+                                &pattern,
+                                before,
+                                limit.min(MAX_SEARCH_RESULTS),
+                            );
+                            Ok(Pdu::SearchScrollbackResponse(SearchScrollbackResponse {
+                                matches,
+                                next,
                             }))
                         },
                         send_response,
@@ -475,10 +1136,56 @@ impl<S: ReadAndWrite> ClientSession<S> {
                 });
             }
 
-            Pdu::GetCodecVersion(_) => {
+            Pdu::SetScrollbackLines(SetScrollbackLines { tab_id, lines }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux
+                                .get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            let lines = lines.min(configuration().mux_max_scrollback_lines);
+                            tab.set_scrollback_lines(lines)?;
+                            let dimensions = tab.renderer().get_dimensions();
+                            Ok(Pdu::SetScrollbackLinesResponse(
+                                SetScrollbackLinesResponse { lines, dimensions },
+                            ))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::SetOutputTap(SetOutputTap { tab_id, enable }) => {
+                // Only adjust the mux's tap count when our own state
+                // changes, so that repeated requests don't leak taps
+                let changed = if enable {
+                    self.output_taps.insert(tab_id)
+                } else {
+                    self.output_taps.remove(&tab_id)
+                };
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.get_tab(tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", tab_id))?;
+                            if changed {
+                                mux.tap_output(tab_id, enable);
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                });
+            }
+
+            Pdu::GetCodecVersion(GetCodecVersion { features }) => {
+                self.features = ProtocolFeatures::negotiate(features);
                 send_response(Ok(Pdu::GetCodecVersionResponse(GetCodecVersionResponse {
                     codec_vers: CODEC_VERSION,
                     version_string: crate::wezterm_version().to_owned(),
+                    features: self.features,
                 })))
             }
 
@@ -486,11 +1193,26 @@ impl<S: ReadAndWrite> ClientSession<S> {
             Pdu::Pong { .. }
             | Pdu::ListTabsResponse { .. }
             | Pdu::SetClipboard { .. }
+            | Pdu::SetTabTitle { .. }
+            | Pdu::SetWorkingDir { .. }
+            | Pdu::Bell { .. }
+            | Pdu::Notification { .. }
+            | Pdu::SetTabProgress { .. }
+            | Pdu::GetClipboardRequest { .. }
+            | Pdu::TabExited { .. }
+            | Pdu::SetPalette { .. }
+            | Pdu::TabRemoved { .. }
+            | Pdu::TabOutputBytes { .. }
             | Pdu::SpawnResponse { .. }
             | Pdu::GetTabRenderChangesResponse { .. }
+            | Pdu::GetMultiTabRenderChangesResponse { .. }
+            | Pdu::SearchScrollbackResponse { .. }
+            | Pdu::SetScrollbackLinesResponse { .. }
             | Pdu::UnitResponse { .. }
             | Pdu::GetLinesResponse { .. }
             | Pdu::GetCodecVersionResponse { .. }
+            | Pdu::GetLinesDeltaResponse { .. }
+            | Pdu::GetPaneTreeResponse { .. }
             | Pdu::ErrorResponse { .. } => {
                 send_response(Err(anyhow!("expected a request, got {:?}", decoded.pdu)))
             }
@@ -498,6 +1220,22 @@ impl<S: ReadAndWrite> ClientSession<S> {
     }
 }
 
+impl<S: ReadAndWrite> Drop for ClientSession<S> {
+    fn drop(&mut self) {
+        // Release the output taps held by this session, so that the mux
+        // stops broadcasting bytes that nobody is going to forward
+        let taps: Vec<TabId> = self.output_taps.drain().collect();
+        if !taps.is_empty() {
+            spawn_into_main_thread(async move {
+                let mux = Mux::get().unwrap();
+                for tab_id in taps {
+                    mux.tap_output(tab_id, false);
+                }
+            });
+        }
+    }
+}
+
 // Dancing around a little bit here; we can't directly spawn_into_main_thread the domain_spawn
 // function below because the compiler thinks that all of its locals then need to be Send.
 // We need to shimmy through this helper to break that aspect of the compiler flow
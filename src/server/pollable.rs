@@ -6,6 +6,7 @@ use std::cell::RefCell;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub trait ReadAndWrite: std::io::Read + std::io::Write + Send + AsPollFd {
     fn set_non_blocking(&self, non_blocking: bool) -> anyhow::Result<()>;
@@ -148,7 +149,12 @@ impl AsPollFd for UnixStream {
 }
 
 pub fn poll_for_read(pfd: &mut [pollfd]) {
-    if let Err(e) = poll(pfd, None) {
+    poll_for_read_with_timeout(pfd, None);
+}
+
+/// Like poll_for_read, but gives up waiting after `timeout`
+pub fn poll_for_read_with_timeout(pfd: &mut [pollfd], timeout: Option<Duration>) {
+    if let Err(e) = poll(pfd, timeout) {
         log::error!("poll failed for {}", e);
     }
 }
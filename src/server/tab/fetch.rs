@@ -0,0 +1,348 @@
+use crate::config::{configuration, ConfigHandle};
+use crate::ratelim::RateLimiter;
+use crate::server::tab::CacheStats;
+use rangeset::RangeSet;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use term::StableRowIndex;
+
+pub const MAX_FETCH_ATTEMPTS: u32 = 5;
+const BASE_FETCH_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_FETCH_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks failed fetches for a row so that we back off rather than
+/// repeatedly asking a flaky server for the same lines
+#[derive(Debug, Clone, Copy)]
+struct FetchBackoff {
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Returns the number of fetches per second permitted for a tab.
+/// An override set for the tab takes precedence; otherwise tabs in
+/// the background are held to a lower rate than the focused tab.
+fn fetch_rate(config: &ConfigHandle, focused: bool, rate_override: Option<u32>) -> u32 {
+    let rate = match rate_override {
+        Some(rate) => rate,
+        None if focused => config.ratelimit_mux_line_prefetches_per_second,
+        None => config.ratelimit_mux_background_line_prefetches_per_second,
+    };
+    // RateLimiter requires a non-zero rate
+    rate.max(1)
+}
+
+/// Why rows are being fetched, which decides whether the fetch is
+/// subject to the rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchReason {
+    /// The rows are being displayed; they are always fetched
+    Display,
+    /// Rows fetched ahead of being displayed, or that the server told
+    /// us have changed; these are subject to the rate limit
+    Background,
+}
+
+/// The bookkeeping for the GetLines requests of a tab: the rows that
+/// are queued and in flight, the rate limit, and the backoff for rows
+/// whose fetch failed.  The line cache itself is RenderableInner's.
+pub struct FetchState {
+    limiter: RateLimiter,
+    /// The rate that limiter was built for
+    rate: u32,
+    /// A rate set for this tab, which applies whether or not the
+    /// tab is focused
+    rate_override: Option<u32>,
+    /// Rows that are being displayed and have been requested but not
+    /// yet sent to the server
+    pub pending: RangeSet<StableRowIndex>,
+    /// Likewise for rows queued with FetchReason::Background
+    pub pending_background: RangeSet<StableRowIndex>,
+    /// Rows whose most recent fetch failed
+    backoff: HashMap<StableRowIndex, FetchBackoff>,
+    pub issued: usize,
+    pub completed: usize,
+    /// Tallies of the outcomes of checking limiter
+    admitted: usize,
+    throttled: usize,
+    /// Incremented when the viewport jumps away from the rows that
+    /// we were fetching, making the fetches issued before then obsolete
+    pub generation: u64,
+    /// The number of fetch requests that we're waiting on
+    pub in_flight: usize,
+    /// Maps the rows that have been sent to the server, and whose
+    /// fetch has yet to complete, to the fetch_start of that fetch.
+    /// A row that is queued again before then joins that fetch
+    /// rather than being requested a second time.
+    in_flight_rows: HashMap<StableRowIndex, Instant>,
+    pub max_concurrent: usize,
+}
+
+impl FetchState {
+    pub fn new(config: &ConfigHandle) -> Self {
+        Self {
+            limiter: RateLimiter::new(|config| fetch_rate(config, true, None)),
+            rate: fetch_rate(config, true, None),
+            rate_override: None,
+            pending: RangeSet::new(),
+            pending_background: RangeSet::new(),
+            backoff: HashMap::new(),
+            issued: 0,
+            completed: 0,
+            admitted: 0,
+            throttled: 0,
+            generation: 0,
+            in_flight: 0,
+            in_flight_rows: HashMap::new(),
+            max_concurrent: config.mux_max_concurrent_fetches.max(1),
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn set_rate_override(&mut self, rate: Option<u32>) {
+        self.rate_override = rate;
+    }
+
+    /// Returns true if there are rows waiting to be sent to the server
+    pub fn has_queued(&self) -> bool {
+        !self.pending.is_empty() || !self.pending_background.is_empty()
+    }
+
+    /// Queue rows to be sent to the server by the next flush
+    pub fn queue(&mut self, rows: &RangeSet<StableRowIndex>, reason: FetchReason) {
+        match reason {
+            FetchReason::Display => self.pending.add_set(rows),
+            FetchReason::Background => self.pending_background.add_set(rows),
+        }
+    }
+
+    /// Check limiter for permission to fetch, keeping a tally
+    /// of the outcome for cache_stats
+    pub fn admit(&mut self) -> bool {
+        if self.limiter.non_blocking_admittance_check(1) {
+            self.admitted += 1;
+            true
+        } else {
+            self.throttled += 1;
+            false
+        }
+    }
+
+    /// Rebuild limiter if the rate that applies to the tab has
+    /// changed, such as when it moves to or from the background.
+    /// Returns true if it was rebuilt.
+    pub fn update_limiter(&mut self, focused: bool) -> bool {
+        let rate_override = self.rate_override;
+        let rate = fetch_rate(&configuration(), focused, rate_override);
+        if rate == self.rate {
+            return false;
+        }
+        self.rate = rate;
+        self.limiter = RateLimiter::new(move |config| fetch_rate(config, focused, rate_override));
+        true
+    }
+
+    /// Returns false if a prior failure to fetch the row means that
+    /// we're still backing off from it
+    pub fn backoff_elapsed(&self, stable_row: StableRowIndex, now: Instant) -> bool {
+        match self.backoff.get(&stable_row) {
+            Some(backoff) => now >= backoff.next_attempt,
+            None => true,
+        }
+    }
+
+    pub fn is_backing_off(&self, stable_row: StableRowIndex) -> bool {
+        self.backoff.contains_key(&stable_row)
+    }
+
+    /// Record a failed fetch for the row and compute the time at
+    /// which we may next try it, doubling the interval each time.
+    /// Returns the number of attempts if we have now given up on
+    /// the row, in which case the caller should mark it as FetchError.
+    pub fn record_failure(&mut self, stable_row: StableRowIndex, now: Instant) -> Option<u32> {
+        let backoff = self.backoff.entry(stable_row).or_insert(FetchBackoff {
+            attempts: 0,
+            next_attempt: now,
+        });
+        backoff.attempts += 1;
+        let delay = BASE_FETCH_RETRY_INTERVAL
+            .checked_mul(1 << (backoff.attempts - 1).min(16))
+            .unwrap_or(MAX_FETCH_RETRY_INTERVAL)
+            .min(MAX_FETCH_RETRY_INTERVAL);
+        backoff.next_attempt = now + delay;
+        let attempts = backoff.attempts;
+        if attempts >= MAX_FETCH_ATTEMPTS {
+            log::error!(
+                "row {} failed to fetch after {} attempts; giving up",
+                stable_row,
+                attempts
+            );
+            self.backoff.remove(&stable_row);
+            Some(attempts)
+        } else {
+            log::trace!(
+                "row {} fetch failed {} times, retry in {:?}",
+                stable_row,
+                attempts,
+                delay
+            );
+            None
+        }
+    }
+
+    /// Forget the failures of a row, now that we hold a good copy
+    pub fn clear_backoff(&mut self, stable_row: StableRowIndex) {
+        self.backoff.remove(&stable_row);
+    }
+
+    /// Forget about the rows above `floor`, which the server has
+    /// trimmed from its scrollback
+    pub fn trim(&mut self, floor: StableRowIndex) {
+        self.backoff.retain(|stable_row, _| *stable_row >= floor);
+        self.in_flight_rows
+            .retain(|stable_row, _| *stable_row >= floor);
+    }
+
+    /// Forget the failures and in flight fetches of all rows, such
+    /// as when every row is to be fetched afresh
+    pub fn reset(&mut self) {
+        self.backoff.clear();
+        self.in_flight_rows.clear();
+    }
+
+    /// Returns true if we have as many requests in flight as we allow
+    pub fn at_capacity(&self) -> bool {
+        self.in_flight >= self.max_concurrent
+    }
+
+    /// Returns the fetch_start of the fetch that is bringing in the
+    /// row, if there is one
+    pub fn in_flight_since(&self, stable_row: StableRowIndex) -> Option<Instant> {
+        self.in_flight_rows.get(&stable_row).cloned()
+    }
+
+    pub fn mark_in_flight(&mut self, stable_row: StableRowIndex, fetch_start: Instant) {
+        self.in_flight_rows.insert(stable_row, fetch_start);
+    }
+
+    /// Forget the fetch that is in flight for a row, such as when it
+    /// has changed since that fetch was sent
+    pub fn forget_in_flight(&mut self, stable_row: StableRowIndex) {
+        self.in_flight_rows.remove(&stable_row);
+    }
+
+    /// Forget that the rows were in flight as part of the fetch that
+    /// started at `fetch_start`, now that it has completed or been
+    /// abandoned
+    pub fn release_in_flight_rows(
+        &mut self,
+        rows: &RangeSet<StableRowIndex>,
+        fetch_start: Instant,
+    ) {
+        for r in rows.iter() {
+            for stable_row in r.clone() {
+                if self.in_flight_rows.get(&stable_row) == Some(&fetch_start) {
+                    self.in_flight_rows.remove(&stable_row);
+                }
+            }
+        }
+    }
+
+    pub fn record_stats(&self, stats: &mut CacheStats) {
+        stats.fetches_issued = self.issued;
+        stats.fetches_completed = self.completed;
+        stats.fetches_admitted = self.admitted;
+        stats.fetches_throttled = self.throttled;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fetch_failure_backoff() {
+        let mut fetch = FetchState::new(&configuration());
+        let start = Instant::now();
+        assert!(fetch.backoff_elapsed(0, start));
+
+        fetch.record_failure(0, start);
+        assert!(!fetch.backoff_elapsed(0, start));
+        assert!(fetch.backoff_elapsed(0, start + BASE_FETCH_RETRY_INTERVAL));
+
+        fetch.record_failure(0, start);
+        assert!(!fetch.backoff_elapsed(0, start + BASE_FETCH_RETRY_INTERVAL));
+        assert!(fetch.backoff_elapsed(0, start + BASE_FETCH_RETRY_INTERVAL * 2));
+
+        for _ in 2..MAX_FETCH_ATTEMPTS - 1 {
+            assert_eq!(fetch.record_failure(0, start), None);
+        }
+        assert_eq!(fetch.record_failure(0, start), Some(MAX_FETCH_ATTEMPTS));
+        // Giving up hands the row over to FetchError
+        assert!(fetch.backoff_elapsed(0, start));
+        assert!(!fetch.is_backing_off(0));
+    }
+
+    #[test]
+    fn focus_raises_fetch_rate() {
+        let config = configuration();
+        let mut fetch = FetchState::new(&config);
+        assert_eq!(
+            fetch.rate(),
+            config.ratelimit_mux_line_prefetches_per_second
+        );
+
+        assert!(fetch.update_limiter(false));
+        let background = fetch.rate();
+        assert_eq!(
+            background,
+            config.ratelimit_mux_background_line_prefetches_per_second
+        );
+        // Nothing to do if the rate hasn't changed
+        assert!(!fetch.update_limiter(false));
+
+        assert!(fetch.update_limiter(true));
+        assert!(fetch.rate() > background);
+
+        // An override applies regardless of focus
+        fetch.set_rate_override(Some(50));
+        assert!(fetch.update_limiter(true));
+        assert!(!fetch.update_limiter(false));
+        assert_eq!(fetch.rate(), 50);
+
+        assert_eq!(fetch_rate(&config, true, Some(0)), 1);
+    }
+
+    #[test]
+    fn throttled_fetches_counted() {
+        let mut fetch = FetchState::new(&configuration());
+        fetch.set_rate_override(Some(1));
+        fetch.update_limiter(true);
+
+        assert!(fetch.admit());
+        assert!(!fetch.admit());
+        assert!(!fetch.admit());
+        let mut stats = CacheStats::default();
+        fetch.record_stats(&mut stats);
+        assert_eq!(stats.fetches_admitted, 1);
+        assert_eq!(stats.fetches_throttled, 2);
+    }
+
+    #[test]
+    fn in_flight_rows_released_by_their_fetch() {
+        let mut fetch = FetchState::new(&configuration());
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(1);
+        fetch.mark_in_flight(0, t0);
+        fetch.mark_in_flight(1, t1);
+
+        let mut rows = RangeSet::new();
+        rows.add_range(0..2);
+        fetch.release_in_flight_rows(&rows, t0);
+        assert_eq!(fetch.in_flight_since(0), None);
+        // Row 1 belongs to a later fetch
+        assert_eq!(fetch.in_flight_since(1), Some(t1));
+    }
+}
@@ -0,0 +1,5810 @@
+use crate::config::{configuration, Config, ConfigHandle};
+use crate::mux::domain::DomainId;
+use crate::mux::renderable::{
+    LineStatus, Renderable, RenderableDimensions, SelectionRegion, StableCursorPosition,
+    TabModeState,
+};
+use crate::mux::search::{SearchKind, MAX_SEARCH_RESULTS};
+use crate::mux::tab::{alloc_tab_id, Tab, TabId};
+use crate::mux::Mux;
+use crate::ratelim::RateLimiter;
+use crate::server::client::TrafficCounts;
+use crate::server::codec::*;
+use crate::server::domain::ClientInner;
+use anyhow::anyhow;
+use anyhow::bail;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use filedescriptor::Pipe;
+use log::info;
+use lru::LruCache;
+use portable_pty::PtySize;
+use promise::BrokenPromise;
+use rangeset::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cell::RefMut;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use term::color::ColorPalette;
+use term::{
+    Cell, CellAttributes, Clipboard, ClipboardSelection, Intensity, KeyCode, KeyModifiers, Line,
+    MouseEvent, StableRowIndex, TerminalHost,
+};
+use termwiz::escape::osc::Progress;
+use termwiz::hyperlink::Rule;
+use termwiz::input::KeyEvent;
+use url::Url;
+
+mod fetch;
+mod mouse;
+#[cfg(test)]
+mod scheduler;
+mod writer;
+
+use fetch::{FetchReason, FetchState};
+use mouse::MouseState;
+use writer::TabWriter;
+
+/// The tasks that are waiting for the queued input of a tab to be
+/// sent.  The input queues wake them whenever they make progress, so
+/// that they can check whether everything has been sent.
+#[derive(Clone, Default)]
+pub struct InputWaiters {
+    wakers: Rc<RefCell<Vec<Waker>>>,
+}
+
+impl InputWaiters {
+    fn register(&self, waker: &Waker) {
+        self.wakers.borrow_mut().push(waker.clone());
+    }
+
+    fn wake(&self) {
+        let wakers = std::mem::take(&mut *self.wakers.borrow_mut());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// A future that resolves to true once `done` returns true, or to
+/// the final result of `done` once `timeout` has elapsed.  `done` is
+/// checked each time that `waiters` are woken.
+struct WaitUntil<'a, F> {
+    done: F,
+    waiters: &'a InputWaiters,
+    timeout: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl<'a, F: Fn() -> bool + Unpin> WaitUntil<'a, F> {
+    fn new(deadline: Instant, waiters: &'a InputWaiters, done: F) -> Self {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        Self {
+            done,
+            waiters,
+            timeout: Box::pin(promise::spawn::sleep(timeout)),
+        }
+    }
+}
+
+impl<'a, F: Fn() -> bool + Unpin> Future for WaitUntil<'a, F> {
+    type Output = bool;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<bool> {
+        if (self.done)() {
+            return Poll::Ready(true);
+        }
+        if self.timeout.as_mut().poll(cx).is_ready() {
+            return Poll::Ready((self.done)());
+        }
+        self.waiters.register(cx.waker());
+        Poll::Pending
+    }
+}
+
+pub struct ClientTab {
+    client: Arc<ClientInner>,
+    local_tab_id: TabId,
+    remote_tab_id: TabId,
+    renderable: RefCell<RenderableState>,
+    writer: RefCell<TabWriter>,
+    keys: KeyQueue,
+    paste: PasteSender,
+    reader: Pipe,
+    mouse: Rc<RefCell<MouseState>>,
+    clipboard: RefCell<Option<Arc<dyn Clipboard>>>,
+    mouse_grabbed: RefCell<bool>,
+    last_bell: RefCell<Option<Instant>>,
+    /// Whether we apply the scroll positions published by other clients
+    follow_scroll: RefCell<bool>,
+    /// The viewport that we most recently published to the server
+    published_viewport: RefCell<Option<Option<StableRowIndex>>>,
+    /// A viewport published by another client that the GUI has yet
+    /// to apply
+    followed_viewport: RefCell<Option<Option<StableRowIndex>>>,
+    clipboard_limiter: RefCell<RateLimiter>,
+    notification_limiter: RefCell<RateLimiter>,
+    /// Incremented on each local resize, so that the pending resize
+    /// task can tell that it needs to wait some more
+    resize_generation: Rc<std::cell::Cell<usize>>,
+    /// The size to be sent by the pending resize task, if there is one
+    pending_resize: Rc<RefCell<Option<PtySize>>>,
+    /// The palette set by the program running in the remote tab,
+    /// which takes precedence over the one from our config
+    remote_palette: RefCell<Option<ColorPalette>>,
+    /// The receivers of the raw output of the remote tab
+    output_subscribers: RefCell<Vec<Sender<Vec<u8>>>>,
+    /// When set, input is refused rather than sent to the remote tab
+    read_only: RefCell<bool>,
+    /// Set once detach has been called; input is refused from then on
+    detached: RefCell<bool>,
+    /// Woken as the queued input is sent; see detach
+    input_waiters: InputWaiters,
+}
+
+/// How long the size of a tab must remain unchanged before we
+/// send the new size to the server
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Record whether the remote application has grabbed the mouse.
+/// Returns true if that differs from the prior state.
+fn update_mouse_grabbed(mouse_grabbed: &RefCell<bool>, grabbed: bool) -> bool {
+    let prior = mouse_grabbed.replace(grabbed);
+    prior != grabbed
+}
+
+/// Send the output bytes to each of the subscribers, forgetting those
+/// that have dropped their receiver.
+/// Returns true if any subscribers remain.
+fn deliver_output(subscribers: &mut Vec<Sender<Vec<u8>>>, data: &[u8]) -> bool {
+    subscribers.retain(|tx| tx.send(data.to_vec()).is_ok());
+    !subscribers.is_empty()
+}
+
+/// Returns the range of rows holding the last `n` lines of the tab,
+/// clamped to the available scrollback
+fn tail_range(dims: &RenderableDimensions, n: usize) -> Range<StableRowIndex> {
+    let end = dims.physical_top + dims.viewport_rows as StableRowIndex;
+    let available = (end - dims.scrollback_top).max(0) as usize;
+    end - n.min(available) as StableRowIndex..end
+}
+
+/// Returns the text of the lines, one per line of output, without
+/// trailing whitespace
+fn lines_to_text(lines: &[Line]) -> String {
+    lines
+        .iter()
+        .map(|line| line.as_str().trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The largest clipboard payload that we'll accept from the server
+const MAX_REMOTE_CLIPBOARD_SIZE: usize = 1024 * 1024;
+
+/// Decide whether a clipboard update from the server should be applied
+/// to the local clipboard.  Oversized payloads are rejected outright,
+/// and updates that arrive faster than the configured rate are dropped.
+fn admit_clipboard(limiter: &mut RateLimiter, clipboard: &Option<String>) -> bool {
+    let len = clipboard.as_ref().map(String::len).unwrap_or(0);
+    if len > MAX_REMOTE_CLIPBOARD_SIZE {
+        log::error!(
+            "ClientTab: rejecting SetClipboard of {} bytes (max is {})",
+            len,
+            MAX_REMOTE_CLIPBOARD_SIZE
+        );
+        return false;
+    }
+    if !limiter.non_blocking_admittance_check(1) {
+        log::error!("ClientTab: throttling SetClipboard requests");
+        return false;
+    }
+    true
+}
+
+/// The largest desktop notification, title and body combined,
+/// that we'll accept from the server
+const MAX_REMOTE_NOTIFICATION_SIZE: usize = 4096;
+
+/// Decide whether a desktop notification from the server should be
+/// shown.  Like admit_clipboard, this guards against a program that
+/// floods us with them.
+fn admit_notification(limiter: &mut RateLimiter, title: &Option<String>, body: &str) -> bool {
+    let len = title.as_ref().map(String::len).unwrap_or(0) + body.len();
+    if len > MAX_REMOTE_NOTIFICATION_SIZE {
+        log::error!(
+            "ClientTab: rejecting Notification of {} bytes (max is {})",
+            len,
+            MAX_REMOTE_NOTIFICATION_SIZE
+        );
+        return false;
+    }
+    if !limiter.non_blocking_admittance_check(1) {
+        log::error!("ClientTab: throttling Notification requests");
+        return false;
+    }
+    true
+}
+
+impl ClientTab {
+    pub fn new(
+        client: &Arc<ClientInner>,
+        remote_tab_id: TabId,
+        size: PtySize,
+        title: &str,
+    ) -> Self {
+        let local_tab_id = alloc_tab_id();
+        let config = configuration();
+        let waiters = InputWaiters::default();
+        let mouse = Rc::new(RefCell::new(MouseState::new(
+            &client.client,
+            remote_tab_id,
+            &config,
+            &waiters,
+        )));
+
+        let render = RenderableState {
+            inner: RefCell::new(RenderableInner::new(
+                client,
+                remote_tab_id,
+                local_tab_id,
+                size,
+                title,
+                &config,
+            )),
+        };
+        let writer = TabWriter::new(
+            client,
+            remote_tab_id,
+            config.mux_write_buffer_size,
+            &render.inner.borrow().dead_flag,
+            &waiters,
+        );
+
+        let reader = Pipe::new().expect("Pipe::new failed");
+
+        Self {
+            client: Arc::clone(client),
+            mouse,
+            remote_tab_id,
+            local_tab_id,
+            renderable: RefCell::new(render),
+            writer: RefCell::new(writer),
+            keys: KeyQueue::new(client, remote_tab_id, &waiters),
+            paste: PasteSender::new(client, remote_tab_id, config.mux_paste_chunk_size, &waiters),
+            reader,
+            clipboard: RefCell::new(None),
+            mouse_grabbed: RefCell::new(false),
+            last_bell: RefCell::new(None),
+            follow_scroll: RefCell::new(config.mux_follow_scroll_position),
+            published_viewport: RefCell::new(None),
+            followed_viewport: RefCell::new(None),
+            clipboard_limiter: RefCell::new(RateLimiter::new(|config| {
+                config.ratelimit_remote_clipboard_sets_per_second
+            })),
+            notification_limiter: RefCell::new(RateLimiter::new(|config| {
+                config.ratelimit_remote_notifications_per_second
+            })),
+            resize_generation: Rc::new(std::cell::Cell::new(0)),
+            pending_resize: Rc::new(RefCell::new(None)),
+            remote_palette: RefCell::new(None),
+            output_subscribers: RefCell::new(vec![]),
+            read_only: RefCell::new(false),
+            detached: RefCell::new(false),
+            input_waiters: waiters,
+        }
+    }
+
+    pub fn process_unilateral(&self, pdu: Pdu) -> anyhow::Result<()> {
+        match pdu {
+            Pdu::GetTabRenderChangesResponse(delta) => {
+                if update_mouse_grabbed(&self.mouse_grabbed, delta.mouse_grabbed) {
+                    notify_mux(crate::mux::MuxNotification::MouseGrabChanged(
+                        self.local_tab_id,
+                    ));
+                }
+                self.renderable
+                    .borrow()
+                    .inner
+                    .borrow_mut()
+                    .apply_changes_to_surface(delta);
+            }
+            Pdu::SetTabTitle(SetTabTitle { title, .. }) => {
+                {
+                    let renderable = self.renderable.borrow();
+                    let mut inner = renderable.inner.borrow_mut();
+                    inner.title = title;
+                    inner.title_pushed = true;
+                }
+                notify_mux(crate::mux::MuxNotification::TabTitleChanged(
+                    self.local_tab_id,
+                ));
+            }
+            Pdu::SetWorkingDir(SetWorkingDir { url, .. }) => {
+                let url = match Url::parse(&url) {
+                    Ok(url) => url,
+                    Err(err) => {
+                        log::warn!(
+                            "ignoring malformed working dir {:?} for tab {}: {}",
+                            url,
+                            self.remote_tab_id,
+                            err
+                        );
+                        return Ok(());
+                    }
+                };
+                let changed = {
+                    let renderable = self.renderable.borrow();
+                    let mut inner = renderable.inner.borrow_mut();
+                    inner.working_dir_pushed = true;
+                    inner.update_working_dir(Some(url))
+                };
+                if changed {
+                    notify_mux(crate::mux::MuxNotification::WorkingDirChanged(
+                        self.local_tab_id,
+                    ));
+                }
+            }
+            Pdu::Bell(Bell { .. }) => {
+                // Coalesce a rapid series of bells into a single notification
+                let now = Instant::now();
+                let mut last_bell = self.last_bell.borrow_mut();
+                let debounced = last_bell
+                    .map(|last| now.duration_since(last) < BELL_DEBOUNCE_INTERVAL)
+                    .unwrap_or(false);
+                if !debounced {
+                    last_bell.replace(now);
+                    notify_mux(crate::mux::MuxNotification::Bell(self.local_tab_id));
+                }
+            }
+            Pdu::SetTabProgress(progress) => {
+                let changed = {
+                    let renderable = self.renderable.borrow();
+                    let mut inner = renderable.inner.borrow_mut();
+                    inner.update_progress(progress.progress())
+                };
+                if changed {
+                    notify_mux(crate::mux::MuxNotification::TabProgressChanged(
+                        self.local_tab_id,
+                    ));
+                }
+            }
+            Pdu::Notification(Notification { title, body, .. }) => {
+                let mut limiter = self.notification_limiter.borrow_mut();
+                if admit_notification(&mut limiter, &title, &body) {
+                    notify_mux(crate::mux::MuxNotification::DesktopNotification {
+                        tab_id: self.local_tab_id,
+                        title,
+                        body,
+                    });
+                }
+            }
+            Pdu::GetClipboardRequest(GetClipboardRequest { .. }) => {
+                let clipboard = self.clipboard_for_remote();
+                let client = Arc::clone(&self.client);
+                let remote_tab_id = self.remote_tab_id;
+                promise::spawn::spawn(async move {
+                    client
+                        .client
+                        .clipboard_response(GetClipboardResponse {
+                            tab_id: remote_tab_id,
+                            clipboard,
+                        })
+                        .await
+                });
+            }
+            Pdu::SetClipboard(SetClipboard {
+                clipboard,
+                selection,
+                ..
+            }) => {
+                self.set_remote_clipboard(&configuration(), selection, clipboard)?;
+            }
+            Pdu::TabScrollPosition(TabScrollPosition { viewport, .. }) => {
+                if *self.follow_scroll.borrow() {
+                    self.followed_viewport.borrow_mut().replace(viewport);
+                    // Remember it as our own position, so that we don't
+                    // echo it back when the GUI applies it
+                    self.published_viewport.borrow_mut().replace(viewport);
+                }
+            }
+            Pdu::SetPalette(SetPalette { palette, .. }) => {
+                *self.remote_palette.borrow_mut() = palette.map(Into::into);
+                // Everything needs to be repainted in the new colors
+                self.renderable.borrow().inner.borrow_mut().make_all_dirty();
+                notify_mux(crate::mux::MuxNotification::TabOutput(self.local_tab_id));
+            }
+            Pdu::SetSelection(SetSelection { range, .. }) => {
+                let changed = self
+                    .renderable
+                    .borrow()
+                    .inner
+                    .borrow_mut()
+                    .set_remote_selection(range);
+                if changed {
+                    notify_mux(crate::mux::MuxNotification::TabOutput(self.local_tab_id));
+                }
+            }
+            Pdu::TabRemoved(TabRemoved { .. }) => {
+                self.renderable.borrow().inner.borrow_mut().remote_removed();
+                self.client.forget_tab(self.remote_tab_id);
+                // Remove it once we're done processing this pdu
+                let local_tab_id = self.local_tab_id;
+                promise::spawn::spawn(async move {
+                    if let Some(mux) = completion_mux("tab removal") {
+                        mux.remove_tab(local_tab_id);
+                    }
+                });
+            }
+            Pdu::TabOutputBytes(TabOutputBytes { data, .. }) => {
+                let mut subscribers = self.output_subscribers.borrow_mut();
+                if !subscribers.is_empty() && !deliver_output(&mut subscribers, &data) {
+                    // The last subscriber went away
+                    self.set_output_tap(false);
+                }
+            }
+            Pdu::TabExited(TabExited { exit_code, .. }) => {
+                let reason = DeadReason::RemoteClosed { exit_code };
+                {
+                    let renderable = self.renderable.borrow();
+                    let mut inner = renderable.inner.borrow_mut();
+                    inner.set_dead(Some(reason.clone()));
+                }
+                // As with a local tab whose process exits, the mux prunes
+                // the tab now that it is dead, so the exit status is only
+                // reported via the notification and, for a failure, a toast
+                notify_mux(crate::mux::MuxNotification::TabExited(
+                    self.local_tab_id,
+                    exit_code,
+                ));
+                notify_mux(crate::mux::MuxNotification::TabOutput(self.local_tab_id));
+
+                if exit_code.unwrap_or(0) != 0 && crate::frontend::has_gui_front_end() {
+                    crate::toast_notification(&self.get_title(), &reason.to_string());
+                }
+            }
+            _ => bail!("unhandled unilateral pdu: {:?}", pdu),
+        };
+        Ok(())
+    }
+
+    /// Apply a clipboard update requested by the server
+    fn set_remote_clipboard(
+        &self,
+        config: &Config,
+        selection: ClipboardSelection,
+        clipboard: Option<String>,
+    ) -> anyhow::Result<()> {
+        if !config.enable_remote_clipboard_write {
+            log::error!("ClientTab: remote clipboard writes are disabled; ignoring SetClipboard");
+            return Ok(());
+        }
+        match self.clipboard.borrow().as_ref() {
+            Some(clip) => {
+                let mut limiter = self.clipboard_limiter.borrow_mut();
+                if admit_clipboard(&mut limiter, &clipboard) {
+                    clip.set_selection_contents(selection, clipboard)?;
+                }
+            }
+            None => {
+                log::error!("ClientTab: Ignoring SetClipboard request {:?}", clipboard);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remote_tab_id(&self) -> TabId {
+        self.remote_tab_id
+    }
+
+    /// Returns the local clipboard contents for delivery to the server.
+    /// Returns an empty string if we have no clipboard, or if its
+    /// contents exceed the configured size limit.
+    fn clipboard_for_remote(&self) -> String {
+        let contents = match self.clipboard.borrow().as_ref() {
+            Some(clip) => match clip.get_contents() {
+                Ok(contents) => contents,
+                Err(err) => {
+                    log::error!("ClientTab: failed to read clipboard: {}", err);
+                    return String::new();
+                }
+            },
+            None => return String::new(),
+        };
+        let limit = configuration().mux_clipboard_request_max_bytes;
+        if contents.len() > limit {
+            log::error!(
+                "ClientTab: clipboard is {} bytes which exceeds the limit of {}; \
+                 not sending it to the server",
+                contents.len(),
+                limit
+            );
+            return String::new();
+        }
+        contents
+    }
+
+    /// Called in response to local user input so that we promptly
+    /// pick up its effect rather than waiting out an idle poll interval
+    fn note_local_input(&self) {
+        if let Ok(renderable) = self.renderable.try_borrow() {
+            if let Ok(mut inner) = renderable.inner.try_borrow_mut() {
+                inner.reset_poll_for_input();
+            }
+        }
+    }
+
+    /// Returns the reason that the tab died, or None if it is alive
+    pub fn dead_reason(&self) -> Option<DeadReason> {
+        self.renderable.borrow().inner.borrow().dead.clone()
+    }
+
+    /// Returns the number of bytes transferred on behalf of this tab
+    pub fn traffic(&self) -> TrafficCounts {
+        self.client.client.tab_traffic(self.remote_tab_id)
+    }
+
+    /// Returns the number of bytes transferred over the connection
+    /// that this tab shares with the other tabs of its domain
+    pub fn connection_traffic(&self) -> TrafficCounts {
+        self.client.client.traffic()
+    }
+
+    /// Discard our cached view of the tab and resync it from the server.
+    /// This is safe to call while a poll or fetch is in flight; a fetch
+    /// that completes afterwards won't replace fresher data.
+    pub fn refresh(&self) {
+        self.renderable.borrow().inner.borrow_mut().refresh();
+    }
+
+    /// Search the full scrollback of the tab on the server, rather than
+    /// just the lines that we have cached.  Pass the `next` row from
+    /// the response as `before` to continue the search.
+    pub async fn search_scrollback(
+        &self,
+        pattern: &str,
+        kind: SearchKind,
+        before: Option<StableRowIndex>,
+    ) -> anyhow::Result<SearchScrollbackResponse> {
+        self.client
+            .client
+            .search_scrollback(SearchScrollback {
+                tab_id: self.remote_tab_id,
+                pattern: pattern.to_string(),
+                kind,
+                before,
+                limit: MAX_SEARCH_RESULTS,
+            })
+            .await
+    }
+
+    /// Fetch the lines that are currently on the screen of the remote
+    /// tab.  Unlike get_lines, this bypasses our cache and waits for
+    /// the server, so the result reflects the server's current state
+    /// rather than placeholders for rows that we have yet to fetch.
+    pub async fn capture_viewport(&self) -> anyhow::Result<Vec<Line>> {
+        // The server locates the screen for us, as the output may
+        // scroll before our request reaches it
+        let range = {
+            let renderable = self.renderable.borrow();
+            let dims = renderable.inner.borrow().dimensions;
+            0..dims.viewport_rows as StableRowIndex
+        };
+        self.capture_rows(range, Some(RowAnchor::PhysicalTop)).await
+    }
+
+    /// Fetch the last `n` lines of the tab from the server, which
+    /// may reach back into the scrollback.  Fewer lines are returned
+    /// if the tab doesn't have that many.
+    pub async fn tail(&self, n: usize) -> anyhow::Result<String> {
+        let range = {
+            let renderable = self.renderable.borrow();
+            let dims = renderable.inner.borrow().dimensions;
+            tail_range(&dims, n)
+        };
+        let lines = self.capture_rows(range, None).await?;
+        Ok(lines_to_text(&lines))
+    }
+
+    /// Fetch `range`, which is relative to `anchor` if one is given,
+    /// from the server, bypassing and then refreshing our cache
+    async fn capture_rows(
+        &self,
+        range: Range<StableRowIndex>,
+        anchor: Option<RowAnchor>,
+    ) -> anyhow::Result<Vec<Line>> {
+        if range.start >= range.end {
+            return Ok(vec![]);
+        }
+        let response = self
+            .client
+            .client
+            .get_lines(GetLines {
+                tab_id: self.remote_tab_id,
+                lines: vec![range],
+                anchor,
+            })
+            .await?;
+        let seqno = response.seqno;
+        let lines = response.lines.lines();
+        if let Some(anchor_row) = response.anchor_row {
+            log::trace!(
+                "tab_id={} anchor {:?} resolved to {}",
+                self.remote_tab_id,
+                anchor,
+                anchor_row
+            );
+        }
+
+        // The server considers these rows to be clean now that it has
+        // sent them to us, so bring our cache up to date with them
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        let config = configuration();
+        for (stable_row, line) in &lines {
+            inner.put_line(*stable_row, line.clone(), &config, None, seqno);
+        }
+
+        Ok(lines.into_iter().map(|(_, line)| line).collect())
+    }
+
+    /// Like capture_viewport, but returns the text of the screen
+    /// without any styling
+    pub async fn capture_viewport_text(&self) -> anyhow::Result<String> {
+        let lines = self.capture_viewport().await?;
+        Ok(lines_to_text(&lines))
+    }
+
+    /// Set the rate at which this tab may fetch lines, in place of the
+    /// rates from the config.  None reverts to those rates.
+    pub fn set_fetch_rate(&self, rate: Option<u32>) {
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        inner.fetch.set_rate_override(rate);
+        inner.update_fetch_limiter();
+    }
+
+    /// Ask the server for the layout of the panes within this tab
+    pub async fn pane_tree(&self) -> anyhow::Result<PaneNode> {
+        let response = self
+            .client
+            .client
+            .get_pane_tree(GetPaneTree {
+                tab_id: self.remote_tab_id,
+            })
+            .await?;
+        Ok(response.tree)
+    }
+
+    /// Ask the server to retain `lines` lines of scrollback for this
+    /// tab.  The server may clamp the value; the number of lines that
+    /// it applied is returned.  If the scrollback shrank then the rows
+    /// that no longer exist are evicted from our cache.
+    pub async fn set_scrollback_lines(&self, lines: usize) -> anyhow::Result<usize> {
+        let response = self
+            .client
+            .client
+            .set_scrollback_lines(SetScrollbackLines {
+                tab_id: self.remote_tab_id,
+                lines,
+            })
+            .await?;
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        inner.update_dimensions(response.dimensions);
+        Ok(response.lines)
+    }
+
+    /// Ask the server to apply a full reset (RIS) to the terminal of
+    /// this tab, which is a more direct way to recover from a mangled
+    /// screen than typing `reset` into a shell that may not be listening.
+    /// Everything that we hold is refetched afterwards.
+    pub async fn send_reset(&self) -> anyhow::Result<()> {
+        self.client
+            .client
+            .reset_terminal(ResetTerminal {
+                tab_id: self.remote_tab_id,
+            })
+            .await?;
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        inner.discard_after_reset();
+        Ok(())
+    }
+
+    /// Returns the progress of the pastes that are being sent to the
+    /// server, or None if there are none
+    pub fn paste_progress(&self) -> Option<PasteProgress> {
+        self.paste.progress()
+    }
+
+    /// Stop sending the pastes that are in progress.  The part of
+    /// the paste that was already sent remains with the remote program.
+    pub fn cancel_paste(&self) {
+        self.paste.cancel();
+    }
+
+    /// Subscribe to the raw output of the remote tab.  The receiver
+    /// yields the bytes that the remote program writes to its pty,
+    /// including escape sequences, from the time that the server
+    /// processes the subscription onwards; earlier output is not
+    /// replayed.  The stream is independent of the cells that we
+    /// render: it doesn't reflect scrolling, resizing or the contents
+    /// of the screen, and is not resumed after a reconnect.
+    /// Dropping the receiver ends the subscription.
+    pub fn subscribe_output(&self) -> Receiver<Vec<u8>> {
+        let (tx, rx) = unbounded();
+        self.add_output_subscriber(tx);
+        rx
+    }
+
+    fn add_output_subscriber(&self, tx: Sender<Vec<u8>>) {
+        let mut subscribers = self.output_subscribers.borrow_mut();
+        subscribers.push(tx);
+        if subscribers.len() == 1 {
+            self.set_output_tap(true);
+        }
+    }
+
+    fn set_output_tap(&self, enable: bool) {
+        let client = Arc::clone(&self.client);
+        let remote_tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            if let Err(err) = client
+                .client
+                .set_output_tap(SetOutputTap {
+                    tab_id: remote_tab_id,
+                    enable,
+                })
+                .await
+            {
+                log::error!("set_output_tap {} {}: {}", remote_tab_id, enable, err);
+            }
+        });
+    }
+
+    /// Send all of the queued mouse events to the server in a single
+    /// request and wait for it to be acknowledged, rather than letting
+    /// them trickle out in batches.  The GUI calls this at the end of
+    /// a gesture, such as releasing a drag, so that the events are
+    /// delivered ahead of any subsequent key presses or pastes.
+    /// A batch that is already in flight was sent ahead of these
+    /// events, so it is delivered first.  Does nothing if the queue
+    /// is empty.  If sending fails, the events are returned to the
+    /// queue to be retried along with the next mouse event.
+    pub async fn flush_mouse(&self) -> anyhow::Result<()> {
+        self.keys.flush();
+        MouseState::flush(Rc::clone(&self.mouse)).await
+    }
+
+    /// Serialize the cached lines, cursor position and dimensions of
+    /// the tab, so that they can be restored into a new tab via
+    /// restore_cache when reattaching to it.  The snapshot holds no
+    /// more lines than the cache does.
+    pub fn snapshot_cache(&self) -> anyhow::Result<Vec<u8>> {
+        self.renderable.borrow().inner.borrow().snapshot().encode()
+    }
+
+    /// Populate the cache of a newly attached tab from the output of
+    /// snapshot_cache, so that it can be rendered before the server
+    /// has sent us anything.  The restored lines are treated as stale
+    /// and are fetched again as they are displayed.
+    pub fn restore_cache(&self, data: &[u8]) -> anyhow::Result<()> {
+        let snapshot = CacheSnapshot::decode(data)?;
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        inner.restore(snapshot);
+        Ok(())
+    }
+
+    /// Tell the other clients attached to the tab about our selection,
+    /// so that they can show it.  None clears it.
+    pub fn publish_selection(&self, range: Option<SelectionRegion>) {
+        let client = Arc::clone(&self.client);
+        let remote_tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .set_selection(SetSelection {
+                    tab_id: remote_tab_id,
+                    range,
+                })
+                .await
+        });
+    }
+
+    /// Returns the selection most recently made by another client
+    /// attached to the tab, so that the GUI can highlight it.  It is
+    /// purely informational and is independent of our own selection.
+    pub fn remote_selection(&self) -> Option<SelectionRegion> {
+        self.renderable.borrow().inner.borrow().remote_selection
+    }
+
+    /// Returns when the content of the remote tab last changed, which
+    /// can be used to sort tabs by recency or to spot idle tabs.
+    /// Unlike the time of the last poll, this only advances when the
+    /// server reports changed rows.
+    pub fn last_activity(&self) -> Instant {
+        self.renderable.borrow().inner.borrow().last_activity
+    }
+
+    /// Returns the estimated round trip time to the server, so that
+    /// the GUI can show it when the session seems sluggish
+    pub fn latency(&self) -> Option<Duration> {
+        self.client.latency()
+    }
+
+    /// Hint that the user is about to view the rows in `region`, such
+    /// as when jumping to the top of the scrollback, so that we can
+    /// fetch them in the background ahead of time rather than one
+    /// screenful at a time.
+    pub fn warm_region(&self, region: Range<StableRowIndex>) {
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        inner.warm_region(region);
+    }
+
+    /// Set whether the tab is read-only.  A read-only tab renders the
+    /// remote tab as usual, but refuses keys, mouse events, pastes and
+    /// writes, so that a session can be watched without any risk of
+    /// sending it input.
+    pub fn set_read_only(&self, read_only: bool) {
+        *self.read_only.borrow_mut() = read_only;
+        self.writer.borrow_mut().set_read_only(read_only);
+    }
+
+    /// Fails if the tab is read-only or has been detached
+    fn check_writable(&self) -> anyhow::Result<()> {
+        if *self.read_only.borrow() {
+            bail!("remote tab {} is read-only", self.remote_tab_id);
+        }
+        if *self.detached.borrow() {
+            bail!("remote tab {} has been detached", self.remote_tab_id);
+        }
+        Ok(())
+    }
+
+    /// Returns true if none of the input for the tab is waiting to be
+    /// sent or acknowledged, as far as we can tell; keys are sent
+    /// without waiting for a reply.
+    fn input_drained(&self) -> bool {
+        let keys = self.keys.state.borrow();
+        keys.events.is_empty()
+            && !keys.flush_scheduled
+            && self.mouse.borrow().is_idle()
+            && self.writer.borrow().is_idle()
+            && self.paste.progress().is_none()
+    }
+
+    /// Send the input that is still queued for the tab, wait up to
+    /// `timeout` for the server to acknowledge it, and then remove
+    /// the tab from the mux.  No further input is accepted once this
+    /// has been called.
+    /// Returns Ok(true) if all of the input was delivered in time,
+    /// or Ok(false) if we gave up waiting.  It is safe to call this
+    /// more than once.
+    pub async fn detach(&self, timeout: Duration) -> anyhow::Result<bool> {
+        *self.detached.borrow_mut() = true;
+        self.writer.borrow_mut().set_detached();
+        let deadline = Instant::now() + timeout;
+
+        self.keys.flush();
+        MouseState::next(Rc::clone(&self.mouse));
+        self.writer.borrow().schedule_flush();
+
+        let waiters = &self.input_waiters;
+        let mut clean = WaitUntil::new(deadline, waiters, || self.input_drained()).await;
+        if clean {
+            // The server handles our requests in order, so once this
+            // empty write is acknowledged, so is everything before it,
+            // including the keys
+            let barrier_done = Rc::new(RefCell::new(false));
+            {
+                let client = Arc::clone(&self.client);
+                let tab_id = self.remote_tab_id;
+                let barrier_done = Rc::clone(&barrier_done);
+                let waiters = waiters.clone();
+                promise::spawn::spawn(async move {
+                    let result = client
+                        .client
+                        .write_to_tab(WriteToTab {
+                            tab_id,
+                            data: vec![],
+                        })
+                        .await;
+                    *barrier_done.borrow_mut() = true;
+                    waiters.wake();
+                    result
+                });
+            }
+            clean = WaitUntil::new(deadline, waiters, || *barrier_done.borrow()).await;
+        }
+        if !clean {
+            log::warn!(
+                "remote tab {}: detached before all input was sent",
+                self.remote_tab_id
+            );
+        }
+
+        if let Some(mux) = completion_mux("detach") {
+            mux.remove_tab(self.local_tab_id);
+        }
+        Ok(clean)
+    }
+
+    /// Set whether the viewport of this tab should follow the scroll
+    /// position published by other clients attached to the same tab
+    pub fn set_follow_scroll(&self, follow: bool) {
+        *self.follow_scroll.borrow_mut() = follow;
+        if !follow {
+            self.followed_viewport.borrow_mut().take();
+        }
+    }
+
+    /// Returns true if the viewport of this tab is following the scroll
+    /// position published by other clients
+    pub fn is_following_scroll(&self) -> bool {
+        *self.follow_scroll.borrow()
+    }
+
+    /// Returns the state of our connection to the server, so that the
+    /// UI can indicate when we are trying to reconnect
+    pub fn reconnect_state(&self) -> ReconnectState {
+        self.renderable.borrow().inner.borrow().reconnect
+    }
+
+    /// Returns true if we hold up to date content for every row of the
+    /// viewport, so that what is on screen is not going to change
+    /// unless the remote tab does.  Unlike TabFirstPaint, this can be
+    /// asked at any time, such as to wait for the view to settle
+    /// before taking a screenshot.
+    pub fn viewport_ready(&self) -> bool {
+        self.renderable.borrow().inner.borrow().viewport_ready()
+    }
+
+    /// Returns the number of cached rows that we have given up trying
+    /// to fetch from the server
+    pub fn fetch_error_count(&self) -> usize {
+        self.renderable.borrow().inner.borrow().fetch_error_count()
+    }
+
+    /// Returns a summary of the line cache and fetch activity, to
+    /// help diagnose laggy remote sessions
+    pub fn cache_stats(&self) -> CacheStats {
+        self.renderable.borrow().inner.borrow().cache_stats()
+    }
+}
+
+impl Tab for ClientTab {
+    fn tab_id(&self) -> TabId {
+        self.local_tab_id
+    }
+    fn renderer(&self) -> RefMut<dyn Renderable> {
+        self.renderable.borrow_mut()
+    }
+
+    fn set_clipboard(&self, clipboard: &Arc<dyn Clipboard>) {
+        self.clipboard.borrow_mut().replace(Arc::clone(clipboard));
+    }
+
+    fn get_title(&self) -> String {
+        let renderable = self.renderable.borrow();
+        let inner = renderable.inner.borrow();
+        inner.title.clone()
+    }
+
+    fn send_paste(&self, text: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
+        self.note_local_input();
+        self.keys.flush();
+        self.paste.paste(text.to_owned());
+        Ok(())
+    }
+
+    fn trickle_paste(&self, text: String) -> anyhow::Result<()> {
+        // send_paste already sends large pastes a chunk at a time
+        self.send_paste(&text)
+    }
+
+    fn reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        info!("made reader for ClientTab");
+        Ok(Box::new(self.reader.read.try_clone()?))
+    }
+
+    fn writer(&self) -> RefMut<dyn std::io::Write> {
+        self.keys.flush();
+        self.writer.borrow_mut()
+    }
+
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let render = self.renderable.borrow();
+        let mut inner = render.inner.borrow_mut();
+
+        let (size, changed) = inner.resize_needed(size);
+        if changed {
+            // Only the most recent resize in a burst (eg: while the
+            // window is being dragged) is sent to the server.  There
+            // is at most one task waiting to send it; a resize that
+            // arrives while it waits re-arms it by bumping the
+            // generation, and updates the size that it will send.
+            let mut generation = self.resize_generation.get() + 1;
+            self.resize_generation.set(generation);
+            if self.pending_resize.borrow_mut().replace(size).is_some() {
+                return Ok(());
+            }
+            let resize_generation = Rc::clone(&self.resize_generation);
+            let pending_resize = Rc::clone(&self.pending_resize);
+            let client = Arc::clone(&self.client);
+            let remote_tab_id = self.remote_tab_id;
+            promise::spawn::spawn(async move {
+                loop {
+                    promise::spawn::sleep(RESIZE_DEBOUNCE).await;
+                    let latest = resize_generation.get();
+                    if latest == generation {
+                        break;
+                    }
+                    generation = latest;
+                }
+                let size = match pending_resize.borrow_mut().take() {
+                    Some(size) => size,
+                    None => return Ok(()),
+                };
+                client
+                    .client
+                    .resize(Resize {
+                        tab_id: remote_tab_id,
+                        size,
+                    })
+                    .await?;
+                Ok::<(), anyhow::Error>(())
+            });
+        }
+        Ok(())
+    }
+
+    fn key_down(&self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()> {
+        self.check_writable()?;
+        self.note_local_input();
+        self.keys.key_down(KeyEvent {
+            key,
+            modifiers: mods,
+        });
+        Ok(())
+    }
+
+    fn mouse_event(&self, event: MouseEvent, _host: &mut dyn TerminalHost) -> anyhow::Result<()> {
+        self.check_writable()?;
+        self.note_local_input();
+        self.keys.flush();
+        self.mouse.borrow_mut().append(event);
+        MouseState::next(Rc::clone(&self.mouse));
+        Ok(())
+    }
+
+    /// The remote terminal parses its own output and we receive the
+    /// results as render deltas, so there is nothing sensible to do
+    /// with output bytes here.  They are not forwarded as input, as
+    /// that would feed the output of a program back into it.
+    fn advance_bytes(&self, buf: &[u8], _host: &mut dyn TerminalHost) {
+        log::error!(
+            "ClientTab::advance_bytes: ignoring {} bytes for remote tab {}",
+            buf.len(),
+            self.remote_tab_id
+        );
+    }
+
+    fn is_dead(&self) -> bool {
+        self.renderable.borrow().inner.borrow().dead.is_some()
+    }
+
+    fn exit_code(&self) -> Option<u32> {
+        match self.dead_reason() {
+            Some(DeadReason::RemoteClosed { exit_code }) => exit_code,
+            _ => None,
+        }
+    }
+
+    fn palette(&self) -> ColorPalette {
+        if let Some(palette) = self.remote_palette.borrow().as_ref() {
+            return palette.clone();
+        }
+
+        let config = configuration();
+
+        if let Some(scheme_name) = config.color_scheme.as_ref() {
+            if let Some(palette) = config.color_schemes.get(scheme_name) {
+                return palette.clone().into();
+            }
+        }
+
+        config
+            .colors
+            .as_ref()
+            .cloned()
+            .map(Into::into)
+            .unwrap_or_else(ColorPalette::default)
+    }
+
+    fn palette_override(&self) -> Option<ColorPalette> {
+        self.remote_palette.borrow().clone()
+    }
+
+    fn domain_id(&self) -> DomainId {
+        self.client.local_domain_id
+    }
+
+    fn is_mouse_grabbed(&self) -> bool {
+        *self.mouse_grabbed.borrow()
+    }
+
+    fn is_read_only(&self) -> bool {
+        *self.read_only.borrow()
+    }
+
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        self.renderable.borrow().inner.borrow().bracketed_paste
+    }
+
+    fn mode_state(&self) -> TabModeState {
+        self.renderable.borrow().inner.borrow().modes
+    }
+
+    fn get_current_working_dir(&self) -> Option<Url> {
+        self.renderable.borrow().inner.borrow().working_dir.clone()
+    }
+
+    fn get_progress(&self) -> Progress {
+        self.renderable.borrow().inner.borrow().progress
+    }
+
+    fn viewport_changed(&self, viewport: Option<StableRowIndex>) {
+        if self.published_viewport.borrow().as_ref() == Some(&viewport) {
+            return;
+        }
+        self.published_viewport.borrow_mut().replace(viewport);
+        let client = Arc::clone(&self.client);
+        let remote_tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .set_scroll_position(TabScrollPosition {
+                    tab_id: remote_tab_id,
+                    viewport,
+                })
+                .await
+        });
+    }
+
+    fn take_followed_viewport(&self) -> Option<Option<StableRowIndex>> {
+        self.followed_viewport.borrow_mut().take()
+    }
+
+    fn set_dpi(&self, dpi: usize) {
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        if dpi != inner.dimensions.dpi {
+            inner.dimensions.dpi = dpi;
+            inner.dpi_changed = true;
+        }
+    }
+
+    fn set_focused(&self, focused: bool) {
+        let renderable = self.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        let was_focused = inner.focused.swap(focused, Ordering::SeqCst);
+        inner.update_fetch_limiter();
+        if focused && !was_focused {
+            // We've been brought to the foreground; catch up right away
+            inner.reset_poll_interval();
+            // We weren't listening for the server while in the background
+            inner.last_success = Instant::now();
+            if let Err(err) = inner.poll() {
+                log::error!("poll on focus failed: {}", err);
+            }
+        }
+    }
+
+    fn set_scrollback_lines(&self, lines: usize) -> anyhow::Result<()> {
+        let local_tab_id = self.local_tab_id;
+        promise::spawn::spawn(async move {
+            let mux = match completion_mux("set_scrollback_lines") {
+                Some(mux) => mux,
+                None => return,
+            };
+            if let Some(tab) = mux.get_tab(local_tab_id) {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    if let Err(err) = client_tab.set_scrollback_lines(lines).await {
+                        log::error!("set_scrollback_lines {}: {}", lines, err);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn reset_terminal(&self) -> anyhow::Result<()> {
+        let local_tab_id = self.local_tab_id;
+        promise::spawn::spawn(async move {
+            let mux = match completion_mux("reset_terminal") {
+                Some(mux) => mux,
+                None => return,
+            };
+            if let Some(tab) = mux.get_tab(local_tab_id) {
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    if let Err(err) = client_tab.send_reset().await {
+                        log::error!("reset_terminal: {}", err);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum LineEntry {
+    // Up to date wrt. server and has been rendered at least once
+    Line(Line),
+    // Up to date wrt. server but needs to be rendered
+    Dirty(Line),
+    // Currently being downloaded from the server
+    Fetching(Instant),
+    // We have a version of the line locally and are treating it
+    // as needing rendering because we are also in the process of
+    // downloading a newer version from the server
+    DirtyAndFetching(Line, Instant),
+    // We have a local copy but it is stale and will need to be
+    // fetched again
+    Stale(Line),
+    // We gave up trying to fetch this line after repeated failures
+    FetchError { since: Instant, attempts: u32 },
+}
+
+impl LineEntry {
+    /// Entries with a fetch in flight must not be evicted from the cache,
+    /// otherwise the fetch result would have nowhere to land
+    fn is_pinned(&self) -> bool {
+        match self {
+            Self::Fetching(_) | Self::DirtyAndFetching(..) => true,
+            _ => false,
+        }
+    }
+
+    fn kind(&self) -> (&'static str, Option<Instant>) {
+        match self {
+            Self::Line(_) => ("Line", None),
+            Self::Dirty(_) => ("Dirty", None),
+            Self::Fetching(since) => ("Fetching", Some(*since)),
+            Self::DirtyAndFetching(_, since) => ("DirtyAndFetching", Some(*since)),
+            Self::Stale(_) => ("Stale", None),
+            Self::FetchError { since, .. } => ("FetchError", Some(*since)),
+        }
+    }
+}
+
+/// A snapshot of the line cache of a ClientTab, as returned
+/// by ClientTab::cache_stats
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub line: usize,
+    pub dirty: usize,
+    pub fetching: usize,
+    pub dirty_and_fetching: usize,
+    pub stale: usize,
+    pub fetch_error: usize,
+    pub poll_interval: Duration,
+    /// The number of GetLines requests sent to the server
+    pub fetches_issued: usize,
+    /// The number of GetLines requests that have completed,
+    /// whether successfully or not
+    pub fetches_completed: usize,
+    /// The number of times that the fetch rate limiter let a fetch through
+    pub fetches_admitted: usize,
+    /// The number of times that the fetch rate limiter held back a fetch.
+    /// If this climbs quickly, consider raising
+    /// ratelimit_mux_line_prefetches_per_second.
+    pub fetches_throttled: usize,
+}
+
+/// Explains why a ClientTab is considered to be dead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeadReason {
+    /// The tab no longer exists on the server; eg: the program
+    /// running in it exited.  The exit code is known if the server
+    /// told us about the exit.
+    RemoteClosed { exit_code: Option<u32> },
+    /// We lost our connection to the server
+    ConnectionLost,
+    /// Polling the server failed for some other reason
+    PollError(String),
+    /// We heard nothing from the server for longer than
+    /// mux_idle_timeout_seconds
+    IdleTimeout,
+}
+
+impl DeadReason {
+    fn from_poll_error(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<BrokenPromise>().is_some() {
+            Self::ConnectionLost
+        } else if err.to_string().contains("no such tab") {
+            Self::RemoteClosed { exit_code: None }
+        } else {
+            Self::PollError(err.to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for DeadReason {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::RemoteClosed {
+                exit_code: Some(code),
+            } => write!(fmt, "the remote process exited with status {}", code),
+            Self::RemoteClosed { exit_code: None } => write!(fmt, "the remote tab was closed"),
+            Self::ConnectionLost => write!(fmt, "the connection to the server was lost"),
+            Self::PollError(err) => write!(fmt, "polling the server failed: {}", err),
+            Self::IdleTimeout => write!(fmt, "the server stopped responding"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectState {
+    /// We're in contact with the server
+    Connected,
+    /// We lost contact with the server and will make the specified
+    /// attempt to re-establish it at next_attempt
+    Reconnecting { attempt: u32, next_attempt: Instant },
+}
+
+/// Tracks the arrival of the rows of the first viewport that we
+/// displayed, so that we can announce when the tab is first painted
+#[derive(Debug)]
+enum FirstPaint {
+    /// Nothing has been displayed yet
+    Unarmed,
+    /// The rows of the first viewport that are still to arrive
+    Pending(RangeSet<StableRowIndex>),
+    Done,
+}
+
+struct RenderableInner {
+    client: Arc<ClientInner>,
+    remote_tab_id: TabId,
+    local_tab_id: TabId,
+    last_poll: Instant,
+    /// Set when the tab is dead
+    dead: Option<DeadReason>,
+    /// Mirrors whether dead is set, for the benefit of the TabWriter
+    dead_flag: Arc<AtomicBool>,
+    poll_in_progress: AtomicBool,
+    /// When poll_in_progress was last set, so that we can detect
+    /// a poll that is never going to complete
+    poll_started: Instant,
+    poll_interval: Duration,
+    base_poll_interval: Duration,
+    max_poll_interval: Duration,
+    poll_jitter: PollJitter,
+    /// The generation of the config that our cached lines were
+    /// scanned for hyperlinks with
+    config_generation: usize,
+    /// Whether the tab is visible; we don't poll hidden tabs
+    focused: AtomicBool,
+    reconnect: ReconnectState,
+    /// Whether we should try to reconnect after a failed poll
+    auto_reconnect: bool,
+    reconnect_max_attempts: u32,
+    reconnect_initial_rows: usize,
+
+    cursor_position: StableCursorPosition,
+    dimensions: RenderableDimensions,
+    /// Set when set_dpi changed the DPI, so that the next resize
+    /// is sent to the server
+    dpi_changed: bool,
+
+    lines: LruCache<StableRowIndex, LineEntry>,
+    /// The server's version of the lines that we fetched via
+    /// GetLinesDelta, which we can ask it to send patches against
+    line_versions: HashMap<StableRowIndex, u64>,
+    /// The server's sequence number for the lines that we hold, used
+    /// to avoid replacing a line with an older copy
+    line_seqnos: HashMap<StableRowIndex, u64>,
+    /// When get_lines last returned each row, so that rows that
+    /// haven't been displayed for a while can be discarded
+    line_access: HashMap<StableRowIndex, Instant>,
+    /// How long a row may go without being displayed before it is
+    /// discarded; None keeps rows until the cache is full
+    line_cache_ttl: Option<Duration>,
+    last_line_sweep: Instant,
+    /// When a poll last succeeded or a delta last arrived
+    last_success: Instant,
+    /// How long we may go without a last_success before the tab
+    /// is declared dead; None waits forever
+    idle_timeout: Option<Duration>,
+    title: String,
+    /// Set once the server has pushed us a title via SetTabTitle;
+    /// from then on we prefer those over the title in render deltas
+    title_pushed: bool,
+    working_dir: Option<Url>,
+    /// Set once the server has pushed us a directory via SetWorkingDir;
+    /// from then on the working_dir in render deltas is ignored
+    working_dir_pushed: bool,
+    /// The progress most recently reported by the remote program
+    progress: Progress,
+    /// Whether the remote application has enabled bracketed paste;
+    /// the server brackets our SendPaste requests accordingly
+    bracketed_paste: bool,
+    /// The terminal modes reported by the server
+    modes: TabModeState,
+
+    fetch: FetchState,
+    /// The range most recently passed to get_lines; used to infer
+    /// the scroll direction for prefetching
+    last_requested: Option<Range<StableRowIndex>>,
+    prefetch_rows: Option<usize>,
+    /// When get_lines was last called; used to decide whether
+    /// a FetchError row has been rendered yet
+    last_render: Instant,
+    /// When the content of the tab last changed; starts out as the
+    /// time that we attached to the tab
+    last_activity: Instant,
+    /// The selection made by another client attached to the tab
+    remote_selection: Option<SelectionRegion>,
+    first_paint: FirstPaint,
+    /// What fills the rows that we're waiting on
+    placeholder_fill: Option<Cell>,
+    /// The most recent content that we returned for the cursor's row,
+    /// so that we have something to draw the cursor over if that row
+    /// has to be fetched again
+    cursor_line: Option<(StableRowIndex, Line)>,
+    /// The delta_seq of the most recent render delta
+    last_delta_seq: u64,
+    /// How many rows we may be waiting on before we defer fetching
+    /// the changes to rows outside of the viewport
+    max_pending_fetch_rows: usize,
+    /// For rows that were patched rather than replaced, the columns
+    /// that the patches changed since the row was last rendered.
+    /// Only maintained when cell granular updates are enabled.
+    dirty_columns: HashMap<StableRowIndex, Range<usize>>,
+    cell_granular_updates: bool,
+}
+
+struct RenderableState {
+    inner: RefCell<RenderableInner>,
+}
+
+/// The version of the format produced by ClientTab::snapshot_cache.
+/// It is written ahead of the snapshot so that we can refuse to
+/// restore a snapshot that was saved in a different format.
+const CACHE_SNAPSHOT_VERSION: u32 = 2;
+
+/// The cached state of a tab that we persist across a reattach
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct CacheSnapshot {
+    cursor_position: StableCursorPosition,
+    dimensions: RenderableDimensions,
+    /// The cached lines, from the least to the most recently used
+    lines: Vec<(StableRowIndex, Line)>,
+}
+
+impl CacheSnapshot {
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut compress = zstd::Encoder::new(&mut data, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        let mut encode = varbincode::Serializer::new(&mut compress);
+        CACHE_SNAPSHOT_VERSION.serialize(&mut encode)?;
+        self.serialize(&mut encode)?;
+        drop(encode);
+        compress.finish()?;
+        Ok(data)
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut decompress = zstd::Decoder::new(data)?;
+        let mut decode = varbincode::Deserializer::new(&mut decompress);
+        let version = u32::deserialize(&mut decode)?;
+        if version != CACHE_SNAPSHOT_VERSION {
+            bail!(
+                "cache snapshot has version {}, but we can only restore version {}",
+                version,
+                CACHE_SNAPSHOT_VERSION
+            );
+        }
+        Ok(Self::deserialize(&mut decode)?)
+    }
+}
+
+const BASE_RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+const BELL_DEBOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+/// How many multiples of the max poll interval a poll may be in
+/// progress for before we assume that it has been lost
+const POLL_WATCHDOG_INTERVALS: u32 = 5;
+/// How often we look for cached rows that have outlived their TTL
+const LINE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// The fraction by which the poll interval is randomly adjusted
+const POLL_JITTER: f64 = 0.1;
+
+/// A small xorshift generator used to spread out the polls of tabs
+/// that would otherwise back off in lockstep.  It is seeded from the
+/// tab id, which keeps it deterministic for tests.
+struct PollJitter {
+    state: u64,
+}
+
+impl PollJitter {
+    fn new(seed: u64) -> Self {
+        // xorshift must not be seeded with zero
+        Self {
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    /// Returns interval scaled by a random factor within POLL_JITTER of 1
+    fn apply(&mut self, interval: Duration) -> Duration {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        let unit = (x >> 11) as f64 / (1u64 << 53) as f64;
+        interval.mul_f64(1.0 - POLL_JITTER + 2.0 * POLL_JITTER * unit)
+    }
+}
+
+/// Returns the (base, max) poll intervals from the config, making
+/// sure that the base is non-zero and doesn't exceed the max
+fn poll_interval_bounds(config: &Config) -> (Duration, Duration) {
+    let base = Duration::from_millis(config.mux_poll_interval_base_ms.max(1));
+    let max = Duration::from_millis(config.mux_poll_interval_max_ms).max(base);
+    (base, max)
+}
+
+/// Split the rows to fetch into those in the visible range, along
+/// with the cursor row, and the rest
+fn split_visible(
+    to_fetch: &RangeSet<StableRowIndex>,
+    visible: &Range<StableRowIndex>,
+    cursor_row: StableRowIndex,
+) -> (RangeSet<StableRowIndex>, RangeSet<StableRowIndex>) {
+    let mut inside = to_fetch.intersection_with_range(visible.clone());
+    if to_fetch.contains(cursor_row) {
+        inside.add(cursor_row);
+    }
+    let outside = to_fetch.difference(&inside);
+    (inside, outside)
+}
+
+/// Returns the ranges of rows to request from the server, with the
+/// cursor row first so that it is produced ahead of the others
+fn cursor_first(
+    to_fetch: &RangeSet<StableRowIndex>,
+    cursor_row: StableRowIndex,
+) -> Vec<Range<StableRowIndex>> {
+    let mut ranges = vec![];
+    if to_fetch.contains(cursor_row) {
+        ranges.push(cursor_row..cursor_row + 1);
+    }
+    for r in to_fetch.iter() {
+        if r.contains(&cursor_row) {
+            if r.start < cursor_row {
+                ranges.push(r.start..cursor_row);
+            }
+            if cursor_row + 1 < r.end {
+                ranges.push(cursor_row + 1..r.end);
+            }
+        } else {
+            ranges.push(r.clone());
+        }
+    }
+    ranges
+}
+
+/// Produces the line that is displayed in place of a row that we're
+/// waiting on, filled with `fill` if set, otherwise blank.  It is
+/// always made to the current width, rather than being kept, so that
+/// it is never left behind by a resize.
+fn placeholder_line(cols: usize, fill: Option<&Cell>) -> Line {
+    let mut line = Line::with_width(cols);
+    if let Some(fill) = fill {
+        line.fill_range(0..cols, fill);
+    }
+    line
+}
+
+/// Returns the cell used to fill placeholder rows, per the config
+fn placeholder_fill(config: &ConfigHandle) -> Option<Cell> {
+    let c = config.mux_placeholder_char?;
+    let mut attrs = CellAttributes::default();
+    if config.mux_placeholder_dim {
+        attrs.set_intensity(Intensity::Half);
+    }
+    let cell = Cell::new(c, attrs);
+    if cell.width() != 1 {
+        log::warn!(
+            "mux_placeholder_char {:?} is not single width; leaving placeholders blank",
+            c
+        );
+        return None;
+    }
+    Some(cell)
+}
+
+/// Truncate a line that is wider than `cols`, such as one that was
+/// fetched before the server learned of a resize.  A double width
+/// character that would straddle the new right edge is replaced with
+/// blanks rather than leaving half of it behind.
+/// Returns true if the line was truncated.
+fn clamp_line_width(line: &mut Line, cols: usize) -> bool {
+    if line.cells().len() <= cols {
+        return false;
+    }
+    let straddling = line
+        .visible_cells()
+        .find(|(idx, cell)| *idx < cols && idx + cell.width() > cols)
+        .map(|(idx, _)| idx);
+    line.resize(cols);
+    if let Some(start) = straddling {
+        for idx in start..cols {
+            line.set_cell(idx, Cell::default());
+        }
+    }
+    true
+}
+
+/// Produces the placeholder that is displayed in place of a line
+/// that we have given up trying to fetch from the server
+fn fetch_error_line(cols: usize) -> Line {
+    let mut attrs = CellAttributes::default();
+    attrs.set_intensity(Intensity::Half);
+    let mut line = Line::with_width(cols);
+    line.fill_range(0..cols, &Cell::new('\u{b7}', attrs));
+    line
+}
+
+impl RenderableInner {
+    fn new(
+        client: &Arc<ClientInner>,
+        remote_tab_id: TabId,
+        local_tab_id: TabId,
+        size: PtySize,
+        title: &str,
+        config: &ConfigHandle,
+    ) -> Self {
+        let (base_poll_interval, max_poll_interval) = poll_interval_bounds(config);
+
+        Self {
+            client: Arc::clone(client),
+            remote_tab_id,
+            local_tab_id,
+            last_poll: Instant::now(),
+            dead: None,
+            dead_flag: Arc::new(AtomicBool::new(false)),
+            poll_in_progress: AtomicBool::new(false),
+            poll_started: Instant::now(),
+            poll_interval: base_poll_interval,
+            base_poll_interval,
+            config_generation: config.generation(),
+            max_poll_interval,
+            poll_jitter: PollJitter::new(local_tab_id as u64),
+            focused: AtomicBool::new(true),
+            reconnect: ReconnectState::Connected,
+            auto_reconnect: config.mux_auto_reconnect && client.client.is_reconnectable(),
+            reconnect_max_attempts: config.mux_reconnect_max_attempts,
+            reconnect_initial_rows: config.mux_reconnect_initial_rows,
+            cursor_position: StableCursorPosition::default(),
+            dimensions: RenderableDimensions {
+                cols: size.cols as _,
+                viewport_rows: size.rows as _,
+                scrollback_rows: size.rows as _,
+                physical_top: 0,
+                scrollback_top: 0,
+                pixel_width: size.pixel_width as _,
+                pixel_height: size.pixel_height as _,
+                dpi: config.dpi as _,
+            },
+            dpi_changed: false,
+            lines: LruCache::new(config.mux_client_line_cache_size.max(1)),
+            line_versions: HashMap::new(),
+            line_seqnos: HashMap::new(),
+            line_access: HashMap::new(),
+            line_cache_ttl: match config.mux_line_cache_ttl_seconds {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            last_line_sweep: Instant::now(),
+            last_success: Instant::now(),
+            idle_timeout: match config.mux_idle_timeout_seconds {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            title: title.to_string(),
+            title_pushed: false,
+            working_dir: None,
+            working_dir_pushed: false,
+            progress: Progress::None,
+            bracketed_paste: false,
+            modes: TabModeState::default(),
+            fetch: FetchState::new(config),
+            last_requested: None,
+            prefetch_rows: config.mux_prefetch_rows,
+            last_render: Instant::now(),
+            last_activity: Instant::now(),
+            remote_selection: None,
+            first_paint: FirstPaint::Unarmed,
+            placeholder_fill: placeholder_fill(config),
+            cursor_line: None,
+            last_delta_seq: 0,
+            max_pending_fetch_rows: config.mux_max_pending_fetch_rows,
+            dirty_columns: HashMap::new(),
+            cell_granular_updates: config.mux_cell_granular_updates,
+        }
+    }
+
+    /// Returns true if we're permitted to (re)fetch the row; false if
+    /// a prior failure means that we're still backing off.
+    fn fetch_allowed(&self, stable_row: StableRowIndex, now: Instant) -> bool {
+        if self.remote_exited() {
+            // There is nothing left to fetch
+            return false;
+        }
+        self.fetch.backoff_elapsed(stable_row, now)
+    }
+
+    /// Called when the server tells us that the tab was removed.
+    /// If we already know how the tab died, we keep that reason.
+    fn remote_removed(&mut self) {
+        match self.dead {
+            Some(DeadReason::RemoteClosed { .. }) => {}
+            _ => self.set_dead(Some(DeadReason::RemoteClosed { exit_code: None })),
+        }
+    }
+
+    fn set_dead(&mut self, reason: Option<DeadReason>) {
+        self.dead_flag.store(reason.is_some(), Ordering::SeqCst);
+        self.dead = reason;
+    }
+
+    /// Returns true if the tab is gone from the server
+    fn remote_exited(&self) -> bool {
+        match self.dead {
+            Some(DeadReason::RemoteClosed { .. }) => true,
+            _ => false,
+        }
+    }
+
+    fn fetch_error_count(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|(_, entry)| match entry {
+                LineEntry::FetchError { .. } => true,
+                _ => false,
+            })
+            .count()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            poll_interval: self.poll_interval,
+            ..CacheStats::default()
+        };
+        self.fetch.record_stats(&mut stats);
+        for (_, entry) in self.lines.iter() {
+            match entry {
+                LineEntry::Line(_) => stats.line += 1,
+                LineEntry::Dirty(_) => stats.dirty += 1,
+                LineEntry::Fetching(_) => stats.fetching += 1,
+                LineEntry::DirtyAndFetching(..) => stats.dirty_and_fetching += 1,
+                LineEntry::Stale(_) => stats.stale += 1,
+                LineEntry::FetchError { .. } => stats.fetch_error += 1,
+            }
+        }
+        stats
+    }
+
+    /// Insert an entry into the line cache.
+    /// If the cache is full then the least recently used entry will
+    /// be evicted to make room, but entries that have a fetch in flight
+    /// are pinned and will be retained, as is the cursor row.
+    fn put_entry(&mut self, stable_row: StableRowIndex, entry: LineEntry) {
+        if self.lines.len() >= self.lines.cap() && !self.lines.contains(&stable_row) {
+            let cursor_row = self.cursor_position.y;
+            let mut pinned = vec![];
+            while let Some((row, prior)) = self.lines.pop_lru() {
+                if prior.is_pinned() || row == cursor_row {
+                    pinned.push((row, prior));
+                } else {
+                    log::trace!("row {} {:?} evicted from cache", row, prior.kind());
+                    self.line_versions.remove(&row);
+                    self.line_seqnos.remove(&row);
+                    self.dirty_columns.remove(&row);
+                    break;
+                }
+            }
+            // Restore the pinned entries in their original relative order
+            for (row, prior) in pinned {
+                self.lines.put(row, prior);
+            }
+        }
+        self.lines.put(stable_row, entry);
+    }
+
+    /// Record the progress reported by the server.
+    /// Returns true if it differs from the prior value.
+    fn update_progress(&mut self, progress: Progress) -> bool {
+        if progress == self.progress {
+            return false;
+        }
+        self.progress = progress;
+        true
+    }
+
+    /// Record the working directory reported by the server.
+    /// Returns true if it differs from the prior value.
+    fn update_working_dir(&mut self, working_dir: Option<Url>) -> bool {
+        if working_dir == self.working_dir {
+            return false;
+        }
+        self.working_dir = working_dir;
+        true
+    }
+
+    /// Rebuild the fetch rate limiter if the rate that applies to the
+    /// tab has changed, such as when it moves to or from the background.
+    /// Returns true if it was rebuilt.
+    fn update_fetch_limiter(&mut self) -> bool {
+        let focused = self.focused.load(Ordering::SeqCst);
+        if !self.fetch.update_limiter(focused) {
+            return false;
+        }
+        log::trace!(
+            "tab_id={} fetch rate {}/s",
+            self.remote_tab_id,
+            self.fetch.rate()
+        );
+        true
+    }
+
+    /// Record the terminal modes reported by the server.
+    /// Returns true if they differ from the prior modes.
+    fn update_modes(&mut self, modes: TabModeState) -> bool {
+        if modes == self.modes {
+            return false;
+        }
+        log::trace!("tab_id={} modes {:?}", self.remote_tab_id, modes);
+        self.modes = modes;
+        true
+    }
+
+    /// Adopt the dimensions reported by the server.  If the server has
+    /// trimmed its scrollback then any rows we hold from above the new
+    /// top no longer exist, so we discard them.
+    fn update_dimensions(&mut self, dimensions: RenderableDimensions) {
+        let floor = dimensions.scrollback_top;
+        if floor > self.dimensions.scrollback_top {
+            let trimmed: Vec<StableRowIndex> = self
+                .lines
+                .iter()
+                .map(|(stable_row, _)| *stable_row)
+                .filter(|stable_row| *stable_row < floor)
+                .collect();
+            for stable_row in trimmed {
+                self.lines.pop(&stable_row);
+                self.line_versions.remove(&stable_row);
+                self.line_seqnos.remove(&stable_row);
+                self.dirty_columns.remove(&stable_row);
+            }
+            self.line_access
+                .retain(|stable_row, _| *stable_row >= floor);
+            self.fetch.trim(floor);
+        }
+        self.dimensions = self.with_local_geometry(dimensions);
+    }
+
+    /// The server doesn't know the DPI at which we display the tab,
+    /// and may not know its pixel size, so we keep our own values for
+    /// those that it reports as unknown.
+    fn with_local_geometry(&self, mut dimensions: RenderableDimensions) -> RenderableDimensions {
+        if dimensions.pixel_width == 0 || dimensions.pixel_height == 0 {
+            dimensions.pixel_width = self.dimensions.pixel_width;
+            dimensions.pixel_height = self.dimensions.pixel_height;
+        }
+        if dimensions.dpi == 0 {
+            dimensions.dpi = self.dimensions.dpi;
+        }
+        dimensions
+    }
+
+    /// Record the pixel size of the viewport for a resize to `size`.
+    /// If the caller doesn't know the pixel size then it is computed
+    /// from the size of our cells, which is unchanged by a resize.
+    /// Returns `size` with its pixel size filled in, and whether the
+    /// pixel size changed.
+    fn apply_pixel_size(&mut self, mut size: PtySize) -> (PtySize, bool) {
+        if size.pixel_width == 0 || size.pixel_height == 0 {
+            let dims = &self.dimensions;
+            if dims.cols > 0 && dims.viewport_rows > 0 {
+                let cell_width = dims.pixel_width / dims.cols;
+                let cell_height = dims.pixel_height / dims.viewport_rows;
+                size.pixel_width = (cell_width * size.cols as usize) as u16;
+                size.pixel_height = (cell_height * size.rows as usize) as u16;
+            }
+        }
+        let changed = self.dimensions.pixel_width != size.pixel_width as usize
+            || self.dimensions.pixel_height != size.pixel_height as usize;
+        self.dimensions.pixel_width = size.pixel_width as usize;
+        self.dimensions.pixel_height = size.pixel_height as usize;
+        (size, changed)
+    }
+
+    /// Apply a resize of the tab to `size`, returning `size` with its
+    /// pixel size filled in, and whether we need to tell the server.
+    /// That is the case if the grid or pixel size changed, or the DPI
+    /// did since the last resize, even though the grid is the same.
+    fn resize_needed(&mut self, size: PtySize) -> (PtySize, bool) {
+        let (size, pixels_changed) = self.apply_pixel_size(size);
+        let grid_changed = self.apply_resize(size.cols as usize, size.rows as usize);
+        let dpi_changed = std::mem::replace(&mut self.dpi_changed, false);
+        (size, grid_changed || pixels_changed || dpi_changed)
+    }
+
+    /// Returns true if applying `delta` would change nothing.  The
+    /// mouse grab state isn't considered, as ClientTab handles it.
+    fn is_noop_delta(&self, delta: &GetTabRenderChangesResponse) -> bool {
+        delta.dirty_lines.is_empty()
+            && delta.bonus_lines.is_empty()
+            && delta.cursor_position == self.cursor_position
+            && self.with_local_geometry(delta.dimensions) == self.dimensions
+            && (self.title_pushed || delta.title == self.title)
+            && (self.working_dir_pushed
+                || delta.working_dir.as_ref().map(|url| &url.url) == self.working_dir.as_ref())
+            && delta.bracketed_paste == self.bracketed_paste
+            && delta.modes == self.modes
+    }
+
+    /// Record the delta_seq of a render delta.  Returns true if it
+    /// shows that we missed one or more deltas since the last.
+    /// A lower number than before is not a gap: the server starts
+    /// again from 1 when we reconnect.
+    fn check_delta_seq(&mut self, delta_seq: u64) -> bool {
+        if delta_seq == 0 {
+            // The server doesn't count its deltas
+            return false;
+        }
+        let last = self.last_delta_seq;
+        self.last_delta_seq = delta_seq;
+        if last == 0 || delta_seq <= last + 1 {
+            return false;
+        }
+        log::warn!(
+            "tab_id={} missed render deltas {}..{}; resyncing",
+            self.remote_tab_id,
+            last + 1,
+            delta_seq
+        );
+        true
+    }
+
+    /// Discard what we know about the content of the tab, after we've
+    /// missed some changes to it.  The rows are shown as they are
+    /// until they have been fetched again.
+    fn resync(&mut self) {
+        self.make_all_stale();
+        // The server's idea of what we hold may be wrong too, so
+        // don't let it send patches against those lines
+        self.line_versions.clear();
+        self.dirty_columns.clear();
+        notify_mux(crate::mux::MuxNotification::TabOutput(self.local_tab_id));
+    }
+
+    /// Called after the server has reset the terminal.  Nothing that
+    /// we hold reflects the cleared screen, so refetch all of it; the
+    /// content of the cursor row in particular mustn't be shown in
+    /// place of the fetch.
+    fn discard_after_reset(&mut self) {
+        self.cursor_line = None;
+        self.resync();
+        self.reset_poll_interval();
+    }
+
+    fn apply_changes_to_surface(&mut self, delta: GetTabRenderChangesResponse) {
+        self.last_success = Instant::now();
+        if self.check_delta_seq(delta.delta_seq) {
+            self.resync();
+        }
+        if self.is_noop_delta(&delta) {
+            // The tab is idle, so let the poll interval keep backing off
+            log::trace!("tab_id={} ignoring empty delta", self.remote_tab_id);
+            return;
+        }
+        self.poll_interval = self.base_poll_interval;
+
+        let mut dirty = RangeSet::new();
+        for r in delta.dirty_lines {
+            dirty.add_range(r.clone());
+        }
+        self.record_activity(&dirty, Instant::now());
+        // The rows that the cursor moved between need to be rendered
+        // again, but their content hasn't necessarily changed
+        let mut cursor_rows = vec![];
+        if delta.cursor_position != self.cursor_position {
+            cursor_rows.push(self.cursor_position.y);
+            cursor_rows.push(delta.cursor_position.y);
+        }
+
+        self.cursor_position = delta.cursor_position;
+        self.update_dimensions(delta.dimensions);
+        if !self.title_pushed {
+            self.title = delta.title;
+        }
+        if !self.working_dir_pushed && self.update_working_dir(delta.working_dir.map(Into::into)) {
+            notify_mux(crate::mux::MuxNotification::WorkingDirChanged(
+                self.local_tab_id,
+            ));
+        }
+        self.bracketed_paste = delta.bracketed_paste;
+        if self.update_modes(delta.modes) {
+            notify_mux(crate::mux::MuxNotification::TabModesChanged(
+                self.local_tab_id,
+            ));
+        }
+
+        let config = configuration();
+        let mut repaint = false;
+        for (stable_row, line) in delta.bonus_lines.lines() {
+            self.put_line(stable_row, line, &config, None, delta.seqno);
+            dirty.remove(stable_row);
+            cursor_rows.retain(|row| *row != stable_row);
+            repaint = true;
+        }
+        for stable_row in cursor_rows {
+            if dirty.contains(stable_row) {
+                continue;
+            }
+            if self.repaint_without_fetch(stable_row) {
+                repaint = true;
+            } else {
+                // We don't have a good copy of the row, so we'll
+                // need to fetch it
+                dirty.add(stable_row);
+            }
+        }
+
+        if repaint || !dirty.is_empty() {
+            notify_mux(crate::mux::MuxNotification::TabOutput(self.local_tab_id));
+        }
+        self.check_first_paint();
+
+        // The first changes after reconnecting may cover a lot of rows;
+        // only fetch those near the viewport for now
+        let catch_up = match self.reconnect {
+            ReconnectState::Reconnecting { .. } => Some(self.catch_up_range()),
+            ReconnectState::Connected => None,
+        };
+
+        let to_fetch = self.mark_dirty_rows_for_fetch(
+            &dirty,
+            delta.dimensions.physical_top,
+            catch_up,
+            Instant::now(),
+        );
+        self.schedule_fetch_lines(to_fetch, FetchReason::Background);
+    }
+
+    /// Arrange for the rows that the server reported as dirty to be
+    /// fetched, returning the set of rows to fetch.
+    /// Rows that are unlikely to be displayed soon are made stale so
+    /// that they are fetched on demand.  If we're already waiting on
+    /// more than max_pending_fetch_rows rows then we're falling behind
+    /// the output of the tab, so we also defer the rows outside of the
+    /// viewport, leaving our fetches for the rows that the user can see.
+    /// The fetch rate limit is applied later, when the requests are sent.
+    fn mark_dirty_rows_for_fetch(
+        &mut self,
+        dirty: &RangeSet<StableRowIndex>,
+        physical_top: StableRowIndex,
+        catch_up: Option<Range<StableRowIndex>>,
+        now: Instant,
+    ) -> RangeSet<StableRowIndex> {
+        let visible = self.visible_rows();
+        let pending = self.lines.iter().filter(|(_, e)| e.is_pinned()).count();
+        let mut budget = self.max_pending_fetch_rows.saturating_sub(pending);
+        let cursor_row = self.cursor_position.y;
+
+        let mut to_fetch = RangeSet::new();
+        for r in dirty.iter() {
+            for stable_row in r.clone() {
+                // The row changed since any fetch that is in flight
+                // for it was sent, so that fetch is no longer enough
+                self.fetch.forget_in_flight(stable_row);
+                // If a line is in the (probable) viewport region,
+                // then we'll likely want to fetch it.
+                // If it is outside that region, remove it from our cache
+                // so that we'll fetch it on demand later.
+                let fetchable = stable_row >= physical_top
+                    && catch_up
+                        .as_ref()
+                        .map(|range| range.contains(&stable_row))
+                        .unwrap_or(true)
+                    && self.fetch_allowed(stable_row, now);
+                if !fetchable {
+                    self.make_stale(stable_row);
+                    continue;
+                }
+                let in_view = visible.contains(&stable_row) || stable_row == cursor_row;
+                if !in_view {
+                    if budget == 0 {
+                        log::trace!(
+                            "tab_id={} stable_row={} outcome=deferred: too many rows pending",
+                            self.remote_tab_id,
+                            stable_row
+                        );
+                        self.make_stale(stable_row);
+                        continue;
+                    }
+                    budget -= 1;
+                }
+                let prior = self.lines.pop(&stable_row);
+                let prior_kind = prior.as_ref().map(|e| e.kind());
+                to_fetch.add(stable_row);
+                let entry = match prior {
+                    Some(LineEntry::Fetching(_)) | Some(LineEntry::FetchError { .. }) | None => {
+                        LineEntry::Fetching(now)
+                    }
+                    Some(LineEntry::DirtyAndFetching(old, ..))
+                    | Some(LineEntry::Stale(old))
+                    | Some(LineEntry::Dirty(old))
+                    | Some(LineEntry::Line(old)) => LineEntry::DirtyAndFetching(old, now),
+                };
+                log::trace!(
+                    "tab_id={} stable_row={} fetch_start={:?} outcome=scheduled: \
+                     {:?} -> {:?} due to dirty and {}",
+                    self.remote_tab_id,
+                    stable_row,
+                    now,
+                    prior_kind,
+                    entry.kind().0,
+                    if in_view {
+                        "in viewport"
+                    } else {
+                        "within pending budget"
+                    }
+                );
+                self.put_entry(stable_row, entry);
+            }
+        }
+        to_fetch
+    }
+
+    /// If the config has been reloaded, rescan the cached lines for
+    /// hyperlinks in case the rules have changed
+    fn check_config_reload(&mut self) {
+        let config = configuration();
+        if config.generation() != self.config_generation {
+            self.config_generation = config.generation();
+            self.rescan_hyperlinks(&config.hyperlink_rules);
+        }
+    }
+
+    /// Re-apply the hyperlink rules to the cached lines.  Rows whose
+    /// hyperlinks changed are marked dirty so that they are rendered
+    /// again.  Rows that are being fetched will pick up the new rules
+    /// when they arrive.
+    fn rescan_hyperlinks(&mut self, rules: &[Rule]) {
+        let rescan = |line: &mut Line| {
+            let prior = line.clone();
+            line.invalidate_implicit_hyperlinks();
+            line.scan_and_create_hyperlinks(rules);
+            *line != prior
+        };
+
+        let mut lines = LruCache::new(self.lines.cap());
+        while let Some((stable_row, entry)) = self.lines.pop_lru() {
+            let entry = match entry {
+                LineEntry::Line(mut line) => {
+                    if rescan(&mut line) {
+                        LineEntry::Dirty(line)
+                    } else {
+                        LineEntry::Line(line)
+                    }
+                }
+                // A dirty row has yet to be rendered, so it stays dirty
+                LineEntry::Dirty(mut line) => {
+                    rescan(&mut line);
+                    LineEntry::Dirty(line)
+                }
+                LineEntry::Stale(mut line) => {
+                    rescan(&mut line);
+                    LineEntry::Stale(line)
+                }
+                entry => entry,
+            };
+            lines.put(stable_row, entry);
+        }
+        self.lines = lines;
+    }
+
+    /// The rows that we fetch straight away after reconnecting: the
+    /// visible rows plus a margin either side
+    fn catch_up_range(&self) -> Range<StableRowIndex> {
+        let margin = self.reconnect_initial_rows as StableRowIndex;
+        let visible = self.visible_rows();
+        visible.start - margin..visible.end + margin
+    }
+
+    /// The rows that the user is looking at: those most recently
+    /// rendered, or the bottom of the screen if we haven't rendered yet
+    fn visible_rows(&self) -> Range<StableRowIndex> {
+        self.last_requested.clone().unwrap_or_else(|| {
+            let top = self.dimensions.physical_top;
+            top..top + self.dimensions.viewport_rows as StableRowIndex
+        })
+    }
+
+    /// Returns true if none of the visible rows are missing or waiting
+    /// to be fetched.  Rows that we gave up fetching count as ready,
+    /// as nothing more is coming for them.
+    fn viewport_ready(&self) -> bool {
+        self.visible_rows()
+            .all(|stable_row| match self.lines.peek(&stable_row) {
+                Some(LineEntry::Line(_))
+                | Some(LineEntry::Dirty(_))
+                | Some(LineEntry::FetchError { .. }) => true,
+                Some(LineEntry::Fetching(_))
+                | Some(LineEntry::DirtyAndFetching(..))
+                | Some(LineEntry::Stale(_))
+                | None => false,
+            })
+    }
+
+    fn make_all_stale(&mut self) {
+        let mut lines = LruCache::new(self.lines.cap());
+        while let Some((stable_row, entry)) = self.lines.pop_lru() {
+            let entry = match entry {
+                LineEntry::Dirty(old) | LineEntry::Stale(old) | LineEntry::Line(old) => {
+                    LineEntry::Stale(old)
+                }
+                // Give rows that we gave up on another chance
+                LineEntry::FetchError { .. } => continue,
+                entry => entry,
+            };
+            lines.put(stable_row, entry);
+        }
+        self.lines = lines;
+        self.fetch.reset();
+    }
+
+    /// Arrange for a row to be rendered again without fetching it,
+    /// which is possible if its content is unchanged and we hold an
+    /// up to date copy of it, or are already fetching it.
+    /// Returns false if the row needs to be fetched.
+    fn repaint_without_fetch(&mut self, stable_row: StableRowIndex) -> bool {
+        match self.lines.pop(&stable_row) {
+            Some(LineEntry::Line(line)) | Some(LineEntry::Dirty(line)) => {
+                self.put_entry(stable_row, LineEntry::Dirty(line));
+                true
+            }
+            Some(entry @ LineEntry::Fetching(_))
+            | Some(entry @ LineEntry::DirtyAndFetching(..))
+            | Some(entry @ LineEntry::FetchError { .. }) => {
+                self.put_entry(stable_row, entry);
+                true
+            }
+            Some(entry @ LineEntry::Stale(_)) => {
+                self.put_entry(stable_row, entry);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Mark the cached lines as dirty so that they are all rendered
+    /// again, without fetching them
+    fn make_all_dirty(&mut self) {
+        let mut lines = LruCache::new(self.lines.cap());
+        while let Some((stable_row, entry)) = self.lines.pop_lru() {
+            let entry = match entry {
+                LineEntry::Line(line) => LineEntry::Dirty(line),
+                entry => entry,
+            };
+            lines.put(stable_row, entry);
+        }
+        self.lines = lines;
+    }
+
+    /// Update our dimensions to match a resize of the tab.
+    /// Returns true if the dimensions changed.
+    fn apply_resize(&mut self, cols: usize, rows: usize) -> bool {
+        if self.dimensions.cols == cols && self.dimensions.viewport_rows == rows {
+            return false;
+        }
+        let cols_changed = self.dimensions.cols != cols;
+        self.dimensions.cols = cols;
+        self.dimensions.viewport_rows = rows;
+
+        if cols_changed {
+            // The server will rewrap the lines, so our cached copies are
+            // no longer accurate.  We keep showing them until the fresh
+            // lines arrive, rather than flashing a blank screen.
+            self.make_all_stale();
+        }
+        // A change in the number of rows leaves the cached lines valid;
+        // whatever the server changes will show up on the next poll.
+        self.reset_poll_interval();
+        true
+    }
+
+    /// If the viewport has moved to rows that don't overlap those of
+    /// the prior request, such as when the user scrolls quickly, then
+    /// the fetches for the rows that we have left behind are obsolete.
+    /// We revert those rows so that their results are discarded when
+    /// they arrive, and so that they aren't sent with the fetch for the
+    /// new viewport.  The server still answers the obsolete requests.
+    /// Returns the number of rows that were abandoned.
+    fn abandon_obsolete_fetches(&mut self, lines: &Range<StableRowIndex>) -> usize {
+        match &self.last_requested {
+            Some(prior) if prior.end <= lines.start || lines.end <= prior.start => {}
+            _ => return 0,
+        }
+
+        let cursor_row = self.cursor_position.y;
+        let abandoned: Vec<StableRowIndex> = self
+            .lines
+            .iter()
+            .filter(|(stable_row, entry)| {
+                entry.is_pinned() && !lines.contains(stable_row) && **stable_row != cursor_row
+            })
+            .map(|(stable_row, _)| *stable_row)
+            .collect();
+        if abandoned.is_empty() {
+            return 0;
+        }
+
+        self.fetch.generation += 1;
+        for stable_row in &abandoned {
+            match self.lines.pop(stable_row) {
+                Some(LineEntry::DirtyAndFetching(line, _)) => {
+                    self.put_entry(*stable_row, LineEntry::Stale(line));
+                }
+                // Leave it popped so that it will be fetched again on demand
+                Some(LineEntry::Fetching(_)) | None => {}
+                Some(entry) => self.put_entry(*stable_row, entry),
+            }
+        }
+        log::trace!(
+            "viewport moved to {:?}; abandoned fetches for {} rows (generation {})",
+            lines,
+            abandoned.len(),
+            self.fetch.generation
+        );
+        abandoned.len()
+    }
+
+    fn make_stale(&mut self, stable_row: StableRowIndex) {
+        match self.lines.pop(&stable_row) {
+            Some(LineEntry::Dirty(old))
+            | Some(LineEntry::Stale(old))
+            | Some(LineEntry::Line(old))
+            | Some(LineEntry::DirtyAndFetching(old, _)) => {
+                self.put_entry(stable_row, LineEntry::Stale(old));
+            }
+            Some(entry @ LineEntry::FetchError { .. }) => {
+                self.put_entry(stable_row, entry);
+            }
+            Some(LineEntry::Fetching(_)) | None => {}
+        }
+    }
+
+    fn put_line(
+        &mut self,
+        stable_row: StableRowIndex,
+        mut line: Line,
+        config: &ConfigHandle,
+        fetch_start: Option<Instant>,
+        seqno: u64,
+    ) -> bool {
+        if self.is_outdated(stable_row, seqno, fetch_start) {
+            return false;
+        }
+        clamp_line_width(&mut line, self.dimensions.cols);
+        // Replace any implicit links that the server found using its
+        // own rules with ours; explicit links set by the remote program
+        // are preserved and the text they cover isn't scanned.
+        line.invalidate_implicit_hyperlinks();
+        line.scan_and_create_hyperlinks(&config.hyperlink_rules);
+
+        let entry = if let Some(fetch_start) = fetch_start {
+            // If we're completing a fetch, only replace entries that were
+            // set to fetching as part of our fetch.  If they are now longer
+            // tagged that way, then someone came along after us and changed
+            // the state, so we should leave it alone
+
+            match self.lines.pop(&stable_row) {
+                Some(LineEntry::DirtyAndFetching(_, then)) | Some(LineEntry::Fetching(then))
+                    if fetch_start == then =>
+                {
+                    log::trace!(
+                        "tab_id={} stable_row={} fetch_start={:?} outcome=applied: -> Dirty",
+                        self.remote_tab_id,
+                        stable_row,
+                        fetch_start
+                    );
+                    LineEntry::Dirty(line)
+                }
+                Some(e) => {
+                    // It changed since we started: leave it alone!
+                    log::trace!(
+                        "tab_id={} stable_row={} fetch_start={:?} outcome=discarded: \
+                         {:?} changed since fetch started, so leave it be",
+                        self.remote_tab_id,
+                        stable_row,
+                        fetch_start,
+                        e.kind()
+                    );
+                    self.put_entry(stable_row, e);
+                    return false;
+                }
+                None => {
+                    log::trace!(
+                        "tab_id={} stable_row={} fetch_start={:?} outcome=discarded: \
+                         no longer cached",
+                        self.remote_tab_id,
+                        stable_row,
+                        fetch_start
+                    );
+                    return false;
+                }
+            }
+        } else {
+            // If the line matches what we've already rendered for the row,
+            // then there is no need to repaint it.  A Dirty row stays dirty
+            // because it has yet to be rendered.
+            match self.lines.pop(&stable_row) {
+                Some(LineEntry::Line(prior))
+                | Some(LineEntry::Stale(prior))
+                | Some(LineEntry::DirtyAndFetching(prior, _))
+                    if prior == line =>
+                {
+                    LineEntry::Line(line)
+                }
+                _ => LineEntry::Dirty(line),
+            }
+        };
+        // Now that the line has been accepted, whatever version of the
+        // row we had is being replaced
+        self.fetch.clear_backoff(stable_row);
+        self.line_seqnos.insert(stable_row, seqno);
+        self.line_versions.remove(&stable_row);
+        self.dirty_columns.remove(&stable_row);
+        self.put_entry(stable_row, entry);
+        true
+    }
+
+    /// Returns true if we already hold a newer copy of the row than the
+    /// one with the specified sequence number.  If the older copy is
+    /// the result of our fetch, then the fetch is treated as complete.
+    fn is_outdated(
+        &mut self,
+        stable_row: StableRowIndex,
+        seqno: u64,
+        fetch_start: Option<Instant>,
+    ) -> bool {
+        match self.line_seqnos.get(&stable_row) {
+            Some(prior) if seqno < *prior => {
+                log::trace!(
+                    "tab_id={} stable_row={} fetch_start={:?} outcome=outdated: \
+                     seqno {} is older than {}; ignoring it",
+                    self.remote_tab_id,
+                    stable_row,
+                    fetch_start,
+                    seqno,
+                    prior
+                );
+            }
+            _ => return false,
+        }
+        if let Some(fetch_start) = fetch_start {
+            match self.lines.pop(&stable_row) {
+                Some(LineEntry::DirtyAndFetching(line, then)) if then == fetch_start => {
+                    self.put_entry(stable_row, LineEntry::Dirty(line));
+                }
+                // Leave it popped so that it will be fetched again
+                Some(LineEntry::Fetching(then)) if then == fetch_start => {}
+                Some(entry) => self.put_entry(stable_row, entry),
+                None => {}
+            }
+        }
+        true
+    }
+
+    /// Record that a patch changed `columns` of a row.  If the row
+    /// hasn't been rendered since an earlier patch then the columns of
+    /// both must be repainted.
+    fn note_dirty_columns(
+        &mut self,
+        stable_row: StableRowIndex,
+        prior: Option<Range<usize>>,
+        columns: Range<usize>,
+    ) {
+        if !self.cell_granular_updates {
+            return;
+        }
+        let columns = match prior {
+            Some(prior) => prior.start.min(columns.start)..prior.end.max(columns.end),
+            None => columns,
+        };
+        self.dirty_columns.insert(stable_row, columns);
+    }
+
+    /// Returns the columns of a dirty row that need to be repainted,
+    /// or None if the whole row should be
+    fn dirty_columns(&self, stable_row: StableRowIndex) -> Option<Range<usize>> {
+        match self.lines.peek(&stable_row) {
+            Some(LineEntry::Dirty(_)) => self.dirty_columns.get(&stable_row).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Called when `lines` are displayed.  The first time, those rows
+    /// become the ones that must arrive for the first paint.
+    fn arm_first_paint(&mut self, lines: &Range<StableRowIndex>) {
+        if let FirstPaint::Unarmed = self.first_paint {
+            let mut pending = RangeSet::new();
+            pending.add_range(lines.clone());
+            self.first_paint = FirstPaint::Pending(pending);
+        }
+    }
+
+    /// Removes the rows that have arrived from those needed for the
+    /// first paint.  Rows that we gave up fetching count as arrived,
+    /// as nothing more is coming for them.  Once none remain, sends
+    /// MuxNotification::TabFirstPaint; this happens only once for the
+    /// life of the tab.  Returns true if it was sent.
+    fn check_first_paint(&mut self) -> bool {
+        let pending = match &self.first_paint {
+            FirstPaint::Pending(pending) => pending,
+            _ => return false,
+        };
+        let mut remaining = RangeSet::new();
+        for stable_row in pending.iter().flat_map(|r| r.clone()) {
+            match self.lines.peek(&stable_row) {
+                Some(LineEntry::Line(_))
+                | Some(LineEntry::Dirty(_))
+                | Some(LineEntry::FetchError { .. }) => {}
+                _ => remaining.add(stable_row),
+            }
+        }
+        if !remaining.is_empty() {
+            self.first_paint = FirstPaint::Pending(remaining);
+            return false;
+        }
+        log::trace!("tab_id={} first paint", self.remote_tab_id);
+        self.first_paint = FirstPaint::Done;
+        notify_mux(crate::mux::MuxNotification::TabFirstPaint(
+            self.local_tab_id,
+        ));
+        true
+    }
+
+    /// Returns a row for get_lines, along with its status, adding it
+    /// to `to_fetch` if it needs to be fetched.
+    /// While the cursor's row is being fetched, the content that we
+    /// last returned for it is shown as stale in place of a blank
+    /// placeholder, so that the cursor stays in context.
+    fn read_line(
+        &mut self,
+        idx: StableRowIndex,
+        now: Instant,
+        to_fetch: &mut RangeSet<StableRowIndex>,
+    ) -> (Line, LineStatus) {
+        let item = self.read_entry(idx, now, to_fetch);
+        if idx != self.cursor_position.y {
+            return item;
+        }
+        if item.1 != LineStatus::Placeholder {
+            self.cursor_line = Some((idx, item.0.clone()));
+            return item;
+        }
+        match (&self.cursor_line, self.lines.peek(&idx)) {
+            (Some((row, line)), Some(LineEntry::Fetching(_))) if *row == idx => {
+                let mut line = line.clone();
+                clamp_line_width(&mut line, self.dimensions.cols);
+                (line, LineStatus::Stale)
+            }
+            _ => item,
+        }
+    }
+
+    /// Entries whose state doesn't change are read in place: the lookup
+    /// marks them as recently used just as re-inserting them would, so
+    /// the eviction order is the same, but without the extra hashing.
+    /// Only a state transition pops and puts the entry.
+    fn read_entry(
+        &mut self,
+        idx: StableRowIndex,
+        now: Instant,
+        to_fetch: &mut RangeSet<StableRowIndex>,
+    ) -> (Line, LineStatus) {
+        let cols = self.dimensions.cols;
+        match self.lines.get(&idx) {
+            Some(LineEntry::Line(line)) => return (line.clone(), LineStatus::Current),
+            Some(LineEntry::DirtyAndFetching(line, _)) => {
+                // This may predate a resize
+                let mut line = line.clone();
+                clamp_line_width(&mut line, cols);
+                return (line, LineStatus::Stale);
+            }
+            Some(LineEntry::Fetching(_)) => {
+                return (
+                    placeholder_line(cols, self.placeholder_fill.as_ref()),
+                    LineStatus::Placeholder,
+                )
+            }
+            Some(LineEntry::FetchError { .. }) => {
+                return (fetch_error_line(cols), LineStatus::Placeholder)
+            }
+            Some(LineEntry::Dirty(_)) | Some(LineEntry::Stale(_)) | None => {}
+        }
+
+        let (item, entry) = match self.lines.pop(&idx) {
+            Some(LineEntry::Dirty(line)) => {
+                // Clear the dirty status as part of this retrieval
+                self.dirty_columns.remove(&idx);
+                ((line.clone(), LineStatus::Current), LineEntry::Line(line))
+            }
+            Some(LineEntry::Stale(line)) => {
+                // This may predate a resize
+                let mut shown = line.clone();
+                clamp_line_width(&mut shown, cols);
+                let item = (shown, LineStatus::Stale);
+                if !self.fetch_allowed(idx, now) {
+                    (item, LineEntry::Stale(line))
+                } else {
+                    to_fetch.add(idx);
+                    (item, LineEntry::DirtyAndFetching(line, now))
+                }
+            }
+            None => {
+                let item = (
+                    placeholder_line(cols, self.placeholder_fill.as_ref()),
+                    LineStatus::Placeholder,
+                );
+                if !self.fetch_allowed(idx, now) {
+                    return item;
+                }
+                to_fetch.add(idx);
+                (item, LineEntry::Fetching(now))
+            }
+            Some(entry) => unreachable!("{:?} entries are read in place", entry.kind()),
+        };
+        self.put_entry(idx, entry);
+        item
+    }
+
+    /// Returns the rows in `to_fetch` for which we hold a line from a
+    /// prior GetLinesDelta, along with its version
+    fn known_line_versions(
+        &self,
+        to_fetch: &RangeSet<StableRowIndex>,
+    ) -> Vec<(StableRowIndex, u64)> {
+        let mut known = vec![];
+        for r in to_fetch.iter() {
+            for stable_row in r.clone() {
+                if let Some(LineEntry::DirtyAndFetching(..)) = self.lines.peek(&stable_row) {
+                    if let Some(version) = self.line_versions.get(&stable_row) {
+                        known.push((stable_row, *version));
+                    }
+                }
+            }
+        }
+        known
+    }
+
+    /// Apply the lines from a GetLinesDelta request that was issued
+    /// at `fetch_start`
+    fn apply_line_deltas(
+        &mut self,
+        response: GetLinesDeltaResponse,
+        config: &ConfigHandle,
+        fetch_start: Instant,
+    ) {
+        let seqno = response.seqno;
+        let lines = response.lines.lines();
+        for ((stable_row, line), delta) in lines.into_iter().zip(response.deltas) {
+            let (line, version) = match delta {
+                LineDelta::Full { version } => (line, version),
+                LineDelta::Patch {
+                    base,
+                    version,
+                    start,
+                    width,
+                } => {
+                    let mut columns = start..start + line.cells().len();
+                    let patched = match self.lines.peek(&stable_row) {
+                        Some(LineEntry::DirtyAndFetching(old, then)) if *then == fetch_start => {
+                            if self.line_versions.get(&stable_row) == Some(&base) {
+                                let old_width = old.cells().len();
+                                if old_width != width {
+                                    // The cells past the end of the
+                                    // shorter of the two rows changed too
+                                    columns.end = columns.end.max(old_width.max(width));
+                                }
+                                apply_line_patch(old, start, width, &line)
+                            } else {
+                                None
+                            }
+                        }
+                        // It changed since we started, so put_line
+                        // would ignore it anyway
+                        _ => continue,
+                    };
+                    match patched {
+                        Some(line) => {
+                            let prior = self.dirty_columns.get(&stable_row).cloned();
+                            let truncated = line.cells().len() > self.dimensions.cols;
+                            if self.put_line(stable_row, line, config, Some(fetch_start), seqno)
+                                && !truncated
+                            {
+                                self.line_versions.insert(stable_row, version);
+                                self.note_dirty_columns(stable_row, prior, columns);
+                            }
+                            continue;
+                        }
+                        None => {
+                            // We don't have the line that the patch is
+                            // based on; fetch the whole line when it is
+                            // next needed
+                            log::trace!("row {} cannot apply patch to {}", stable_row, base);
+                            self.line_versions.remove(&stable_row);
+                            self.make_stale(stable_row);
+                            continue;
+                        }
+                    }
+                }
+            };
+            // If the line was made for another width then our copy is
+            // truncated, so it can't serve as the base of a patch
+            let truncated = line.cells().len() > self.dimensions.cols;
+            if self.put_line(stable_row, line, config, Some(fetch_start), seqno) && !truncated {
+                self.line_versions.insert(stable_row, version);
+            }
+        }
+    }
+
+    /// Accumulate rows that need to be fetched.  Rather than issuing
+    /// a GetLines for each caller, the rows are merged together and
+    /// flushed as a single request on the next event loop tick.
+    /// Rows queued for FetchReason::Display are always sent, while
+    /// the others are subject to the fetch rate limit.
+    /// The trace messages for a fetch carry `tab_id`, `rows`,
+    /// `fetch_start` and `outcome` fields so that its progress can be
+    /// followed from here through to apply_lines.
+    fn schedule_fetch_lines(&mut self, to_fetch: RangeSet<StableRowIndex>, reason: FetchReason) {
+        if to_fetch.is_empty() {
+            return;
+        }
+
+        let need_flush = !self.fetch.has_queued();
+        log::trace!(
+            "tab_id={} rows={:?} reason={:?} outcome=queued",
+            self.remote_tab_id,
+            to_fetch,
+            reason
+        );
+        self.fetch.queue(&to_fetch, reason);
+
+        if need_flush {
+            let local_tab_id = self.local_tab_id;
+            promise::spawn::spawn(async move {
+                let mux = match completion_mux("fetch flush") {
+                    Some(mux) => mux,
+                    None => return Ok(()),
+                };
+                let tab = mux
+                    .get_tab(local_tab_id)
+                    .ok_or_else(|| anyhow!("no such tab {}", local_tab_id))?;
+                if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                    let renderable = client_tab.renderable.borrow_mut();
+                    let mut inner = renderable.inner.borrow_mut();
+                    inner.flush_pending_fetches();
+                }
+                Ok::<(), anyhow::Error>(())
+            });
+        }
+    }
+
+    /// Issue a single GetLines request for all of the rows accumulated
+    /// by schedule_fetch_lines.  The rate limiter is applied once to
+    /// the background rows of the merged request; if we're over the
+    /// limit then those rows are made stale and will be fetched again
+    /// on demand.  The rows being displayed are sent regardless.
+    /// If we already have as many requests in flight as we allow then
+    /// the rows remain queued until one of those requests completes.
+    fn flush_pending_fetches(&mut self) {
+        if !self.fetch.has_queued() {
+            return;
+        }
+        if self.fetch.at_capacity() {
+            log::trace!(
+                "tab_id={} rows={:?} background={:?} outcome=waiting: {} fetches in flight",
+                self.remote_tab_id,
+                self.fetch.pending,
+                self.fetch.pending_background,
+                self.fetch.in_flight
+            );
+            return;
+        }
+        let mut pending = std::mem::replace(&mut self.fetch.pending, RangeSet::new());
+        let background = std::mem::replace(&mut self.fetch.pending_background, RangeSet::new());
+
+        let cursor_row = self.cursor_position.y;
+        if !background.is_empty() {
+            if self.fetch.admit() {
+                pending.add_set(&background);
+            } else {
+                log::trace!(
+                    "tab_id={} rows={:?} outcome=throttled",
+                    self.remote_tab_id,
+                    background
+                );
+                for r in background.iter() {
+                    for stable_row in r.clone() {
+                        if stable_row == cursor_row {
+                            // The cursor row is where the user is typing;
+                            // leaving it blank is too disruptive, so fetch
+                            // it regardless
+                            pending.add(stable_row);
+                        } else if !pending.contains(stable_row) {
+                            self.make_stale(stable_row);
+                        }
+                    }
+                }
+                if pending.is_empty() {
+                    return;
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let to_fetch = self.claim_pending_rows(&pending, now);
+        if to_fetch.is_empty() {
+            return;
+        }
+
+        let local_tab_id = self.local_tab_id;
+        log::trace!(
+            "tab_id={} rows={:?} fetch_start={:?} outcome=requested",
+            self.remote_tab_id,
+            to_fetch,
+            now
+        );
+
+        // The visible rows are requested first, on their own, so that
+        // they aren't held up behind the margins around them.  The
+        // margins follow once the visible rows have arrived, unless the
+        // viewport has since jumped elsewhere.
+        let (visible, margin) = split_visible(&to_fetch, &self.visible_rows(), cursor_row);
+        let client = Arc::clone(&self.client);
+        let remote_tab_id = self.remote_tab_id;
+        let generation = self.fetch.generation;
+        self.fetch.issued += 1;
+        self.fetch.in_flight += 1;
+        let (visible_known, margin_known) = if client.supports(ProtocolFeatures::LINE_DELTAS) {
+            (
+                Some(self.known_line_versions(&visible)),
+                Some(self.known_line_versions(&margin)),
+            )
+        } else {
+            (None, None)
+        };
+
+        let fetch = async move {
+            if !visible.is_empty() {
+                let result =
+                    Self::fetch_rows(&client, remote_tab_id, &visible, cursor_row, visible_known)
+                        .await;
+                Self::apply_lines(local_tab_id, result, visible, now, generation)?;
+            }
+            if !margin.is_empty()
+                && Self::margin_still_wanted(local_tab_id, &margin, now, generation)
+            {
+                let result =
+                    Self::fetch_rows(&client, remote_tab_id, &margin, cursor_row, margin_known)
+                        .await;
+                Self::apply_lines(local_tab_id, result, margin, now, generation)?;
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        promise::spawn::spawn(async move {
+            let result = fetch.await;
+            Self::fetch_finished(local_tab_id);
+            result
+        });
+    }
+
+    /// Re-tag the rows that are still waiting on a fetch with the
+    /// time of the merged request that starts at `now`, so that
+    /// apply_lines can match the results against it, returning the
+    /// rows to request.  Rows that were resolved in the meantime
+    /// (eg: via bonus_lines) are not requested, and nor are those that
+    /// an earlier fetch is still bringing in: they are tagged with
+    /// that fetch instead, so that its results are applied to them.
+    fn claim_pending_rows(
+        &mut self,
+        pending: &RangeSet<StableRowIndex>,
+        now: Instant,
+    ) -> RangeSet<StableRowIndex> {
+        let mut to_fetch = RangeSet::new();
+        for r in pending.iter() {
+            for stable_row in r.clone() {
+                let in_flight = self.fetch.in_flight_since(stable_row);
+                let fetch_start = in_flight.unwrap_or(now);
+                let entry = match self.lines.pop(&stable_row) {
+                    Some(LineEntry::Fetching(_)) => LineEntry::Fetching(fetch_start),
+                    Some(LineEntry::DirtyAndFetching(line, _)) => {
+                        LineEntry::DirtyAndFetching(line, fetch_start)
+                    }
+                    Some(entry) => {
+                        self.put_entry(stable_row, entry);
+                        continue;
+                    }
+                    None => continue,
+                };
+                match in_flight {
+                    Some(then) => log::trace!(
+                        "tab_id={} stable_row={} fetch_start={:?} outcome=joined: \
+                         already requested",
+                        self.remote_tab_id,
+                        stable_row,
+                        then
+                    ),
+                    None => {
+                        to_fetch.add(stable_row);
+                        self.fetch.mark_in_flight(stable_row, now);
+                    }
+                }
+                self.put_entry(stable_row, entry);
+            }
+        }
+        to_fetch
+    }
+
+    /// Release the slot held by a completed fetch, and send the rows
+    /// that were queued while we were at our limit
+    fn fetch_finished(local_tab_id: TabId) {
+        let mux = match completion_mux("fetch") {
+            Some(mux) => mux,
+            None => return,
+        };
+        if let Some(tab) = mux.get_tab(local_tab_id) {
+            if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                let renderable = client_tab.renderable.borrow_mut();
+                let mut inner = renderable.inner.borrow_mut();
+                inner.fetch.in_flight = inner.fetch.in_flight.saturating_sub(1);
+                inner.flush_pending_fetches();
+            }
+        }
+    }
+
+    async fn fetch_rows(
+        client: &Arc<ClientInner>,
+        remote_tab_id: TabId,
+        to_fetch: &RangeSet<StableRowIndex>,
+        cursor_row: StableRowIndex,
+        known: Option<Vec<(StableRowIndex, u64)>>,
+    ) -> anyhow::Result<FetchedLines> {
+        let lines = cursor_first(to_fetch, cursor_row);
+        match known {
+            Some(known) => client
+                .client
+                .get_lines_delta(GetLinesDelta {
+                    tab_id: remote_tab_id,
+                    lines,
+                    known,
+                })
+                .await
+                .map(FetchedLines::Delta),
+            None => client
+                .client
+                .get_lines(GetLines {
+                    tab_id: remote_tab_id,
+                    lines,
+                    anchor: None,
+                })
+                .await
+                .map(FetchedLines::Full),
+        }
+    }
+
+    /// Called once the visible rows of a fetch have arrived to decide
+    /// whether to go on to fetch its margins.  If the viewport jumped
+    /// in the meantime then the margins are no longer of interest, and
+    /// the rows that are still waiting on them are abandoned so that
+    /// they will be fetched again if they are displayed.
+    fn margin_still_wanted(
+        local_tab_id: TabId,
+        margin: &RangeSet<StableRowIndex>,
+        fetch_start: Instant,
+        generation: u64,
+    ) -> bool {
+        let mux = match completion_mux("visible row fetch") {
+            Some(mux) => mux,
+            None => return false,
+        };
+        let tab = match mux.get_tab(local_tab_id) {
+            Some(tab) => tab,
+            None => return false,
+        };
+        let client_tab = match tab.downcast_ref::<ClientTab>() {
+            Some(client_tab) => client_tab,
+            None => return false,
+        };
+        let renderable = client_tab.renderable.borrow_mut();
+        let mut inner = renderable.inner.borrow_mut();
+        if inner.fetch.generation != generation {
+            inner.abandon_fetch(margin, fetch_start);
+            return false;
+        }
+        inner.fetch.issued += 1;
+        true
+    }
+
+    /// Revert the rows that are waiting on the fetch that started at
+    /// `fetch_start`, as we're not going to send it
+    fn abandon_fetch(&mut self, rows: &RangeSet<StableRowIndex>, fetch_start: Instant) {
+        self.fetch.release_in_flight_rows(rows, fetch_start);
+        for r in rows.iter() {
+            for stable_row in r.clone() {
+                match self.lines.pop(&stable_row) {
+                    Some(LineEntry::DirtyAndFetching(line, then)) if then == fetch_start => {
+                        self.put_entry(stable_row, LineEntry::Stale(line));
+                    }
+                    // Leave it popped so that it will be fetched again on demand
+                    Some(LineEntry::Fetching(then)) if then == fetch_start => {}
+                    Some(entry) => self.put_entry(stable_row, entry),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    fn apply_lines(
+        local_tab_id: TabId,
+        result: anyhow::Result<FetchedLines>,
+        to_fetch: RangeSet<StableRowIndex>,
+        now: Instant,
+        generation: u64,
+    ) -> anyhow::Result<()> {
+        let mux = match completion_mux("line fetch") {
+            Some(mux) => mux,
+            None => return Ok(()),
+        };
+        let tab = mux
+            .get_tab(local_tab_id)
+            .ok_or_else(|| anyhow!("no such tab {}", local_tab_id))?;
+        if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+            let renderable = client_tab.renderable.borrow_mut();
+            let mut inner = renderable.inner.borrow_mut();
+            inner.fetch.completed += 1;
+            inner.fetch.release_in_flight_rows(&to_fetch, now);
+            if generation != inner.fetch.generation {
+                // The rows that we abandoned are no longer tagged with
+                // this fetch, so put_line will discard their results;
+                // any that are still tagged are applied as usual.
+                log::trace!(
+                    "tab_id={} rows={:?} fetch_start={:?} outcome=obsolete: \
+                     generation {} is now {}",
+                    inner.remote_tab_id,
+                    to_fetch,
+                    now,
+                    generation,
+                    inner.fetch.generation
+                );
+            }
+
+            match result {
+                Ok(FetchedLines::Full(result)) => {
+                    let config = configuration();
+                    let seqno = result.seqno;
+                    let lines = result.lines.lines();
+
+                    log::trace!(
+                        "tab_id={} rows={:?} fetch_start={:?} outcome=received",
+                        inner.remote_tab_id,
+                        to_fetch,
+                        now
+                    );
+                    for (stable_row, line) in lines.into_iter() {
+                        inner.put_line(stable_row, line, &config, Some(now), seqno);
+                    }
+                }
+                Ok(FetchedLines::Delta(result)) => {
+                    log::trace!(
+                        "tab_id={} rows={:?} fetch_start={:?} outcome=received: delta",
+                        inner.remote_tab_id,
+                        to_fetch,
+                        now
+                    );
+                    inner.apply_line_deltas(result, &configuration(), now);
+                }
+                Err(err) => {
+                    log::error!(
+                        "tab_id={} rows={:?} fetch_start={:?} outcome=failed: {}",
+                        inner.remote_tab_id,
+                        to_fetch,
+                        now,
+                        err
+                    );
+                    let failed_at = Instant::now();
+                    for r in to_fetch.iter() {
+                        for stable_row in r.clone() {
+                            let entry = match inner.lines.pop(&stable_row) {
+                                Some(LineEntry::Fetching(then)) if then == now => {
+                                    match inner.fetch.record_failure(stable_row, failed_at) {
+                                        Some(attempts) => LineEntry::FetchError {
+                                            since: failed_at,
+                                            attempts,
+                                        },
+                                        // leave it popped; it will be retried
+                                        // once the backoff has elapsed
+                                        None => continue,
+                                    }
+                                }
+                                Some(LineEntry::DirtyAndFetching(line, then)) if then == now => {
+                                    match inner.fetch.record_failure(stable_row, failed_at) {
+                                        Some(attempts) => LineEntry::FetchError {
+                                            since: failed_at,
+                                            attempts,
+                                        },
+                                        // keep showing what we have, but
+                                        // retry once the backoff has elapsed
+                                        None => LineEntry::Stale(line),
+                                    }
+                                }
+                                Some(entry) => entry,
+                                None => continue,
+                            };
+                            inner.put_entry(stable_row, entry);
+                        }
+                    }
+                }
+            }
+            inner.check_first_paint();
+        }
+        Ok(())
+    }
+
+    /// If the requested range has advanced in a consistent direction
+    /// relative to the prior request, returns the range of rows that
+    /// we should speculatively fetch ahead of it in that direction.
+    fn prefetch_range(&self, lines: &Range<StableRowIndex>) -> Option<Range<StableRowIndex>> {
+        let prior = self.last_requested.as_ref()?;
+        let rows = self.prefetch_rows.unwrap_or(self.dimensions.viewport_rows) as StableRowIndex;
+        if rows == 0 {
+            return None;
+        }
+
+        let top = self.dimensions.scrollback_top;
+        let bottom = self.dimensions.physical_top + self.dimensions.viewport_rows as StableRowIndex;
+
+        let range = if lines.start > prior.start && lines.end > prior.end {
+            // Scrolling down towards the bottom of the screen
+            lines.end..(lines.end + rows).min(bottom)
+        } else if lines.start < prior.start && lines.end < prior.end {
+            // Scrolling up into the scrollback
+            (lines.start - rows).max(top)..lines.start
+        } else {
+            return None;
+        };
+
+        if range.start < range.end {
+            Some(range)
+        } else {
+            None
+        }
+    }
+
+    /// Schedule a fetch for the rows in `region` that we don't already
+    /// have.  The region is clipped to the scrollback and to the size
+    /// of the line cache, as rows beyond that would just be evicted.
+    /// The rows are subject to the fetch rate limit when they are sent.
+    fn warm_region(&mut self, region: Range<StableRowIndex>) {
+        let to_fetch = self.mark_region_for_fetch(region, Instant::now());
+        log::trace!("warming {:?}", to_fetch);
+        self.schedule_fetch_lines(to_fetch, FetchReason::Background);
+    }
+
+    /// Transition the rows in `region` that are absent or stale to
+    /// the fetching state, returning the set of rows that need to
+    /// be fetched.  Rows that are cached or in flight are skipped.
+    fn mark_region_for_fetch(
+        &mut self,
+        region: Range<StableRowIndex>,
+        now: Instant,
+    ) -> RangeSet<StableRowIndex> {
+        let top = self.dimensions.scrollback_top;
+        let bottom = self.dimensions.physical_top + self.dimensions.viewport_rows as StableRowIndex;
+        let start = region.start.max(top);
+        let end = region
+            .end
+            .min(bottom)
+            .min(start.saturating_add(self.lines.cap() as StableRowIndex));
+
+        let mut to_fetch = RangeSet::new();
+        for idx in start..end {
+            if !self.fetch_allowed(idx, now) {
+                continue;
+            }
+            let entry = match self.lines.pop(&idx) {
+                Some(LineEntry::Stale(line)) => LineEntry::DirtyAndFetching(line, now),
+                Some(entry) => {
+                    self.put_entry(idx, entry);
+                    continue;
+                }
+                None => LineEntry::Fetching(now),
+            };
+            to_fetch.add(idx);
+            self.put_entry(idx, entry);
+        }
+        to_fetch
+    }
+
+    fn refresh(&mut self) {
+        self.make_all_stale();
+        self.reset_poll_interval();
+        if let Err(err) = self.poll() {
+            log::error!("remote tab {} refresh failed: {}", self.remote_tab_id, err);
+        }
+    }
+
+    /// Arrange for the next call to poll to hit the server immediately
+    fn reset_poll_interval(&mut self) {
+        self.poll_interval = self.base_poll_interval;
+        let now = Instant::now();
+        self.last_poll = now.checked_sub(self.max_poll_interval).unwrap_or(now);
+    }
+
+    /// Reset the poll interval following local input, unless a poll is
+    /// already in flight, in which case it will pick up the changes
+    fn reset_poll_for_input(&mut self) {
+        if !self.poll_in_progress.load(Ordering::SeqCst) {
+            self.reset_poll_interval();
+        }
+    }
+
+    fn poll_succeeded(&mut self) {
+        if self.remote_exited() {
+            // A poll that was in flight when the tab exited
+            return;
+        }
+        self.last_success = Instant::now();
+        if let ReconnectState::Reconnecting { attempt, .. } = self.reconnect {
+            log::info!(
+                "remote tab {} reconnected after {} attempts",
+                self.remote_tab_id,
+                attempt
+            );
+            self.reconnect = ReconnectState::Connected;
+            // We don't know what we missed while we were disconnected,
+            // so refetch everything
+            self.make_all_stale();
+            self.reset_poll_interval();
+        }
+        self.set_dead(None);
+    }
+
+    /// Called when polling the server failed.  If auto-reconnect is
+    /// enabled we'll schedule another attempt with an exponentially
+    /// increasing delay, otherwise (or once we've exhausted our attempts)
+    /// the tab is marked as dead.
+    fn poll_failed(&mut self, err: &anyhow::Error) {
+        if self.remote_exited() {
+            return;
+        }
+        if !self.auto_reconnect {
+            self.set_dead(Some(DeadReason::from_poll_error(err)));
+            return;
+        }
+
+        let attempt = match self.reconnect {
+            ReconnectState::Connected => 1,
+            ReconnectState::Reconnecting { attempt, .. } => attempt + 1,
+        };
+        if attempt > self.reconnect_max_attempts {
+            log::error!(
+                "remote tab {} poll failed: {}; giving up after {} reconnect attempts",
+                self.remote_tab_id,
+                err,
+                self.reconnect_max_attempts
+            );
+            self.set_dead(Some(DeadReason::ConnectionLost));
+            return;
+        }
+
+        let delay = BASE_RECONNECT_INTERVAL
+            .checked_mul(1 << (attempt - 1).min(16))
+            .unwrap_or(MAX_RECONNECT_INTERVAL)
+            .min(MAX_RECONNECT_INTERVAL);
+        log::error!(
+            "remote tab {} poll failed: {}; reconnect attempt {} in {:?}",
+            self.remote_tab_id,
+            err,
+            attempt,
+            delay
+        );
+        self.reconnect = ReconnectState::Reconnecting {
+            attempt,
+            next_attempt: Instant::now() + delay,
+        };
+    }
+
+    fn poll(&mut self) -> anyhow::Result<()> {
+        // This comes first, as a poll that never completes is one of
+        // the ways in which the server can go quiet
+        if self.check_idle_timeout(Instant::now()) {
+            return Ok(());
+        }
+
+        if self.poll_in_progress.load(Ordering::SeqCst) {
+            // We have a poll in progress
+            return Ok(());
+        }
+
+        if self.remote_exited() {
+            return Ok(());
+        }
+
+        if !self.focused.load(Ordering::SeqCst) {
+            // The tab isn't visible, so there's no sense in fetching
+            // changes; we'll poll again when it is brought to the front
+            return Ok(());
+        }
+
+        match self.reconnect {
+            ReconnectState::Reconnecting { next_attempt, .. } => {
+                // The poll doubles as our reconnection attempt
+                if Instant::now() < next_attempt {
+                    return Ok(());
+                }
+                self.last_success = Instant::now();
+            }
+            ReconnectState::Connected => {
+                let interval = self.poll_interval;
+                let interval = self
+                    .poll_jitter
+                    .apply((interval + interval).min(self.max_poll_interval))
+                    .max(self.base_poll_interval)
+                    .min(self.max_poll_interval);
+                self.poll_interval = interval;
+
+                let last = self.last_poll;
+                if last.elapsed() < self.poll_interval {
+                    return Ok(());
+                }
+            }
+        }
+
+        self.last_poll = Instant::now();
+        self.poll_started = self.last_poll;
+        self.poll_in_progress.store(true, Ordering::SeqCst);
+        // Other tabs on this connection that poll during this turn of
+        // the main loop will share the request
+        if self
+            .client
+            .queue_poll(self.remote_tab_id, self.local_tab_id)
+        {
+            let client = Arc::clone(&self.client);
+            promise::spawn::spawn(async move { Self::poll_pending_tabs(client).await });
+        }
+        Ok(())
+    }
+
+    /// Mark the tab as dead if we're waiting to hear from the server
+    /// and haven't done so for longer than idle_timeout.
+    /// Unfocused tabs don't poll, so they aren't expected to hear
+    /// anything.  Returns true if the tab has timed out, in which case
+    /// we stop polling it.
+    fn check_idle_timeout(&mut self, now: Instant) -> bool {
+        match self.dead {
+            Some(DeadReason::IdleTimeout) => return true,
+            // Leave it to the reconnect logic
+            Some(_) => return false,
+            None => {}
+        }
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+        if !self.focused.load(Ordering::SeqCst)
+            || now.saturating_duration_since(self.last_success) < timeout
+        {
+            return false;
+        }
+        log::error!(
+            "remote tab {}: nothing from the server for {:?}; giving up",
+            self.remote_tab_id,
+            timeout
+        );
+        self.set_dead(Some(DeadReason::IdleTimeout));
+        true
+    }
+
+    /// Discard the Line and Stale rows that haven't been displayed
+    /// within the TTL.  Rows that are being fetched, or that are yet
+    /// to be rendered, are retained, as are the most recently
+    /// requested rows and the cursor row.  Rows that we have never
+    /// displayed, such as prefetched rows, start their TTL the first
+    /// time that we sweep them.  Returns the number of rows discarded.
+    fn sweep_idle_lines(&mut self, now: Instant) -> usize {
+        let ttl = match self.line_cache_ttl {
+            Some(ttl) => ttl,
+            None => return 0,
+        };
+        if now.saturating_duration_since(self.last_line_sweep) < LINE_SWEEP_INTERVAL {
+            return 0;
+        }
+        self.last_line_sweep = now;
+
+        let visible = self.last_requested.clone().unwrap_or(0..0);
+        let cursor_row = self.cursor_position.y;
+        let mut expired = vec![];
+        for (stable_row, entry) in self.lines.iter() {
+            match entry {
+                LineEntry::Line(_) | LineEntry::Stale(_) => {}
+                _ => continue,
+            }
+            if visible.contains(stable_row) || *stable_row == cursor_row {
+                continue;
+            }
+            let accessed = *self.line_access.entry(*stable_row).or_insert(now);
+            if now.saturating_duration_since(accessed) >= ttl {
+                expired.push(*stable_row);
+            }
+        }
+
+        for stable_row in &expired {
+            self.lines.pop(stable_row);
+            self.line_versions.remove(stable_row);
+            self.line_seqnos.remove(stable_row);
+        }
+        // Also forget rows that the cache has since evicted
+        let lines = &self.lines;
+        self.line_access.retain(|row, _| lines.contains(row));
+
+        if !expired.is_empty() {
+            log::trace!(
+                "discarded {} rows idle for longer than {:?}",
+                expired.len(),
+                ttl
+            );
+        }
+        expired.len()
+    }
+
+    /// If a poll has been in progress for much longer than any poll
+    /// should take then its completion was lost, perhaps because the
+    /// future was dropped, and we'd otherwise never poll again.
+    /// Clear the in progress state so that the next poll can proceed.
+    /// Returns true if the poll was considered to be wedged.
+    fn check_poll_watchdog(&mut self, now: Instant) -> bool {
+        if !self.poll_in_progress.load(Ordering::SeqCst) {
+            return false;
+        }
+        let elapsed = now.saturating_duration_since(self.poll_started);
+        if elapsed < self.max_poll_interval * POLL_WATCHDOG_INTERVALS {
+            return false;
+        }
+        log::warn!(
+            "poll for remote tab {} has been in progress for {:?}; \
+             assuming that it was lost and retrying",
+            self.remote_tab_id,
+            elapsed
+        );
+        self.client.cancel_poll(self.remote_tab_id);
+        self.poll_in_progress.store(false, Ordering::SeqCst);
+        true
+    }
+
+    /// Request the render changes for all of the tabs queued by poll
+    /// and report the outcome to each of them
+    async fn poll_pending_tabs(client: Arc<ClientInner>) -> anyhow::Result<()> {
+        let pending = client.take_pending_polls();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let result = client
+            .client
+            .get_multi_tab_render_changes(GetMultiTabRenderChanges {
+                tab_ids: pending.keys().cloned().collect(),
+            })
+            .await;
+        if result.is_ok() {
+            client.record_round_trip(start.elapsed());
+        }
+
+        let mux = match completion_mux("poll") {
+            Some(mux) => mux,
+            None => return Ok(()),
+        };
+        for (remote_tab_id, local_tab_id) in pending {
+            let tab = match mux.get_tab(local_tab_id) {
+                Some(tab) => tab,
+                None => continue,
+            };
+            if let Some(client_tab) = tab.downcast_ref::<ClientTab>() {
+                let renderable = client_tab.renderable.borrow_mut();
+                let mut inner = renderable.inner.borrow_mut();
+
+                match &result {
+                    Ok(response) => match response
+                        .errors
+                        .iter()
+                        .find(|(tab_id, _)| *tab_id == remote_tab_id)
+                    {
+                        Some((_, err)) => inner.poll_failed(&anyhow!("{}", err)),
+                        None => inner.poll_succeeded(),
+                    },
+                    Err(err) => inner.poll_failed(err),
+                }
+                inner.poll_in_progress.store(false, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Renderable for RenderableState {
+    fn get_cursor_position(&self) -> StableCursorPosition {
+        self.inner.borrow().cursor_position
+    }
+
+    fn get_lines(&mut self, lines: Range<StableRowIndex>) -> (StableRowIndex, Vec<Line>) {
+        let (first, lines) = self.get_lines_with_status(lines);
+        (first, lines.into_iter().map(|(line, _)| line).collect())
+    }
+
+    fn get_lines_with_status(
+        &mut self,
+        lines: Range<StableRowIndex>,
+    ) -> (StableRowIndex, Vec<(Line, LineStatus)>) {
+        let mut inner = self.inner.borrow_mut();
+        let mut result = vec![];
+        let mut to_fetch = RangeSet::new();
+        let now = Instant::now();
+        inner.abandon_obsolete_fetches(&lines);
+
+        for idx in lines.clone() {
+            inner.line_access.insert(idx, now);
+            result.push(inner.read_line(idx, now, &mut to_fetch));
+        }
+        inner.arm_first_paint(&lines);
+        inner.check_first_paint();
+
+        let mut to_prefetch = RangeSet::new();
+        if let Some(prefetch) = inner.prefetch_range(&lines) {
+            // The flush checks these rows against the rate limiter
+            log::trace!("prefetch {:?} ahead of {:?}", prefetch, lines);
+            for idx in prefetch {
+                if !inner.fetch_allowed(idx, now) {
+                    continue;
+                }
+                match inner.lines.get(&idx) {
+                    Some(LineEntry::Stale(_)) | None => {}
+                    // Already held or on its way; the lookup marks
+                    // it as recently used
+                    Some(_) => continue,
+                }
+                let entry = match inner.lines.pop(&idx) {
+                    Some(LineEntry::Stale(line)) => LineEntry::DirtyAndFetching(line, now),
+                    _ => LineEntry::Fetching(now),
+                };
+                to_prefetch.add(idx);
+                inner.put_entry(idx, entry);
+            }
+        }
+        inner.last_requested.replace(lines.clone());
+        inner.last_render = now;
+
+        inner.schedule_fetch_lines(to_fetch, FetchReason::Display);
+        inner.schedule_fetch_lines(to_prefetch, FetchReason::Background);
+        (lines.start, result)
+    }
+
+    fn get_dirty_lines(&self, lines: Range<StableRowIndex>) -> RangeSet<StableRowIndex> {
+        let mut inner = self.inner.borrow_mut();
+        inner.check_config_reload();
+        inner.check_poll_watchdog(Instant::now());
+        inner.sweep_idle_lines(Instant::now());
+        if let Err(err) = inner.poll() {
+            // We allow for BrokenPromise here for now; for a TLS backed
+            // session it indicates that we'll retry.  For a local unix
+            // domain session it is terminal... but we will detect that
+            // terminal condition elsewhere
+            if let Err(err) = err.downcast::<BrokenPromise>() {
+                log::error!("remote tab poll failed: {}", err);
+                inner.poll_failed(&err);
+            }
+        }
+
+        let now = Instant::now();
+        let last_render = inner.last_render;
+        let exited = inner.remote_exited();
+        let mut result = RangeSet::new();
+        for r in lines {
+            // Rows that are backing off after a failed fetch are only
+            // reported once we're allowed to try them again; that
+            // will cause the renderer to call get_lines and retry.
+            let backing_off = inner.fetch.is_backing_off(r);
+            let retry_due = backing_off && inner.fetch_allowed(r, now);
+            match inner.lines.get(&r) {
+                Some(LineEntry::Dirty(_)) | Some(LineEntry::DirtyAndFetching(..)) => {
+                    result.add(r);
+                }
+                None | Some(LineEntry::Stale(_)) if retry_due => {
+                    result.add(r);
+                }
+                None if !backing_off && !exited => {
+                    result.add(r);
+                }
+                // Newly failed rows need rendering to show the placeholder
+                Some(LineEntry::FetchError { since, .. }) if *since >= last_render => {
+                    result.add(r);
+                }
+                _ => {}
+            }
+        }
+
+        if !result.is_empty() {
+            log::trace!("get_dirty_lines: {:?}", result);
+        }
+
+        result
+    }
+
+    fn get_dirty_columns(&self, stable_row: StableRowIndex) -> Option<Range<usize>> {
+        self.inner.borrow().dirty_columns(stable_row)
+    }
+
+    fn get_dimensions(&self) -> RenderableDimensions {
+        self.inner.borrow().dimensions
+    }
+}
+
+/// Sends key presses to a remote tab.  A key pressed while no others
+/// are pending is sent straight away to keep interactive typing
+/// responsive, but keys that arrive in a burst behind it are held
+/// until the next turn of the main loop and sent as a single
+/// SendKeyDowns request.
+struct KeyQueue {
+    client: Arc<ClientInner>,
+    remote_tab_id: TabId,
+    state: Rc<RefCell<KeyQueueState>>,
+    waiters: InputWaiters,
+}
+
+#[derive(Default)]
+struct KeyQueueState {
+    events: Vec<KeyEvent>,
+    flush_scheduled: bool,
+}
+
+impl KeyQueue {
+    fn new(client: &Arc<ClientInner>, remote_tab_id: TabId, waiters: &InputWaiters) -> Self {
+        Self {
+            client: Arc::clone(client),
+            remote_tab_id,
+            state: Rc::new(RefCell::new(KeyQueueState::default())),
+            waiters: waiters.clone(),
+        }
+    }
+
+    /// Returns the event back to the caller if it should be sent
+    /// immediately rather than queued
+    fn enqueue(&self, event: KeyEvent) -> Option<KeyEvent> {
+        let mut state = self.state.borrow_mut();
+        if state.flush_scheduled {
+            state.events.push(event);
+            None
+        } else {
+            Some(event)
+        }
+    }
+
+    fn key_down(&self, event: KeyEvent) {
+        let event = match self.enqueue(event) {
+            Some(event) => event,
+            None => return,
+        };
+
+        let client = Arc::clone(&self.client);
+        let tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            client.client.key_down(SendKeyDown { tab_id, event }).await
+        });
+
+        // Hold any keys that follow in this turn of the main loop
+        self.state.borrow_mut().flush_scheduled = true;
+        let client = Arc::clone(&self.client);
+        let state = Rc::clone(&self.state);
+        let waiters = self.waiters.clone();
+        promise::spawn::spawn(async move {
+            let events = {
+                let mut state = state.borrow_mut();
+                state.flush_scheduled = false;
+                std::mem::replace(&mut state.events, vec![])
+            };
+            waiters.wake();
+            if events.is_empty() {
+                return Ok(());
+            }
+            client
+                .client
+                .key_downs(SendKeyDowns { tab_id, events })
+                .await?;
+            Ok::<(), anyhow::Error>(())
+        });
+    }
+
+    /// Send any queued keys now.  This is called before other input
+    /// is sent to the tab, so that the server sees it all in the
+    /// order that it happened.
+    fn flush(&self) {
+        let events = std::mem::replace(&mut self.state.borrow_mut().events, vec![]);
+        if events.is_empty() {
+            return;
+        }
+        self.waiters.wake();
+        let client = Arc::clone(&self.client);
+        let tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .key_downs(SendKeyDowns { tab_id, events })
+                .await
+        });
+    }
+}
+
+/// Sends pastes to the server in bounded chunks, waiting for each
+/// chunk to be acknowledged before sending the next, so that a large
+/// paste neither stalls the connection nor floods the remote program
+struct PasteSender {
+    client: Arc<ClientInner>,
+    remote_tab_id: TabId,
+    chunk_size: usize,
+    state: Rc<RefCell<PasteState>>,
+    waiters: InputWaiters,
+}
+
+#[derive(Default)]
+struct PasteState {
+    /// The pastes waiting to be sent; the first is in progress
+    queue: VecDeque<String>,
+    /// How much of the first paste has been sent
+    offset: usize,
+    sent: usize,
+    total: usize,
+    /// Whether a task is sending the queued pastes
+    active: bool,
+}
+
+/// The progress of the pastes that are being sent to a remote tab
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasteProgress {
+    pub sent: usize,
+    pub total: usize,
+}
+
+/// Returns the end of the chunk of `text` that starts at `start`.
+/// The chunk is extended as needed to end on a char boundary.
+fn paste_chunk_end(text: &str, start: usize, chunk_size: usize) -> usize {
+    let mut end = (start + chunk_size.max(1)).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    end
+}
+
+impl PasteState {
+    /// Take the next chunk to be sent, or returns None and marks the
+    /// sender inactive if everything has been sent
+    fn next_chunk(&mut self, chunk_size: usize) -> Option<(String, PasteChunk)> {
+        let text = match self.queue.front() {
+            Some(text) => text,
+            None => {
+                self.active = false;
+                self.sent = 0;
+                self.total = 0;
+                return None;
+            }
+        };
+        let start = self.offset;
+        let end = paste_chunk_end(text, start, chunk_size);
+        let chunk = PasteChunk {
+            first: start == 0,
+            last: end == text.len(),
+        };
+        let data = text[start..end].to_owned();
+
+        self.sent += end - start;
+        if chunk.last {
+            self.queue.pop_front();
+            self.offset = 0;
+        } else {
+            self.offset = end;
+        }
+        Some((data, chunk))
+    }
+
+    fn cancel(&mut self) {
+        // A paste that is partially sent is cut short rather than
+        // dropped, so that we still send its closing chunk
+        let offset = self.offset;
+        let partial = self.queue.pop_front().filter(|_| offset > 0);
+        self.queue.clear();
+        if let Some(mut text) = partial {
+            text.truncate(offset);
+            self.queue.push_back(text);
+        }
+        self.total = self.sent;
+    }
+}
+
+impl PasteSender {
+    fn new(
+        client: &Arc<ClientInner>,
+        remote_tab_id: TabId,
+        chunk_size: usize,
+        waiters: &InputWaiters,
+    ) -> Self {
+        Self {
+            client: Arc::clone(client),
+            remote_tab_id,
+            chunk_size,
+            state: Rc::new(RefCell::new(PasteState::default())),
+            waiters: waiters.clone(),
+        }
+    }
+
+    fn paste(&self, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        {
+            let mut state = self.state.borrow_mut();
+            state.total += text.len();
+            state.queue.push_back(text);
+            if state.active {
+                return;
+            }
+            state.active = true;
+        }
+
+        let client = Arc::clone(&self.client);
+        let tab_id = self.remote_tab_id;
+        let chunk_size = self.chunk_size;
+        let state = Rc::clone(&self.state);
+        let waiters = self.waiters.clone();
+        promise::spawn::spawn(async move {
+            loop {
+                let next = state.borrow_mut().next_chunk(chunk_size);
+                let (data, chunk) = match next {
+                    Some(next) => next,
+                    None => {
+                        waiters.wake();
+                        return Ok(());
+                    }
+                };
+                let result = client
+                    .client
+                    .send_paste(SendPaste {
+                        tab_id,
+                        data,
+                        chunk: Some(chunk),
+                    })
+                    .await;
+                if let Err(err) = result {
+                    log::error!("failed to send paste to remote tab {}: {}", tab_id, err);
+                    *state.borrow_mut() = PasteState::default();
+                    waiters.wake();
+                    return Err(err);
+                }
+            }
+        });
+    }
+
+    fn progress(&self) -> Option<PasteProgress> {
+        let state = self.state.borrow();
+        if state.active {
+            Some(PasteProgress {
+                sent: state.sent,
+                total: state.total,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn cancel(&self) {
+        self.state.borrow_mut().cancel();
+    }
+}
+
+/// The response to a GetLines or GetLinesDelta request
+enum FetchedLines {
+    Full(GetLinesResponse),
+    Delta(GetLinesDeltaResponse),
+}
+
+/// Returns the mux so that the result of a request can be applied.
+/// The mux is torn down at shutdown while requests may still be in
+/// flight; there is nothing useful to do with their results then, so
+/// this logs and returns None rather than panicking.
+fn completion_mux(what: &str) -> Option<Rc<Mux>> {
+    let mux = Mux::get();
+    if mux.is_none() {
+        log::debug!("mux is gone; dropping the result of {}", what);
+    }
+    mux
+}
+
+/// Send a notification to the mux, unless it has been torn down
+fn notify_mux(notification: crate::mux::MuxNotification) {
+    if let Some(mux) = Mux::get() {
+        mux.notify(notification);
+    }
+}
+
+/// Construct the new version of a line from a patch sent by the
+/// server.  `patch` holds the cells from `start` onwards that differ
+/// from `base`.  Returns None if the patch doesn't fit the line.
+fn apply_line_patch(base: &Line, start: usize, width: usize, patch: &Line) -> Option<Line> {
+    let end = start + patch.cells().len();
+    if end > width {
+        return None;
+    }
+    let mut cells = base.cells().to_vec();
+    cells.resize(width, Cell::default());
+    cells.splice(start..end, patch.cells().iter().cloned());
+    let mut line = Line::from_cells(cells);
+    // Our implicit hyperlinks may not match those of the server;
+    // they will be recomputed when the line is stored
+    line.invalidate_implicit_hyperlinks();
+    Some(line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::fetch::MAX_FETCH_ATTEMPTS;
+    use super::*;
+    use crate::server::client::Client;
+    use std::sync::Mutex;
+    use term::{MouseButton, MouseEventKind};
+    use termwiz::hyperlink::Hyperlink;
+
+    fn renderable_inner() -> RenderableInner {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        RenderableInner::new(&client, 0, 0, PtySize::default(), "test", &configuration())
+    }
+
+    #[test]
+    fn cache_eviction_retains_fetching() {
+        let mut inner = renderable_inner();
+        inner.lines = LruCache::new(4);
+        let now = Instant::now();
+
+        inner.put_entry(0, LineEntry::Fetching(now));
+        inner.put_entry(1, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::DirtyAndFetching(Line::with_width(4), now));
+        inner.put_entry(3, LineEntry::Stale(Line::with_width(4)));
+        for row in 4..8 {
+            inner.put_entry(row, LineEntry::Line(Line::with_width(4)));
+        }
+
+        assert_eq!(inner.lines.len(), 4);
+        assert!(inner.lines.contains(&0));
+        assert!(inner.lines.contains(&2));
+        assert!(!inner.lines.contains(&1));
+        assert!(!inner.lines.contains(&3));
+
+        inner.make_all_stale();
+        assert_eq!(inner.lines.cap(), 4);
+    }
+
+    #[test]
+    fn input_resets_poll_interval() {
+        let mut inner = renderable_inner();
+        inner.poll_interval = inner.max_poll_interval;
+        inner.last_poll = Instant::now();
+
+        inner.reset_poll_for_input();
+        assert_eq!(inner.poll_interval, inner.base_poll_interval);
+        // The next poll should be due straight away
+        assert!(inner.last_poll.elapsed() >= inner.base_poll_interval * 2);
+
+        // An in-progress poll is left alone
+        inner.poll_interval = inner.max_poll_interval;
+        inner.poll_in_progress.store(true, Ordering::SeqCst);
+        inner.reset_poll_for_input();
+        assert_eq!(inner.poll_interval, inner.max_poll_interval);
+    }
+
+    #[test]
+    fn dead_reasons() {
+        assert_eq!(
+            DeadReason::from_poll_error(&anyhow::Error::new(BrokenPromise {})),
+            DeadReason::ConnectionLost
+        );
+        assert_eq!(
+            DeadReason::from_poll_error(&anyhow!("no such tab 3")),
+            DeadReason::RemoteClosed { exit_code: None }
+        );
+        assert_eq!(
+            DeadReason::from_poll_error(&anyhow!("oops")),
+            DeadReason::PollError("oops".to_string())
+        );
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let mut inner = renderable_inner();
+        let err = anyhow!("broken");
+
+        inner.poll_failed(&err);
+        assert_eq!(
+            inner.dead,
+            Some(DeadReason::PollError("broken".to_string()))
+        );
+
+        inner.dead = None;
+        inner.auto_reconnect = true;
+        inner.reconnect_max_attempts = 2;
+        inner.poll_failed(&err);
+        inner.poll_failed(&err);
+        match inner.reconnect {
+            ReconnectState::Reconnecting { attempt, .. } => assert_eq!(attempt, 2),
+            ReconnectState::Connected => panic!("should be reconnecting"),
+        }
+        assert_eq!(inner.dead, None);
+
+        inner.poll_succeeded();
+        assert_eq!(inner.reconnect, ReconnectState::Connected);
+
+        for _ in 0..3 {
+            inner.poll_failed(&err);
+        }
+        assert_eq!(inner.dead, Some(DeadReason::ConnectionLost));
+    }
+
+    #[test]
+    fn fetch_error_cleared_by_put_line() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.put_entry(
+            0,
+            LineEntry::FetchError {
+                since: now,
+                attempts: MAX_FETCH_ATTEMPTS,
+            },
+        );
+        inner.put_entry(1, LineEntry::Line(Line::with_width(4)));
+        assert_eq!(inner.fetch_error_count(), 1);
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "FetchError");
+
+        inner.put_line(0, Line::with_width(4), &configuration(), None, 0);
+        assert_eq!(inner.fetch_error_count(), 0);
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Dirty");
+    }
+
+    #[test]
+    fn tail_range_clamps_to_scrollback() {
+        let mut dims = renderable_inner().dimensions;
+        dims.scrollback_top = 10;
+        dims.physical_top = 100;
+        dims.viewport_rows = 24;
+        assert_eq!(tail_range(&dims, 5), 119..124);
+        assert_eq!(tail_range(&dims, 24), 100..124);
+        assert_eq!(tail_range(&dims, 50), 74..124);
+        assert_eq!(tail_range(&dims, 1000), 10..124);
+        assert_eq!(tail_range(&dims, 0), 124..124);
+    }
+
+    #[test]
+    fn cache_stats_counts_entries() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Dirty(Line::with_width(4)));
+        inner.put_entry(3, LineEntry::Fetching(now));
+        inner.put_entry(4, LineEntry::Stale(Line::with_width(4)));
+        inner.fetch.issued = 3;
+        inner.fetch.completed = 2;
+
+        assert_eq!(
+            inner.cache_stats(),
+            CacheStats {
+                line: 2,
+                dirty: 1,
+                fetching: 1,
+                dirty_and_fetching: 0,
+                stale: 1,
+                fetch_error: 0,
+                poll_interval: inner.base_poll_interval,
+                fetches_issued: 3,
+                fetches_completed: 2,
+                fetches_admitted: 0,
+                fetches_throttled: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn identical_line_not_dirty() {
+        let mut inner = renderable_inner();
+        let config = configuration();
+        let line = Line::from_text("hello", &CellAttributes::default());
+
+        inner.put_line(0, line.clone(), &config, None, 0);
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Dirty");
+
+        // Simulate rendering the row
+        if let Some(LineEntry::Dirty(rendered)) = inner.lines.pop(&0) {
+            inner.put_entry(0, LineEntry::Line(rendered));
+        }
+        inner.put_line(0, line.clone(), &config, None, 0);
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Line");
+
+        inner.make_all_stale();
+        inner.put_line(0, line.clone(), &config, None, 0);
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Line");
+
+        inner.put_line(
+            0,
+            Line::from_text("bye", &CellAttributes::default()),
+            &config,
+            None,
+            0,
+        );
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Dirty");
+    }
+
+    #[test]
+    fn poll_jitter_is_bounded() {
+        let interval = Duration::from_secs(1);
+        let mut jitter = PollJitter::new(1);
+        let mut other = PollJitter::new(2);
+        let mut differs = false;
+        for _ in 0..100 {
+            let a = jitter.apply(interval);
+            assert!(a >= interval.mul_f64(0.9) && a <= interval.mul_f64(1.1));
+            differs |= a != other.apply(interval);
+        }
+        assert!(differs);
+
+        let mut a = PollJitter::new(3);
+        let mut b = PollJitter::new(3);
+        assert_eq!(a.apply(interval), b.apply(interval));
+    }
+
+    #[test]
+    fn poll_interval_bounds_clamped() {
+        let config = Config::default();
+        assert_eq!(
+            poll_interval_bounds(&config),
+            (Duration::from_millis(20), Duration::from_secs(30))
+        );
+
+        let config = Config {
+            mux_poll_interval_base_ms: 500,
+            mux_poll_interval_max_ms: 100,
+            ..Config::default()
+        };
+        assert_eq!(
+            poll_interval_bounds(&config),
+            (Duration::from_millis(500), Duration::from_millis(500))
+        );
+
+        let config = Config {
+            mux_poll_interval_base_ms: 0,
+            ..Config::default()
+        };
+        assert_eq!(poll_interval_bounds(&config).0, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn polls_are_queued_per_connection() {
+        let client = ClientInner::new(0, Client::new_for_test(0));
+        assert!(client.queue_poll(1, 10));
+        assert!(!client.queue_poll(2, 20));
+        assert!(!client.queue_poll(1, 10));
+
+        let pending = client.take_pending_polls();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.get(&2), Some(&20));
+        assert!(client.take_pending_polls().is_empty());
+        assert!(client.queue_poll(1, 10));
+    }
+
+    #[test]
+    fn exited_tab_keeps_exit_status() {
+        let mut inner = renderable_inner();
+        inner.dead = Some(DeadReason::RemoteClosed { exit_code: Some(2) });
+
+        assert!(!inner.fetch_allowed(1, Instant::now()));
+        inner.poll_succeeded();
+        inner.poll_failed(&anyhow!("no such tab 0"));
+        assert_eq!(
+            inner.dead,
+            Some(DeadReason::RemoteClosed { exit_code: Some(2) })
+        );
+        assert_eq!(
+            inner.dead.as_ref().unwrap().to_string(),
+            "the remote process exited with status 2"
+        );
+    }
+
+    #[test]
+    fn tab_exited_records_exit_code() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        assert_eq!(tab.exit_code(), None);
+
+        tab.process_unilateral(Pdu::TabExited(TabExited {
+            tab_id: 0,
+            exit_code: Some(3),
+        }))
+        .unwrap();
+        assert!(tab.is_dead());
+        assert_eq!(tab.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn follow_scroll_position() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        let scrolled = |viewport| {
+            Pdu::TabScrollPosition(TabScrollPosition {
+                tab_id: 0,
+                viewport,
+            })
+        };
+
+        tab.set_follow_scroll(false);
+        assert!(!tab.is_following_scroll());
+        tab.process_unilateral(scrolled(Some(5))).unwrap();
+        assert_eq!(tab.take_followed_viewport(), None);
+
+        tab.set_follow_scroll(true);
+        tab.process_unilateral(scrolled(Some(5))).unwrap();
+        tab.process_unilateral(scrolled(None)).unwrap();
+        assert_eq!(tab.take_followed_viewport(), Some(None));
+        assert_eq!(tab.take_followed_viewport(), None);
+    }
+
+    struct NullHost {
+        sink: Vec<u8>,
+    }
+
+    impl TerminalHost for NullHost {
+        fn writer(&mut self) -> &mut dyn std::io::Write {
+            &mut self.sink
+        }
+    }
+
+    #[test]
+    fn advance_bytes_ignored() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        let mut host = NullHost { sink: vec![] };
+        tab.advance_bytes(b"hello\r\n", &mut host);
+        assert!(host.sink.is_empty());
+        assert!(tab.writer.borrow().is_idle());
+    }
+
+    #[test]
+    fn delta_seq_gap_resyncs() {
+        let mut inner = renderable_inner();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.line_versions.insert(0, 3);
+
+        assert!(!inner.check_delta_seq(0));
+        assert!(!inner.check_delta_seq(1));
+        assert!(!inner.check_delta_seq(2));
+        assert!(inner.check_delta_seq(5));
+        inner.resync();
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Stale");
+        assert!(inner.line_versions.is_empty());
+
+        // Starting again after a reconnect isn't a gap
+        assert!(!inner.check_delta_seq(1));
+        assert!(!inner.check_delta_seq(2));
+    }
+
+    #[test]
+    fn pixel_size_follows_resize() {
+        let mut inner = renderable_inner();
+        inner.dimensions.pixel_width = 800;
+        inner.dimensions.pixel_height = 480;
+        inner.dimensions.dpi = 96;
+
+        // Without a pixel size, our cell size is kept
+        let size = PtySize {
+            rows: 12,
+            cols: 40,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        let (size, changed) = inner.apply_pixel_size(size);
+        assert!(changed);
+        assert_eq!((size.pixel_width, size.pixel_height), (400, 240));
+        inner.apply_resize(40, 12);
+
+        let size = PtySize {
+            rows: 12,
+            cols: 40,
+            pixel_width: 480,
+            pixel_height: 288,
+        };
+        assert!(inner.apply_pixel_size(size).1);
+        assert!(!inner.apply_pixel_size(size).1);
+
+        // The server doesn't know our DPI
+        let mut reported = inner.dimensions;
+        reported.dpi = 0;
+        inner.update_dimensions(reported);
+        assert_eq!(inner.dimensions.dpi, 96);
+        assert_eq!(inner.dimensions.pixel_width, 480);
+    }
+
+    #[test]
+    fn empty_delta_ignored() {
+        let mut inner = renderable_inner();
+        let delta = |inner: &RenderableInner| GetTabRenderChangesResponse {
+            tab_id: 0,
+            mouse_grabbed: false,
+            cursor_position: inner.cursor_position,
+            dimensions: inner.dimensions,
+            dirty_lines: vec![],
+            title: inner.title.clone(),
+            working_dir: None,
+            bonus_lines: Vec::<(StableRowIndex, Line)>::new().into(),
+            bracketed_paste: false,
+            seqno: 1,
+            modes: TabModeState::default(),
+            delta_seq: 0,
+        };
+        inner.poll_interval = inner.max_poll_interval;
+        let activity = inner.last_activity;
+
+        let noop = delta(&inner);
+        assert!(inner.is_noop_delta(&noop));
+        inner.apply_changes_to_surface(noop);
+        assert_eq!(inner.poll_interval, inner.max_poll_interval);
+        assert_eq!(inner.last_activity, activity);
+
+        let mut moved = delta(&inner);
+        moved.cursor_position.x += 1;
+        assert!(!inner.is_noop_delta(&moved));
+        let mut retitled = delta(&inner);
+        retitled.title = "other".to_string();
+        assert!(!inner.is_noop_delta(&retitled));
+    }
+
+    #[test]
+    fn placeholder_fill_matches_width() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        let mut to_fetch = RangeSet::new();
+        let (line, status) = inner.read_line(0, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Placeholder);
+        assert_eq!(line, Line::with_width(inner.dimensions.cols));
+
+        inner.placeholder_fill = Some(Cell::new('~', CellAttributes::default()));
+        inner.apply_resize(5, 24);
+        let (line, _) = inner.read_line(0, now, &mut to_fetch);
+        assert_eq!(line.as_str(), "~~~~~");
+        let (line, _) = inner.read_line(1, now, &mut to_fetch);
+        assert_eq!(line.as_str(), "~~~~~");
+    }
+
+    #[test]
+    fn first_paint_fires_once() {
+        let mut inner = renderable_inner();
+        let config = configuration();
+        let now = Instant::now();
+        assert!(!inner.check_first_paint());
+
+        let mut to_fetch = RangeSet::new();
+        for idx in 0..3 {
+            inner.read_line(idx, now, &mut to_fetch);
+        }
+        inner.arm_first_paint(&(0..3));
+        assert!(!inner.check_first_paint());
+
+        inner.put_line(0, Line::with_width(4), &config, Some(now), 1);
+        inner.put_line(1, Line::with_width(4), &config, Some(now), 1);
+        assert!(!inner.check_first_paint());
+        inner.put_line(2, Line::with_width(4), &config, Some(now), 1);
+        assert!(inner.check_first_paint());
+        assert!(!inner.check_first_paint());
+
+        // Later viewports, such as after a resize, don't re-arm it
+        inner.apply_resize(40, 10);
+        inner.arm_first_paint(&(0..10));
+        assert!(!inner.check_first_paint());
+    }
+
+    #[test]
+    fn wide_lines_clamped_to_width() {
+        let attrs = CellAttributes::default();
+        // The double width character occupies cells 2 and 3
+        let mut line = Line::from_text("ab\u{4e00}c", &attrs);
+        assert!(!clamp_line_width(&mut line, 5));
+        assert!(clamp_line_width(&mut line, 3));
+        assert_eq!(line.cells().len(), 3);
+        assert_eq!(line.as_str(), "ab ");
+
+        let mut inner = renderable_inner();
+        let config = configuration();
+        let now = Instant::now();
+        inner.put_entry(0, LineEntry::Fetching(now));
+        inner.put_entry(
+            1,
+            LineEntry::Stale(Line::from_text("\u{4e00}\u{4e00}", &attrs)),
+        );
+        // The viewport shrinks while the fetch is in flight
+        inner.apply_resize(3, 24);
+
+        let mut to_fetch = RangeSet::new();
+        let (line, status) = inner.read_line(2, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Placeholder);
+        assert_eq!(line.cells().len(), 3);
+        let (line, status) = inner.read_line(1, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Stale);
+        assert_eq!(line.as_str(), "\u{4e00} ");
+
+        let wide = Line::from_text("\u{4e00}\u{4e00}\u{4e00}", &attrs);
+        assert!(inner.put_line(0, wide, &config, Some(now), 1));
+        match inner.lines.peek(&0) {
+            Some(LineEntry::Dirty(line)) => {
+                assert_eq!(line.cells().len(), 3);
+                assert_eq!(line.as_str(), "\u{4e00} ");
+            }
+            _ => panic!("expected row 0 to be replaced"),
+        }
+    }
+
+    #[test]
+    fn read_line_preserves_recency() {
+        let mut inner = renderable_inner();
+        inner.lines = LruCache::new(3);
+        inner.cursor_position.y = 100;
+        let now = Instant::now();
+        let mut to_fetch = RangeSet::new();
+
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Dirty(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Stale(Line::with_width(4)));
+
+        // Unchanged entries are read in place but still become the
+        // most recently used
+        let (_, status) = inner.read_line(0, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Current);
+        let (_, status) = inner.read_line(1, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Current);
+        assert_eq!(inner.lines.peek(&1).unwrap().kind().0, "Line");
+        let (_, status) = inner.read_line(2, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Stale);
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "DirtyAndFetching");
+        assert!(to_fetch.contains(2));
+        assert!(!to_fetch.contains(0));
+
+        // Reading 0 again makes 1 the least recently used
+        inner.read_line(0, now, &mut to_fetch);
+        inner.put_entry(3, LineEntry::Line(Line::with_width(4)));
+        assert!(inner.lines.contains(&0));
+        assert!(!inner.lines.contains(&1));
+    }
+
+    #[test]
+    fn modes_change_detected() {
+        let mut inner = renderable_inner();
+        assert!(inner.modes.cursor_visible);
+        assert!(!inner.update_modes(TabModeState::default()));
+
+        let alt = TabModeState {
+            alt_screen: true,
+            ..TabModeState::default()
+        };
+        assert!(inner.update_modes(alt));
+        assert!(!inner.update_modes(alt));
+        assert!(inner.modes.alt_screen);
+        assert!(inner.update_modes(TabModeState::default()));
+    }
+
+    #[test]
+    fn read_only_refuses_input() {
+        use std::io::Write;
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        assert!(!tab.is_read_only());
+        tab.set_read_only(true);
+        assert!(tab.is_read_only());
+
+        let mut host = NullHost { sink: vec![] };
+        assert!(tab
+            .key_down(KeyCode::Char('a'), KeyModifiers::NONE)
+            .is_err());
+        assert!(tab
+            .mouse_event(
+                MouseEvent {
+                    kind: MouseEventKind::Press,
+                    x: 0,
+                    y: 0,
+                    button: MouseButton::Left,
+                    modifiers: KeyModifiers::NONE,
+                },
+                &mut host
+            )
+            .is_err());
+        assert!(tab.send_paste("hello").is_err());
+        let err = tab.writer().write(b"hello").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(tab.writer.borrow().is_idle());
+        assert!(tab.mouse.borrow().is_idle());
+
+        scheduler::install();
+        tab.set_read_only(false);
+        assert!(!tab.is_read_only());
+        assert_eq!(tab.writer().write(b"hello").unwrap(), 5);
+    }
+
+    #[test]
+    fn cursor_row_pinned_and_fetched_first() {
+        let mut inner = renderable_inner();
+        inner.lines = LruCache::new(2);
+        inner.cursor_position.y = 5;
+        let now = Instant::now();
+
+        inner.put_entry(5, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(3, LineEntry::Line(Line::with_width(4)));
+        assert!(inner.lines.contains(&5));
+        assert!(!inner.lines.contains(&1));
+
+        // A fetching cursor row survives eviction too, so that the
+        // result of the fetch lands in the cache
+        inner.put_entry(5, LineEntry::Fetching(now));
+        inner.put_entry(4, LineEntry::Line(Line::with_width(4)));
+        assert_eq!(inner.lines.peek(&5).unwrap().kind().0, "Fetching");
+
+        let mut to_fetch = RangeSet::new();
+        to_fetch.add_range(0..3);
+        to_fetch.add_range(4..8);
+        assert_eq!(cursor_first(&to_fetch, 5), vec![5..6, 0..3, 4..5, 6..8]);
+        assert_eq!(cursor_first(&to_fetch, 3), vec![0..3, 4..8]);
+    }
+
+    #[test]
+    fn working_dir_change_detected() {
+        let mut inner = renderable_inner();
+        let url = Url::parse("file://host/tmp").unwrap();
+        assert!(inner.update_working_dir(Some(url.clone())));
+        assert!(!inner.update_working_dir(Some(url)));
+        assert_eq!(inner.working_dir.as_ref().unwrap().path(), "/tmp");
+        assert!(inner.update_working_dir(None));
+        assert!(!inner.update_working_dir(None));
+    }
+
+    #[test]
+    fn refresh_makes_lines_stale() {
+        scheduler::install();
+        let mut inner = renderable_inner();
+        inner.poll_interval = inner.max_poll_interval;
+        inner.last_poll = Instant::now();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Dirty(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Stale(Line::with_width(4)));
+
+        inner.refresh();
+        for row in 0..3 {
+            assert_eq!(inner.lines.peek(&row).unwrap().kind().0, "Stale");
+        }
+        // The poll is sent straight away rather than at the end of
+        // the interval, which starts over from the base interval
+        assert!(inner.poll_in_progress.load(Ordering::SeqCst));
+        assert!(inner.poll_interval < inner.max_poll_interval);
+        assert_eq!(scheduler::run_pending(), 1);
+    }
+
+    #[test]
+    fn clipboard_sets_are_limited() {
+        let mut limiter = RateLimiter::new(|_| 5);
+        assert!(!admit_clipboard(
+            &mut limiter,
+            &Some("x".repeat(MAX_REMOTE_CLIPBOARD_SIZE + 1))
+        ));
+
+        let admitted = (0..100)
+            .filter(|_| admit_clipboard(&mut limiter, &Some("hello".to_string())))
+            .count();
+        assert!(admitted > 0);
+        assert!(admitted < 100);
+    }
+
+    struct TestClip {
+        contents: Mutex<Option<String>>,
+    }
+
+    impl Clipboard for TestClip {
+        fn get_contents(&self) -> anyhow::Result<String> {
+            Ok(self.contents.lock().unwrap().clone().unwrap_or_default())
+        }
+
+        fn set_contents(&self, data: Option<String>) -> anyhow::Result<()> {
+            *self.contents.lock().unwrap() = data;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn remote_clipboard_write_disabled() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        let clip = Arc::new(TestClip {
+            contents: Mutex::new(None),
+        });
+        let as_clip: Arc<dyn Clipboard> = clip.clone();
+        tab.set_clipboard(&as_clip);
+
+        let disabled = Config {
+            enable_remote_clipboard_write: false,
+            ..Config::default()
+        };
+        tab.set_remote_clipboard(
+            &disabled,
+            ClipboardSelection::Clipboard,
+            Some("nope".to_string()),
+        )
+        .unwrap();
+        assert_eq!(*clip.contents.lock().unwrap(), None);
+
+        tab.set_remote_clipboard(
+            &Config::default(),
+            ClipboardSelection::Clipboard,
+            Some("yes".to_string()),
+        )
+        .unwrap();
+        assert_eq!(*clip.contents.lock().unwrap(), Some("yes".to_string()));
+    }
+
+    #[test]
+    fn resize_rows_keeps_lines() {
+        let mut inner = renderable_inner();
+        let cols = inner.dimensions.cols;
+        let rows = inner.dimensions.viewport_rows;
+        inner.put_entry(0, LineEntry::Line(Line::with_width(cols)));
+
+        assert!(!inner.apply_resize(cols, rows));
+        assert!(inner.apply_resize(cols, rows + 10));
+        assert_eq!(inner.dimensions.viewport_rows, rows + 10);
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Line");
+    }
+
+    #[test]
+    fn resize_cols_keeps_stale_content() {
+        let mut inner = renderable_inner();
+        let cols = inner.dimensions.cols;
+        let rows = inner.dimensions.viewport_rows;
+        let line = Line::from_text("hello", &CellAttributes::default());
+        inner.put_entry(0, LineEntry::Line(line.clone()));
+
+        assert!(inner.apply_resize(cols + 10, rows));
+        assert_eq!(inner.dimensions.cols, cols + 10);
+        match inner.lines.peek(&0) {
+            Some(LineEntry::Stale(stale)) => assert_eq!(*stale, line),
+            _ => panic!("expected the line to be stale"),
+        }
+    }
+
+    #[test]
+    fn keys_queue_behind_pending_flush() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let keys = KeyQueue::new(&client, 0, &InputWaiters::default());
+        let event = |c| KeyEvent {
+            key: KeyCode::Char(c),
+            modifiers: KeyModifiers::NONE,
+        };
+
+        // With nothing pending, the key is sent immediately
+        assert_eq!(keys.enqueue(event('a')), Some(event('a')));
+
+        keys.state.borrow_mut().flush_scheduled = true;
+        assert_eq!(keys.enqueue(event('b')), None);
+        assert_eq!(keys.enqueue(event('c')), None);
+        assert_eq!(keys.state.borrow().events, vec![event('b'), event('c')]);
+    }
+
+    #[test]
+    fn trimmed_scrollback_evicted() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        for row in 0..10 {
+            inner.put_entry(row, LineEntry::Line(Line::with_width(4)));
+            inner.line_access.insert(row, now);
+        }
+        inner.fetch.mark_in_flight(2, now);
+        inner.fetch.mark_in_flight(6, now);
+
+        let mut dimensions = inner.dimensions;
+        dimensions.scrollback_top = 4;
+        inner.update_dimensions(dimensions);
+
+        assert_eq!(inner.dimensions.scrollback_top, 4);
+        for row in 0..4 {
+            assert!(inner.lines.peek(&row).is_none());
+            assert!(!inner.line_access.contains_key(&row));
+        }
+        for row in 4..10 {
+            assert!(inner.lines.peek(&row).is_some());
+            assert!(inner.line_access.contains_key(&row));
+        }
+        assert_eq!(inner.fetch.in_flight_since(2), None);
+        assert_eq!(inner.fetch.in_flight_since(6), Some(now));
+    }
+
+    #[test]
+    fn catch_up_range_covers_viewport() {
+        let mut inner = renderable_inner();
+        inner.reconnect_initial_rows = 5;
+        inner.dimensions.physical_top = 100;
+        inner.dimensions.viewport_rows = 24;
+        assert_eq!(inner.catch_up_range(), 95..129);
+
+        // If the user has scrolled back, that's what we fetch around
+        inner.last_requested = Some(40..64);
+        assert_eq!(inner.catch_up_range(), 35..69);
+    }
+
+    #[test]
+    fn rescan_hyperlinks_marks_dirty() {
+        let mut inner = renderable_inner();
+        let mut line = Line::from_text("see issue 123", &CellAttributes::default());
+        line.scan_and_create_hyperlinks(&[]);
+        inner.put_entry(0, LineEntry::Line(line.clone()));
+        inner.put_entry(1, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Dirty(Line::with_width(4)));
+
+        let rules = vec![Rule::new(r"\d+", "https://example.com/$0").unwrap()];
+        inner.rescan_hyperlinks(&rules);
+
+        match inner.lines.peek(&0) {
+            Some(LineEntry::Dirty(line)) => {
+                assert!(line.cells()[10].attrs().hyperlink.is_some());
+                assert!(line.cells()[0].attrs().hyperlink.is_none());
+            }
+            _ => panic!("expected row 0 to be dirty"),
+        }
+        assert_eq!(inner.lines.peek(&1).unwrap().kind().0, "Line");
+        // A row that was awaiting a repaint still is, even though
+        // there was no link in it
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Dirty");
+    }
+
+    #[test]
+    fn line_patch_applied() {
+        let attrs = CellAttributes::default();
+        let base = Line::from_text("hello world", &attrs);
+        let patch = Line::from_text("there", &attrs);
+        let patched = apply_line_patch(&base, 6, 11, &patch).unwrap();
+        assert_eq!(patched.as_str(), "hello there");
+
+        // The patch can shrink or extend the line
+        let patched = apply_line_patch(&base, 5, 5, &Line::from_text("", &attrs)).unwrap();
+        assert_eq!(patched.as_str(), "hello");
+        let patched = apply_line_patch(&base, 11, 12, &Line::from_text("!", &attrs)).unwrap();
+        assert_eq!(patched.as_str(), "hello world!");
+
+        assert!(apply_line_patch(&base, 10, 11, &patch).is_none());
+    }
+
+    #[test]
+    fn line_deltas_use_known_versions() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        let attrs = CellAttributes::default();
+        inner.put_entry(
+            0,
+            LineEntry::DirtyAndFetching(Line::from_text("hello world", &attrs), now),
+        );
+        inner.put_entry(1, LineEntry::Fetching(now));
+        inner.line_versions.insert(0, 7);
+
+        let mut to_fetch = RangeSet::new();
+        to_fetch.add_range(0..2);
+        assert_eq!(inner.known_line_versions(&to_fetch), vec![(0, 7)]);
+
+        let response = GetLinesDeltaResponse {
+            tab_id: 0,
+            lines: vec![
+                (0, Line::from_text("there", &attrs)),
+                (1, Line::from_text("new", &attrs)),
+            ]
+            .into(),
+            deltas: vec![
+                LineDelta::Patch {
+                    base: 7,
+                    version: 8,
+                    start: 6,
+                    width: 11,
+                },
+                LineDelta::Full { version: 9 },
+            ],
+            seqno: 1,
+        };
+        inner.apply_line_deltas(response, &configuration(), now);
+
+        match inner.lines.peek(&0) {
+            Some(LineEntry::Dirty(line)) => assert_eq!(line.as_str(), "hello there"),
+            _ => panic!("expected row 0 to be patched"),
+        }
+        assert_eq!(inner.line_versions.get(&0), Some(&8));
+        assert_eq!(inner.line_versions.get(&1), Some(&9));
+    }
+
+    #[test]
+    fn patches_track_dirty_columns() {
+        let mut inner = renderable_inner();
+        inner.cell_granular_updates = true;
+        let attrs = CellAttributes::default();
+        let patch = |inner: &mut RenderableInner, seqno, base, start, text: &str| {
+            let now = Instant::now();
+            let line = match inner.lines.pop(&0) {
+                Some(LineEntry::Dirty(line)) | Some(LineEntry::Line(line)) => line,
+                _ => panic!("expected row 0 to be cached"),
+            };
+            inner.put_entry(0, LineEntry::DirtyAndFetching(line, now));
+            let response = GetLinesDeltaResponse {
+                tab_id: 0,
+                lines: vec![(0, Line::from_text(text, &attrs))].into(),
+                deltas: vec![LineDelta::Patch {
+                    base,
+                    version: base + 1,
+                    start,
+                    width: 11,
+                }],
+                seqno,
+            };
+            inner.apply_line_deltas(response, &configuration(), now);
+        };
+
+        inner.put_entry(0, LineEntry::Line(Line::from_text("hello world", &attrs)));
+        inner.line_versions.insert(0, 1);
+        patch(&mut inner, 1, 1, 6, "there");
+        assert_eq!(inner.dirty_columns(0), Some(6..11));
+
+        // Not yet rendered, so the earlier columns are still dirty
+        patch(&mut inner, 2, 2, 0, "j");
+        assert_eq!(inner.dirty_columns(0), Some(0..11));
+
+        // A full line replaces the whole row
+        let config = configuration();
+        inner.put_line(0, Line::from_text("hello world", &attrs), &config, None, 3);
+        assert_eq!(inner.dirty_columns(0), None);
+
+        // Without the option, the whole row is repainted
+        inner.cell_granular_updates = false;
+        inner.line_versions.insert(0, 4);
+        patch(&mut inner, 4, 4, 6, "there");
+        assert_eq!(inner.dirty_columns(0), None);
+    }
+
+    #[test]
+    fn older_seqno_rejected() {
+        let mut inner = renderable_inner();
+        let config = configuration();
+        let attrs = CellAttributes::default();
+
+        inner.put_line(0, Line::from_text("new", &attrs), &config, None, 5);
+
+        // A fetch that started before the push but completed after it
+        let then = Instant::now();
+        let prior = match inner.lines.pop(&0) {
+            Some(LineEntry::Dirty(line)) => line,
+            _ => panic!("expected row 0 to be dirty"),
+        };
+        inner.put_entry(0, LineEntry::DirtyAndFetching(prior, then));
+        inner.line_versions.insert(0, 3);
+        assert!(!inner.put_line(0, Line::from_text("old", &attrs), &config, Some(then), 4));
+        match inner.lines.peek(&0) {
+            Some(LineEntry::Dirty(line)) => assert_eq!(line.as_str(), "new"),
+            _ => panic!("expected row 0 to keep the newer line"),
+        }
+        assert_eq!(inner.line_seqnos.get(&0), Some(&5));
+        assert_eq!(inner.line_versions.get(&0), Some(&3));
+
+        assert!(inner.put_line(0, Line::from_text("newer", &attrs), &config, None, 6));
+    }
+
+    #[test]
+    fn discarded_fetch_keeps_row_state() {
+        let mut inner = renderable_inner();
+        let config = configuration();
+        let attrs = CellAttributes::default();
+        let then = Instant::now();
+
+        inner.put_line(0, Line::from_text("pushed", &attrs), &config, None, 5);
+        inner.line_versions.insert(0, 7);
+        inner.fetch.record_failure(0, then);
+        // The row was fetched again after the fetch that completes here
+        inner.put_entry(0, LineEntry::Fetching(then + Duration::from_millis(1)));
+
+        assert!(!inner.put_line(0, Line::from_text("old", &attrs), &config, Some(then), 6));
+        assert_eq!(inner.line_seqnos.get(&0), Some(&5));
+        assert_eq!(inner.line_versions.get(&0), Some(&7));
+        assert!(inner.fetch.is_backing_off(0));
+    }
+
+    #[test]
+    fn viewport_text() {
+        let attrs = CellAttributes::default();
+        let lines = vec![
+            Line::from_text("hello   ", &attrs),
+            Line::with_width(4),
+            Line::from_text("  world", &attrs),
+        ];
+        assert_eq!(lines_to_text(&lines), "hello\n\n  world");
+    }
+
+    #[test]
+    fn warm_region_skips_cached_rows() {
+        let mut inner = renderable_inner();
+        inner.dimensions.scrollback_top = 0;
+        inner.dimensions.physical_top = 100;
+        inner.dimensions.viewport_rows = 24;
+        let now = Instant::now();
+
+        inner.put_entry(2, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(3, LineEntry::Stale(Line::with_width(4)));
+
+        let to_fetch = inner.mark_region_for_fetch(-5..6, now);
+        assert_eq!(
+            to_fetch.iter().cloned().collect::<Vec<_>>(),
+            vec![0..2, 3..6]
+        );
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Fetching");
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Line");
+        assert_eq!(inner.lines.peek(&3).unwrap().kind().0, "DirtyAndFetching");
+
+        // Rows already in flight aren't requested a second time
+        assert!(inner.mark_region_for_fetch(0..6, now).is_empty());
+    }
+
+    #[test]
+    fn warm_region_admitted_once() {
+        scheduler::install();
+        let mut inner = renderable_inner();
+        inner.dimensions.scrollback_top = 0;
+        inner.dimensions.physical_top = 100;
+        inner.dimensions.viewport_rows = 24;
+
+        inner.warm_region(0..10);
+        assert!(inner.fetch.pending_background.contains(5));
+        inner.flush_pending_fetches();
+        assert_eq!(inner.fetch.issued, 1);
+        let mut stats = CacheStats::default();
+        inner.fetch.record_stats(&mut stats);
+        assert_eq!(stats.fetches_admitted, 1);
+        assert_eq!(stats.fetches_throttled, 0);
+    }
+
+    #[test]
+    fn wedged_poll_recovered() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.poll_started = now;
+        inner.poll_in_progress.store(true, Ordering::SeqCst);
+        assert!(!inner.check_poll_watchdog(now + inner.max_poll_interval));
+        assert!(inner.poll_in_progress.load(Ordering::SeqCst));
+
+        let wedged = now + inner.max_poll_interval * (POLL_WATCHDOG_INTERVALS + 1);
+        assert!(inner.check_poll_watchdog(wedged));
+        assert!(!inner.poll_in_progress.load(Ordering::SeqCst));
+        assert!(!inner.check_poll_watchdog(wedged));
+    }
+
+    #[test]
+    fn make_all_dirty_keeps_lines() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Stale(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Fetching(now));
+
+        inner.make_all_dirty();
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Dirty");
+        assert_eq!(inner.lines.peek(&1).unwrap().kind().0, "Stale");
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Fetching");
+    }
+
+    #[test]
+    fn cursor_move_repaints_without_fetch() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Stale(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Fetching(now));
+
+        assert!(inner.repaint_without_fetch(0));
+        assert_eq!(inner.lines.peek(&0).unwrap().kind().0, "Dirty");
+        assert!(!inner.repaint_without_fetch(1));
+        assert_eq!(inner.lines.peek(&1).unwrap().kind().0, "Stale");
+        assert!(inner.repaint_without_fetch(2));
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Fetching");
+        assert!(!inner.repaint_without_fetch(3));
+        assert!(!inner.lines.contains(&3));
+    }
+
+    #[test]
+    fn paste_sent_in_chunks() {
+        let mut state = PasteState::default();
+        state.queue.push_back("abcde".to_owned());
+        state.queue.push_back("\u{4e00}x".to_owned());
+        state.total = 9;
+        state.active = true;
+
+        let mut chunks = vec![];
+        while let Some((data, PasteChunk { first, last })) = state.next_chunk(2) {
+            chunks.push((data, first, last));
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                ("ab".to_owned(), true, false),
+                ("cd".to_owned(), false, false),
+                ("e".to_owned(), false, true),
+                // Chunks end on a char boundary
+                ("\u{4e00}".to_owned(), true, false),
+                ("x".to_owned(), false, true),
+            ]
+        );
+        assert!(!state.active);
+        assert_eq!(state.total, 0);
+    }
+
+    #[test]
+    fn cancelled_paste_is_closed() {
+        let mut state = PasteState::default();
+        state.queue.push_back("abcde".to_owned());
+        state.queue.push_back("fgh".to_owned());
+        state.total = 8;
+        state.active = true;
+
+        let (data, _) = state.next_chunk(2).unwrap();
+        assert_eq!(data, "ab");
+        state.cancel();
+        assert_eq!(state.total, 2);
+        // The paste in progress is closed by an empty final chunk
+        let (data, chunk) = state.next_chunk(2).unwrap();
+        assert_eq!(data, "");
+        assert_eq!(
+            chunk,
+            PasteChunk {
+                first: false,
+                last: true
+            }
+        );
+        assert_eq!(state.next_chunk(2), None);
+    }
+
+    #[test]
+    fn mouse_grab_transitions() {
+        let mouse_grabbed = RefCell::new(false);
+        assert!(!update_mouse_grabbed(&mouse_grabbed, false));
+        assert!(update_mouse_grabbed(&mouse_grabbed, true));
+        assert!(!update_mouse_grabbed(&mouse_grabbed, true));
+        assert!(*mouse_grabbed.borrow());
+        assert!(update_mouse_grabbed(&mouse_grabbed, false));
+    }
+
+    #[test]
+    fn remote_removal_keeps_exit_code() {
+        let mut inner = renderable_inner();
+        inner.remote_removed();
+        assert_eq!(
+            inner.dead,
+            Some(DeadReason::RemoteClosed { exit_code: None })
+        );
+
+        inner.dead = Some(DeadReason::RemoteClosed { exit_code: Some(1) });
+        inner.remote_removed();
+        assert_eq!(
+            inner.dead,
+            Some(DeadReason::RemoteClosed { exit_code: Some(1) })
+        );
+        assert!(inner.remote_exited());
+    }
+
+    #[test]
+    fn provisional_lines_flagged() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::DirtyAndFetching(Line::with_width(4), now));
+        inner.put_entry(2, LineEntry::Fetching(now));
+        let mut state = RenderableState {
+            inner: RefCell::new(inner),
+        };
+
+        let (first, lines) = state.get_lines_with_status(0..3);
+        assert_eq!(first, 0);
+        assert_eq!(
+            lines.iter().map(|(_, status)| *status).collect::<Vec<_>>(),
+            vec![
+                LineStatus::Current,
+                LineStatus::Stale,
+                LineStatus::Placeholder
+            ]
+        );
+    }
+
+    #[test]
+    fn prefetch_admitted_once() {
+        scheduler::install();
+        let mut inner = renderable_inner();
+        inner.dimensions.scrollback_top = 0;
+        inner.dimensions.physical_top = 100;
+        inner.dimensions.viewport_rows = 24;
+        inner.last_requested = Some(50..74);
+        let mut state = RenderableState {
+            inner: RefCell::new(inner),
+        };
+
+        // Scrolling down prefetches the rows below the viewport
+        state.get_lines_with_status(51..75);
+        let mut inner = state.inner.borrow_mut();
+        assert!(inner.fetch.pending.contains(51));
+        assert!(inner.fetch.pending_background.contains(75));
+
+        inner.flush_pending_fetches();
+        assert_eq!(inner.fetch.issued, 1);
+        let stats = inner.cache_stats();
+        assert_eq!(stats.fetches_admitted, 1);
+        assert_eq!(stats.fetches_throttled, 0);
+    }
+
+    #[test]
+    fn output_delivered_to_live_subscribers() {
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        let mut subscribers = vec![tx1, tx2];
+
+        assert!(deliver_output(&mut subscribers, b"hello"));
+        assert_eq!(rx1.try_recv().unwrap(), b"hello".to_vec());
+        assert_eq!(rx2.try_recv().unwrap(), b"hello".to_vec());
+
+        drop(rx1);
+        assert!(deliver_output(&mut subscribers, b"\x1b[1m"));
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(rx2.try_recv().unwrap(), b"\x1b[1m".to_vec());
+
+        drop(rx2);
+        assert!(!deliver_output(&mut subscribers, b"bye"));
+        assert!(subscribers.is_empty());
+    }
+
+    #[test]
+    fn idle_lines_swept() {
+        let mut inner = renderable_inner();
+        let ttl = Duration::from_secs(10);
+        inner.line_cache_ttl = Some(ttl);
+        // Leave room to express times before the start
+        let start = Instant::now() + ttl;
+        inner.last_line_sweep = start;
+        inner.last_requested = Some(10..12);
+        inner.cursor_position.y = 20;
+
+        for row in 0..4 {
+            inner.put_entry(row, LineEntry::Line(Line::with_width(4)));
+        }
+        inner.put_entry(1, LineEntry::Stale(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Fetching(start));
+        inner.put_entry(3, LineEntry::Dirty(Line::with_width(4)));
+        inner.put_entry(10, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(20, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(30, LineEntry::Line(Line::with_width(4)));
+        for row in &[0, 1, 2, 3, 10, 20] {
+            inner.line_access.insert(*row, start);
+        }
+
+        // Row 30 has never been displayed; its TTL starts now
+        assert_eq!(inner.sweep_idle_lines(start + Duration::from_secs(2)), 0);
+        // Too soon after the prior sweep
+        inner.line_access.insert(0, start - ttl);
+        assert_eq!(inner.sweep_idle_lines(start + Duration::from_secs(2)), 0);
+
+        assert_eq!(
+            inner.sweep_idle_lines(start + ttl + Duration::from_secs(1)),
+            2
+        );
+        assert!(inner.lines.peek(&0).is_none());
+        assert!(inner.lines.peek(&1).is_none());
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Fetching");
+        assert_eq!(inner.lines.peek(&3).unwrap().kind().0, "Dirty");
+        assert!(inner.lines.peek(&10).is_some());
+        assert!(inner.lines.peek(&20).is_some());
+        assert!(inner.lines.peek(&30).is_some());
+        assert!(!inner.line_access.contains_key(&0));
+
+        assert_eq!(
+            inner.sweep_idle_lines(start + ttl * 2 + Duration::from_secs(3)),
+            1
+        );
+        assert!(inner.lines.peek(&30).is_none());
+    }
+
+    #[test]
+    fn explicit_links_survive_fetch() {
+        let mut inner = renderable_inner();
+        let config = configuration();
+        let explicit = Arc::new(Hyperlink::new("https://explicit.example/"));
+        let linked = CellAttributes::default()
+            .set_hyperlink(Some(Arc::clone(&explicit)))
+            .clone();
+
+        // A wide character ahead of the links shifts the cells
+        // relative to the characters of the text
+        let mut cells = Line::from_text("\u{4e00} ", &CellAttributes::default())
+            .cells()
+            .to_vec();
+        let explicit_cells = cells.len()..cells.len() + 18;
+        cells.extend_from_slice(Line::from_text("http://a.example/x", &linked).cells());
+        cells.extend_from_slice(
+            Line::from_text(" http://b.example/y", &CellAttributes::default()).cells(),
+        );
+        let mut line = Line::from_cells(cells);
+        // The server has already scanned the line with its own rules
+        line.scan_and_create_hyperlinks(&[]);
+
+        assert!(inner.put_line(0, line, &config, None, 1));
+        let line = match inner.lines.peek(&0) {
+            Some(LineEntry::Dirty(line)) => line.clone(),
+            _ => panic!("expected a Dirty line"),
+        };
+        for (idx, cell) in line.cells().iter().enumerate() {
+            let link = cell.attrs().hyperlink.as_ref();
+            if explicit_cells.contains(&idx) {
+                assert_eq!(link, Some(&explicit), "cell {}", idx);
+            } else if idx > explicit_cells.end {
+                let link = link.expect("implicit link");
+                assert!(link.is_implicit());
+                assert_eq!(link.uri(), "http://b.example/y");
+            } else {
+                assert!(link.is_none(), "cell {}", idx);
+            }
+        }
+    }
+
+    #[test]
+    fn viewport_jump_abandons_fetches() {
+        let mut inner = renderable_inner();
+        let start = Instant::now();
+        inner.cursor_position.y = 100;
+        inner.last_requested = Some(0..10);
+        inner.put_entry(2, LineEntry::Fetching(start));
+        inner.put_entry(3, LineEntry::DirtyAndFetching(Line::with_width(4), start));
+        inner.put_entry(4, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(45, LineEntry::Fetching(start));
+        inner.put_entry(100, LineEntry::Fetching(start));
+
+        // Overlapping the prior request isn't a jump
+        assert_eq!(inner.abandon_obsolete_fetches(&(5..15)), 0);
+        assert_eq!(inner.fetch.generation, 0);
+
+        assert_eq!(inner.abandon_obsolete_fetches(&(40..50)), 2);
+        assert_eq!(inner.fetch.generation, 1);
+        assert!(inner.lines.peek(&2).is_none());
+        assert_eq!(inner.lines.peek(&3).unwrap().kind().0, "Stale");
+        assert_eq!(inner.lines.peek(&4).unwrap().kind().0, "Line");
+        // The rows in the new viewport, and the cursor row, are
+        // still being fetched
+        assert_eq!(inner.lines.peek(&45).unwrap().kind().0, "Fetching");
+        assert_eq!(inner.lines.peek(&100).unwrap().kind().0, "Fetching");
+
+        // The results for the abandoned rows are discarded
+        let config = configuration();
+        assert!(!inner.put_line(2, Line::with_width(4), &config, Some(start), 1));
+        assert!(!inner.put_line(3, Line::with_width(4), &config, Some(start), 1));
+        assert!(inner.put_line(45, Line::with_width(4), &config, Some(start), 1));
+    }
+
+    #[test]
+    fn only_changed_rows_are_activity() {
+        let mut inner = renderable_inner();
+        let attached = inner.last_activity;
+        let later = attached + Duration::from_secs(5);
+
+        assert!(!inner.record_activity(&RangeSet::new(), later));
+        assert_eq!(inner.last_activity, attached);
+
+        let mut dirty = RangeSet::new();
+        dirty.add(3);
+        assert!(inner.record_activity(&dirty, later));
+        assert_eq!(inner.last_activity, later);
+    }
+
+    #[test]
+    fn cache_snapshot_round_trip() {
+        let mut inner = renderable_inner();
+        let attrs = CellAttributes::default();
+        inner.cursor_position.y = 2;
+        inner.put_entry(0, LineEntry::Line(Line::from_text("zero", &attrs)));
+        inner.put_entry(1, LineEntry::Fetching(Instant::now()));
+        inner.put_entry(2, LineEntry::Dirty(Line::from_text("two", &attrs)));
+
+        let data = inner.snapshot().encode().unwrap();
+        let snapshot = CacheSnapshot::decode(&data).unwrap();
+        assert_eq!(snapshot, inner.snapshot());
+        let rows: Vec<StableRowIndex> = snapshot.lines.iter().map(|(row, _)| *row).collect();
+        assert_eq!(rows, vec![0, 2]);
+
+        let mut restored = renderable_inner();
+        restored.put_entry(2, LineEntry::Line(Line::from_text("new", &attrs)));
+        restored.restore(snapshot);
+        match restored.lines.peek(&0) {
+            Some(LineEntry::Stale(line)) => assert_eq!(line.as_str(), "zero"),
+            _ => panic!("expected a Stale line"),
+        }
+        assert!(restored.lines.peek(&1).is_none());
+        // We already held row 2, so the snapshot doesn't replace it
+        assert_eq!(restored.lines.peek(&2).unwrap().kind().0, "Line");
+
+        assert!(CacheSnapshot::decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn remote_selection_repaints_rows() {
+        let mut inner = renderable_inner();
+        for row in 0..6 {
+            inner.put_entry(row, LineEntry::Line(Line::with_width(4)));
+        }
+        let region = SelectionRegion {
+            start_x: 3,
+            start_y: 4,
+            end_x: 0,
+            end_y: 1,
+        };
+        assert!(inner.set_remote_selection(Some(region)));
+        assert!(!inner.set_remote_selection(Some(region)));
+        let kinds: Vec<&str> = (0..6)
+            .map(|row| inner.lines.peek(&row).unwrap().kind().0)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["Line", "Dirty", "Dirty", "Dirty", "Dirty", "Line"]
+        );
+
+        // Clearing the selection repaints the rows that it covered
+        inner.put_entry(2, LineEntry::Line(Line::with_width(4)));
+        assert!(inner.set_remote_selection(None));
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Dirty");
+        assert_eq!(inner.lines.peek(&5).unwrap().kind().0, "Line");
+    }
+
+    #[test]
+    fn backlog_defers_offscreen_rows() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.max_pending_fetch_rows = 3;
+        inner.last_requested = Some(100..110);
+        inner.cursor_position.y = 60;
+        inner.put_entry(10, LineEntry::Fetching(now));
+        inner.put_entry(11, LineEntry::Fetching(now));
+        inner.put_entry(50, LineEntry::Line(Line::with_width(4)));
+
+        let mut dirty = RangeSet::new();
+        for row in &[50, 51, 52, 60, 105] {
+            dirty.add(*row);
+        }
+        let to_fetch = inner.mark_dirty_rows_for_fetch(&dirty, 0, None, now);
+        let rows: Vec<StableRowIndex> = to_fetch.iter().flat_map(|r| r.clone()).collect();
+        // There was room for one more offscreen row; the visible rows
+        // and the cursor row are fetched regardless
+        assert_eq!(rows, vec![50, 60, 105]);
+        assert_eq!(inner.lines.peek(&50).unwrap().kind().0, "DirtyAndFetching");
+        assert!(inner.lines.peek(&51).is_none());
+        assert!(inner.lines.peek(&52).is_none());
+    }
+
+    #[test]
+    fn visible_rows_fetched_first() {
+        let mut to_fetch = RangeSet::new();
+        to_fetch.add_range(0..30);
+        to_fetch.add(50);
+        let (visible, margin) = split_visible(&to_fetch, &(10..20), 50);
+        let rows = |set: &RangeSet<StableRowIndex>| set.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(rows(&visible), vec![10..20, 50..51]);
+        assert_eq!(rows(&margin), vec![0..10, 20..30]);
+
+        // A margin that we won't be sending is abandoned
+        let mut inner = renderable_inner();
+        let start = Instant::now();
+        inner.put_entry(0, LineEntry::Fetching(start));
+        inner.put_entry(1, LineEntry::DirtyAndFetching(Line::with_width(4), start));
+        inner.put_entry(2, LineEntry::Fetching(start + Duration::from_millis(1)));
+        let mut margin = RangeSet::new();
+        margin.add_range(0..3);
+        inner.abandon_fetch(&margin, start);
+        assert!(inner.lines.peek(&0).is_none());
+        assert_eq!(inner.lines.peek(&1).unwrap().kind().0, "Stale");
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Fetching");
+    }
+
+    #[test]
+    fn concurrent_fetches_capped() {
+        let mut inner = renderable_inner();
+        let now = Instant::now();
+        inner.fetch.max_concurrent = 2;
+        inner.fetch.in_flight = 2;
+        inner.put_entry(5, LineEntry::Fetching(now));
+        inner.fetch.pending.add(5);
+
+        inner.flush_pending_fetches();
+        assert_eq!(inner.fetch.issued, 0);
+        assert_eq!(inner.fetch.in_flight, 2);
+        // The row stays queued for when a slot frees up
+        assert!(inner.fetch.pending.contains(5));
+        assert_eq!(inner.lines.peek(&5).unwrap().kind().0, "Fetching");
+    }
+
+    #[test]
+    fn throttle_spares_displayed_rows() {
+        scheduler::install();
+        let mut inner = renderable_inner();
+        inner.fetch.set_rate_override(Some(1));
+        inner.update_fetch_limiter();
+        // Use up the allowance
+        assert!(inner.fetch.admit());
+
+        let now = Instant::now();
+        inner.put_entry(1, LineEntry::Fetching(now));
+        inner.put_entry(2, LineEntry::DirtyAndFetching(Line::with_width(4), now));
+        let mut displayed = RangeSet::new();
+        displayed.add(1);
+        let mut background = RangeSet::new();
+        background.add(2);
+        inner.schedule_fetch_lines(displayed, FetchReason::Display);
+        inner.schedule_fetch_lines(background, FetchReason::Background);
+
+        inner.flush_pending_fetches();
+        assert_eq!(inner.fetch.issued, 1);
+        assert!(inner.fetch.in_flight_since(1).is_some());
+        assert!(inner.fetch.in_flight_since(2).is_none());
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().0, "Stale");
+        let mut stats = CacheStats::default();
+        inner.fetch.record_stats(&mut stats);
+        assert_eq!(stats.fetches_admitted, 1);
+        assert_eq!(stats.fetches_throttled, 1);
+    }
+
+    #[test]
+    fn completion_after_mux_gone() {
+        // There is no mux in the tests, as is the case when a request
+        // completes during shutdown
+        assert!(Mux::get().is_none());
+        let mut rows = RangeSet::new();
+        rows.add(0);
+        let result = Err(anyhow!("lost"));
+        assert!(RenderableInner::apply_lines(0, result, rows.clone(), Instant::now(), 0).is_ok());
+        assert!(!RenderableInner::margin_still_wanted(
+            0,
+            &rows,
+            Instant::now(),
+            0
+        ));
+        RenderableInner::fetch_finished(0);
+        notify_mux(crate::mux::MuxNotification::TabOutput(0));
+    }
+
+    #[test]
+    fn overlapping_fetches_join() {
+        let mut inner = renderable_inner();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(1);
+        let t2 = t0 + Duration::from_millis(2);
+        let t3 = t0 + Duration::from_millis(3);
+
+        let mut first = RangeSet::new();
+        first.add_range(0..4);
+        for row in 0..4 {
+            inner.put_entry(row, LineEntry::Fetching(t0));
+        }
+        assert_eq!(inner.claim_pending_rows(&first, t1), first);
+
+        // Rows 2 and 3 are asked for again, as happens when the viewport
+        // jumps away and back, while the first fetch is in flight
+        let mut second = RangeSet::new();
+        second.add_range(2..6);
+        for row in 2..6 {
+            inner.put_entry(row, LineEntry::Fetching(t2));
+        }
+        let mut expect = RangeSet::new();
+        expect.add_range(4..6);
+        assert_eq!(inner.claim_pending_rows(&second, t2), expect);
+        // They will be filled in by the first fetch
+        assert_eq!(inner.lines.peek(&2).unwrap().kind().1, Some(t1));
+        assert_eq!(inner.lines.peek(&4).unwrap().kind().1, Some(t2));
+
+        // A row that changed since the first fetch was sent needs
+        // to be requested again
+        let mut dirty = RangeSet::new();
+        dirty.add(3);
+        let refetch = inner.mark_dirty_rows_for_fetch(&dirty, 0, None, t3);
+        assert_eq!(inner.claim_pending_rows(&refetch, t3), dirty);
+
+        // Once the first fetch completes, its rows are free to be
+        // requested again
+        inner.fetch.release_in_flight_rows(&first, t1);
+        let mut again = RangeSet::new();
+        again.add(2);
+        assert_eq!(inner.claim_pending_rows(&again, t3), again);
+    }
+
+    #[test]
+    fn cursor_row_keeps_content_while_fetching() {
+        let mut inner = renderable_inner();
+        inner.cursor_position.y = 5;
+        let attrs = CellAttributes::default();
+        let now = Instant::now();
+        let mut to_fetch = RangeSet::new();
+
+        inner.put_entry(5, LineEntry::Line(Line::from_text("$ ls", &attrs)));
+        inner.put_entry(6, LineEntry::Line(Line::from_text("foo", &attrs)));
+        inner.read_line(5, now, &mut to_fetch);
+        inner.read_line(6, now, &mut to_fetch);
+
+        // Both rows drop out of the cache and have to be fetched again
+        inner.lines.pop(&5);
+        inner.lines.pop(&6);
+        let (line, status) = inner.read_line(5, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Stale);
+        assert_eq!(line.as_str().trim_end(), "$ ls");
+        assert_eq!(line.cells().len(), inner.dimensions.cols);
+        let (_, status) = inner.read_line(6, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Placeholder);
+        assert!(to_fetch.contains(5));
+
+        // Once the cursor moves on, its old row is just another row
+        inner.cursor_position.y = 6;
+        let (_, status) = inner.read_line(6, now, &mut to_fetch);
+        assert_eq!(status, LineStatus::Placeholder);
+    }
+
+    #[test]
+    fn dpi_change_sends_resize() {
+        let mut inner = renderable_inner();
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 640,
+            pixel_height: 384,
+        };
+        assert!(inner.resize_needed(size).1);
+        assert!(!inner.resize_needed(size).1);
+
+        // Moving to a display with a different scale factor leaves
+        // the grid as it was
+        inner.dimensions.dpi = 192;
+        inner.dpi_changed = true;
+        assert!(inner.resize_needed(size).1);
+        assert!(!inner.resize_needed(size).1);
+
+        let size = PtySize {
+            pixel_width: 1280,
+            pixel_height: 768,
+            ..size
+        };
+        assert!(inner.resize_needed(size).1);
+    }
+
+    #[test]
+    fn detach_waits_for_queued_input() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        assert!(tab.input_drained());
+
+        tab.keys.state.borrow_mut().events.push(KeyEvent {
+            key: KeyCode::Char('a'),
+            modifiers: KeyModifiers::NONE,
+        });
+        assert!(!tab.input_drained());
+        tab.keys.state.borrow_mut().events.clear();
+
+        scheduler::install();
+        tab.writer().write(b"exit\n").unwrap();
+        assert!(!tab.input_drained());
+        // The test client has no server, so the write fails straight away
+        scheduler::run_pending();
+        assert!(tab.input_drained());
+
+        // Once detached, the tab refuses input
+        *tab.detached.borrow_mut() = true;
+        assert!(tab
+            .key_down(KeyCode::Char('a'), KeyModifiers::NONE)
+            .is_err());
+        assert!(tab.send_paste("hello").is_err());
+    }
+
+    #[test]
+    fn idle_timeout_marks_dead() {
+        let mut inner = renderable_inner();
+        let start = inner.last_success;
+        assert!(!inner.check_idle_timeout(start + Duration::from_secs(3600)));
+
+        inner.idle_timeout = Some(Duration::from_secs(30));
+        assert!(!inner.check_idle_timeout(start + Duration::from_secs(29)));
+
+        // A background tab isn't expecting to hear anything
+        inner.focused.store(false, Ordering::SeqCst);
+        assert!(!inner.check_idle_timeout(start + Duration::from_secs(60)));
+        inner.focused.store(true, Ordering::SeqCst);
+
+        // Hearing from the server starts the period again
+        inner.poll_succeeded();
+        let heard = inner.last_success;
+        assert!(!inner.check_idle_timeout(heard + Duration::from_secs(29)));
+
+        assert!(inner.check_idle_timeout(heard + Duration::from_secs(30)));
+        assert_eq!(inner.dead, Some(DeadReason::IdleTimeout));
+        assert_eq!(
+            inner.dead.as_ref().unwrap().to_string(),
+            "the server stopped responding"
+        );
+        assert!(inner.check_idle_timeout(heard + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn viewport_ready_when_all_rows_held() {
+        let mut inner = renderable_inner();
+        inner.last_requested = Some(0..4);
+        let now = Instant::now();
+        assert!(!inner.viewport_ready());
+
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Dirty(Line::with_width(4)));
+        inner.put_entry(2, LineEntry::Fetching(now));
+        inner.put_entry(3, LineEntry::Stale(Line::with_width(4)));
+        assert!(!inner.viewport_ready());
+
+        inner.put_entry(2, LineEntry::Line(Line::with_width(4)));
+        assert!(!inner.viewport_ready());
+        inner.put_entry(3, LineEntry::DirtyAndFetching(Line::with_width(4), now));
+        assert!(!inner.viewport_ready());
+        inner.put_entry(
+            3,
+            LineEntry::FetchError {
+                since: now,
+                attempts: 3,
+            },
+        );
+        assert!(inner.viewport_ready());
+
+        // Rows outside of the viewport don't matter
+        inner.put_entry(10, LineEntry::Fetching(now));
+        assert!(inner.viewport_ready());
+    }
+
+    #[test]
+    fn pushed_working_dir_is_authoritative() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        let push = |url: &str| {
+            Pdu::SetWorkingDir(SetWorkingDir {
+                tab_id: 0,
+                url: url.to_string(),
+            })
+        };
+
+        tab.process_unilateral(push("file://host/tmp")).unwrap();
+        let expected = Url::parse("file://host/tmp").unwrap();
+        assert_eq!(tab.get_current_working_dir(), Some(expected.clone()));
+
+        // A malformed url leaves the directory alone
+        tab.process_unilateral(push("not a url")).unwrap();
+        assert_eq!(tab.get_current_working_dir(), Some(expected.clone()));
+
+        // Nor does a render delta that disagrees with the push
+        let renderable = tab.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        let delta = GetTabRenderChangesResponse {
+            tab_id: 0,
+            mouse_grabbed: false,
+            cursor_position: inner.cursor_position,
+            dimensions: inner.dimensions,
+            dirty_lines: vec![],
+            title: inner.title.clone(),
+            working_dir: Some(Url::parse("file://host/home").unwrap().into()),
+            bonus_lines: Vec::<(StableRowIndex, Line)>::new().into(),
+            bracketed_paste: false,
+            seqno: 1,
+            modes: TabModeState::default(),
+            delta_seq: 0,
+        };
+        assert!(inner.is_noop_delta(&delta));
+        inner.apply_changes_to_surface(delta);
+        assert_eq!(inner.working_dir, Some(expected));
+    }
+
+    #[test]
+    fn reset_discards_cached_content() {
+        let mut inner = renderable_inner();
+        inner.put_entry(0, LineEntry::Line(Line::with_width(4)));
+        inner.put_entry(1, LineEntry::Dirty(Line::with_width(4)));
+        inner.line_versions.insert(0, 3);
+        inner.cursor_line = Some((0, Line::with_width(4)));
+        inner.poll_interval = inner.max_poll_interval;
+
+        inner.discard_after_reset();
+        for row in 0..2 {
+            assert_eq!(inner.lines.peek(&row).unwrap().kind().0, "Stale");
+        }
+        assert!(inner.line_versions.is_empty());
+        assert!(inner.cursor_line.is_none());
+        assert_eq!(inner.poll_interval, inner.base_poll_interval);
+    }
+
+    #[test]
+    fn notifications_are_limited() {
+        let mut limiter = RateLimiter::new(|_| 1);
+        assert!(!admit_notification(
+            &mut limiter,
+            &None,
+            &"x".repeat(MAX_REMOTE_NOTIFICATION_SIZE + 1)
+        ));
+
+        let title = Some("build".to_string());
+        let admitted = (0..100)
+            .filter(|_| admit_notification(&mut limiter, &title, "done"))
+            .count();
+        assert!(admitted > 0);
+        assert!(admitted < 100);
+    }
+
+    #[test]
+    fn progress_follows_server() {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        let tab = ClientTab::new(&client, 0, PtySize::default(), "test");
+        let push = |progress| Pdu::SetTabProgress(SetTabProgress::new(0, progress));
+        assert_eq!(tab.get_progress(), Progress::None);
+
+        tab.process_unilateral(push(Progress::Normal(40))).unwrap();
+        assert_eq!(tab.get_progress(), Progress::Normal(40));
+        tab.process_unilateral(push(Progress::Error(40))).unwrap();
+        assert_eq!(tab.get_progress(), Progress::Error(40));
+        tab.process_unilateral(push(Progress::None)).unwrap();
+        assert_eq!(tab.get_progress(), Progress::None);
+
+        // An out of range percentage from the server is clamped
+        tab.process_unilateral(Pdu::SetTabProgress(SetTabProgress {
+            tab_id: 0,
+            state: ProgressState::Normal,
+            percent: 250,
+        }))
+        .unwrap();
+        assert_eq!(tab.get_progress(), Progress::Normal(100));
+
+        let renderable = tab.renderable.borrow();
+        let mut inner = renderable.inner.borrow_mut();
+        assert!(!inner.update_progress(Progress::Normal(100)));
+        assert!(inner.update_progress(Progress::Indeterminate));
+    }
+}
@@ -0,0 +1,403 @@
+use crate::config::ConfigHandle;
+use crate::mux::tab::TabId;
+use crate::server::client::Client;
+use crate::server::codec::SendMouseEvents;
+use crate::server::tab::InputWaiters;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use term::{MouseButton, MouseEvent, MouseEventKind};
+
+/// A mouse event along with the time at which it was queued
+#[derive(Debug, Clone, Copy)]
+struct QueuedMouseEvent {
+    event: MouseEvent,
+    queued: Instant,
+}
+
+pub struct MouseState {
+    pending: AtomicBool,
+    queue: VecDeque<QueuedMouseEvent>,
+    client: Client,
+    remote_tab_id: TabId,
+    batch_size: usize,
+    queue_limit: usize,
+    move_expiry: Duration,
+    waiters: InputWaiters,
+}
+
+/// Returns true if the event can be discarded without losing
+/// anything of semantic importance; moves and wheel scrolls can
+/// be dropped but presses and releases cannot.
+fn is_discardable(event: &MouseEvent) -> bool {
+    match event.button {
+        MouseButton::WheelUp(_)
+        | MouseButton::WheelDown(_)
+        | MouseButton::WheelLeft(_)
+        | MouseButton::WheelRight(_) => true,
+        _ => event.kind == MouseEventKind::Move,
+    }
+}
+
+impl MouseState {
+    pub fn new(
+        client: &Client,
+        remote_tab_id: TabId,
+        config: &ConfigHandle,
+        waiters: &InputWaiters,
+    ) -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            queue: VecDeque::new(),
+            client: client.clone(),
+            remote_tab_id,
+            batch_size: config.mux_mouse_event_batch_size,
+            queue_limit: config.mouse_event_queue_limit,
+            move_expiry: Duration::from_millis(config.mouse_move_expiry_ms),
+            waiters: waiters.clone(),
+        }
+    }
+
+    /// Returns true if there are no events queued or in flight
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && !self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn append(&mut self, event: MouseEvent) {
+        self.append_at(event, Instant::now());
+    }
+
+    fn append_at(&mut self, event: MouseEvent, now: Instant) {
+        if let Some(last) = self.queue.back_mut() {
+            if last.event.modifiers == event.modifiers {
+                if last.event.kind == MouseEventKind::Move
+                    && event.kind == MouseEventKind::Move
+                    && last.event.button == event.button
+                {
+                    // Collapse any interim moves and just buffer up
+                    // the last of them
+                    last.event = event;
+                    last.queued = now;
+                    return;
+                }
+
+                // Similarly, for repeated wheel scrolls, add up the deltas
+                // rather than swamping the queue
+                match (&last.event.button, &event.button) {
+                    (MouseButton::WheelUp(a), MouseButton::WheelUp(b)) => {
+                        last.event.button = MouseButton::WheelUp(a + b);
+                        return;
+                    }
+                    (MouseButton::WheelDown(a), MouseButton::WheelDown(b)) => {
+                        last.event.button = MouseButton::WheelDown(a + b);
+                        return;
+                    }
+                    (MouseButton::WheelLeft(a), MouseButton::WheelLeft(b)) => {
+                        last.event.button = MouseButton::WheelLeft(a + b);
+                        return;
+                    }
+                    (MouseButton::WheelRight(a), MouseButton::WheelRight(b)) => {
+                        last.event.button = MouseButton::WheelRight(a + b);
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.queue
+            .push_back(QueuedMouseEvent { event, queued: now });
+        log::trace!("MouseEvent {}: queued", self.queue.len());
+        self.enforce_queue_limit();
+    }
+
+    /// If the queue has grown beyond its limit, drop the oldest
+    /// discardable events until it fits.
+    fn enforce_queue_limit(&mut self) {
+        while self.queue.len() > self.queue_limit {
+            match self.queue.iter().position(|q| is_discardable(&q.event)) {
+                Some(idx) => {
+                    let dropped = self.queue.remove(idx);
+                    log::trace!(
+                        "MouseEvent queue exceeds limit {}, dropped {:?}",
+                        self.queue_limit,
+                        dropped
+                    );
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Take up to `batch_size` events from the front of the queue,
+    /// preserving their order.  Returns an empty batch if there is
+    /// already a batch in flight.
+    fn pop_batch(&mut self, now: Instant) -> Vec<QueuedMouseEvent> {
+        if self.pending.load(Ordering::SeqCst) {
+            return vec![];
+        }
+        let batch_size = self.batch_size.max(1);
+        self.take_events(now, batch_size)
+    }
+
+    /// Take up to `limit` events from the front of the queue, skipping
+    /// any moves that have been queued for longer than `move_expiry`
+    fn take_events(&mut self, now: Instant, limit: usize) -> Vec<QueuedMouseEvent> {
+        let mut batch = vec![];
+        while batch.len() < limit {
+            match self.queue.pop_front() {
+                Some(q) => {
+                    if q.event.kind == MouseEventKind::Move
+                        && now.saturating_duration_since(q.queued) > self.move_expiry
+                    {
+                        log::trace!("MouseEvent expired, skipping {:?}", q.event);
+                        continue;
+                    }
+                    batch.push(q);
+                }
+                None => break,
+            }
+        }
+        batch
+    }
+
+    /// Put a batch that failed to send back at the front of the queue
+    /// so that we don't lose its events, dropping whatever the queue
+    /// can no longer hold now that it has them back
+    fn requeue(&mut self, batch: Vec<QueuedMouseEvent>) {
+        for q in batch.into_iter().rev() {
+            self.queue.push_front(q);
+        }
+        self.enforce_queue_limit();
+    }
+
+    pub fn next(state: Rc<RefCell<Self>>) {
+        let mut mouse = state.borrow_mut();
+        let batch = mouse.pop_batch(Instant::now());
+        if batch.is_empty() {
+            return;
+        }
+        let client = mouse.client.clone();
+
+        let state = Rc::clone(&state);
+        mouse.pending.store(true, Ordering::SeqCst);
+        let remote_tab_id = mouse.remote_tab_id;
+
+        promise::spawn::spawn(async move {
+            let result = client
+                .mouse_events(SendMouseEvents {
+                    tab_id: remote_tab_id,
+                    events: batch.iter().map(|q| q.event).collect(),
+                })
+                .await;
+
+            let mut mouse = state.borrow_mut();
+            // Only clear pending once the whole batch has been
+            // acknowledged (or has failed)
+            mouse.pending.store(false, Ordering::SeqCst);
+            mouse.waiters.wake();
+            match result {
+                Ok(_) => {
+                    drop(mouse);
+                    Self::next(Rc::clone(&state));
+                }
+                Err(err) => {
+                    // We'll try again when the next mouse event arrives
+                    log::error!("failed to send {} mouse events: {}", batch.len(), err);
+                    mouse.requeue(batch);
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        });
+    }
+
+    /// Send all of the queued events in a single request and wait for
+    /// it to be acknowledged; see ClientTab::flush_mouse
+    pub async fn flush(state: Rc<RefCell<Self>>) -> anyhow::Result<()> {
+        let (client, remote_tab_id, events) = {
+            let mut mouse = state.borrow_mut();
+            let events = mouse.take_events(Instant::now(), usize::max_value());
+            (mouse.client.clone(), mouse.remote_tab_id, events)
+        };
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let result = client
+            .mouse_events(SendMouseEvents {
+                tab_id: remote_tab_id,
+                events: events.iter().map(|q| q.event).collect(),
+            })
+            .await;
+        if let Err(err) = result {
+            state.borrow_mut().requeue(events);
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::configuration;
+    use crate::server::tab::scheduler;
+    use term::KeyModifiers;
+
+    fn mouse_state() -> MouseState {
+        let mut state = MouseState::new(
+            &Client::new_for_test(0),
+            0,
+            &configuration(),
+            &InputWaiters::default(),
+        );
+        state.batch_size = 32;
+        state.queue_limit = 8;
+        state.move_expiry = Duration::from_millis(100);
+        state
+    }
+
+    fn mouse(kind: MouseEventKind, button: MouseButton) -> MouseEvent {
+        MouseEvent {
+            kind,
+            x: 0,
+            y: 0,
+            button,
+            modifiers: KeyModifiers::NONE,
+        }
+    }
+
+    #[test]
+    fn coalesce_horizontal_wheel() {
+        let mut state = mouse_state();
+        for _ in 0..10 {
+            state.append(mouse(MouseEventKind::Press, MouseButton::WheelLeft(1)));
+        }
+        assert_eq!(state.queue.len(), 1);
+        assert_eq!(state.queue[0].event.button, MouseButton::WheelLeft(10));
+    }
+
+    #[test]
+    fn queue_limit_retains_presses() {
+        let mut state = mouse_state();
+        state.append(mouse(MouseEventKind::Press, MouseButton::Left));
+        for i in 0..20 {
+            // Alternate the modifiers so that the moves don't collapse
+            let mut event = mouse(MouseEventKind::Move, MouseButton::Left);
+            event.x = i;
+            if i % 2 == 0 {
+                event.modifiers = KeyModifiers::SHIFT;
+            }
+            state.append(event);
+        }
+        state.append(mouse(MouseEventKind::Release, MouseButton::Left));
+
+        assert_eq!(state.queue.len(), state.queue_limit);
+        assert_eq!(
+            state.queue.front().unwrap().event.kind,
+            MouseEventKind::Press
+        );
+        assert_eq!(
+            state.queue.back().unwrap().event.kind,
+            MouseEventKind::Release
+        );
+        // The oldest moves were the ones that got discarded
+        assert_eq!(state.queue[1].event.x, 14);
+    }
+
+    #[test]
+    fn stale_moves_expire() {
+        let mut state = mouse_state();
+        let start = Instant::now();
+        state.append_at(mouse(MouseEventKind::Press, MouseButton::Left), start);
+        state.append_at(mouse(MouseEventKind::Move, MouseButton::Left), start);
+        state.append_at(mouse(MouseEventKind::Release, MouseButton::Left), start);
+        let mut fresh = mouse(MouseEventKind::Move, MouseButton::None);
+        fresh.x = 5;
+        state.append_at(fresh, start + Duration::from_millis(450));
+
+        let batch = state.pop_batch(start + Duration::from_millis(500));
+        let kinds: Vec<MouseEventKind> = batch.iter().map(|q| q.event.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                MouseEventKind::Press,
+                MouseEventKind::Release,
+                MouseEventKind::Move
+            ]
+        );
+        assert_eq!(batch[2].event.x, 5);
+        assert!(state.queue.is_empty());
+    }
+
+    #[test]
+    fn flush_takes_whole_queue() {
+        let mut state = mouse_state();
+        state.batch_size = 2;
+        let start = Instant::now();
+        for x in 0..5 {
+            let mut event = mouse(MouseEventKind::Press, MouseButton::Left);
+            event.x = x;
+            state.append_at(event, start);
+        }
+        state.append_at(mouse(MouseEventKind::Move, MouseButton::None), start);
+
+        // A batch in flight doesn't hold up the flush
+        state.pending.store(true, Ordering::SeqCst);
+        assert!(state.pop_batch(start).is_empty());
+
+        let events = state.take_events(start + Duration::from_millis(500), usize::max_value());
+        let xs: Vec<usize> = events.iter().map(|q| q.event.x).collect();
+        assert_eq!(xs, vec![0, 1, 2, 3, 4]);
+        assert!(state.queue.is_empty());
+        assert!(state.take_events(start, usize::max_value()).is_empty());
+    }
+
+    #[test]
+    fn failed_batch_is_requeued() {
+        scheduler::install();
+        let state = Rc::new(RefCell::new(mouse_state()));
+        state
+            .borrow_mut()
+            .append(mouse(MouseEventKind::Press, MouseButton::Left));
+        state
+            .borrow_mut()
+            .append(mouse(MouseEventKind::Release, MouseButton::Left));
+
+        MouseState::next(Rc::clone(&state));
+        assert!(state.borrow().queue.is_empty());
+        assert!(!state.borrow().is_idle());
+
+        // The test client has no server, so the request fails
+        scheduler::run_pending();
+        let mouse = state.borrow();
+        assert!(!mouse.pending.load(Ordering::SeqCst));
+        let kinds: Vec<MouseEventKind> = mouse.queue.iter().map(|q| q.event.kind).collect();
+        assert_eq!(kinds, vec![MouseEventKind::Press, MouseEventKind::Release]);
+    }
+
+    #[test]
+    fn requeue_enforces_queue_limit() {
+        let mut state = mouse_state();
+        let start = Instant::now();
+        let mut batch = vec![];
+        for x in 0..6 {
+            let mut event = mouse(MouseEventKind::Move, MouseButton::None);
+            event.x = x;
+            batch.push(QueuedMouseEvent {
+                event,
+                queued: start,
+            });
+        }
+        // More events arrived while the batch was in flight
+        for _ in 0..4 {
+            state.append_at(mouse(MouseEventKind::Press, MouseButton::Left), start);
+        }
+
+        state.requeue(batch);
+        assert_eq!(state.queue.len(), state.queue_limit);
+        let xs: Vec<usize> = state.queue.iter().take(4).map(|q| q.event.x).collect();
+        assert_eq!(xs, vec![2, 3, 4, 5]);
+    }
+}
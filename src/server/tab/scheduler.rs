@@ -0,0 +1,51 @@
+//! A scheduler for the tests, so that they can exercise the code
+//! that spawns tasks via promise::spawn.  The spawned tasks are
+//! queued until the test runs them with run_pending.
+use async_task::Task;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Once;
+
+thread_local! {
+    static QUEUE: RefCell<Option<VecDeque<Task<()>>>> = RefCell::new(None);
+}
+
+fn schedule(task: Task<()>) {
+    QUEUE.with(|queue| match queue.borrow_mut().as_mut() {
+        Some(queue) => queue.push_back(task),
+        // The task was woken on a thread other than the test that
+        // spawned it, such as by a timer.  It can only be run or
+        // dropped by the thread that spawned it, so we leak it.
+        None => std::mem::forget(task),
+    });
+}
+
+/// Install the scheduler, and a queue for the tasks spawned by
+/// the calling thread.  The tests run on threads of their own, so
+/// each test that spawns tasks needs to call this.
+pub fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        promise::spawn::set_schedulers(Box::new(schedule), Box::new(schedule));
+    });
+    QUEUE.with(|queue| {
+        queue.borrow_mut().get_or_insert_with(VecDeque::new);
+    });
+}
+
+/// Run the tasks queued by the calling thread, along with any that
+/// they spawn in turn, until there are none left.
+/// Returns the number of tasks that were run.
+pub fn run_pending() -> usize {
+    let mut count = 0;
+    loop {
+        let task = QUEUE.with(|queue| queue.borrow_mut().as_mut().and_then(VecDeque::pop_front));
+        match task {
+            Some(task) => {
+                task.run();
+                count += 1;
+            }
+            None => return count,
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use crate::mux::tab::TabId;
+use crate::server::codec::WriteToTab;
+use crate::server::domain::ClientInner;
+use crate::server::tab::InputWaiters;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The most WriteToTab requests that a TabWriter will have outstanding
+/// before try_write starts to report WouldBlock
+const MAX_WRITES_IN_FLIGHT: usize = 8;
+
+/// Buffers data written to a remote tab.  Small writes are coalesced
+/// and sent to the server as a single WriteToTab request from a
+/// background task.
+pub struct TabWriter {
+    client: Arc<ClientInner>,
+    remote_tab_id: TabId,
+    state: Rc<RefCell<TabWriterState>>,
+    max_buffer_size: usize,
+    /// When set, writes fail with PermissionDenied
+    read_only: bool,
+    /// Set by ClientTab::detach; writes fail with BrokenPipe from then on
+    detached: bool,
+    /// Shared with RenderableInner; set while the tab is dead
+    dead: Arc<AtomicBool>,
+    waiters: InputWaiters,
+}
+
+#[derive(Default)]
+struct TabWriterState {
+    buffer: Vec<u8>,
+    flush_scheduled: bool,
+    /// The number of background writes that have been sent but
+    /// not yet acknowledged by the server
+    in_flight: usize,
+    /// The error from a failed background write; it is reported by
+    /// the next call to write or flush
+    error: Option<String>,
+}
+
+impl TabWriter {
+    pub fn new(
+        client: &Arc<ClientInner>,
+        remote_tab_id: TabId,
+        max_buffer_size: usize,
+        dead: &Arc<AtomicBool>,
+        waiters: &InputWaiters,
+    ) -> Self {
+        Self {
+            client: Arc::clone(client),
+            remote_tab_id,
+            state: Rc::new(RefCell::new(TabWriterState::default())),
+            max_buffer_size,
+            read_only: false,
+            detached: false,
+            dead: Arc::clone(dead),
+            waiters: waiters.clone(),
+        }
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn set_detached(&mut self) {
+        self.detached = true;
+    }
+
+    /// Returns true if nothing is buffered or waiting on the server
+    pub fn is_idle(&self) -> bool {
+        let state = self.state.borrow();
+        state.buffer.is_empty() && !state.flush_scheduled && state.in_flight == 0
+    }
+
+    /// Fails with BrokenPipe if the tab is dead; there is no point
+    /// in sending the server data for a tab that it no longer has
+    fn check_alive(&self) -> Result<(), std::io::Error> {
+        if self.dead.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("remote tab {} is dead", self.remote_tab_id),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails if the tab is read-only or has been detached, matching
+    /// ClientTab::check_writable
+    fn check_writable(&self) -> Result<(), std::io::Error> {
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "remote tab is read-only",
+            ));
+        }
+        if self.detached {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("remote tab {} has been detached", self.remote_tab_id),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Queue data to be sent to the tab without blocking.
+    /// Fails with `ErrorKind::WouldBlock` if accepting the data would
+    /// exceed the buffer size, or if too many writes are already
+    /// waiting on the server; in that case none of the data has been
+    /// accepted and the caller should try again later, once the server
+    /// has caught up.  A prior background write failure is reported
+    /// as an error in the same way as for `write`.
+    fn try_write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        self.take_error()?;
+        self.check_alive()?;
+        self.check_writable()?;
+        {
+            let mut state = self.state.borrow_mut();
+            let would_overflow =
+                !state.buffer.is_empty() && state.buffer.len() + data.len() > self.max_buffer_size;
+            if would_overflow || state.in_flight >= MAX_WRITES_IN_FLIGHT {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            state.buffer.extend_from_slice(data);
+        }
+        self.schedule_flush();
+        Ok(data.len())
+    }
+
+    fn take_error(&self) -> Result<(), std::io::Error> {
+        match self.state.borrow_mut().error.take() {
+            Some(err) => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Arrange for the buffer to be sent on the next turn of the
+    /// main loop, so that writes made before then share a request.
+    pub fn schedule_flush(&self) {
+        if self.state.borrow().flush_scheduled {
+            return;
+        }
+        self.state.borrow_mut().flush_scheduled = true;
+
+        let client = Arc::clone(&self.client);
+        let tab_id = self.remote_tab_id;
+        let state = Rc::clone(&self.state);
+        let waiters = self.waiters.clone();
+        promise::spawn::spawn(async move {
+            let data = {
+                let mut state = state.borrow_mut();
+                state.flush_scheduled = false;
+                std::mem::replace(&mut state.buffer, vec![])
+            };
+            if data.is_empty() {
+                waiters.wake();
+                return;
+            }
+            state.borrow_mut().in_flight += 1;
+            // Polling the request sends it immediately, which keeps
+            // it ordered ahead of anything written after this point
+            let result = client
+                .client
+                .write_to_tab(WriteToTab { tab_id, data })
+                .await;
+            let mut state = state.borrow_mut();
+            state.in_flight -= 1;
+            if let Err(err) = result {
+                log::error!("write_to_tab failed: {}", err);
+                state.error.replace(err.to_string());
+            }
+            waiters.wake();
+        });
+    }
+}
+
+impl std::io::Write for TabWriter {
+    /// The GUI writes from its main thread, which mustn't wait on the
+    /// server, so data that try_write can't accept is queued anyway
+    /// rather than waiting for the buffer to drain.
+    fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        match self.try_write(data) {
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                log::trace!(
+                    "remote tab {} is behind on writes; queueing {} bytes",
+                    self.remote_tab_id,
+                    data.len()
+                );
+                self.state.borrow_mut().buffer.extend_from_slice(data);
+                self.schedule_flush();
+                Ok(data.len())
+            }
+            result => result,
+        }
+    }
+
+    /// Arrange for the buffered data to be sent without waiting for
+    /// the server to acknowledge it; ClientTab::detach waits for that.
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.take_error()?;
+        self.check_alive()?;
+        self.check_writable()?;
+        if !self.state.borrow().buffer.is_empty() {
+            self.schedule_flush();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::client::Client;
+    use crate::server::tab::scheduler;
+    use std::io::Write;
+
+    fn tab_writer(max_buffer_size: usize, dead: &Arc<AtomicBool>) -> TabWriter {
+        let client = Arc::new(ClientInner::new(0, Client::new_for_test(0)));
+        TabWriter::new(&client, 0, max_buffer_size, dead, &InputWaiters::default())
+    }
+
+    #[test]
+    fn writer_reports_background_error() {
+        scheduler::install();
+        let mut writer = tab_writer(1024, &Arc::new(AtomicBool::new(false)));
+        assert_eq!(writer.write(b"hello").unwrap(), 5);
+        assert!(!writer.is_idle());
+
+        // The test client has no server, so the background write fails
+        scheduler::run_pending();
+        assert!(writer.is_idle());
+        let err = writer.write(b"world").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        assert!(writer.state.borrow().buffer.is_empty());
+
+        // The error is only reported once, and an empty writer has
+        // nothing to wait for
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn writer_refuses_dead_tab() {
+        let dead = Arc::new(AtomicBool::new(true));
+        let mut writer = tab_writer(1024, &dead);
+
+        let err = writer.write(b"hello").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(err.to_string(), "remote tab 0 is dead");
+        assert!(writer.state.borrow().buffer.is_empty());
+        assert!(writer.flush().is_err());
+
+        // The tab may come back to life, such as after reconnecting
+        dead.store(false, Ordering::SeqCst);
+        assert!(writer.check_alive().is_ok());
+    }
+
+    #[test]
+    fn blocked_write_is_queued() {
+        scheduler::install();
+        let mut writer = tab_writer(8, &Arc::new(AtomicBool::new(false)));
+        assert_eq!(writer.write(b"hello").unwrap(), 5);
+        assert_eq!(writer.write(b"world").unwrap(), 5);
+        assert_eq!(writer.state.borrow().buffer, b"helloworld".to_vec());
+
+        // Flushing doesn't wait for the server
+        writer.flush().unwrap();
+        assert!(!writer.is_idle());
+        assert_eq!(scheduler::run_pending(), 1);
+        assert!(writer.is_idle());
+    }
+
+    #[test]
+    fn flush_refused_when_not_writable() {
+        let mut writer = tab_writer(1024, &Arc::new(AtomicBool::new(false)));
+        writer.set_read_only(true);
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        writer.set_read_only(false);
+        writer.flush().unwrap();
+        writer.set_detached();
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert!(writer.write(b"hello").is_err());
+        assert!(writer.is_idle());
+    }
+
+    #[test]
+    fn try_write_would_block() {
+        scheduler::install();
+        let mut writer = tab_writer(8, &Arc::new(AtomicBool::new(false)));
+
+        // The flush scheduled by the first write has yet to run, so
+        // the writes that follow it accumulate in the buffer
+        assert_eq!(writer.try_write(b"hello").unwrap(), 5);
+        let err = writer.try_write(b"world").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(writer.try_write(b"!").unwrap(), 1);
+        assert_eq!(writer.state.borrow().buffer, b"hello!".to_vec());
+
+        writer.state.borrow_mut().in_flight = MAX_WRITES_IN_FLIGHT;
+        let err = writer.try_write(b"!").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(writer.state.borrow().buffer, b"hello!".to_vec());
+    }
+}
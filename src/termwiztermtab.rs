@@ -132,6 +132,9 @@ impl Renderable for RenderableState {
             scrollback_rows: viewport_rows,
             physical_top: 0,
             scrollback_top: 0,
+            pixel_width: 0,
+            pixel_height: 0,
+            dpi: 0,
         }
     }
 }
@@ -28,6 +28,8 @@ pub struct Screen {
     /// config so we can access Maximum number of lines of scrollback
     config: Arc<dyn TerminalConfiguration>,
     allow_scrollback: bool,
+    /// Overrides the scrollback size from the config, if set
+    scrollback_override: Option<usize>,
 
     /// Physical, visible height of the screen (not including scrollback)
     pub physical_rows: usize,
@@ -66,6 +68,7 @@ impl Screen {
             lines,
             config: Arc::clone(config),
             allow_scrollback,
+            scrollback_override: None,
             physical_rows,
             physical_cols,
             stable_row_index_offset: 0,
@@ -73,7 +76,23 @@ impl Screen {
     }
 
     fn scrollback_size(&self) -> usize {
-        scrollback_size(&self.config, self.allow_scrollback)
+        match self.scrollback_override {
+            Some(size) if self.allow_scrollback => size,
+            _ => scrollback_size(&self.config, self.allow_scrollback),
+        }
+    }
+
+    /// Change the maximum number of lines of scrollback, overriding
+    /// the value from the config.  If the scrollback currently holds
+    /// more lines than that, the oldest lines are discarded.
+    pub fn set_scrollback_size(&mut self, size: usize) {
+        self.scrollback_override = Some(size);
+        let capacity = self.physical_rows + self.scrollback_size();
+        if self.lines.len() > capacity {
+            let excess = self.lines.len() - capacity;
+            self.lines.drain(0..excess);
+            self.stable_row_index_offset += excess;
+        }
     }
 
     fn rewrap_lines(
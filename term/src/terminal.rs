@@ -1,10 +1,46 @@
 use super::*;
 use std::sync::Arc;
+use termwiz::escape::osc::Progress;
 use termwiz::escape::parser::Parser;
 
+/// Identifies which of the system selections is being manipulated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ClipboardSelection {
+    /// The regular clipboard
+    Clipboard,
+    /// The X11/Wayland primary selection, which is typically
+    /// pasted with the middle mouse button
+    PrimarySelection,
+}
+
+impl Default for ClipboardSelection {
+    fn default() -> Self {
+        Self::Clipboard
+    }
+}
+
 pub trait Clipboard {
     fn get_contents(&self) -> anyhow::Result<String>;
     fn set_contents(&self, data: Option<String>) -> anyhow::Result<()>;
+
+    /// Set the contents of the primary selection.
+    /// The default implementation does nothing, leaving the
+    /// regular clipboard untouched.
+    fn set_primary_selection(&self, _data: Option<String>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Set the contents of the specified selection
+    fn set_selection_contents(
+        &self,
+        selection: ClipboardSelection,
+        data: Option<String>,
+    ) -> anyhow::Result<()> {
+        match selection {
+            ClipboardSelection::Clipboard => self.set_contents(data),
+            ClipboardSelection::PrimarySelection => self.set_primary_selection(data),
+        }
+    }
 }
 
 impl Clipboard for Box<dyn Clipboard> {
@@ -15,14 +51,39 @@ impl Clipboard for Box<dyn Clipboard> {
     fn set_contents(&self, data: Option<String>) -> anyhow::Result<()> {
         self.as_ref().set_contents(data)
     }
+
+    fn set_primary_selection(&self, data: Option<String>) -> anyhow::Result<()> {
+        self.as_ref().set_primary_selection(data)
+    }
 }
 
 /// Represents the host of the terminal.
 /// Provides a means for sending data to the connected pty
 pub trait TerminalHost {
     /// Returns an object that can be used to send data to the
-    /// slave end of the associated pty.
+    /// slave end of the associated pty.  The responses to queries
+    /// made by the application, such as DA and DSR, are written here.
     fn writer(&mut self) -> &mut dyn std::io::Write;
+
+    /// Called when the application changes the title via an
+    /// escape sequence
+    fn set_title(&mut self, _title: &str) {}
+
+    /// Called when the application rings the bell
+    fn bell(&mut self) {}
+
+    /// Called when the application asks to read the clipboard via
+    /// OSC 52.  The host may respond at a later time by writing an
+    /// OSC 52 sequence with the contents to the pty.
+    fn request_clipboard(&mut self) {}
+
+    /// Called when the application asks for a desktop notification
+    /// to be shown, via OSC 9 or OSC 777
+    fn show_notification(&mut self, _title: Option<&str>, _body: &str) {}
+
+    /// Called when the application reports a change in the progress
+    /// of a long running operation, via OSC 9;4
+    fn set_progress(&mut self, _progress: Progress) {}
 }
 
 pub struct Terminal {
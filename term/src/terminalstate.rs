@@ -13,7 +13,9 @@ use termwiz::escape::csi::{
     Cursor, CursorStyle, DecPrivateMode, DecPrivateModeCode, Device, Edit, EraseInDisplay,
     EraseInLine, Mode, Sgr, TerminalMode, TerminalModeCode, Window,
 };
-use termwiz::escape::osc::{ChangeColorPair, ColorOrQuery, ITermFileData, ITermProprietary};
+use termwiz::escape::osc::{
+    ChangeColorPair, ColorOrQuery, ITermFileData, ITermProprietary, Progress, Selection,
+};
 use termwiz::escape::{Action, ControlCode, Esc, EscCode, OneBased, OperatingSystemCommand, CSI};
 use termwiz::image::{ImageCell, ImageData, TextureCoordinate};
 use termwiz::surface::CursorShape;
@@ -229,6 +231,9 @@ pub struct TerminalState {
     clipboard: Option<Arc<dyn Clipboard>>,
 
     current_dir: Option<Url>,
+
+    /// The progress most recently reported by the application
+    progress: Progress,
 }
 
 fn encode_modifiers(mods: KeyModifiers) -> u8 {
@@ -309,6 +314,7 @@ impl TerminalState {
             pixel_width,
             clipboard: None,
             current_dir: None,
+            progress: Progress::None,
         }
     }
 
@@ -324,6 +330,10 @@ impl TerminalState {
         self.current_dir.as_ref()
     }
 
+    pub fn get_progress(&self) -> Progress {
+        self.progress
+    }
+
     /// Returns a copy of the palette.
     /// By default we don't keep a copy in the terminal state,
     /// preferring to take the config values from the users
@@ -338,6 +348,12 @@ impl TerminalState {
             .unwrap_or_else(|| self.config.color_palette())
     }
 
+    /// Returns the palette set by dynamic color scheme escape sequences,
+    /// or None if the palette is still that from the config.
+    pub fn palette_override(&self) -> Option<&ColorPalette> {
+        self.palette.as_ref()
+    }
+
     /// Called in response to dynamic color scheme escape sequences.
     /// Will make a copy of the palette from the config file if this
     /// is the first of these escapes we've seen.
@@ -356,9 +372,26 @@ impl TerminalState {
         &mut self.screen
     }
 
-    fn set_clipboard_contents(&self, text: Option<String>) -> anyhow::Result<()> {
+    /// Change the maximum number of lines of scrollback of the
+    /// primary screen; the alternate screen has no scrollback.
+    pub fn set_scrollback_size(&mut self, size: usize) {
+        self.screen.screen.set_scrollback_size(size);
+    }
+
+    fn set_clipboard_contents(
+        &self,
+        selection: Selection,
+        text: Option<String>,
+    ) -> anyhow::Result<()> {
         if let Some(clip) = self.clipboard.as_ref() {
-            clip.set_contents(text)?;
+            if selection.contains(Selection::PRIMARY) {
+                clip.set_primary_selection(text.clone())?;
+            }
+            // Anything other than an explicit request for just the
+            // primary selection updates the regular clipboard
+            if selection.contains(Selection::CLIPBOARD) || !selection.contains(Selection::PRIMARY) {
+                clip.set_contents(text)?;
+            }
         }
         Ok(())
     }
@@ -369,8 +402,10 @@ impl TerminalState {
         writer: &mut dyn std::io::Write,
     ) -> Result<(), Error> {
         let (report_button, key) = match event.button {
-            MouseButton::WheelUp(_) => (64, KeyCode::UpArrow),
-            MouseButton::WheelDown(_) => (65, KeyCode::DownArrow),
+            MouseButton::WheelUp(_) => (64, Some(KeyCode::UpArrow)),
+            MouseButton::WheelDown(_) => (65, Some(KeyCode::DownArrow)),
+            MouseButton::WheelLeft(_) => (66, None),
+            MouseButton::WheelRight(_) => (67, None),
             _ => bail!("unexpected mouse event {:?}", event),
         };
 
@@ -380,7 +415,9 @@ impl TerminalState {
             )?;
         } else if self.screen.is_alt_screen_active() {
             // Send cursor keys instead (equivalent to xterm's alternateScroll mode)
-            self.key_down(key, KeyModifiers::default(), writer)?;
+            if let Some(key) = key {
+                self.key_down(key, KeyModifiers::default(), writer)?;
+            }
         }
         Ok(())
     }
@@ -464,6 +501,16 @@ impl TerminalState {
                 kind: MouseEventKind::Press,
                 button: MouseButton::WheelDown(_),
                 ..
+            }
+            | MouseEvent {
+                kind: MouseEventKind::Press,
+                button: MouseButton::WheelLeft(_),
+                ..
+            }
+            | MouseEvent {
+                kind: MouseEventKind::Press,
+                button: MouseButton::WheelRight(_),
+                ..
             } => self.mouse_wheel(event, host.writer()),
             MouseEvent {
                 kind: MouseEventKind::Press,
@@ -488,12 +535,55 @@ impl TerminalState {
         self.bracketed_paste
     }
 
+    pub fn pixel_width(&self) -> usize {
+        self.pixel_width
+    }
+
+    pub fn pixel_height(&self) -> usize {
+        self.pixel_height
+    }
+
+    pub fn is_alt_screen_active(&self) -> bool {
+        self.screen.is_alt_screen_active()
+    }
+
+    pub fn is_cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn is_application_keypad(&self) -> bool {
+        self.application_keypad
+    }
+
     /// Send text to the terminal that is the result of pasting.
     /// If bracketed paste mode is enabled, the paste is enclosed
     /// in the bracketing, otherwise it is fed to the pty as-is.
+    /// Any end-of-paste markers embedded in the text are removed
+    /// so that the paste cannot terminate the bracketing early.
     pub fn send_paste(&mut self, text: &str, writer: &mut dyn std::io::Write) -> Result<(), Error> {
+        self.send_paste_chunk(text, true, true, writer)
+    }
+
+    /// Send part of a paste that is delivered in several chunks.
+    /// `first` and `last` indicate where the chunk falls in the paste,
+    /// so that the bracketed paste markers surround the paste as a
+    /// whole rather than each chunk.
+    pub fn send_paste_chunk(
+        &mut self,
+        text: &str,
+        first: bool,
+        last: bool,
+        writer: &mut dyn std::io::Write,
+    ) -> Result<(), Error> {
         if self.bracketed_paste {
-            let buf = format!("\x1b[200~{}\x1b[201~", text);
+            let mut buf = String::new();
+            if first {
+                buf.push_str("\x1b[200~");
+            }
+            buf.push_str(&text.replace("\x1b[201~", ""));
+            if last {
+                buf.push_str("\x1b[201~");
+            }
             writer.write_all(buf.as_bytes())?;
         } else {
             writer.write_all(text.as_bytes())?;
@@ -774,6 +864,37 @@ impl TerminalState {
         }
     }
 
+    /// Perform a full reset (RIS): return to the primary screen with
+    /// it cleared and restore the modes, attributes and palette to their
+    /// initial state.  The title and the scrollback are retained.
+    pub fn full_reset(&mut self) {
+        let rows = self.screen().physical_rows;
+        let cols = self.screen().physical_cols;
+        if self.screen.is_alt_screen_active() {
+            self.screen.activate_primary_screen();
+        }
+        self.screen.saved_cursor = None;
+        self.screen.alt_saved_cursor = None;
+        self.pen = CellAttributes::default();
+        self.cursor = CursorPosition::default();
+        self.scroll_region = 0..rows as VisibleRowIndex;
+        self.wrap_next = false;
+        self.insert = false;
+        self.dec_auto_wrap = true;
+        self.dec_origin_mode = false;
+        self.application_cursor_keys = false;
+        self.application_keypad = false;
+        self.bracketed_paste = false;
+        self.sgr_mouse = false;
+        self.button_event_mouse = false;
+        self.current_mouse_button = MouseButton::None;
+        self.cursor_visible = true;
+        self.dec_line_drawing_mode = false;
+        self.tabs = TabStop::new(cols, 8);
+        self.palette = None;
+        self.erase_in_display(EraseInDisplay::EraseDisplay);
+    }
+
     /// Returns the 0-based cursor position relative to the top left of
     /// the visible screen
     pub fn cursor_pos(&self) -> CursorPosition {
@@ -1037,17 +1158,28 @@ impl TerminalState {
                 // TODO: see https://vt100.net/docs/vt510-rm/DECSTR.html
             }
             Device::RequestPrimaryDeviceAttributes => {
-                host.writer().write(DEVICE_IDENT).ok();
+                Self::send_response(host, DEVICE_IDENT);
             }
             Device::RequestSecondaryDeviceAttributes => {
-                host.writer().write(b"\x1b[>0;0;0c").ok();
+                Self::send_response(host, b"\x1b[>0;0;0c");
             }
             Device::StatusReport => {
-                host.writer().write(b"\x1b[0n").ok();
+                Self::send_response(host, b"\x1b[0n");
             }
         }
     }
 
+    /// Send the response to a query back to the application.  The
+    /// application is typically blocked waiting for it, so the whole
+    /// response must be written and flushed; a short write would
+    /// leave it hanging.
+    fn send_response(host: &mut dyn TerminalHost, response: &[u8]) {
+        let writer = host.writer();
+        if let Err(err) = writer.write_all(response).and_then(|_| writer.flush()) {
+            error!("failed to send response {:?}: {}", response, err);
+        }
+    }
+
     fn perform_csi_mode(&mut self, mode: Mode) {
         match mode {
             Mode::SetDecPrivateMode(DecPrivateMode::Code(
@@ -1704,7 +1836,10 @@ impl<'a> Performer<'a> {
                 self.set_cursor_pos(&Position::Relative(-1), &Position::Relative(0));
             }
             ControlCode::HorizontalTab => self.c0_horizontal_tab(),
-            ControlCode::Bell => error!("Ding! (this is the bell)"),
+            ControlCode::Bell => {
+                error!("Ding! (this is the bell)");
+                self.host.bell();
+            }
             _ => error!("unhandled ControlCode {:?}", control),
         }
     }
@@ -1752,6 +1887,7 @@ impl<'a> Performer<'a> {
             }
             Esc::Code(EscCode::DecSaveCursorPosition) => self.save_cursor(),
             Esc::Code(EscCode::DecRestoreCursorPosition) => self.restore_cursor(),
+            Esc::Code(EscCode::FullReset) => self.full_reset(),
 
             Esc::Code(EscCode::DecScreenAlignmentDisplay) => {
                 // This one is just to make vttest happy;
@@ -1781,6 +1917,7 @@ impl<'a> Performer<'a> {
             OperatingSystemCommand::SetIconNameAndWindowTitle(title)
             | OperatingSystemCommand::SetWindowTitle(title) => {
                 self.title = title.clone();
+                self.host.set_title(&title);
             }
             OperatingSystemCommand::SetIconName(_) => {}
             OperatingSystemCommand::SetHyperlink(link) => {
@@ -1795,12 +1932,14 @@ impl<'a> Performer<'a> {
                 error!("{}", output);
             }
 
-            OperatingSystemCommand::ClearSelection(_) => {
-                self.set_clipboard_contents(None).ok();
+            OperatingSystemCommand::ClearSelection(selection) => {
+                self.set_clipboard_contents(selection, None).ok();
             }
-            OperatingSystemCommand::QuerySelection(_) => {}
-            OperatingSystemCommand::SetSelection(_, selection_data) => {
-                match self.set_clipboard_contents(Some(selection_data)) {
+            OperatingSystemCommand::QuerySelection(_) => {
+                self.host.request_clipboard();
+            }
+            OperatingSystemCommand::SetSelection(selection, selection_data) => {
+                match self.set_clipboard_contents(selection, Some(selection_data)) {
                     Ok(_) => (),
                     Err(err) => error!("failed to set clipboard in response to OSC 52: {:?}", err),
                 }
@@ -1810,7 +1949,22 @@ impl<'a> Performer<'a> {
                 _ => error!("unhandled iterm2: {:?}", iterm),
             },
             OperatingSystemCommand::SystemNotification(message) => {
-                error!("Application sends SystemNotification: {}", message);
+                self.host.show_notification(None, &message);
+            }
+            OperatingSystemCommand::SetProgress(progress) => {
+                if progress != self.progress {
+                    self.progress = progress;
+                    self.host.set_progress(progress);
+                }
+            }
+            OperatingSystemCommand::RxvtExtension(params) => {
+                if params.len() >= 3 && params[0] == "notify" {
+                    // The body may itself contain semicolons
+                    let body = params[2..].join(";");
+                    self.host.show_notification(Some(&params[1]), &body);
+                } else {
+                    error!("unhandled OSC 777: {:?}", params);
+                }
             }
             OperatingSystemCommand::CurrentWorkingDirectory(url) => {
                 self.current_dir = Url::parse(&url).ok();
@@ -619,6 +619,22 @@ fn test_scrollup() {
     assert_eq!(term.screen().visible_row_to_stable_row(0), 7);
 }
 
+#[test]
+fn test_set_scrollback_size() {
+    let mut term = TestTerm::new(2, 1, 4);
+    term.print("1\n2\n3\n4\n5\n");
+    assert_all_contents(&term, file!(), line!(), &["1", "2", "3", "4", "5", " "]);
+
+    term.set_scrollback_size(1);
+    assert_all_contents(&term, file!(), line!(), &["4", "5", " "]);
+    assert_eq!(term.screen().visible_row_to_stable_row(0), 4);
+    assert_eq!(term.screen().phys_to_stable_row_index(0), 3);
+
+    term.print("6\n");
+    assert_all_contents(&term, file!(), line!(), &["5", "6", " "]);
+    assert_eq!(term.screen().visible_row_to_stable_row(0), 5);
+}
+
 #[test]
 fn test_scroll_margins() {
     let mut term = TestTerm::new(3, 1, 10);
@@ -747,3 +763,72 @@ fn test_hyperlinks() {
         Compare::TEXT | Compare::ATTRS,
     );
 }
+
+#[test]
+fn test_bracketed_paste() {
+    let mut term = TestTerm::new(3, 10, 0);
+    let mut out = vec![];
+    term.send_paste("ls\n", &mut out).unwrap();
+    assert_eq!(out, b"ls\n");
+
+    term.set_mode("?2004", true);
+    assert!(term.bracketed_paste_enabled());
+    out.clear();
+    term.send_paste("ls\x1b[201~rm\n", &mut out).unwrap();
+    assert_eq!(out, b"\x1b[200~lsrm\n\x1b[201~".to_vec());
+
+    out.clear();
+    term.send_paste_chunk("l", true, false, &mut out).unwrap();
+    term.send_paste_chunk("s", false, false, &mut out).unwrap();
+    term.send_paste_chunk("\n", false, true, &mut out).unwrap();
+    assert_eq!(out, b"\x1b[200~ls\n\x1b[201~".to_vec());
+}
+
+#[test]
+fn test_full_reset() {
+    let mut term = TestTerm::new(3, 4, 0);
+    term.print("hey");
+    term.set_mode("?1049", true);
+    term.set_mode("?2004", true);
+    term.print("\x1b[7malt");
+    assert!(term.is_alt_screen_active());
+
+    term.print("\x1bc");
+    assert!(!term.is_alt_screen_active());
+    assert!(!term.bracketed_paste_enabled());
+    term.assert_cursor_pos(0, 0, Some("RIS homes the cursor"));
+    assert_visible_contents(&term, file!(), line!(), &["    ", "    ", "    "]);
+
+    // The pen is back to the default attributes
+    term.print("ok");
+    assert_visible_contents(&term, file!(), line!(), &["ok  ", "    ", "    "]);
+    assert_eq!(
+        *term.screen().visible_lines()[0].cells()[0].attrs(),
+        CellAttributes::default()
+    );
+}
+
+#[test]
+fn test_device_query_responses() {
+    struct RecordingHost {
+        output: Vec<u8>,
+    }
+    impl TerminalHost for RecordingHost {
+        fn writer(&mut self) -> &mut dyn std::io::Write {
+            &mut self.output
+        }
+    }
+
+    let mut term = TestTerm::new(3, 4, 0);
+    let mut host = RecordingHost { output: vec![] };
+    term.term.advance_bytes("\x1b[c", &mut host);
+    assert_eq!(host.output, DEVICE_IDENT.to_vec());
+
+    host.output.clear();
+    term.term.advance_bytes("\x1b[>c", &mut host);
+    assert_eq!(host.output, b"\x1b[>0;0;0c".to_vec());
+
+    host.output.clear();
+    term.term.advance_bytes("\x1b[5n", &mut host);
+    assert_eq!(host.output, b"\x1b[0n".to_vec());
+}
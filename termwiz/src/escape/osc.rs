@@ -35,7 +35,11 @@ pub enum OperatingSystemCommand {
     QuerySelection(Selection),
     SetSelection(Selection, String),
     SystemNotification(String),
+    /// ConEmu style progress report; `9;4;state;percent`
+    SetProgress(Progress),
     ITermProprietary(ITermProprietary),
+    /// OSC 777, used by urxvt extensions; eg: `777;notify;title;body`
+    RxvtExtension(Vec<String>),
     ChangeColorNumber(Vec<ChangeColorPair>),
     ChangeDynamicColors(DynamicColorNumber, Vec<ColorOrQuery>),
     CurrentWorkingDirectory(String),
@@ -44,6 +48,33 @@ pub enum OperatingSystemCommand {
     Unspecified(Vec<Vec<u8>>),
 }
 
+/// The progress of a long running operation, as reported by the
+/// application via OSC 9;4.  The percentages are in the range 0-100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    None,
+    Normal(u8),
+    Error(u8),
+    Indeterminate,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress::None
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Progress::None => write!(f, "0"),
+            Progress::Normal(pct) => write!(f, "1;{}", pct),
+            Progress::Error(pct) => write!(f, "2;{}", pct),
+            Progress::Indeterminate => write!(f, "3"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
 #[repr(u8)]
 pub enum DynamicColorNumber {
@@ -235,6 +266,26 @@ impl OperatingSystemCommand {
         ))
     }
 
+    fn parse_progress(osc: &[&[u8]]) -> anyhow::Result<Self> {
+        let state: u8 = str::from_utf8(osc[2])?.parse()?;
+        let percent = || -> anyhow::Result<u8> {
+            match osc.get(3) {
+                Some(pct) if !pct.is_empty() => {
+                    Ok(str::from_utf8(pct)?.parse::<u16>()?.min(100) as u8)
+                }
+                _ => Ok(0),
+            }
+        };
+        let progress = match state {
+            0 => Progress::None,
+            1 => Progress::Normal(percent()?),
+            2 => Progress::Error(percent()?),
+            3 => Progress::Indeterminate,
+            _ => bail!("unknown progress state {}", state),
+        };
+        Ok(OperatingSystemCommand::SetProgress(progress))
+    }
+
     fn internal_parse(osc: &[&[u8]]) -> anyhow::Result<Self> {
         ensure!(!osc.is_empty(), "no params");
         let p1str = String::from_utf8_lossy(osc[0]);
@@ -260,11 +311,19 @@ impl OperatingSystemCommand {
             SetIconName => single_string!(SetIconName),
             SetHyperlink => Ok(OperatingSystemCommand::SetHyperlink(Hyperlink::parse(osc)?)),
             ManipulateSelectionData => Self::parse_selection(osc),
+            SystemNotification if osc.len() > 2 && osc[1] == b"4" => Self::parse_progress(osc),
             SystemNotification => single_string!(SystemNotification),
             SetCurrentWorkingDirectory => single_string!(CurrentWorkingDirectory),
             ITermProprietary => {
                 self::ITermProprietary::parse(osc).map(OperatingSystemCommand::ITermProprietary)
             }
+            RxvtProprietary => {
+                let params = osc[1..]
+                    .iter()
+                    .map(|p| String::from_utf8(p.to_vec()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(OperatingSystemCommand::RxvtExtension(params))
+            }
             ChangeColorNumber => Self::parse_change_color_number(osc),
             ResetColors => Self::parse_reset_colors(osc),
 
@@ -348,7 +407,9 @@ impl Display for OperatingSystemCommand {
             QuerySelection(s) => write!(f, "52;{};?", s)?,
             SetSelection(s, val) => write!(f, "52;{};{}", s, base64::encode(val))?,
             SystemNotification(s) => write!(f, "9;{}", s)?,
+            SetProgress(progress) => write!(f, "9;4;{}", progress)?,
             ITermProprietary(i) => i.fmt(f)?,
+            RxvtExtension(params) => write!(f, "777;{}", params.join(";"))?,
             ResetColors(colors) => {
                 write!(f, "104")?;
                 for c in colors {
@@ -1066,4 +1127,43 @@ mod test {
             )))
         );
     }
+
+    #[test]
+    fn rxvt() {
+        assert_eq!(
+            parse(
+                &["777", "notify", "title", "the body"],
+                "\x1b]777;notify;title;the body\x07"
+            ),
+            OperatingSystemCommand::RxvtExtension(vec![
+                "notify".into(),
+                "title".into(),
+                "the body".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn progress() {
+        assert_eq!(
+            parse(&["9", "4", "1", "42"], "\x1b]9;4;1;42\x07"),
+            OperatingSystemCommand::SetProgress(Progress::Normal(42))
+        );
+        assert_eq!(
+            parse(&["9", "4", "2", "300"], "\x1b]9;4;2;100\x07"),
+            OperatingSystemCommand::SetProgress(Progress::Error(100))
+        );
+        assert_eq!(
+            parse(&["9", "4", "3"], "\x1b]9;4;3\x07"),
+            OperatingSystemCommand::SetProgress(Progress::Indeterminate)
+        );
+        assert_eq!(
+            parse(&["9", "4", "0"], "\x1b]9;4;0\x07"),
+            OperatingSystemCommand::SetProgress(Progress::None)
+        );
+        assert_eq!(
+            parse(&["9", "hello"], "\x1b]9;hello\x07"),
+            OperatingSystemCommand::SystemNotification("hello".into())
+        );
+    }
 }
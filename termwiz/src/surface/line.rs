@@ -61,6 +61,15 @@ impl Line {
         }
     }
 
+    /// Construct a line holding the specified cells
+    pub fn from_cells(cells: Vec<Cell>) -> Line {
+        let mut bits = LineBits::DIRTY;
+        if cells.iter().any(|cell| cell.attrs().hyperlink.is_some()) {
+            bits |= LineBits::HAS_HYPERLINK;
+        }
+        Line { cells, bits }
+    }
+
     pub fn from_text_with_wrapped_last_col(s: &str, attrs: &CellAttributes) -> Line {
         let mut line = Self::from_text(s, attrs);
         line.cells
@@ -180,32 +189,41 @@ impl Line {
             return;
         }
 
-        // FIXME: let's build a string and a byte-to-cell map here, and
-        // use this as an opportunity to rebuild HAS_HYPERLINK, skip matching
-        // cells with existing non-implicit hyperlinks, and avoid matching
-        // text with zero-width cells.
-        let line = self.as_str();
+        // Build the text along with a map from byte offsets to the
+        // cells that produced them, so that matches land on the right
+        // cells even when the line holds wide or multi-codepoint cells.
+        let mut line = String::new();
+        let mut byte_to_cell = vec![];
+        for (idx, cell) in self.visible_cells() {
+            let s = cell.str();
+            line.push_str(s);
+            byte_to_cell.extend(std::iter::repeat(idx).take(s.len()));
+        }
         self.bits |= LineBits::SCANNED_IMPLICIT_HYPERLINKS;
         self.bits &= !LineBits::HAS_IMPLICIT_HYPERLINKS;
 
         for m in Rule::match_hyperlinks(&line, rules) {
-            // The capture range is measured in bytes but we need to translate
-            // that to the char index of the column.
-            for (cell_idx, (byte_idx, _char)) in line.char_indices().enumerate() {
-                if self.cells[cell_idx].attrs().hyperlink.is_some() {
-                    // Don't replace existing links
-                    continue;
-                }
-                if m.range.contains(&byte_idx) {
-                    let attrs = self.cells[cell_idx]
-                        .attrs()
-                        .clone()
-                        .set_hyperlink(Some(Arc::clone(&m.link)))
-                        .clone();
-                    let cell = Cell::new_grapheme(self.cells[cell_idx].str(), attrs);
-                    self.cells[cell_idx] = cell;
-                    self.bits |= LineBits::HAS_IMPLICIT_HYPERLINKS;
-                }
+            let mut cells: Vec<usize> = byte_to_cell[m.range.clone()].to_vec();
+            cells.dedup();
+            // Text that already carries a link, such as one set
+            // explicitly via OSC 8, is left alone; we don't link
+            // the remainder of the match either, so that the text
+            // isn't annotated with two different links.
+            if cells
+                .iter()
+                .any(|&idx| self.cells[idx].attrs().hyperlink.is_some())
+            {
+                continue;
+            }
+            for cell_idx in cells {
+                let attrs = self.cells[cell_idx]
+                    .attrs()
+                    .clone()
+                    .set_hyperlink(Some(Arc::clone(&m.link)))
+                    .clone();
+                let cell = Cell::new_grapheme(self.cells[cell_idx].str(), attrs);
+                self.cells[cell_idx] = cell;
+                self.bits |= LineBits::HAS_IMPLICIT_HYPERLINKS;
             }
         }
     }